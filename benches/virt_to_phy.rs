@@ -1,5 +1,6 @@
 use blue_rdma_driver::bench_wrappers::{
-    virt_to_phy_bench_range_wrapper, virt_to_phy_bench_wrapper,
+    pagemap_bench_range_wrapper, virt_to_phy_bench_range_wrapper, virt_to_phy_bench_wrapper,
+    PagemapBench,
 };
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
 
@@ -25,15 +26,36 @@ fn benchmark_virt_to_phy_range_batch(c: &mut Criterion) {
     let data: Vec<Vec<u8>> = (0..100).map(|_| vec![0u8; 4096]).collect();
     let start = data[0].as_ptr();
 
-    c.bench_function("virt_to_phy 100 addresses", |b| {
+    c.bench_function("virt_to_phy_range 100 pages", |b| {
         b.iter(|| virt_to_phy_bench_range_wrapper(start, 100))
     });
+    c.bench_function("pagemap 100 pages", |b| {
+        b.iter(|| pagemap_bench_range_wrapper(start, 100))
+    });
+}
+
+fn benchmark_large_region(c: &mut Criterion) {
+    const NUM_PAGES: usize = 16384;
+    let data: Vec<u8> = vec![1u8; NUM_PAGES * 4096];
+    let start = data.as_ptr();
+    let pagemap = PagemapBench::open().unwrap();
+
+    let mut group = c.benchmark_group("64 MiB region");
+    group.sample_size(10);
+    group.bench_function("virt_to_phy_range", |b| {
+        b.iter(|| virt_to_phy_bench_range_wrapper(black_box(start), NUM_PAGES))
+    });
+    group.bench_function("pagemap", |b| {
+        b.iter(|| pagemap.range(black_box(start), NUM_PAGES))
+    });
+    group.finish();
 }
 
 criterion_group!(
     benches,
     benchmark_virt_to_phy_batch,
     benchmark_virt_to_phy_single,
-    benchmark_virt_to_phy_range_batch
+    benchmark_virt_to_phy_range_batch,
+    benchmark_large_region
 );
 criterion_main!(benches);
@@ -7,12 +7,22 @@ use pnet::{
         ip::IpNextHeaderProtocols,
         ipv4::{Ipv4Flags, MutableIpv4Packet},
         udp::MutableUdpPacket,
+        vlan::{ClassOfService, MutableVlanPacket},
     },
     util::MacAddr,
 };
 use tracing::error;
 
-use crate::{constants::PSN_MASK, device_protocol::FrameTx, qp::QueuePairAttrTable, utils::Psn};
+use crate::{
+    affinity::{CpuAffinity, WorkerClass},
+    constants::PSN_MASK,
+    device_protocol::FrameTx,
+    metrics::Metrics,
+    neigh::to_pnet,
+    net::config::{MacAddress, NetworkConfig, SharedNetworkConfig},
+    qp::{QueuePairAttr, QueuePairAttrTable},
+    utils::Psn,
+};
 
 #[derive(Debug)]
 pub(crate) enum AckResponse {
@@ -26,53 +36,82 @@ pub(crate) enum AckResponse {
         base_psn: Psn,
         ack_req_packet_psn: Psn,
     },
+    /// Congestion Notification Packet, sent to the peer of the QP on receiving ECN marked
+    /// packets
+    Cnp {
+        qpn: u32,
+    },
 }
 
 impl AckResponse {
     fn qpn(&self) -> u32 {
         match *self {
-            AckResponse::Ack { qpn, .. } | AckResponse::Nak { qpn, .. } => qpn,
+            AckResponse::Ack { qpn, .. }
+            | AckResponse::Nak { qpn, .. }
+            | AckResponse::Cnp { qpn } => qpn,
         }
     }
 }
 
 pub(crate) struct AckResponder {
     qp_table: QueuePairAttrTable,
+    /// Local addresses of the frames
+    network: SharedNetworkConfig,
     rx: flume::Receiver<AckResponse>,
     raw_frame_tx: Box<dyn FrameTx + Send + 'static>,
+    metrics: Metrics,
 }
 
 impl AckResponder {
     pub(crate) fn new(
         qp_table: QueuePairAttrTable,
+        network: SharedNetworkConfig,
         rx: flume::Receiver<AckResponse>,
         raw_frame_tx: Box<dyn FrameTx + Send + 'static>,
+        metrics: Metrics,
     ) -> Self {
         Self {
             qp_table,
+            network,
             rx,
             raw_frame_tx,
+            metrics,
         }
     }
 
-    pub(crate) fn spawn(self) {
+    pub(crate) fn spawn(self, affinity: CpuAffinity) {
         let _handle = std::thread::Builder::new()
             .name("ack-responder-worker".into())
-            .spawn(move || self.run())
+            .spawn(move || {
+                affinity.apply(WorkerClass::AckResponder, 0);
+                self.run();
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
     }
 
     fn run(mut self) {
         const NUM_BITS_STRIDE: u8 = 16;
         while let Ok(x) = self.rx.recv() {
-            let Some(dqpn) = self.qp_table.get(x.qpn()).map(|attr| attr.dqpn) else {
+            let Some(attr) = self.qp_table.get(x.qpn()) else {
                 error!("invalid qpn");
                 continue;
             };
+            let dqpn = attr.dqpn;
+            let network = self.network.get();
+            let addrs = FrameAddrs::to_peer(&attr, &network);
+            let qos = FrameQos::of_qp(&attr, &network);
             let frame = match x {
-                AckResponse::Ack { qpn, msn, last_psn } => {
-                    AckFrameBuilder::build_ack(last_psn, u128::MAX, 0.into(), 0, dqpn, false, false)
-                }
+                AckResponse::Ack { qpn, msn, last_psn } => AckFrameBuilder::build_ack(
+                    last_psn,
+                    u128::MAX,
+                    0.into(),
+                    0,
+                    dqpn,
+                    false,
+                    false,
+                    addrs,
+                    qos,
+                ),
                 AckResponse::Nak {
                     qpn,
                     base_psn,
@@ -85,15 +124,79 @@ impl AckResponder {
                     dqpn,
                     true,
                     true,
+                    addrs,
+                    qos,
                 ),
+                AckResponse::Cnp { .. } => AckFrameBuilder::build_cnp(dqpn, addrs, qos),
             };
             if let Err(e) = self.raw_frame_tx.send(&frame) {
                 error!("failed to send ack frame");
+                continue;
+            }
+            match x {
+                AckResponse::Ack { qpn, .. } => self.metrics.record_ack_generated(qpn, false),
+                AckResponse::Nak { qpn, .. } => self.metrics.record_ack_generated(qpn, true),
+                AckResponse::Cnp { .. } => {}
             }
         }
     }
 }
 
+/// Link and network addresses of a driver built frame
+#[derive(Debug, Clone, Copy)]
+struct FrameAddrs {
+    src_mac: MacAddr,
+    dst_mac: MacAddr,
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+}
+
+impl FrameAddrs {
+    /// Returns the addresses of frames sent to the peer of a QP, the destination MAC is
+    /// the next hop resolved for the QP
+    fn to_peer(attr: &QueuePairAttr, local: &NetworkConfig) -> Self {
+        Self {
+            src_mac: to_pnet(local.mac),
+            dst_mac: to_pnet(MacAddress::from(attr.mac_addr)),
+            src_ip: local.ip.ip(),
+            dst_ip: Ipv4Addr::from_bits(attr.dqp_ip),
+        }
+    }
+}
+
+/// Marking of a driver built frame, the hardware does not mark its own packets as the
+/// QP management descriptor carries no traffic class, service level or VLAN
+#[derive(Debug, Clone, Copy)]
+struct FrameQos {
+    /// Traffic class of the QP, its upper 6 bits are the DSCP
+    traffic_class: u8,
+    /// 802.1Q tag, `None` for untagged frames
+    vlan: Option<VlanTag>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct VlanTag {
+    id: u16,
+    /// Priority code point
+    pcp: u8,
+}
+
+impl FrameQos {
+    /// Returns the marking of frames sent to the peer of a QP, a service level of 0
+    /// takes the configured priority
+    fn of_qp(attr: &QueuePairAttr, local: &NetworkConfig) -> Self {
+        let pcp = if attr.sl == 0 {
+            local.priority
+        } else {
+            attr.sl
+        };
+        Self {
+            traffic_class: attr.traffic_class,
+            vlan: local.vlan.map(|id| VlanTag { id, pcp }),
+        }
+    }
+}
+
 struct AckFrameBuilder;
 
 #[allow(
@@ -104,6 +207,7 @@ struct AckFrameBuilder;
     clippy::big_endian_bytes
 )]
 impl AckFrameBuilder {
+    #[allow(clippy::too_many_arguments)]
     fn build_ack(
         now_psn: Psn,
         now_bitmap: u128,
@@ -112,11 +216,12 @@ impl AckFrameBuilder {
         dqpn: u32,
         is_packet_loss: bool,
         is_window_slided: bool,
+        addrs: FrameAddrs,
+        qos: FrameQos,
     ) -> Vec<u8> {
         const TRANS_TYPE_RC: u8 = 0x00;
         const OPCODE_ACKNOWLEDGE: u8 = 0x11;
         const PAYLOAD_SIZE: usize = 48;
-        let mac = MacAddr::new(0xAA, 0xBB, 0xCC, 0xDD, 0xEE, 0x0A);
         let mut payload = [0u8; PAYLOAD_SIZE];
 
         let mut bth = Bth::default();
@@ -135,31 +240,64 @@ impl AckFrameBuilder {
         payload[28..44].copy_from_slice(&now_bitmap.to_be_bytes());
         payload[44..].copy_from_slice(&aeth_seg0.value.to_be_bytes());
 
-        Self::build_ethernet_frame(mac, mac, &payload)
+        Self::build_ethernet_frame(addrs, qos, &payload)
+    }
+
+    fn build_cnp(dqpn: u32, addrs: FrameAddrs, qos: FrameQos) -> Vec<u8> {
+        const TRANS_TYPE_CNP: u8 = 0x04;
+        const OPCODE_CNP: u8 = 0x01;
+        /// BTH followed by 16 reserved bytes
+        const PAYLOAD_SIZE: usize = 28;
+        let mut payload = [0u8; PAYLOAD_SIZE];
+
+        let mut bth = Bth::default();
+        bth.set_opcode(u5::from_u8(OPCODE_CNP));
+        bth.set_dqpn(u24::from_u32(dqpn));
+        bth.set_becn(true);
+        bth.set_trans_type(u3::from_u8(TRANS_TYPE_CNP));
+        payload[..12].copy_from_slice(&bth.value.to_be_bytes());
+
+        Self::build_ethernet_frame(addrs, qos, &payload)
     }
 
-    fn build_ethernet_frame(src_mac: MacAddr, dst_mac: MacAddr, payload: &[u8]) -> Vec<u8> {
-        const CARD_IP_ADDRESS: u32 = 0x1122_330A;
+    fn build_ethernet_frame(addrs: FrameAddrs, qos: FrameQos, payload: &[u8]) -> Vec<u8> {
         const UDP_PORT: u16 = 4791;
         const ETH_HEADER_LEN: usize = 14;
+        const VLAN_TAG_LEN: usize = 4;
         const IP_HEADER_LEN: usize = 20;
         const UDP_HEADER_LEN: usize = 8;
 
-        let total_len = ETH_HEADER_LEN + IP_HEADER_LEN + UDP_HEADER_LEN + payload.len();
+        let l2_header_len = match qos.vlan {
+            Some(_) => ETH_HEADER_LEN + VLAN_TAG_LEN,
+            None => ETH_HEADER_LEN,
+        };
+        let total_len = l2_header_len + IP_HEADER_LEN + UDP_HEADER_LEN + payload.len();
 
         let mut buffer = vec![0u8; total_len];
 
         let mut eth_packet = MutableEthernetPacket::new(&mut buffer)
             .unwrap_or_else(|| unreachable!("Failed to create ethernet packet"));
-        eth_packet.set_source(src_mac);
-        eth_packet.set_destination(dst_mac);
-        eth_packet.set_ethertype(EtherTypes::Ipv4);
+        eth_packet.set_source(addrs.src_mac);
+        eth_packet.set_destination(addrs.dst_mac);
+        if let Some(tag) = qos.vlan {
+            eth_packet.set_ethertype(EtherTypes::Vlan);
+            let mut vlan_packet = MutableVlanPacket::new(&mut buffer[ETH_HEADER_LEN..])
+                .unwrap_or_else(|| unreachable!("Failed to create VLAN packet"));
+            vlan_packet.set_priority_code_point(ClassOfService::new(tag.pcp & 0x7));
+            vlan_packet.set_drop_eligible_indicator(0);
+            vlan_packet.set_vlan_identifier(tag.id & 0xfff);
+            vlan_packet.set_ethertype(EtherTypes::Ipv4);
+        } else {
+            eth_packet.set_ethertype(EtherTypes::Ipv4);
+        }
 
-        let mut ipv4_packet = MutableIpv4Packet::new(&mut buffer[ETH_HEADER_LEN..])
+        let mut ipv4_packet = MutableIpv4Packet::new(&mut buffer[l2_header_len..])
             .unwrap_or_else(|| unreachable!("Failed to create IPv4 packet"));
         ipv4_packet.set_version(4);
         ipv4_packet.set_header_length(5);
-        ipv4_packet.set_dscp(0);
+        // Driver built frames are not ECN capable, only the DSCP of the traffic class is
+        // kept
+        ipv4_packet.set_dscp(qos.traffic_class >> 2);
         ipv4_packet.set_ecn(0);
         ipv4_packet.set_total_length((IP_HEADER_LEN + UDP_HEADER_LEN + payload.len()) as u16);
         ipv4_packet.set_identification(0);
@@ -167,11 +305,11 @@ impl AckFrameBuilder {
         ipv4_packet.set_fragment_offset(0);
         ipv4_packet.set_ttl(64);
         ipv4_packet.set_next_level_protocol(IpNextHeaderProtocols::Udp);
-        ipv4_packet.set_source(Ipv4Addr::from_bits(CARD_IP_ADDRESS));
-        ipv4_packet.set_destination(Ipv4Addr::from_bits(CARD_IP_ADDRESS));
+        ipv4_packet.set_source(addrs.src_ip);
+        ipv4_packet.set_destination(addrs.dst_ip);
         ipv4_packet.set_checksum(ipv4_packet.get_checksum());
 
-        let mut udp_packet = MutableUdpPacket::new(&mut buffer[ETH_HEADER_LEN + IP_HEADER_LEN..])
+        let mut udp_packet = MutableUdpPacket::new(&mut buffer[l2_header_len + IP_HEADER_LEN..])
             .unwrap_or_else(|| unreachable!("Failed to create UDP packet"));
         udp_packet.set_source(UDP_PORT);
         udp_packet.set_destination(UDP_PORT);
@@ -211,3 +349,108 @@ pub(crate) struct Bth {
     opcode: u5,
     trans_type: u3,
 }
+
+#[cfg(test)]
+mod test {
+    use ipnetwork::Ipv4Network;
+    use pnet::packet::{
+        ethernet::EthernetPacket, ipv4::Ipv4Packet, udp::UdpPacket, vlan::VlanPacket, Packet,
+    };
+
+    use super::*;
+
+    #[test]
+    fn ack_frames_are_addressed_to_the_peer() {
+        let mut local = NetworkConfig::unconfigured(MacAddress([0x02, 0, 0, 0, 0, 0x01]));
+        local.ip = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
+        let attr = QueuePairAttr {
+            dqp_ip: Ipv4Addr::new(10, 0, 1, 2).to_bits(),
+            mac_addr: MacAddress([0x02, 0, 0, 0, 0, 0x02]).into(),
+            ..Default::default()
+        };
+        let addrs = FrameAddrs::to_peer(&attr, &local);
+        let frame = AckFrameBuilder::build_ack(
+            1.into(),
+            u128::MAX,
+            0.into(),
+            0,
+            0x12,
+            false,
+            false,
+            addrs,
+            FrameQos::of_qp(&attr, &local),
+        );
+        assert_eq!(frame.len(), 14 + 20 + 8 + 48);
+
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth.get_source(), MacAddr::new(0x02, 0, 0, 0, 0, 0x01));
+        assert_eq!(eth.get_destination(), MacAddr::new(0x02, 0, 0, 0, 0, 0x02));
+        assert_eq!(eth.get_ethertype(), EtherTypes::Ipv4);
+        let ip = Ipv4Packet::new(eth.payload()).unwrap();
+        assert_eq!(ip.get_version(), 4);
+        assert_eq!(ip.get_next_level_protocol(), IpNextHeaderProtocols::Udp);
+        assert_eq!(ip.get_total_length(), 20 + 8 + 48);
+        assert_eq!(ip.get_source(), Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(ip.get_destination(), Ipv4Addr::new(10, 0, 1, 2));
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        assert_eq!(udp.get_destination(), 4791);
+        assert_eq!(udp.get_length(), 8 + 48);
+    }
+
+    #[test]
+    fn cnps_are_addressed_to_the_flow_source() {
+        let local = NetworkConfig::unconfigured(MacAddress([0x02, 0, 0, 0, 0, 0x01]));
+        let attr = QueuePairAttr {
+            dqp_ip: Ipv4Addr::new(10, 0, 1, 2).to_bits(),
+            mac_addr: MacAddress([0x02, 0, 0, 0, 0, 0x02]).into(),
+            ..Default::default()
+        };
+        let frame = AckFrameBuilder::build_cnp(
+            0x12,
+            FrameAddrs::to_peer(&attr, &local),
+            FrameQos::of_qp(&attr, &local),
+        );
+        assert_eq!(frame.len(), 14 + 20 + 8 + 28);
+
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth.get_destination(), MacAddr::new(0x02, 0, 0, 0, 0, 0x02));
+        let ip = Ipv4Packet::new(eth.payload()).unwrap();
+        assert_eq!(ip.get_destination(), Ipv4Addr::new(10, 0, 1, 2));
+        let udp = UdpPacket::new(ip.payload()).unwrap();
+        let bth = Bth::from(u96::from_be_bytes(udp.payload()[..12].try_into().unwrap()));
+        assert_eq!(bth.dqpn().value(), 0x12);
+        assert!(bth.becn());
+    }
+
+    #[test]
+    fn frames_carry_the_dscp_and_vlan_priority_of_the_qp() {
+        let mut local = NetworkConfig::unconfigured(MacAddress([0x02, 0, 0, 0, 0, 0x01]));
+        local.vlan = Some(100);
+        local.priority = 3;
+        let mut attr = QueuePairAttr {
+            dqp_ip: Ipv4Addr::new(10, 0, 1, 2).to_bits(),
+            traffic_class: 26 << 2,
+            ..Default::default()
+        };
+        let addrs = FrameAddrs::to_peer(&attr, &local);
+        let frame = AckFrameBuilder::build_cnp(0x12, addrs, FrameQos::of_qp(&attr, &local));
+        assert_eq!(frame.len(), 14 + 4 + 20 + 8 + 28);
+
+        let eth = EthernetPacket::new(&frame).unwrap();
+        assert_eq!(eth.get_ethertype(), EtherTypes::Vlan);
+        let vlan = VlanPacket::new(eth.payload()).unwrap();
+        assert_eq!(vlan.get_vlan_identifier(), 100);
+        assert_eq!(vlan.get_priority_code_point(), ClassOfService::new(3));
+        assert_eq!(vlan.get_ethertype(), EtherTypes::Ipv4);
+        let ip = Ipv4Packet::new(vlan.payload()).unwrap();
+        assert_eq!(ip.get_dscp(), 26);
+        assert_eq!(ip.get_ecn(), 0);
+        assert_eq!(ip.get_destination(), Ipv4Addr::new(10, 0, 1, 2));
+
+        attr.sl = 5;
+        let frame = AckFrameBuilder::build_cnp(0x12, addrs, FrameQos::of_qp(&attr, &local));
+        let eth = EthernetPacket::new(&frame).unwrap();
+        let vlan = VlanPacket::new(eth.payload()).unwrap();
+        assert_eq!(vlan.get_priority_code_point(), ClassOfService::new(5));
+    }
+}
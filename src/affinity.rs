@@ -0,0 +1,189 @@
+use std::{fs, io, path::Path, sync::Arc};
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+const SYSFS_NODE_PATH: &str = "/sys/devices/system/node";
+
+/// CPU core pinning for each worker class, unset classes keep the default affinity
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct AffinityConfig {
+    /// Cores for the send workers, worker `N` is pinned to `send_workers[N % len]`
+    send_workers: Option<Vec<usize>>,
+    meta_worker: Option<Vec<usize>>,
+    completion_worker: Option<Vec<usize>>,
+    timer_worker: Option<Vec<usize>>,
+    rdma_write_worker: Option<Vec<usize>>,
+    ack_responder: Option<Vec<usize>>,
+    /// Pins the unconfigured classes to the cores local to the device's NUMA node
+    numa_local: bool,
+}
+
+/// Worker thread classes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum WorkerClass {
+    Send,
+    Meta,
+    Completion,
+    Timer,
+    RdmaWrite,
+    AckResponder,
+}
+
+/// Resolved cpu sets of all worker classes
+#[derive(Debug, Clone, Default)]
+pub(crate) struct CpuAffinity {
+    inner: Arc<[Option<Vec<usize>>; 6]>,
+}
+
+impl CpuAffinity {
+    /// Resolves the affinity, `local_cpus` are the cores of the device's NUMA node
+    pub(crate) fn resolve(config: &AffinityConfig, local_cpus: Option<Vec<usize>>) -> Self {
+        let fallback = local_cpus.filter(|cpus| config.numa_local && !cpus.is_empty());
+        let pick = |cpus: &Option<Vec<usize>>| cpus.clone().or_else(|| fallback.clone());
+        Self {
+            inner: Arc::new([
+                pick(&config.send_workers),
+                pick(&config.meta_worker),
+                pick(&config.completion_worker),
+                pick(&config.timer_worker),
+                pick(&config.rdma_write_worker),
+                pick(&config.ack_responder),
+            ]),
+        }
+    }
+
+    /// Returns the cpus that the `index`th worker of the class should run on
+    pub(crate) fn cpus(&self, class: WorkerClass, index: usize) -> Option<Vec<usize>> {
+        #[allow(clippy::indexing_slicing)] // discriminant is always in range
+        let cpus = self.inner[class as usize].as_ref()?;
+        if cpus.is_empty() {
+            return None;
+        }
+        match class {
+            WorkerClass::Send => cpus.get(index % cpus.len()).map(|cpu| vec![*cpu]),
+            WorkerClass::Meta
+            | WorkerClass::Completion
+            | WorkerClass::Timer
+            | WorkerClass::RdmaWrite
+            | WorkerClass::AckResponder => Some(cpus.clone()),
+        }
+    }
+
+    /// Pins the current thread, failures are logged and otherwise ignored
+    pub(crate) fn apply(&self, class: WorkerClass, index: usize) {
+        let Some(cpus) = self.cpus(class, index) else {
+            return;
+        };
+        if let Err(err) = set_current_thread_affinity(&cpus) {
+            warn!("failed to set affinity of {class:?} worker {index} to {cpus:?}: {err}");
+        }
+    }
+}
+
+#[allow(unsafe_code)]
+fn set_current_thread_affinity(cpus: &[usize]) -> io::Result<()> {
+    // SAFETY: cpu_set_t is a plain bitmask, all zeros is a valid empty set
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for &cpu in cpus {
+        if cpu >= libc::CPU_SETSIZE as usize {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        // SAFETY: cpu is within CPU_SETSIZE
+        unsafe { libc::CPU_SET(cpu, &mut set) };
+    }
+    // SAFETY: set is a valid cpu_set_t, pid 0 refers to the calling thread
+    if unsafe { libc::sched_setaffinity(0, size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Parses a sysfs cpu list, e.g. `0-3,8,10-11`
+pub(crate) fn parse_cpulist(s: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("invalid cpu list: {s}"));
+    let mut cpus = Vec::new();
+    for range in s.trim().split(',').filter(|x| !x.is_empty()) {
+        let (start, end) = range.split_once('-').unwrap_or((range, range));
+        let start: usize = start.parse().map_err(|_err| invalid())?;
+        let end: usize = end.parse().map_err(|_err| invalid())?;
+        if start > end {
+            return Err(invalid());
+        }
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+/// Reads the NUMA node of a PCI function, returns `None` if the platform has no NUMA info
+pub(crate) fn pci_numa_node(sysfs_path: &Path) -> Option<u32> {
+    fs::read_to_string(sysfs_path.join("numa_node"))
+        .ok()?
+        .trim()
+        .parse::<i32>()
+        .ok()
+        .and_then(|node| u32::try_from(node).ok())
+}
+
+/// Reads the cpus local to a PCI function
+pub(crate) fn pci_local_cpus(sysfs_path: &Path) -> Option<Vec<usize>> {
+    let list = fs::read_to_string(sysfs_path.join("local_cpulist")).ok()?;
+    parse_cpulist(&list).ok()
+}
+
+/// Returns the NUMA node that the physical address belongs to
+pub(crate) fn phys_addr_numa_node(phys_addr: u64) -> Option<u32> {
+    let block_size = fs::read_to_string("/sys/devices/system/memory/block_size_bytes").ok()?;
+    let block_size = u64::from_str_radix(block_size.trim(), 16).ok()?;
+    let block = phys_addr.checked_div(block_size)?;
+    fs::read_dir(SYSFS_NODE_PATH)
+        .ok()?
+        .flatten()
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse::<u32>()
+                .ok()
+        })
+        .find(|node| {
+            Path::new(SYSFS_NODE_PATH)
+                .join(format!("node{node}"))
+                .join(format!("memory{block}"))
+                .exists()
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpulist_parsing() {
+        assert_eq!(
+            parse_cpulist("0-3,8,10-11\n").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpulist("5").unwrap(), [5]);
+        assert!(parse_cpulist("").unwrap().is_empty());
+        assert!(parse_cpulist("3-1").is_err());
+        assert!(parse_cpulist("a").is_err());
+    }
+
+    #[test]
+    fn resolve_with_numa_fallback() {
+        let config: AffinityConfig =
+            toml::from_str("send_workers = [2, 3]\nnuma_local = true").unwrap();
+        let affinity = CpuAffinity::resolve(&config, Some(vec![0, 1]));
+        assert_eq!(affinity.cpus(WorkerClass::Send, 0), Some(vec![2]));
+        assert_eq!(affinity.cpus(WorkerClass::Send, 3), Some(vec![3]));
+        assert_eq!(affinity.cpus(WorkerClass::Meta, 0), Some(vec![0, 1]));
+
+        let config: AffinityConfig = toml::from_str("meta_worker = [4]").unwrap();
+        let affinity = CpuAffinity::resolve(&config, Some(vec![0, 1]));
+        assert_eq!(affinity.cpus(WorkerClass::Meta, 0), Some(vec![4]));
+        assert_eq!(affinity.cpus(WorkerClass::Timer, 0), None);
+    }
+}
@@ -18,6 +18,8 @@ use std::io;
 
 use crate::mem::{
     page::{ContiguousPages, HostPageAllocator, PageAllocator},
+    page_size,
+    pagemap::PagemapResolver,
     slot_alloc::{RcSlot, SlotAlloc, SlotSize},
     virt_to_phy::{AddressResolver, PhysAddrResolverLinuxX86},
 };
@@ -40,7 +42,35 @@ pub fn virt_to_phy_bench_range_wrapper(
     num_pages: usize,
 ) -> io::Result<Vec<Option<u64>>> {
     let resolver = PhysAddrResolverLinuxX86;
-    resolver.virt_to_phys_range(start_addr as u64, num_pages)
+    resolver.virt_to_phys_range(start_addr as u64, num_pages, page_size() as u64)
+}
+
+#[inline]
+pub fn pagemap_bench_range_wrapper(
+    start_addr: *const u8,
+    num_pages: usize,
+) -> io::Result<Vec<Option<u64>>> {
+    let resolver = PagemapResolver::open()?;
+    resolver.virt_to_phys_range(start_addr as u64, num_pages, page_size() as u64)
+}
+
+/// Resolves a range with a resolver opened once, as memory registration does
+pub struct PagemapBench {
+    resolver: PagemapResolver,
+}
+
+impl PagemapBench {
+    pub fn open() -> io::Result<Self> {
+        Ok(Self {
+            resolver: PagemapResolver::open()?,
+        })
+    }
+
+    #[inline]
+    pub fn range(&self, start_addr: *const u8, num_pages: usize) -> io::Result<Vec<Option<u64>>> {
+        self.resolver
+            .virt_to_phys_range(start_addr as u64, num_pages, page_size() as u64)
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -0,0 +1,119 @@
+//! Inspects and pokes a blue-rdma device.
+//!
+//! Device commands access the CSRs directly, context commands query the introspection socket
+//! of a running context, configured by `[ctl] socket` in the device config.
+
+use std::{
+    env, fs,
+    io::{self, Read},
+    path::PathBuf,
+    process::ExitCode,
+};
+
+use blue_rdma_driver::ctl::{self, CtlDevice};
+
+const USAGE: &str = "\
+usage: bluerdma-ctl [--pci <sysfs path> | --emulated <addr>] <command>
+
+device commands:
+  csr list                      list named CSRs
+  csr read <name|addr>          read a CSR
+  csr write <name|addr> <value> write a CSR
+  rings                         dump descriptor ring base, head and tail
+  mode                          show the device mode
+  decode <ring> [file]          decode a hex dump of descriptors, reads stdin without a file
+
+context commands:
+  ctx <socket> network          show network parameters
+  ctx <socket> mode             show the mode used by the context
+  ctx <socket> qps              show the QP table
+  ctx <socket> mrs              show the MR table
+  ctx <socket> metrics          print transport counters";
+
+enum Target {
+    Pci(Option<PathBuf>),
+    Emulated(String),
+}
+
+impl Target {
+    fn open(self) -> io::Result<CtlDevice> {
+        match self {
+            Target::Pci(path) => CtlDevice::open_pci(path.as_deref()),
+            Target::Emulated(addr) => CtlDevice::open_emulated(&addr),
+        }
+    }
+}
+
+fn invalid_args() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, USAGE)
+}
+
+fn parse_u32(s: &str) -> io::Result<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+    .map_err(|_err| io::Error::new(io::ErrorKind::InvalidInput, format!("invalid value: {s}")))
+}
+
+fn run(args: &[String]) -> io::Result<()> {
+    let (target, args) = match args {
+        [flag, path, rest @ ..] if flag == "--pci" => (Target::Pci(Some(path.into())), rest),
+        [flag, addr, rest @ ..] if flag == "--emulated" => (Target::Emulated(addr.clone()), rest),
+        rest => (Target::Pci(None), rest),
+    };
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    match args.as_slice() {
+        ["csr", "list"] => {
+            for (name, addr) in ctl::csr_names() {
+                println!("{addr:#06x} {name}");
+            }
+        }
+        ["csr", "read", csr] => {
+            let value = target.open()?.read_csr(csr)?;
+            println!("{value:#010x}");
+        }
+        ["csr", "write", csr, value] => target.open()?.write_csr(csr, parse_u32(value)?)?,
+        ["rings"] => {
+            println!(
+                "{:<14} {:>18} {:>10} {:>10}",
+                "ring", "base", "head", "tail"
+            );
+            for ring in target.open()?.rings()? {
+                println!(
+                    "{:<14} {:>#18x} {:>10} {:>10}",
+                    ring.name, ring.base_addr, ring.head, ring.tail
+                );
+            }
+        }
+        ["mode"] => println!("{}", target.open()?.mode()?),
+        ["decode", ring, rest @ ..] => {
+            let dump = match rest {
+                [] => {
+                    let mut dump = String::new();
+                    let _len = io::stdin().read_to_string(&mut dump)?;
+                    dump
+                }
+                [file] => fs::read_to_string(file)?,
+                _ => return Err(invalid_args()),
+            };
+            for line in ctl::decode_hex_dump(ring, &dump)? {
+                println!("{line}");
+            }
+        }
+        ["ctx", socket, command] => print!("{}", ctl::query_context(socket, command)?),
+        _ => return Err(invalid_args()),
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{err}");
+            ExitCode::FAILURE
+        }
+    }
+}
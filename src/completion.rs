@@ -1,11 +1,12 @@
-use std::{collections::VecDeque, iter, ops::ControlFlow, sync::Arc};
+use std::{collections::VecDeque, iter, ops::ControlFlow, sync::Arc, time::Instant};
 
 use bitvec::vec::BitVec;
 use parking_lot::Mutex;
 
 use crate::{
     ack_responder::AckResponse,
-    constants::MAX_CQ_CNT,
+    affinity::{CpuAffinity, WorkerClass},
+    metrics::Metrics,
     qp::QueuePairAttrTable,
     utils::Msn,
     utils::{Psn, QpTable},
@@ -55,6 +56,7 @@ pub(crate) struct CompletionWorker {
     cq_table: CompletionQueueTable,
     qp_table: QueuePairAttrTable,
     ack_resp_tx: flume::Sender<AckResponse>,
+    metrics: Metrics,
 }
 
 impl CompletionWorker {
@@ -63,20 +65,25 @@ impl CompletionWorker {
         cq_table: CompletionQueueTable,
         qp_table: QueuePairAttrTable,
         ack_resp_tx: flume::Sender<AckResponse>,
+        metrics: Metrics,
     ) -> Self {
         Self {
             completion_rx,
-            tracker_table: QpTable::new(),
+            tracker_table: QpTable::new(qp_table.capacity()),
             cq_table,
             qp_table,
             ack_resp_tx,
+            metrics,
         }
     }
 
-    pub(crate) fn spawn(self) {
+    pub(crate) fn spawn(self, affinity: CpuAffinity) {
         let _handle = std::thread::Builder::new()
             .name("completion-worker".into())
-            .spawn(move || self.run())
+            .spawn(move || {
+                affinity.apply(WorkerClass::Completion, 0);
+                self.run();
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
     }
 
@@ -99,13 +106,20 @@ impl CompletionWorker {
                 }
                 CompletionTask::AckSend { base_psn, .. } => {
                     if let Some(send_cq) = qp_attr.send_cq.and_then(|h| self.cq_table.get_cq(h)) {
-                        tracker.ack_send(Some(base_psn), send_cq);
+                        tracker.ack_send(Some(base_psn), send_cq, &self.metrics);
                     }
                 }
                 CompletionTask::AckRecv { base_psn, .. } => {
                     let send_cq = qp_attr.send_cq.and_then(|h| self.cq_table.get_cq(h));
                     if let Some(recv_cq) = qp_attr.recv_cq.and_then(|h| self.cq_table.get_cq(h)) {
-                        tracker.ack_recv(base_psn, recv_cq, send_cq, qpn, &self.ack_resp_tx);
+                        tracker.ack_recv(
+                            base_psn,
+                            recv_cq,
+                            send_cq,
+                            qpn,
+                            &self.ack_resp_tx,
+                            &self.metrics,
+                        );
                     }
                 }
             }
@@ -157,7 +171,7 @@ impl QueuePairMessageTracker {
         }
     }
 
-    fn ack_send(&mut self, psn: Option<Psn>, send_cq: &CompletionQueue) {
+    fn ack_send(&mut self, psn: Option<Psn>, send_cq: &CompletionQueue, metrics: &Metrics) {
         if let Some(psn) = psn {
             self.send.ack(psn);
         }
@@ -170,13 +184,15 @@ impl QueuePairMessageTracker {
                         SendEventOp::SendSignaled => Completion::Send { wr_id: x.wr_id },
                         SendEventOp::ReadSignaled => unreachable!(),
                     };
-                    send_cq.push_back(completion);
+                    metrics.record_latency(x.posted_at.elapsed());
+                    send_cq.push_back(completion, metrics);
                 }
                 SendEventOp::ReadSignaled => {
                     if let Some(recv_event) = self.read_resp_queue.pop_front() {
                         let x = self.send.pop().unwrap_or_else(|| unreachable!());
                         let completion = Completion::RdmaRead { wr_id: x.wr_id };
-                        send_cq.push_back(completion);
+                        metrics.record_latency(x.posted_at.elapsed());
+                        send_cq.push_back(completion, metrics);
                     } else {
                         break;
                     }
@@ -192,13 +208,14 @@ impl QueuePairMessageTracker {
         send_cq: Option<&CompletionQueue>,
         qpn: u32,
         ack_resp_tx: &flume::Sender<AckResponse>,
+        metrics: &Metrics,
     ) {
         self.recv.ack(psn);
         while let Some(event) = self.recv.pop() {
             match event.op {
                 RecvEventOp::WriteWithImm { imm } => {
                     let completion = Completion::RecvRdmaWithImm { imm };
-                    recv_cq.push_back(completion);
+                    recv_cq.push_back(completion, metrics);
                 }
                 RecvEventOp::Recv => {
                     let x = self
@@ -209,7 +226,7 @@ impl QueuePairMessageTracker {
                         wr_id: x.wr_id,
                         imm: None,
                     };
-                    recv_cq.push_back(completion);
+                    recv_cq.push_back(completion, metrics);
                 }
                 RecvEventOp::RecvWithImm { imm } => {
                     let x = self
@@ -220,13 +237,13 @@ impl QueuePairMessageTracker {
                         wr_id: x.wr_id,
                         imm: Some(imm),
                     };
-                    recv_cq.push_back(completion);
+                    recv_cq.push_back(completion, metrics);
                 }
                 RecvEventOp::ReadResp => {
                     self.read_resp_queue.push_back(event);
                     // check if the read  completion could be updated
                     if let Some(cq) = send_cq {
-                        self.ack_send(None, cq);
+                        self.ack_send(None, cq, metrics);
                     }
                 }
                 RecvEventOp::WriteAckReq => {
@@ -309,11 +326,18 @@ pub(crate) struct SendEvent {
     op: SendEventOp,
     meta: MessageMeta,
     wr_id: u64,
+    /// Time when the work request was posted
+    posted_at: Instant,
 }
 
 impl SendEvent {
     pub(crate) fn new(op: SendEventOp, meta: MessageMeta, wr_id: u64) -> Self {
-        Self { op, meta, wr_id }
+        Self {
+            op,
+            meta,
+            wr_id,
+            posted_at: Instant::now(),
+        }
     }
 }
 
@@ -386,11 +410,9 @@ pub(crate) struct CompletionQueueTable {
 }
 
 impl CompletionQueueTable {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_cq: usize) -> Self {
         Self {
-            inner: iter::repeat_with(CompletionQueue::default)
-                .take(MAX_CQ_CNT)
-                .collect(),
+            inner: (0..max_cq as u32).map(CompletionQueue::new).collect(),
         }
     }
 
@@ -405,13 +427,21 @@ impl CompletionQueueTable {
     }
 }
 
-#[derive(Default)]
 pub(crate) struct CompletionQueue {
+    handle: u32,
     inner: Mutex<VecDeque<Completion>>,
 }
 
 impl CompletionQueue {
-    pub(crate) fn push_back(&self, event: Completion) {
+    fn new(handle: u32) -> Self {
+        Self {
+            handle,
+            inner: Mutex::default(),
+        }
+    }
+
+    pub(crate) fn push_back(&self, event: Completion, metrics: &Metrics) {
+        metrics.record_completion(self.handle);
         let mut queue = self.inner.lock();
         queue.push_back(event);
     }
@@ -459,9 +489,9 @@ pub(crate) struct CqManager {
 #[allow(clippy::as_conversions, clippy::indexing_slicing)]
 impl CqManager {
     /// Creates a new `CqManager`
-    pub(crate) fn new() -> Self {
-        let mut bitmap = BitVec::with_capacity(MAX_CQ_CNT);
-        bitmap.resize(MAX_CQ_CNT, false);
+    pub(crate) fn new(max_cq: usize) -> Self {
+        let mut bitmap = BitVec::with_capacity(max_cq);
+        bitmap.resize(max_cq, false);
         Self { bitmap }
     }
 
@@ -475,7 +505,7 @@ impl CqManager {
 
     /// Removes and returns the cq associated with the given cqN
     pub(crate) fn destroy_cq(&mut self, handle: u32) {
-        if handle as usize >= MAX_CQ_CNT {
+        if handle as usize >= self.bitmap.len() {
             return;
         }
         self.bitmap.set(handle as usize, false);
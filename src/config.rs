@@ -1,6 +1,21 @@
 use serde::{Deserialize, Serialize};
 
-use crate::{net::config::NetworkConfig, timeout_retransmit::AckTimeoutConfig};
+use crate::{
+    affinity::AffinityConfig,
+    ctl::CtlConfig,
+    dcqcn::DcqcnConfig,
+    gid::GidConfig,
+    limits::LimitsConfig,
+    metrics::MetricsConfig,
+    mtt::MrCacheConfig,
+    net::{
+        config::{MacAddress, NetworkConfig, PortConfig},
+        dhcp::DhcpConfig,
+    },
+    polling::PollingConfig,
+    protocol_impl::device::hardware::vfio::VfioConfig,
+    timeout_retransmit::AckTimeoutConfig,
+};
 
 const DEFAULT_CONFIG_PATH: &str = "/etc/bluerdma/config.toml";
 
@@ -13,20 +28,118 @@ pub(crate) enum ConfigError {
     ParseError(#[from] toml::de::Error),
 }
 
+/// Network settings of the device, a `[network.dhcp]` table selects DHCP
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum NetworkSettings {
+    /// Address, netmask and gateway obtained by DHCP
+    Dynamic {
+        mac: MacAddress,
+        dhcp: DhcpConfig,
+        /// VLAN ID of the frames built by the driver, see [`NetworkConfig::vlan`]
+        #[serde(default)]
+        vlan: Option<u16>,
+        /// Default priority code point, see [`NetworkConfig::priority`]
+        #[serde(default)]
+        priority: u8,
+    },
+    /// Statically configured
+    Static(NetworkConfig),
+}
+
+impl NetworkSettings {
+    /// Returns the configuration in effect before any lease is obtained
+    pub(crate) fn initial(&self) -> NetworkConfig {
+        match *self {
+            NetworkSettings::Dynamic {
+                mac,
+                vlan,
+                priority,
+                ..
+            } => NetworkConfig {
+                vlan,
+                priority,
+                ..NetworkConfig::unconfigured(mac)
+            },
+            NetworkSettings::Static(config) => config,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct DeviceConfig {
-    pub(crate) network: NetworkConfig,
+    pub(crate) network: NetworkSettings,
+    #[serde(default)]
+    pub(crate) port: PortConfig,
     pub(crate) ack: AckTimeoutConfig,
+    #[serde(default)]
+    pub(crate) polling: PollingConfig,
+    #[serde(default)]
+    pub(crate) affinity: AffinityConfig,
+    #[serde(default)]
+    pub(crate) metrics: MetricsConfig,
+    #[serde(default)]
+    pub(crate) ctl: CtlConfig,
+    #[serde(default)]
+    pub(crate) dcqcn: DcqcnConfig,
+    #[serde(default)]
+    pub(crate) limits: LimitsConfig,
+    #[serde(default)]
+    pub(crate) mr_cache: MrCacheConfig,
+    #[serde(default)]
+    pub(crate) vfio: VfioConfig,
+    #[serde(default)]
+    pub(crate) gid: GidConfig,
 }
 
 impl DeviceConfig {
-    pub(crate) fn network(&self) -> NetworkConfig {
+    pub(crate) fn network(&self) -> NetworkSettings {
         self.network
     }
 
+    pub(crate) fn port(&self) -> PortConfig {
+        self.port
+    }
+
     pub(crate) fn ack(&self) -> AckTimeoutConfig {
         self.ack
     }
+
+    pub(crate) fn polling(&self) -> PollingConfig {
+        self.polling
+    }
+
+    pub(crate) fn affinity(&self) -> &AffinityConfig {
+        &self.affinity
+    }
+
+    pub(crate) fn metrics(&self) -> &MetricsConfig {
+        &self.metrics
+    }
+
+    pub(crate) fn ctl(&self) -> &CtlConfig {
+        &self.ctl
+    }
+
+    pub(crate) fn dcqcn(&self) -> DcqcnConfig {
+        self.dcqcn
+    }
+
+    pub(crate) fn limits(&self) -> LimitsConfig {
+        self.limits
+    }
+
+    pub(crate) fn mr_cache(&self) -> MrCacheConfig {
+        self.mr_cache
+    }
+
+    pub(crate) fn vfio(&self) -> VfioConfig {
+        self.vfio
+    }
+
+    pub(crate) fn gid(&self) -> &GidConfig {
+        &self.gid
+    }
 }
 
 pub(crate) struct ConfigLoader;
@@ -44,3 +157,51 @@ impl ConfigLoader {
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn dhcp_table_selects_dynamic_network() {
+        let network: NetworkSettings = toml::from_str(
+            r#"
+            ip = "10.0.0.2/24"
+            gateway = "10.0.0.1"
+            mac = "02:00:00:00:00:01"
+            "#,
+        )
+        .unwrap();
+        assert!(matches!(network, NetworkSettings::Static(_)));
+
+        let network: NetworkSettings = toml::from_str(
+            r#"
+            mac = "02:00:00:00:00:01"
+            vlan = 100
+            [dhcp]
+            timeout_ms = 1000
+            "#,
+        )
+        .unwrap();
+        let mac = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+        let dhcp = toml::from_str("timeout_ms = 1000").unwrap();
+        assert_eq!(
+            network,
+            NetworkSettings::Dynamic {
+                mac,
+                dhcp,
+                vlan: Some(100),
+                priority: 0,
+            }
+        );
+        assert_eq!(network.initial().ip.ip(), std::net::Ipv4Addr::UNSPECIFIED);
+        assert_eq!(network.initial().vlan, Some(100));
+    }
+
+    #[test]
+    fn port_mtu_defaults_to_ethernet() {
+        assert_eq!(PortConfig::default().mtu(), 1500);
+        let port: PortConfig = toml::from_str("mtu = 9000").unwrap();
+        assert_eq!(port.mtu(), 9000);
+    }
+}
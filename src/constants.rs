@@ -10,11 +10,8 @@ pub(crate) const MAX_MSN_SIZE_BITS: usize = 16;
 /// Maximum size of the PSN window. This represents the maximum number outstanding PSNs.
 pub(crate) const MAX_MSN_WINDOW: usize = 1 << (MAX_MSN_SIZE_BITS - 1);
 
-pub(crate) const MAX_QP_CNT: usize = 1024;
 pub(crate) const QPN_KEY_PART_WIDTH: u32 = 8;
 pub(crate) const QPN_IDX_PART_WIDTH: u32 = 32 - QPN_KEY_PART_WIDTH;
 
-pub(crate) const MAX_CQ_CNT: usize = 1024;
-
 /// Maximum number of outstanding send work requests (WRs) that can be posted to a Queue Pair (QP).
 pub(crate) const MAX_SEND_WR: usize = 0x8000;
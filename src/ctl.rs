@@ -0,0 +1,458 @@
+#![allow(clippy::module_name_repetitions)] // exported
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs,
+    io::{self, BufRead, BufReader, Read, Write},
+    net::Ipv4Addr,
+    os::unix::net::{UnixListener, UnixStream},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+};
+
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::{
+    metrics::Metrics,
+    net::config::{MacAddress, SharedNetworkConfig},
+    protocol_impl::{
+        desc::decode::{decode_ring, parse_hex_dump, RingKind},
+        device::{
+            csr_map,
+            emulated::EmulatedDevice,
+            hardware::{PciHwDevice, SysfsPciCsrAdaptor},
+            mode::Mode,
+            ops_impl::HwDevice,
+            DeviceAdaptor,
+        },
+    },
+    qp::QueuePairAttrTable,
+};
+
+/// Introspection socket of a running context
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct CtlConfig {
+    /// Path of the Unix socket serving `bluerdma-ctl` queries
+    pub(crate) socket: Option<PathBuf>,
+}
+
+/// Direct CSR access to a device, bypassing any running context
+#[derive(Debug)]
+pub struct CtlDevice {
+    backend: Backend,
+}
+
+#[derive(Debug)]
+enum Backend {
+    Pci(SysfsPciCsrAdaptor),
+    Emulated(EmulatedDevice),
+}
+
+/// Head and tail state of a descriptor ring
+#[non_exhaustive]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RingState {
+    /// Ring name, e.g. `sq0` or `cmd_req`
+    pub name: String,
+    /// Base address programmed into the device
+    pub base_addr: u64,
+    /// Head pointer
+    pub head: u32,
+    /// Tail pointer
+    pub tail: u32,
+}
+
+impl CtlDevice {
+    /// Opens a PCI device, `sysfs_path` defaults to the first blue-rdma device found
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device could not be found or its BAR could not be mapped.
+    #[inline]
+    pub fn open_pci(sysfs_path: Option<&Path>) -> io::Result<Self> {
+        let device = match sysfs_path {
+            Some(path) => PciHwDevice::new(path),
+            None => PciHwDevice::open_default()?,
+        };
+        Ok(Self {
+            backend: Backend::Pci(device.new_adaptor()?),
+        })
+    }
+
+    /// Connects to an emulator listening on `addr`, e.g. `127.0.0.1:7701`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the address is invalid.
+    #[inline]
+    pub fn open_emulated(addr: &str) -> io::Result<Self> {
+        let _addr: std::net::SocketAddr = addr
+            .parse()
+            .map_err(|_err| io::Error::new(io::ErrorKind::InvalidInput, "invalid socket addr"))?;
+        Ok(Self {
+            backend: Backend::Emulated(EmulatedDevice::new_with_addr(addr)),
+        })
+    }
+
+    /// Reads a CSR given by name or by address
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the CSR is unknown or the read fails.
+    #[inline]
+    pub fn read_csr(&self, csr: &str) -> io::Result<u32> {
+        self.read_addr(resolve_csr(csr)?)
+    }
+
+    /// Writes a CSR given by name or by address
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the CSR is unknown or the write fails.
+    #[inline]
+    pub fn write_csr(&self, csr: &str, value: u32) -> io::Result<()> {
+        let addr = resolve_csr(csr)?;
+        match self.backend {
+            Backend::Pci(ref dev) => dev.write_csr(addr, value),
+            Backend::Emulated(ref dev) => dev.write_csr(addr, value),
+        }
+    }
+
+    /// Reads the state of all descriptor rings
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading a CSR fails.
+    #[inline]
+    pub fn rings(&self) -> io::Result<Vec<RingState>> {
+        csr_map::rings()
+            .into_iter()
+            .map(|ring| {
+                let low = self.read_addr(ring.base_addr_low)?;
+                let high = self.read_addr(ring.base_addr_high)?;
+                Ok(RingState {
+                    base_addr: (u64::from(high) << 32) | u64::from(low),
+                    head: self.read_addr(ring.head)?,
+                    tail: self.read_addr(ring.tail)?,
+                    name: ring.name,
+                })
+            })
+            .collect()
+    }
+
+    /// Reads the device mode, e.g. `100G`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the mode register fails or it holds an unknown value.
+    #[inline]
+    pub fn mode(&self) -> io::Result<&'static str> {
+        let value = self.read_addr(csr_map::DEVICE_MODE)?;
+        Mode::from_csr_value(value).map(Mode::name).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid device mode: {value}"),
+            )
+        })
+    }
+
+    fn read_addr(&self, addr: usize) -> io::Result<u32> {
+        match self.backend {
+            Backend::Pci(ref dev) => dev.read_csr(addr),
+            Backend::Emulated(ref dev) => dev.read_csr(addr),
+        }
+    }
+}
+
+/// Returns the names and addresses of all known CSRs
+#[inline]
+#[must_use]
+pub fn csr_names() -> Vec<(String, usize)> {
+    csr_map::named_csrs()
+}
+
+/// Decodes a hex dump of descriptors taken from `ring`, returning one line per descriptor.
+///
+/// `ring` is a ring name as listed by [`CtlDevice::rings`], e.g. `sq0` or `cmd_resp`. The dump
+/// must start at the first descriptor of a request, e.g. the output of `xxd -p`.
+///
+/// # Errors
+///
+/// Returns an error if the ring is unknown or the dump is malformed.
+#[inline]
+pub fn decode_hex_dump(ring: &str, dump: &str) -> io::Result<Vec<String>> {
+    let ring: RingKind = ring.parse()?;
+    let descs = parse_hex_dump(dump)?;
+    Ok(decode_ring(ring, &descs)
+        .iter()
+        .map(ToString::to_string)
+        .collect())
+}
+
+/// Resolves a CSR name, or a hex (`0x` prefixed) or decimal address
+fn resolve_csr(csr: &str) -> io::Result<usize> {
+    let addr = match csr.strip_prefix("0x") {
+        Some(hex) => usize::from_str_radix(hex, 16).ok(),
+        None => csr.parse().ok().or_else(|| csr_map::lookup(csr)),
+    };
+    addr.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("unknown csr: {csr}")))
+}
+
+/// Sends a command to the introspection socket of a running context and returns the reply.
+///
+/// Supported commands are `network`, `mode`, `qps`, `mrs` and `metrics`.
+///
+/// # Errors
+///
+/// Returns an error if the socket is not reachable.
+#[inline]
+pub fn query_context(socket: impl AsRef<Path>, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(socket)?;
+    stream.write_all(command.as_bytes())?;
+    stream.write_all(b"\n")?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    let _len = stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// A registered memory region
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct MrInfo {
+    pub(crate) addr: u64,
+    pub(crate) length: usize,
+    pub(crate) pd_handle: u32,
+    pub(crate) access: u8,
+    pub(crate) pgt_index: u32,
+    pub(crate) pgt_count: u32,
+}
+
+/// Registered memory regions indexed by MR key, shared with the introspection server
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MrTable {
+    inner: Arc<RwLock<BTreeMap<u32, MrInfo>>>,
+}
+
+impl MrTable {
+    pub(crate) fn insert(&self, mr_key: u32, info: MrInfo) {
+        let _prev = self.inner.write().insert(mr_key, info);
+    }
+
+    pub(crate) fn get(&self, mr_key: u32) -> Option<MrInfo> {
+        self.inner.read().get(&mr_key).copied()
+    }
+
+    pub(crate) fn remove(&self, mr_key: u32) {
+        let _prev = self.inner.write().remove(&mr_key);
+    }
+
+    fn list(&self) -> Vec<(u32, MrInfo)> {
+        self.inner.read().iter().map(|(k, v)| (*k, *v)).collect()
+    }
+}
+
+/// Answers `bluerdma-ctl` queries about a running context
+pub(crate) struct CtlServer {
+    network: SharedNetworkConfig,
+    mode: Mode,
+    qp_table: QueuePairAttrTable,
+    mr_table: MrTable,
+    metrics: Metrics,
+}
+
+impl CtlServer {
+    pub(crate) fn new(
+        network: SharedNetworkConfig,
+        mode: Mode,
+        qp_table: QueuePairAttrTable,
+        mr_table: MrTable,
+        metrics: Metrics,
+    ) -> Self {
+        Self {
+            network,
+            mode,
+            qp_table,
+            mr_table,
+            metrics,
+        }
+    }
+
+    /// Serves queries on the Unix socket at `path`, one command per connection
+    pub(crate) fn serve(self, path: &Path) -> io::Result<()> {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let _handle = thread::Builder::new()
+            .name("ctl-socket-worker".into())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    if let Err(err) = self.handle_connection(stream) {
+                        error!("failed to serve ctl query: {err}");
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    fn handle_connection(&self, stream: UnixStream) -> io::Result<()> {
+        let mut command = String::new();
+        let _len = BufReader::new(&stream).read_line(&mut command)?;
+        (&stream).write_all(self.handle(command.trim()).as_bytes())
+    }
+
+    fn handle(&self, command: &str) -> String {
+        let mut out = String::new();
+        match command {
+            "network" => {
+                let network = self.network.get();
+                let _ignore = writeln!(out, "ip {}", network.ip);
+                let _ignore = writeln!(out, "gateway {}", network.gateway);
+                let _ignore = writeln!(out, "mac {}", network.mac);
+                if let Some(vlan) = network.vlan {
+                    let _ignore = writeln!(out, "vlan {vlan} priority {}", network.priority);
+                }
+            }
+            "mode" => {
+                let _ignore = writeln!(out, "mode {}", self.mode.name());
+            }
+            "qps" => {
+                let _ignore = writeln!(
+                    out,
+                    "{:>10} {:>10} {:>4} {:>4} {:>6} {:>7} {:>7} {:>15} {:>17} {:>10}",
+                    "qpn",
+                    "dqpn",
+                    "type",
+                    "pmtu",
+                    "access",
+                    "send_cq",
+                    "recv_cq",
+                    "dqp_ip",
+                    "mac",
+                    "rate_kbps"
+                );
+                for attr in self.qp_table.active() {
+                    let _ignore = writeln!(
+                        out,
+                        "{:>10} {:>10} {:>4} {:>4} {:>#6x} {:>7} {:>7} {:>15} {:>17} {:>10}",
+                        attr.qpn,
+                        attr.dqpn,
+                        attr.qp_type,
+                        attr.pmtu,
+                        attr.access_flags,
+                        format_cq(attr.send_cq),
+                        format_cq(attr.recv_cq),
+                        Ipv4Addr::from_bits(attr.dqp_ip),
+                        MacAddress::from(attr.mac_addr),
+                        attr.rate_limit,
+                    );
+                }
+            }
+            "mrs" => {
+                let _ignore = writeln!(
+                    out,
+                    "{:>10} {:>18} {:>12} {:>6} {:>6} {:>9} {:>9}",
+                    "key", "addr", "length", "pd", "access", "pgt_index", "pgt_count"
+                );
+                for (key, mr) in self.mr_table.list() {
+                    let _ignore = writeln!(
+                        out,
+                        "{:>10} {:>#18x} {:>12} {:>6} {:>#6x} {:>9} {:>9}",
+                        key,
+                        mr.addr,
+                        mr.length,
+                        mr.pd_handle,
+                        mr.access,
+                        mr.pgt_index,
+                        mr.pgt_count
+                    );
+                }
+            }
+            "metrics" => out = self.metrics.snapshot().to_prometheus(),
+            _ => {
+                let _ignore = writeln!(out, "error: unknown command `{command}`");
+            }
+        }
+        out
+    }
+}
+
+fn format_cq(cq: Option<u32>) -> String {
+    cq.map_or_else(|| "-".into(), |handle| handle.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use ipnetwork::Ipv4Network;
+
+    use super::*;
+    use crate::net::config::NetworkConfig;
+
+    fn server() -> CtlServer {
+        let network = NetworkConfig {
+            ip: Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 2), 24).unwrap(),
+            gateway: Ipv4Addr::new(10, 0, 0, 1).into(),
+            mac: MacAddress([0x02, 0, 0, 0, 0, 0x01]),
+            vlan: Some(100),
+            priority: 3,
+        };
+        let qp_table = QueuePairAttrTable::new(4);
+        let mr_table = MrTable::default();
+        mr_table.insert(
+            0x100,
+            MrInfo {
+                addr: 0x7f00_0000,
+                length: 4096,
+                pd_handle: 1,
+                access: 0x7,
+                pgt_index: 0,
+                pgt_count: 1,
+            },
+        );
+        CtlServer::new(
+            SharedNetworkConfig::new(network),
+            Mode::default(),
+            qp_table,
+            mr_table,
+            Metrics::new(4, 4),
+        )
+    }
+
+    #[test]
+    fn csr_resolution() {
+        assert_eq!(resolve_csr("0x10").unwrap(), 0x10);
+        assert_eq!(resolve_csr("8").unwrap(), 8);
+        assert_eq!(
+            resolve_csr("cmd_req_head").unwrap(),
+            csr_map::lookup("cmd_req_head").unwrap()
+        );
+        assert!(resolve_csr("0xzz").is_err());
+        assert!(resolve_csr("bogus").is_err());
+    }
+
+    #[test]
+    fn server_commands() {
+        let server = server();
+        assert!(server.handle("network").contains("mac 02:00:00:00:00:01"));
+        assert!(server.handle("network").contains("vlan 100 priority 3"));
+        assert_eq!(server.handle("mode"), "mode 100G\n");
+        let mrs = server.handle("mrs");
+        assert_eq!(mrs.lines().count(), 2);
+        assert!(mrs.contains("0x7f000000"));
+        assert!(server.handle("foo").starts_with("error"));
+    }
+
+    #[test]
+    fn socket_round_trip() {
+        let path = std::env::temp_dir().join(format!("bluerdma-ctl-{}.sock", std::process::id()));
+        server().serve(&path).unwrap();
+        let reply = query_context(&path, "mode").unwrap();
+        assert_eq!(reply, "mode 100G\n");
+        fs::remove_file(path).unwrap();
+    }
+}
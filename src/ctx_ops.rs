@@ -34,6 +34,21 @@ pub unsafe trait RdmaCtxOps {
         port_attr: *mut ffi::ibv_port_attr,
     ) -> ::std::os::raw::c_int;
 
+    fn query_gid(
+        blue_context: *mut ffi::ibv_context,
+        port_num: u8,
+        index: core::ffi::c_int,
+        gid: *mut ffi::ibv_gid,
+    ) -> ::std::os::raw::c_int;
+
+    fn query_gid_ex(
+        blue_context: *mut ffi::ibv_context,
+        port_num: u32,
+        gid_index: u32,
+        entry: *mut ffi::ibv_gid_entry,
+        flags: u32,
+    ) -> ::std::os::raw::c_int;
+
     fn create_cq(
         blue_context: *mut ffi::ibv_context,
         cqe: core::ffi::c_int,
@@ -70,6 +85,15 @@ pub unsafe trait RdmaCtxOps {
 
     fn dereg_mr(mr: *mut ffi::ibv_mr) -> ::std::os::raw::c_int;
 
+    fn reg_dmabuf_mr(
+        pd: *mut ffi::ibv_pd,
+        offset: u64,
+        length: usize,
+        iova: u64,
+        fd: core::ffi::c_int,
+        access: core::ffi::c_int,
+    ) -> *mut ffi::ibv_mr;
+
     fn post_send(
         qp: *mut ffi::ibv_qp,
         wr: *mut ffi::ibv_send_wr,
@@ -0,0 +1,425 @@
+use std::{
+    iter,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::qpn_index;
+
+/// Fixed point scale of `alpha`, 1.0 is represented as `ALPHA_SCALE`
+const ALPHA_SCALE: u64 = 1024;
+/// Maximum number of timer events replayed in one update, a QP idle for longer is
+/// considered fully recovered
+const MAX_REPLAYED_EVENTS: u64 = 64;
+
+const DEFAULT_LINE_RATE_MBPS: u64 = 100_000;
+const DEFAULT_MIN_RATE_MBPS: u64 = 100;
+const DEFAULT_RATE_AI_MBPS: u64 = 40;
+const DEFAULT_RATE_HAI_MBPS: u64 = 200;
+const DEFAULT_G_SHIFT: u32 = 8;
+const DEFAULT_ALPHA_UPDATE_PERIOD_US: u64 = 55;
+const DEFAULT_RATE_INCREASE_PERIOD_US: u64 = 300;
+const DEFAULT_BYTE_COUNTER: u64 = 10 * 1024 * 1024;
+const DEFAULT_FAST_RECOVERY_STEPS: u32 = 5;
+const DEFAULT_CNP_INTERVAL_US: u64 = 50;
+
+/// DCQCN congestion control configuration
+///
+/// DCQCN is opt-in so that existing deployments keep their traffic unchanged. While it
+/// is disabled, outgoing packets are not ECN capable, received CNPs are ignored and no
+/// CNP is generated for ECN marked packets. Fabrics relying on ECN enable it in the
+/// `[dcqcn]` table of `/etc/bluerdma/config.toml`:
+///
+/// ```toml
+/// [dcqcn]
+/// enabled = true
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DcqcnConfig {
+    /// Marks outgoing packets as ECN capable, reacts to CNPs and generates CNPs for ECN
+    /// marked packets, disabled by default
+    enabled: bool,
+    /// Rate of a QP that has not seen congestion
+    line_rate_mbps: u64,
+    /// Lower bound of the rate after cuts
+    min_rate_mbps: u64,
+    /// Additive increase step
+    rate_ai_mbps: u64,
+    /// Hyper increase step
+    rate_hai_mbps: u64,
+    /// Alpha gain `g` is `1 / 2^g_shift`
+    g_shift: u32,
+    /// Period of alpha decay when no CNP is received
+    alpha_update_period_us: u64,
+    /// Period of the rate increase timer
+    rate_increase_period_us: u64,
+    /// Bytes sent between two byte counter rate increase events
+    byte_counter: u64,
+    /// Number of fast recovery stages before additive increase
+    fast_recovery_steps: u32,
+    /// Minimum interval between two CNPs generated for the same QP
+    cnp_interval_us: u64,
+}
+
+impl Default for DcqcnConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            line_rate_mbps: DEFAULT_LINE_RATE_MBPS,
+            min_rate_mbps: DEFAULT_MIN_RATE_MBPS,
+            rate_ai_mbps: DEFAULT_RATE_AI_MBPS,
+            rate_hai_mbps: DEFAULT_RATE_HAI_MBPS,
+            g_shift: DEFAULT_G_SHIFT,
+            alpha_update_period_us: DEFAULT_ALPHA_UPDATE_PERIOD_US,
+            rate_increase_period_us: DEFAULT_RATE_INCREASE_PERIOD_US,
+            byte_counter: DEFAULT_BYTE_COUNTER,
+            fast_recovery_steps: DEFAULT_FAST_RECOVERY_STEPS,
+            cnp_interval_us: DEFAULT_CNP_INTERVAL_US,
+        }
+    }
+}
+
+impl DcqcnConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    fn alpha_update_period(&self) -> Duration {
+        Duration::from_micros(self.alpha_update_period_us.max(1))
+    }
+
+    fn rate_increase_period(&self) -> Duration {
+        Duration::from_micros(self.rate_increase_period_us.max(1))
+    }
+
+    fn cnp_interval(&self) -> Duration {
+        Duration::from_micros(self.cnp_interval_us)
+    }
+}
+
+/// DCQCN reaction point, tracks the sending rate of each QP
+///
+/// A QP only has state after receiving a CNP, and drops it once the rate is
+/// recovered to the line rate.
+#[derive(Debug, Clone)]
+pub(crate) struct ReactionPoint {
+    config: DcqcnConfig,
+    table: Arc<[Mutex<Option<RateState>>]>,
+}
+
+impl ReactionPoint {
+    pub(crate) fn new(config: DcqcnConfig, max_qp: usize) -> Self {
+        Self {
+            config,
+            table: iter::repeat_with(Mutex::default).take(max_qp).collect(),
+        }
+    }
+
+    /// Cuts the rate of the QP on receiving a CNP
+    pub(crate) fn on_cnp(&self, qpn: u32) {
+        self.on_cnp_at(qpn, Instant::now());
+    }
+
+    /// Reserves a transmission slot for `bytes` of the QP.
+    ///
+    /// Returns the time the data may be sent, or `None` if it could be sent immediately.
+    pub(crate) fn reserve(&self, qpn: u32, bytes: u32) -> Option<Instant> {
+        self.reserve_at(qpn, bytes, Instant::now())
+    }
+
+    /// Returns the current rate of the QP in Mbps, `None` if not limited
+    pub(crate) fn rate_mbps(&self, qpn: u32) -> Option<u64> {
+        self.table
+            .get(qpn_index(qpn))?
+            .lock()
+            .as_ref()
+            .map(|state| state.current)
+    }
+
+    /// Clears the state of the QP
+    pub(crate) fn reset(&self, qpn: u32) {
+        if let Some(slot) = self.table.get(qpn_index(qpn)) {
+            *slot.lock() = None;
+        }
+    }
+
+    fn on_cnp_at(&self, qpn: u32, now: Instant) {
+        if !self.config.enabled {
+            return;
+        }
+        let Some(slot) = self.table.get(qpn_index(qpn)) else {
+            return;
+        };
+        let mut guard = slot.lock();
+        let state = guard.get_or_insert_with(|| RateState::new(&self.config, now));
+        state.advance(&self.config, now);
+        state.cut(&self.config, now);
+    }
+
+    fn reserve_at(&self, qpn: u32, bytes: u32, now: Instant) -> Option<Instant> {
+        let slot = self.table.get(qpn_index(qpn))?;
+        let mut guard = slot.lock();
+        let state = guard.as_mut()?;
+        state.advance(&self.config, now);
+        state.count_bytes(&self.config, bytes.into());
+        let start = state.next_send.max(now);
+        state.next_send = start + tx_time(bytes.into(), state.current);
+        if state.current >= self.config.line_rate_mbps && state.next_send <= now {
+            *guard = None;
+        }
+        (start > now).then_some(start)
+    }
+}
+
+/// Rate state of a congested QP
+#[derive(Debug)]
+struct RateState {
+    /// Current rate
+    current: u64,
+    /// Target rate
+    target: u64,
+    /// Congestion estimation, scaled by `ALPHA_SCALE`
+    alpha: u64,
+    /// Number of rate increase timer events since the last cut
+    timer_stage: u32,
+    /// Number of byte counter events since the last cut
+    byte_stage: u32,
+    /// Bytes sent since the last byte counter event
+    bytes: u64,
+    /// Whether a CNP has been received in the current alpha update period
+    cnp_in_period: bool,
+    last_alpha_update: Instant,
+    last_rate_increase: Instant,
+    /// Earliest time the next chunk may be sent
+    next_send: Instant,
+}
+
+impl RateState {
+    fn new(config: &DcqcnConfig, now: Instant) -> Self {
+        Self {
+            current: config.line_rate_mbps,
+            target: config.line_rate_mbps,
+            alpha: ALPHA_SCALE,
+            timer_stage: 0,
+            byte_stage: 0,
+            bytes: 0,
+            cnp_in_period: false,
+            last_alpha_update: now,
+            last_rate_increase: now,
+            next_send: now,
+        }
+    }
+
+    fn cut(&mut self, config: &DcqcnConfig, now: Instant) {
+        self.target = self.current;
+        self.current = (self.current * (2 * ALPHA_SCALE - self.alpha) / (2 * ALPHA_SCALE))
+            .max(config.min_rate_mbps);
+        self.alpha = self.alpha - (self.alpha >> config.g_shift) + (ALPHA_SCALE >> config.g_shift);
+        self.timer_stage = 0;
+        self.byte_stage = 0;
+        self.bytes = 0;
+        self.cnp_in_period = true;
+        self.last_alpha_update = now;
+        self.last_rate_increase = now;
+    }
+
+    /// Replays the alpha and rate increase timers up to `now`
+    fn advance(&mut self, config: &DcqcnConfig, now: Instant) {
+        let period = config.alpha_update_period();
+        let num_events = elapsed_periods(self.last_alpha_update, now, period);
+        for _ in 0..num_events.min(MAX_REPLAYED_EVENTS) {
+            if !self.cnp_in_period {
+                self.alpha -= self.alpha >> config.g_shift;
+            }
+            self.cnp_in_period = false;
+        }
+        self.last_alpha_update = skip_periods(self.last_alpha_update, now, period, num_events);
+
+        let period = config.rate_increase_period();
+        let num_events = elapsed_periods(self.last_rate_increase, now, period);
+        if num_events > MAX_REPLAYED_EVENTS {
+            self.current = config.line_rate_mbps;
+            self.target = config.line_rate_mbps;
+        } else {
+            for _ in 0..num_events {
+                self.timer_stage = self.timer_stage.saturating_add(1);
+                self.increase(config);
+            }
+        }
+        self.last_rate_increase = skip_periods(self.last_rate_increase, now, period, num_events);
+    }
+
+    fn count_bytes(&mut self, config: &DcqcnConfig, bytes: u64) {
+        self.bytes += bytes;
+        while self.bytes >= config.byte_counter.max(1) {
+            self.bytes -= config.byte_counter.max(1);
+            self.byte_stage = self.byte_stage.saturating_add(1);
+            self.increase(config);
+        }
+    }
+
+    fn increase(&mut self, config: &DcqcnConfig) {
+        let steps = config.fast_recovery_steps;
+        let max_stage = self.timer_stage.max(self.byte_stage);
+        let min_stage = self.timer_stage.min(self.byte_stage);
+        if max_stage < steps {
+            // fast recovery, only moves towards the target
+        } else if min_stage > steps {
+            let factor = u64::from(min_stage - steps);
+            self.target = self
+                .target
+                .saturating_add(factor.saturating_mul(config.rate_hai_mbps));
+        } else {
+            self.target = self.target.saturating_add(config.rate_ai_mbps);
+        }
+        self.target = self.target.min(config.line_rate_mbps);
+        self.current = (self.current + self.target).div_ceil(2);
+    }
+}
+
+/// DCQCN notification point, limits the CNPs generated for each QP
+#[derive(Debug)]
+pub(crate) struct NotificationPoint {
+    config: DcqcnConfig,
+    last_cnp: Box<[Option<Instant>]>,
+}
+
+impl NotificationPoint {
+    pub(crate) fn new(config: DcqcnConfig, max_qp: usize) -> Self {
+        Self {
+            config,
+            last_cnp: iter::repeat(None).take(max_qp).collect(),
+        }
+    }
+
+    /// Returns `true` if a CNP should be sent for an ECN marked packet received on the QP
+    pub(crate) fn should_notify(&mut self, qpn: u32) -> bool {
+        self.should_notify_at(qpn, Instant::now())
+    }
+
+    fn should_notify_at(&mut self, qpn: u32, now: Instant) -> bool {
+        if !self.config.enabled {
+            return false;
+        }
+        let Some(last) = self.last_cnp.get_mut(qpn_index(qpn)) else {
+            return false;
+        };
+        if last.is_some_and(|t| now.saturating_duration_since(t) < self.config.cnp_interval()) {
+            return false;
+        }
+        *last = Some(now);
+        true
+    }
+}
+
+/// Number of whole `period`s elapsed since `since`
+fn elapsed_periods(since: Instant, now: Instant, period: Duration) -> u64 {
+    let elapsed = now.saturating_duration_since(since).as_nanos();
+    u64::try_from(elapsed / period.as_nanos()).unwrap_or(u64::MAX)
+}
+
+/// Start time of the period after skipping `num_periods`, resynchronized to `now` after a long idle
+fn skip_periods(since: Instant, now: Instant, period: Duration, num_periods: u64) -> Instant {
+    if num_periods > MAX_REPLAYED_EVENTS {
+        return now;
+    }
+    since + period * num_periods as u32
+}
+
+/// Time to transmit `bytes` at `rate_mbps`
+fn tx_time(bytes: u64, rate_mbps: u64) -> Duration {
+    Duration::from_nanos(bytes.saturating_mul(8000) / rate_mbps.max(1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const QPN: u32 = 1 << 8;
+    const MAX_QP: usize = 4;
+
+    fn enabled() -> DcqcnConfig {
+        DcqcnConfig {
+            enabled: true,
+            ..DcqcnConfig::default()
+        }
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        let rp = ReactionPoint::new(DcqcnConfig::default(), MAX_QP);
+        rp.on_cnp_at(QPN, Instant::now());
+        assert_eq!(rp.rate_mbps(QPN), None, "CNPs are ignored");
+    }
+
+    #[test]
+    fn enabled_by_config_table() {
+        let config: DcqcnConfig = toml::from_str("enabled = true").unwrap();
+        assert_eq!(config, enabled());
+    }
+
+    #[test]
+    fn cnp_cuts_rate_and_timer_recovers() {
+        let config = enabled();
+        let rp = ReactionPoint::new(config, MAX_QP);
+        let now = Instant::now();
+        assert_eq!(rp.rate_mbps(QPN), None);
+
+        rp.on_cnp_at(QPN, now);
+        let cut = rp.rate_mbps(QPN).unwrap();
+        assert_eq!(cut, DEFAULT_LINE_RATE_MBPS / 2, "first cut halves the rate");
+
+        rp.on_cnp_at(QPN, now);
+        let second = rp.rate_mbps(QPN).unwrap();
+        assert!(second < cut, "consecutive CNPs keep cutting");
+
+        let later = now + Duration::from_micros(DEFAULT_RATE_INCREASE_PERIOD_US * 3);
+        let _ignore = rp.reserve_at(QPN, 0, later);
+        let recovered = rp.rate_mbps(QPN).unwrap();
+        assert!(recovered > second, "rate increases with the timer");
+        assert!(recovered <= cut, "fast recovery does not exceed the target");
+    }
+
+    #[test]
+    fn rate_is_dropped_after_full_recovery() {
+        let rp = ReactionPoint::new(enabled(), MAX_QP);
+        let now = Instant::now();
+        rp.on_cnp_at(QPN, now);
+        let later = now + Duration::from_secs(1);
+        let _ignore = rp.reserve_at(QPN, 0, later);
+        assert_eq!(rp.rate_mbps(QPN), None);
+    }
+
+    #[test]
+    fn reserve_paces_at_current_rate() {
+        let rp = ReactionPoint::new(enabled(), MAX_QP);
+        let now = Instant::now();
+        assert_eq!(
+            rp.reserve_at(QPN, 4096, now),
+            None,
+            "unlimited QP is not paced"
+        );
+
+        rp.on_cnp_at(QPN, now);
+        assert_eq!(rp.reserve_at(QPN, 50_000, now), None);
+        // 50000 bytes at 50 Gbps takes 8 us
+        assert_eq!(
+            rp.reserve_at(QPN, 50_000, now),
+            Some(now + Duration::from_micros(8))
+        );
+    }
+
+    #[test]
+    fn notification_point_limits_cnps() {
+        let mut np = NotificationPoint::new(enabled(), MAX_QP);
+        let now = Instant::now();
+        assert!(np.should_notify_at(QPN, now));
+        assert!(!np.should_notify_at(QPN, now + Duration::from_micros(10)));
+        assert!(np.should_notify_at(QPN + (1 << 8), now));
+        assert!(np.should_notify_at(QPN, now + Duration::from_micros(DEFAULT_CNP_INTERVAL_US)));
+    }
+}
@@ -0,0 +1,91 @@
+use std::{io, thread, time::Duration};
+
+use tracing::warn;
+
+use super::FrameRx;
+
+/// Interval of polling for frames while the receiver is idle
+const POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Selects the frames of a route
+pub(crate) type FrameFilter = fn(&[u8]) -> bool;
+
+/// Dispatches the frames of a single receiver to the routes whose filter matches them,
+/// frames matching no route are dropped
+#[derive(Default)]
+pub(crate) struct FrameDispatcher {
+    routes: Vec<(FrameFilter, flume::Sender<Vec<u8>>)>,
+}
+
+impl FrameDispatcher {
+    /// Adds a route, a frame is dispatched to the first matching route
+    pub(crate) fn route(&mut self, filter: FrameFilter) -> RoutedFrameRx {
+        let (tx, rx) = flume::unbounded();
+        self.routes.push((filter, tx));
+        RoutedFrameRx(rx)
+    }
+
+    /// Spawns the worker dispatching the frames of `rx`, which it owns from then on
+    pub(crate) fn spawn<Rx: FrameRx + Send + 'static>(self, rx: Rx) {
+        let _handle = thread::Builder::new()
+            .name("frame-dispatcher".into())
+            .spawn(move || self.run(rx))
+            .unwrap_or_else(|err| unreachable!("Failed to spawn dispatcher thread: {err}"));
+    }
+
+    fn run<Rx: FrameRx>(self, mut rx: Rx) {
+        loop {
+            match rx.recv_nonblocking() {
+                Ok(frame) => self.dispatch(frame),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(POLL_INTERVAL);
+                }
+                Err(err) => warn!("simple NIC receive error: {err}"),
+            }
+        }
+    }
+
+    fn dispatch(&self, frame: Vec<u8>) {
+        if let Some(&(_, ref tx)) = self.routes.iter().find(|&&(filter, _)| filter(&frame)) {
+            // The consumer of the route may have exited
+            let _ignore = tx.send(frame);
+        }
+    }
+}
+
+/// Receives the frames of a route
+pub(crate) struct RoutedFrameRx(flume::Receiver<Vec<u8>>);
+
+impl FrameRx for RoutedFrameRx {
+    fn recv_nonblocking(&mut self) -> io::Result<Vec<u8>> {
+        self.0.try_recv().map_err(|err| match err {
+            flume::TryRecvError::Empty => io::ErrorKind::WouldBlock.into(),
+            flume::TryRecvError::Disconnected => io::ErrorKind::BrokenPipe.into(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn frames_go_to_the_first_matching_route() {
+        let mut dispatcher = FrameDispatcher::default();
+        let mut short = dispatcher.route(|frame| frame.len() < 4);
+        let mut any = dispatcher.route(|_| true);
+        dispatcher.dispatch(vec![1]);
+        dispatcher.dispatch(vec![1, 2, 3, 4]);
+        assert_eq!(short.recv_nonblocking().unwrap(), vec![1]);
+        assert_eq!(
+            short.recv_nonblocking().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        assert_eq!(any.recv_nonblocking().unwrap(), vec![1, 2, 3, 4]);
+        drop(dispatcher);
+        assert_eq!(
+            any.recv_nonblocking().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+}
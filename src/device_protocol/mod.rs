@@ -1,8 +1,13 @@
+/// Dispatching of received frames to their consumers
+mod dispatch;
 mod types;
 
+pub(crate) use dispatch::{FrameDispatcher, RoutedFrameRx};
 pub(crate) use types::*;
 
-use std::io;
+use std::{io, sync::Arc};
+
+use parking_lot::Mutex;
 
 use crate::net::config::NetworkConfig;
 
@@ -10,8 +15,12 @@ use crate::net::config::NetworkConfig;
 pub(crate) trait DeviceCommand {
     /// Updates Memory Translation Table entry
     fn update_mtt(&self, update: MttUpdate) -> io::Result<()>;
+    /// Invalidates the Memory Translation Table entry of a memory region
+    fn invalidate_mtt(&self, mr_key: u32) -> io::Result<()>;
     /// Updates Page Table entry
     fn update_pgt(&self, update: PgtUpdate) -> io::Result<()>;
+    /// Updates Page Table entries, submitting all commands before waiting for the responses
+    fn update_pgt_batch(&self, updates: &[PgtUpdate]) -> io::Result<()>;
     /// Updates Queue Pair entry
     fn update_qp(&self, entry: UpdateQp) -> io::Result<()>;
     /// Sets network parameters
@@ -51,6 +60,22 @@ pub(crate) trait FrameTx {
     fn send(&mut self, buf: &[u8]) -> io::Result<()>;
 }
 
+/// A frame sender shared by several workers
+#[derive(Clone)]
+pub(crate) struct SharedFrameTx(Arc<Mutex<Box<dyn FrameTx + Send>>>);
+
+impl SharedFrameTx {
+    pub(crate) fn new<Tx: FrameTx + Send + 'static>(tx: Tx) -> Self {
+        Self(Arc::new(Mutex::new(Box::new(tx))))
+    }
+}
+
+impl FrameTx for SharedFrameTx {
+    fn send(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().send(buf)
+    }
+}
+
 /// Trait for receiving frames
 pub(crate) trait FrameRx {
     /// Try to receive a frame, returning immediately if none available
@@ -145,6 +145,8 @@ pub(crate) struct HeaderWriteMeta {
     pub(crate) rkey: u32,
     pub(crate) imm: u32,
     pub(crate) header_type: HeaderType,
+    /// The packet was marked with Congestion Experienced
+    pub(crate) ecn_marked: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -158,6 +160,8 @@ pub(crate) struct HeaderReadMeta {
     pub(crate) laddr: u64,
     pub(crate) lkey: u32,
     pub(crate) ack_req: bool,
+    /// The packet was marked with Congestion Experienced
+    pub(crate) ecn_marked: bool,
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -393,9 +397,12 @@ pub(crate) struct QpParams {
     pub(crate) dqpn: u32,
     pub(crate) dqp_ip: u32,
     pub(crate) pmtu: u8,
+    /// Marks the packets as ECN capable
+    pub(crate) enable_ecn: bool,
 }
 
 impl QpParams {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         msn: u16,
         qp_type: u8,
@@ -404,6 +411,7 @@ impl QpParams {
         dqpn: u32,
         dqp_ip: u32,
         pmtu: u8,
+        enable_ecn: bool,
     ) -> Self {
         Self {
             msn,
@@ -413,6 +421,7 @@ impl QpParams {
             dqpn,
             dqp_ip,
             pmtu,
+            enable_ecn,
         }
     }
 }
@@ -439,6 +448,31 @@ pub(crate) enum WorkReqOpCode {
     AtomicWrite = 15,
 }
 
+impl WorkReqOpCode {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        let variant = match value {
+            0 => Self::RdmaWrite,
+            1 => Self::RdmaWriteWithImm,
+            2 => Self::Send,
+            3 => Self::SendWithImm,
+            4 => Self::RdmaRead,
+            5 => Self::AtomicCmpAndSwp,
+            6 => Self::AtomicFetchAndAdd,
+            7 => Self::LocalInv,
+            8 => Self::BindMw,
+            9 => Self::SendWithInv,
+            10 => Self::Tso,
+            11 => Self::Driver1,
+            12 => Self::RdmaReadResp,
+            13 => Self::RdmaAck,
+            14 => Self::Flush,
+            15 => Self::AtomicWrite,
+            _ => return None,
+        };
+        Some(variant)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(crate) enum HeaderType {
     Write,
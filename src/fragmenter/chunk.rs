@@ -120,6 +120,7 @@ impl IntoIterator for ChunkFragmenter {
             laddr: self.wr.laddr(),
             pmtu,
             is_retry: self.is_retry,
+            enable_ecn: self.qp_param.enable_ecn,
         }
     }
 }
@@ -132,6 +133,7 @@ pub(crate) struct IntoIter {
     laddr: u64,
     pmtu: u64,
     is_retry: bool,
+    enable_ecn: bool,
 }
 
 impl Iterator for IntoIter {
@@ -142,8 +144,13 @@ impl Iterator for IntoIter {
         let builder =
             self.builder
                 .set_chunk_meta(self.psn, self.laddr, f.addr, f.len as u32, f.pos);
-        let chunk = if self.is_retry {
-            builder.set_is_retry().build()
+        let builder = if self.is_retry {
+            builder.set_is_retry()
+        } else {
+            builder
+        };
+        let chunk = if self.enable_ecn {
+            builder.set_enable_ecn().build()
         } else {
             builder.build()
         };
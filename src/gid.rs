@@ -0,0 +1,351 @@
+use std::{
+    ffi::CString,
+    io,
+    net::{IpAddr, Ipv6Addr},
+    sync::Arc,
+    thread,
+};
+
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload, NLM_F_DUMP, NLM_F_REQUEST};
+use netlink_packet_route::{
+    address::{AddressAttribute, AddressMessage},
+    RouteNetlinkMessage,
+};
+use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::net::config::{MacAddress, NetworkConfig};
+
+/// Number of entries in the GID table of the port
+pub(crate) const GID_TABLE_LEN: usize = 16;
+
+/// `IBV_GID_TYPE_ROCE_V2` of `enum ibv_gid_type`, the only GID type of the port
+pub(crate) const IBV_GID_TYPE_ROCE_V2: u32 = 2;
+
+/// Sources of the GID table besides the network configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct GidConfig {
+    /// Interface whose addresses are added to the table, usually the TAP of the simple NIC
+    pub(crate) interface: Option<String>,
+}
+
+impl GidConfig {
+    pub(crate) fn interface(&self) -> Option<&str> {
+        self.interface.as_deref()
+    }
+}
+
+/// An entry of the GID table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct GidEntry {
+    pub(crate) gid: Ipv6Addr,
+    /// Index of the network interface of the GID, 0 if none is watched
+    pub(crate) ifindex: u32,
+}
+
+/// RoCEv2 GID table of the port
+///
+/// An address keeps its index while it is assigned, a new address takes the first
+/// free index. Applications cache indices, so they must not shift on unrelated changes.
+#[derive(Debug, Clone)]
+pub(crate) struct GidTable {
+    inner: Arc<RwLock<Slots>>,
+}
+
+#[derive(Debug)]
+struct Slots {
+    entries: [Option<Ipv6Addr>; GID_TABLE_LEN],
+    /// Addresses of the network configuration
+    network: Vec<IpAddr>,
+    /// Addresses of the watched interface
+    interface: Vec<IpAddr>,
+    ifindex: u32,
+}
+
+impl GidTable {
+    /// Creates a table holding the addresses of `config`
+    pub(crate) fn new(config: &NetworkConfig) -> Self {
+        let mut slots = Slots {
+            entries: [None; GID_TABLE_LEN],
+            network: Vec::new(),
+            interface: Vec::new(),
+            ifindex: 0,
+        };
+        slots.network = network_addrs(config);
+        slots.sync();
+        Self {
+            inner: Arc::new(RwLock::new(slots)),
+        }
+    }
+
+    /// Replaces the addresses of the network configuration
+    pub(crate) fn set_network(&self, config: &NetworkConfig) {
+        let mut slots = self.inner.write();
+        slots.network = network_addrs(config);
+        slots.sync();
+    }
+
+    /// Replaces the addresses of the watched interface
+    fn set_interface(&self, addrs: Vec<IpAddr>) {
+        let mut slots = self.inner.write();
+        slots.interface = addrs;
+        slots.sync();
+    }
+
+    /// Returns the entry at `index`, `None` if the index is free
+    ///
+    /// # Errors
+    /// Returns `InvalidInput` if `index` is out of the table
+    pub(crate) fn get(&self, index: u32) -> io::Result<Option<GidEntry>> {
+        let slots = self.inner.read();
+        let entry = usize::try_from(index)
+            .ok()
+            .and_then(|index| slots.entries.get(index))
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        Ok(entry.map(|gid| GidEntry {
+            gid,
+            ifindex: slots.ifindex,
+        }))
+    }
+
+    /// Returns the address of the entry at `sgid_index`, the source address of a QP
+    pub(crate) fn source_ip(&self, sgid_index: u8) -> Option<IpAddr> {
+        let gid = self.get(sgid_index.into()).ok().flatten()?.gid;
+        Some(gid.to_ipv4_mapped().map_or(IpAddr::V6(gid), IpAddr::V4))
+    }
+
+    /// Spawns a worker adding the addresses of `interface` to the table as they are
+    /// assigned and removing them as they are deleted
+    ///
+    /// # Errors
+    /// Returns an error if the interface does not exist or rtnetlink is unavailable
+    pub(crate) fn watch(&self, interface: &str) -> io::Result<()> {
+        let mut watcher = AddrWatcher::new(interface)?;
+        watcher.request_dump()?;
+        self.inner.write().ifindex = watcher.index;
+        let table = self.clone();
+        let _handle = thread::Builder::new()
+            .name("gid-watcher".into())
+            .spawn(move || loop {
+                match watcher.recv() {
+                    Ok(true) => table.set_interface(watcher.addrs.clone()),
+                    Ok(false) => {}
+                    Err(err) => {
+                        // Events may have been dropped, start over from a full dump
+                        warn!("interface address watch error: {err}");
+                        watcher.addrs.clear();
+                        if let Err(err) = watcher.request_dump() {
+                            warn!("failed to dump interface addresses: {err}");
+                            return;
+                        }
+                    }
+                }
+            })?;
+        Ok(())
+    }
+}
+
+impl Slots {
+    /// Frees the entries of removed addresses and places new addresses in free entries
+    fn sync(&mut self) {
+        let wanted: Vec<Ipv6Addr> = self
+            .network
+            .iter()
+            .chain(&self.interface)
+            .map(|&addr| to_gid(addr))
+            .collect();
+        for entry in &mut self.entries {
+            if entry.is_some_and(|gid| !wanted.contains(&gid)) {
+                *entry = None;
+            }
+        }
+        for gid in wanted {
+            if self.entries.contains(&Some(gid)) {
+                continue;
+            }
+            match self.entries.iter_mut().find(|entry| entry.is_none()) {
+                Some(entry) => *entry = Some(gid),
+                None => warn!("GID table full, {gid} is not added"),
+            }
+        }
+    }
+}
+
+/// Returns the RoCEv2 GID of an address, IPv4 addresses are in the format ::ffff:a.b.c.d
+fn to_gid(addr: IpAddr) -> Ipv6Addr {
+    match addr {
+        IpAddr::V4(addr) => addr.to_ipv6_mapped(),
+        IpAddr::V6(addr) => addr,
+    }
+}
+
+/// Returns the addresses of `config`. The QP context of the device holds an IPv4 source
+/// address, so only IPv4 addresses are published.
+fn network_addrs(config: &NetworkConfig) -> Vec<IpAddr> {
+    let ip = config.ip.ip();
+    if ip.is_unspecified() {
+        return Vec::new();
+    }
+    vec![ip.into()]
+}
+
+/// Tracks the addresses of an interface over rtnetlink
+struct AddrWatcher {
+    socket: Socket,
+    index: u32,
+    addrs: Vec<IpAddr>,
+}
+
+impl AddrWatcher {
+    fn new(interface: &str) -> io::Result<Self> {
+        let index = interface_index(interface)?;
+        let mut socket = Socket::new(NETLINK_ROUTE)?;
+        let groups = libc::RTMGRP_IPV4_IFADDR.unsigned_abs();
+        socket.bind(&SocketAddr::new(0, groups))?;
+        Ok(Self {
+            socket,
+            index,
+            addrs: Vec::new(),
+        })
+    }
+
+    /// Requests the addresses of all interfaces, the replies are handled by `recv`
+    fn request_dump(&self) -> io::Result<()> {
+        let message = RouteNetlinkMessage::GetAddress(AddressMessage::default());
+        let mut req = NetlinkMessage::from(message);
+        req.header.flags = NLM_F_REQUEST | NLM_F_DUMP;
+        req.finalize();
+        let mut buffer = vec![0; req.buffer_len()];
+        req.serialize(&mut buffer);
+        let _len = self.socket.send_to(&buffer, &SocketAddr::new(0, 0), 0)?;
+        Ok(())
+    }
+
+    /// Receives a batch of messages, returns whether the addresses changed
+    fn recv(&mut self) -> io::Result<bool> {
+        let (buffer, _addr) = self.socket.recv_from_full()?;
+        let mut changed = false;
+        let mut offset = 0;
+        while let Some(bytes) = buffer.get(offset..).filter(|bytes| !bytes.is_empty()) {
+            let message = NetlinkMessage::<RouteNetlinkMessage>::deserialize(bytes)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+            let len = message.header.length as usize;
+            if len == 0 {
+                break;
+            }
+            changed |= self.apply(message.payload);
+            // Messages are aligned to 4 bytes
+            offset += (len + 3) & !3;
+        }
+        Ok(changed)
+    }
+
+    fn apply(&mut self, payload: NetlinkPayload<RouteNetlinkMessage>) -> bool {
+        let NetlinkPayload::InnerMessage(message) = payload else {
+            return false;
+        };
+        if let RouteNetlinkMessage::NewAddress(message) = message {
+            match self.address_of(&message) {
+                Some(addr) if !self.addrs.contains(&addr) => {
+                    self.addrs.push(addr);
+                    true
+                }
+                Some(_) | None => false,
+            }
+        } else if let RouteNetlinkMessage::DelAddress(message) = message {
+            let Some(addr) = self.address_of(&message) else {
+                return false;
+            };
+            let len = self.addrs.len();
+            self.addrs.retain(|&x| x != addr);
+            self.addrs.len() != len
+        } else {
+            false
+        }
+    }
+
+    /// Returns the local IPv4 address of a message about the watched interface, the dump
+    /// also reports the IPv6 addresses, which the QP context cannot hold
+    fn address_of(&self, message: &AddressMessage) -> Option<IpAddr> {
+        if message.header.index != self.index {
+            return None;
+        }
+        message.attributes.iter().find_map(|attr| {
+            if let AddressAttribute::Local(addr @ IpAddr::V4(_)) = *attr {
+                Some(addr)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+/// Returns the index of the interface named `name`
+#[allow(unsafe_code)]
+fn interface_index(name: &str) -> io::Result<u32> {
+    let name = CString::new(name).map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+    // SAFETY: `name` is a valid nul-terminated string
+    match unsafe { libc::if_nametoindex(name.as_ptr()) } {
+        0 => Err(io::Error::last_os_error()),
+        index => Ok(index),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(ip: &str) -> NetworkConfig {
+        NetworkConfig {
+            ip: ip.parse().unwrap(),
+            gateway: "10.0.0.1".parse().unwrap(),
+            mac: MacAddress([0x02, 0, 0, 0, 0, 0x01]),
+            vlan: None,
+            priority: 0,
+        }
+    }
+
+    fn gid(table: &GidTable, index: u32) -> Option<Ipv6Addr> {
+        table.get(index).unwrap().map(|entry| entry.gid)
+    }
+
+    #[test]
+    fn ipv4_address_is_the_first_gid() {
+        let table = GidTable::new(&config("10.0.0.2/24"));
+        assert_eq!(gid(&table, 0), Some("::ffff:10.0.0.2".parse().unwrap()));
+        assert_eq!(gid(&table, 1), None);
+        assert_eq!(table.source_ip(0), Some("10.0.0.2".parse().unwrap()));
+        assert_eq!(table.source_ip(1), None);
+        assert_eq!(
+            table
+                .get(GID_TABLE_LEN.try_into().unwrap())
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::InvalidInput
+        );
+
+        let table = GidTable::new(&NetworkConfig::unconfigured(MacAddress([0; 6])));
+        assert_eq!(gid(&table, 0), None);
+    }
+
+    #[test]
+    fn indices_are_stable_across_address_changes() {
+        let table = GidTable::new(&config("10.0.0.2/24"));
+        table.set_interface(vec![
+            "10.0.1.2".parse().unwrap(),
+            "10.0.2.2".parse().unwrap(),
+        ]);
+        assert_eq!(gid(&table, 2), Some("::ffff:10.0.2.2".parse().unwrap()));
+
+        table.set_interface(vec!["10.0.2.2".parse().unwrap()]);
+        assert_eq!(gid(&table, 1), None);
+        assert_eq!(gid(&table, 2), Some("::ffff:10.0.2.2".parse().unwrap()));
+
+        table.set_network(&config("10.0.0.3/24"));
+        assert_eq!(gid(&table, 0), Some("::ffff:10.0.0.3".parse().unwrap()));
+        assert_eq!(gid(&table, 2), Some("::ffff:10.0.2.2".parse().unwrap()));
+    }
+}
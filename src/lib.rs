@@ -171,21 +171,35 @@
 #![allow(clippy::arithmetic_side_effects)]
 
 mod ack_responder;
+/// Worker cpu affinity and NUMA placement
+mod affinity;
 mod completion;
 mod config;
 /// Constants used throughout the driver
 mod constants;
+/// DCQCN congestion control
+mod dcqcn;
 mod device_protocol;
 mod fragmenter;
+/// RoCEv2 GID table of the port
+mod gid;
+/// Device resource limits
+mod limits;
 /// Memory operation components
 #[allow(unsafe_code)]
 mod mem;
 mod meta_worker;
 /// Memory translation table
 mod mtt;
+/// ARP resolution of next hop MAC addresses
+mod neigh;
 mod packet_retransmit;
+/// Worker polling strategies
+mod polling;
 mod protocol_impl;
 mod qp;
+/// Per QP rate limiting
+mod rate_limit;
 mod rdma_write_worker;
 mod recv;
 mod ringbuf;
@@ -194,6 +208,8 @@ mod send;
 mod sq_worker;
 mod timeout_retransmit;
 mod timer;
+/// Hierarchical timing wheel
+mod timer_wheel;
 mod tracker;
 mod utils;
 
@@ -201,6 +217,12 @@ mod utils;
 /// Context operations
 pub mod ctx_ops;
 
+/// Device inspection used by `bluerdma-ctl`
+pub mod ctl;
+
+/// Transport counters and exporters
+pub mod metrics;
+
 #[allow(unused)]
 /// Network implementations
 pub mod net;
@@ -0,0 +1,161 @@
+use serde::{Deserialize, Serialize};
+
+use crate::constants::{MAX_SEND_WR, QPN_IDX_PART_WIDTH};
+
+/// Number of QPs used when neither the device nor the config reports one
+const DEFAULT_MAX_QP: usize = 1024;
+/// Number of CQs used when neither the device nor the config reports one
+const DEFAULT_MAX_CQ: usize = 1024;
+/// Number of MRs used when neither the device nor the config reports one
+const DEFAULT_MAX_MR: usize = 8192;
+/// Number of second stage MTT entries used when neither the device nor the config reports one
+const DEFAULT_MAX_MTT_ENTRIES: usize = 0x20000;
+
+/// Upper bound of QPs addressable by the index part of a QPN
+const MAX_QP_INDEX: usize = 1 << QPN_IDX_PART_WIDTH;
+/// Upper bound of MRs addressable by the index part of a memory key
+const MAX_MR_INDEX: usize = 1 << 24;
+/// Upper bound of second stage MTT entries addressable by the 17 bit PGT offset of a MR
+const MAX_MTT_INDEX: usize = 1 << 17;
+/// Upper bound of CQs, each takes a slot in the CQ tables and the metrics
+const MAX_CQ: usize = 1 << 16;
+
+/// Overrides of the device resource limits
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct LimitsConfig {
+    pub(crate) max_qp: Option<usize>,
+    pub(crate) max_cq: Option<usize>,
+    pub(crate) max_mr: Option<usize>,
+    pub(crate) max_mtt_entries: Option<usize>,
+}
+
+/// Capabilities reported by the device, `None` if not reported
+///
+/// The current bitstream has no capability registers, so none are reported.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct DeviceCaps {
+    pub(crate) max_qp: Option<usize>,
+    pub(crate) max_cq: Option<usize>,
+    pub(crate) max_mr: Option<usize>,
+    pub(crate) max_mtt_entries: Option<usize>,
+}
+
+/// Resource limits the driver tables are sized to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DeviceLimits {
+    max_qp: usize,
+    max_cq: usize,
+    max_mr: usize,
+    max_mtt_entries: usize,
+    max_qp_wr: usize,
+}
+
+impl Default for DeviceLimits {
+    fn default() -> Self {
+        Self::resolve(DeviceCaps::default(), LimitsConfig::default())
+    }
+}
+
+impl DeviceLimits {
+    /// Resolves the limits, a config value may lower but never exceed a device capability
+    pub(crate) fn resolve(caps: DeviceCaps, config: LimitsConfig) -> Self {
+        Self {
+            max_qp: resolve(caps.max_qp, config.max_qp, DEFAULT_MAX_QP, MAX_QP_INDEX),
+            max_cq: resolve(caps.max_cq, config.max_cq, DEFAULT_MAX_CQ, MAX_CQ),
+            max_mr: resolve(caps.max_mr, config.max_mr, DEFAULT_MAX_MR, MAX_MR_INDEX),
+            max_mtt_entries: resolve(
+                caps.max_mtt_entries,
+                config.max_mtt_entries,
+                DEFAULT_MAX_MTT_ENTRIES,
+                MAX_MTT_INDEX,
+            ),
+            max_qp_wr: MAX_SEND_WR,
+        }
+    }
+
+    pub(crate) fn max_qp(&self) -> usize {
+        self.max_qp
+    }
+
+    pub(crate) fn max_cq(&self) -> usize {
+        self.max_cq
+    }
+
+    pub(crate) fn max_mr(&self) -> usize {
+        self.max_mr
+    }
+
+    pub(crate) fn max_mtt_entries(&self) -> usize {
+        self.max_mtt_entries
+    }
+
+    /// Maximum number of outstanding send WRs of a QP
+    pub(crate) fn max_qp_wr(&self) -> usize {
+        self.max_qp_wr
+    }
+}
+
+/// Resolves a limit to at most `bound`, a capability above `bound` is not trusted and ignored
+fn resolve(cap: Option<usize>, config: Option<usize>, default: usize, bound: usize) -> usize {
+    let cap = cap.filter(|&cap| cap <= bound);
+    let value = match (cap, config) {
+        (Some(cap), Some(config)) => config.min(cap),
+        (Some(value), None) | (None, Some(value)) => value,
+        (None, None) => default,
+    };
+    value.min(bound)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn defaults_without_caps_or_config() {
+        let limits = DeviceLimits::default();
+        assert_eq!(limits.max_qp(), DEFAULT_MAX_QP);
+        assert_eq!(limits.max_cq(), DEFAULT_MAX_CQ);
+        assert_eq!(limits.max_mr(), DEFAULT_MAX_MR);
+        assert_eq!(limits.max_mtt_entries(), DEFAULT_MAX_MTT_ENTRIES);
+    }
+
+    #[test]
+    fn device_caps_bound_the_config() {
+        let caps = DeviceCaps {
+            max_qp: Some(65536),
+            max_cq: Some(4096),
+            ..DeviceCaps::default()
+        };
+        let config = LimitsConfig {
+            max_qp: Some(1 << 20),
+            max_cq: Some(2048),
+            max_mr: Some(1 << 30),
+            ..LimitsConfig::default()
+        };
+        let limits = DeviceLimits::resolve(caps, config);
+        assert_eq!(limits.max_qp(), 65536);
+        assert_eq!(limits.max_cq(), 2048);
+        assert_eq!(limits.max_mr(), MAX_MR_INDEX);
+        assert_eq!(limits.max_mtt_entries(), DEFAULT_MAX_MTT_ENTRIES);
+    }
+
+    #[test]
+    fn implausible_caps_are_ignored() {
+        let caps = DeviceCaps {
+            max_cq: Some(0x3131_3131),
+            ..DeviceCaps::default()
+        };
+        let limits = DeviceLimits::resolve(caps, LimitsConfig::default());
+        assert_eq!(limits.max_cq(), DEFAULT_MAX_CQ);
+
+        let config = LimitsConfig {
+            max_cq: Some(1 << 30),
+            max_mtt_entries: Some(1 << 20),
+            ..LimitsConfig::default()
+        };
+        let limits = DeviceLimits::resolve(DeviceCaps::default(), config);
+        assert_eq!(limits.max_cq(), MAX_CQ);
+        assert_eq!(limits.max_mtt_entries(), MAX_MTT_INDEX);
+    }
+}
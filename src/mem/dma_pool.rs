@@ -0,0 +1,543 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::c_void,
+    io, ptr,
+};
+
+use super::{
+    page::MmapMut,
+    slot_alloc::{RcSlot, SlotAlloc, SlotSize},
+    DmaBuf, DmaBufAllocator, PAGE_SIZE,
+};
+
+/// Granularity of page allocations and size of a slab
+const GRANULE: usize = 0x1000;
+/// Size of the arenas requested from the backing allocator
+const ARENA_SIZE: usize = PAGE_SIZE;
+
+/// Usage statistics of a `DmaPool`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct DmaPoolStats {
+    /// Memory obtained from the backing allocator
+    pub(crate) reserved_bytes: usize,
+    /// Memory handed out, rounded up to pages or slab slots
+    pub(crate) allocated_bytes: usize,
+    /// Free memory of the arenas
+    pub(crate) free_bytes: usize,
+    /// Largest free range of any arena
+    pub(crate) largest_free_bytes: usize,
+    /// Memory of the pages carved into slabs
+    pub(crate) slab_bytes: usize,
+    /// Memory of the slab slots in use
+    pub(crate) slab_used_bytes: usize,
+}
+
+impl DmaPoolStats {
+    /// External fragmentation in per mille, the share of the free memory outside the
+    /// largest free range
+    pub(crate) fn fragmentation_permille(&self) -> usize {
+        if self.free_bytes == 0 {
+            return 0;
+        }
+        (self.free_bytes - self.largest_free_bytes) * 1000 / self.free_bytes
+    }
+}
+
+/// General purpose DMA memory manager.
+///
+/// Arenas are requested from a backing allocator, which only grows, and never returned.
+/// Requests up to 2 KiB are served from slabs of power of two slots, larger requests
+/// from page ranges of the arenas aligned to their size. Freed memory is reused by later
+/// allocations.
+pub(crate) struct DmaPool<A> {
+    allocator: A,
+    arenas: Vec<Arena>,
+    /// Slab size classes in ascending slot size
+    classes: Vec<Box<dyn SizeClass>>,
+    /// Live page allocations by address
+    pages: HashMap<u64, PageRange>,
+}
+
+impl<A: DmaBufAllocator> DmaPool<A> {
+    pub(crate) fn new(allocator: A) -> Self {
+        Self {
+            allocator,
+            arenas: Vec::new(),
+            classes: vec![
+                Box::new(SlabClass::<64>::default()),
+                Box::new(SlabClass::<128>::default()),
+                Box::new(SlabClass::<256>::default()),
+                Box::new(SlabClass::<512>::default()),
+                Box::new(SlabClass::<1024>::default()),
+                Box::new(SlabClass::<2048>::default()),
+            ],
+            pages: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn stats(&self) -> DmaPoolStats {
+        let mut stats = DmaPoolStats {
+            allocated_bytes: self.pages.values().map(|range| range.len).sum(),
+            ..DmaPoolStats::default()
+        };
+        for arena in &self.arenas {
+            stats.reserved_bytes += arena.buf.len();
+            stats.free_bytes += arena.free_bytes();
+            stats.largest_free_bytes = stats.largest_free_bytes.max(arena.largest_free());
+        }
+        for class in &self.classes {
+            let (num_pages, used_slots) = class.usage();
+            stats.slab_bytes += num_pages * GRANULE;
+            stats.slab_used_bytes += used_slots * class.slot_size();
+        }
+        stats.allocated_bytes += stats.slab_used_bytes;
+        stats
+    }
+
+    #[allow(clippy::as_conversions)]
+    fn alloc_zeroed(&mut self, len: usize) -> io::Result<DmaBuf> {
+        if len == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let class = self.classes.iter().position(|c| c.slot_size() >= len);
+        let (addr, phys_addr) = match class {
+            Some(class) => self.alloc_slot(class)?,
+            None => self.alloc_pages(len)?,
+        };
+        let ptr = addr as *mut c_void;
+        #[allow(unsafe_code)]
+        // SAFETY: the range belongs to an arena mapped for the lifetime of the pool
+        unsafe {
+            ptr::write_bytes(ptr.cast::<u8>(), 0, len);
+        }
+
+        // The range is returned by `free`, dropping the buffer keeps the arena mapped
+        Ok(DmaBuf::new(MmapMut::view(ptr, len), phys_addr))
+    }
+
+    fn alloc_slot(&mut self, class: usize) -> io::Result<(u64, u64)> {
+        let slot = self.classes.get_mut(class).and_then(|c| c.alloc());
+        if let Some(slot) = slot {
+            return Ok(slot);
+        }
+        let (index, offset) = self.alloc_range(GRANULE, GRANULE)?;
+        let arena = self.arena(index)?;
+        let (addr, phys_addr) = (
+            arena.addr() + offset as u64,
+            arena.phys_addr() + offset as u64,
+        );
+        let class = self
+            .classes
+            .get_mut(class)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        class.add_slab(addr, phys_addr);
+        class.alloc().ok_or(Self::exhausted(class.slot_size()))
+    }
+
+    fn alloc_pages(&mut self, len: usize) -> io::Result<(u64, u64)> {
+        let len = len.next_multiple_of(GRANULE);
+        let align = len.next_power_of_two().min(ARENA_SIZE);
+        let (arena, offset) = self.alloc_range(len, align)?;
+        let base = self.arena(arena)?;
+        let (addr, phys_addr) = (
+            base.addr() + offset as u64,
+            base.phys_addr() + offset as u64,
+        );
+        let _prev = self.pages.insert(addr, PageRange { arena, offset, len });
+
+        Ok((addr, phys_addr))
+    }
+
+    /// Allocates a range from the first arena with a fitting free range, requests a new
+    /// arena if none fits. Alignment is best effort as the backing allocator decides the
+    /// physical alignment of new arenas.
+    fn alloc_range(&mut self, len: usize, align: usize) -> io::Result<(usize, usize)> {
+        let fits = |arenas: &mut [Arena], align: usize| {
+            arenas
+                .iter_mut()
+                .enumerate()
+                .find_map(|(index, arena)| arena.alloc(len, align).map(|offset| (index, offset)))
+        };
+        if let Some(range) = fits(&mut self.arenas, align) {
+            return Ok(range);
+        }
+        self.grow(len)?;
+        let index = self.arenas.len().saturating_sub(1);
+        let Some(arena) = self.arenas.get_mut(index) else {
+            return Err(Self::exhausted(len));
+        };
+        arena
+            .alloc(len, align)
+            .or_else(|| arena.alloc(len, GRANULE))
+            .map(|offset| (index, offset))
+            .ok_or(Self::exhausted(len))
+    }
+
+    /// Requests a new arena of at least `len` bytes from the backing allocator
+    fn grow(&mut self, len: usize) -> io::Result<()> {
+        let size = ARENA_SIZE.max(len);
+        let buf = match self.allocator.alloc(size) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::OutOfMemory && size > len => self
+                .allocator
+                .alloc(len)
+                .map_err(|_err| Self::exhausted(len))?,
+            Err(err) if err.kind() == io::ErrorKind::OutOfMemory => {
+                return Err(Self::exhausted(len))
+            }
+            Err(err) => return Err(err),
+        };
+        self.arenas.push(Arena::new(buf));
+
+        Ok(())
+    }
+
+    fn arena(&self, index: usize) -> io::Result<&Arena> {
+        self.arenas
+            .get(index)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))
+    }
+
+    fn release(&mut self, addr: u64) -> io::Result<()> {
+        if let Some(range) = self.pages.remove(&addr) {
+            if let Some(arena) = self.arenas.get_mut(range.arena) {
+                arena.free(range.offset, range.len);
+            }
+            return Ok(());
+        }
+        for class in &mut self.classes {
+            match class.free(addr) {
+                SlotFree::NotOwned => {}
+                SlotFree::Freed => return Ok(()),
+                SlotFree::SlabReleased(slab_addr) => {
+                    if let Some(arena) = self.arenas.iter_mut().find(|a| a.contains(slab_addr)) {
+                        let offset = (slab_addr - arena.addr()) as usize;
+                        arena.free(offset, GRANULE);
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "buffer not allocated from this pool",
+        ))
+    }
+
+    fn exhausted(len: usize) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::OutOfMemory,
+            format!("DMA memory exhausted, failed to allocate {len} bytes"),
+        )
+    }
+}
+
+impl<A: DmaBufAllocator> DmaBufAllocator for DmaPool<A> {
+    fn alloc(&mut self, len: usize) -> io::Result<DmaBuf> {
+        self.alloc_zeroed(len)
+    }
+
+    #[allow(clippy::as_conversions)]
+    fn free(&mut self, buf: DmaBuf) -> io::Result<()> {
+        self.release(buf.buf.ptr as u64)
+    }
+}
+
+/// A page range handed out from an arena
+#[derive(Debug, Clone, Copy)]
+struct PageRange {
+    arena: usize,
+    offset: usize,
+    len: usize,
+}
+
+/// A buffer of the backing allocator with its free ranges
+struct Arena {
+    buf: DmaBuf,
+    /// Free ranges, length by offset, adjacent ranges are merged
+    free: BTreeMap<usize, usize>,
+}
+
+#[allow(clippy::as_conversions)]
+impl Arena {
+    fn new(buf: DmaBuf) -> Self {
+        let free = BTreeMap::from([(0, buf.len())]);
+        Self { buf, free }
+    }
+
+    fn addr(&self) -> u64 {
+        self.buf.ptr as u64
+    }
+
+    fn phys_addr(&self) -> u64 {
+        self.buf.phys_addr
+    }
+
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.addr() && addr < self.addr() + self.buf.len() as u64
+    }
+
+    /// Allocates the first free range fitting `len` bytes at a physical address aligned
+    /// to `align`, returns its offset
+    fn alloc(&mut self, len: usize, align: usize) -> Option<usize> {
+        let phys_addr = self.phys_addr();
+        let (start, free_offset, free_len) =
+            self.free.iter().find_map(|(&offset, &free_len)| {
+                let start = (phys_addr + offset as u64).next_multiple_of(align as u64) - phys_addr;
+                let start = start as usize;
+                (start + len <= offset + free_len).then_some((start, offset, free_len))
+            })?;
+        let _removed = self.free.remove(&free_offset);
+        if start > free_offset {
+            let _prev = self.free.insert(free_offset, start - free_offset);
+        }
+        let end = start + len;
+        if end < free_offset + free_len {
+            let _prev = self.free.insert(end, free_offset + free_len - end);
+        }
+        Some(start)
+    }
+
+    /// Returns a range, merging it with the adjacent free ranges
+    fn free(&mut self, offset: usize, len: usize) {
+        let mut start = offset;
+        let mut end = offset + len;
+        if let Some((&prev, &prev_len)) = self.free.range(..offset).next_back() {
+            if prev + prev_len == start {
+                let _removed = self.free.remove(&prev);
+                start = prev;
+            }
+        }
+        if let Some(next_len) = self.free.remove(&end) {
+            end += next_len;
+        }
+        let _prev = self.free.insert(start, end - start);
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.free.values().sum()
+    }
+
+    fn largest_free(&self) -> usize {
+        self.free.values().copied().max().unwrap_or(0)
+    }
+}
+
+/// Outcome of returning a slot to a size class
+enum SlotFree {
+    /// The slot does not belong to the class
+    NotOwned,
+    /// The slot was returned to its slab
+    Freed,
+    /// The slot was returned and its slab, at the given address, released
+    SlabReleased(u64),
+}
+
+/// Slabs of one slot size
+trait SizeClass: Send {
+    fn slot_size(&self) -> usize;
+    /// Allocates a slot, returns its address and physical address
+    fn alloc(&mut self) -> Option<(u64, u64)>;
+    /// Carves the page at `addr` into slots
+    fn add_slab(&mut self, addr: u64, phys_addr: u64);
+    fn free(&mut self, addr: u64) -> SlotFree;
+    /// Returns the number of slabs and of slots in use
+    fn usage(&self) -> (usize, usize);
+}
+
+/// A page of an arena carved into slots
+struct SlabPage(MmapMut);
+
+impl AsMut<[u8]> for SlabPage {
+    #[allow(unsafe_code)]
+    fn as_mut(&mut self) -> &mut [u8] {
+        // SAFETY: the page belongs to an arena mapped for the lifetime of the pool
+        unsafe { std::slice::from_raw_parts_mut(self.0.ptr.cast::<u8>(), self.0.len) }
+    }
+}
+
+struct SlabSlot<const SIZE: usize>;
+
+impl<const SIZE: usize> SlotSize for SlabSlot<SIZE> {
+    fn size() -> usize {
+        SIZE
+    }
+}
+
+struct Slab<const SIZE: usize> {
+    addr: u64,
+    phys_addr: u64,
+    slots: SlotAlloc<SlabPage, SlabSlot<SIZE>>,
+    /// Allocated slots by address
+    live: HashMap<u64, RcSlot<SlabPage, SlabSlot<SIZE>>>,
+}
+
+impl<const SIZE: usize> Slab<SIZE> {
+    fn contains(&self, addr: u64) -> bool {
+        addr >= self.addr && addr < self.addr + GRANULE as u64
+    }
+}
+
+#[derive(Default)]
+struct SlabClass<const SIZE: usize> {
+    slabs: Vec<Slab<SIZE>>,
+}
+
+#[allow(clippy::as_conversions)]
+impl<const SIZE: usize> SizeClass for SlabClass<SIZE> {
+    fn slot_size(&self) -> usize {
+        SIZE
+    }
+
+    fn alloc(&mut self) -> Option<(u64, u64)> {
+        let slab = self.slabs.iter_mut().find(|slab| !slab.slots.is_empty())?;
+        let mut slot = slab.slots.alloc_one()?;
+        let addr = slot.as_mut().as_mut_ptr() as u64;
+        let phys_addr = slab.phys_addr + (addr - slab.addr);
+        let _prev = slab.live.insert(addr, slot);
+        Some((addr, phys_addr))
+    }
+
+    fn add_slab(&mut self, addr: u64, phys_addr: u64) {
+        let page = MmapMut::view(addr as *mut c_void, GRANULE);
+        self.slabs.push(Slab {
+            addr,
+            phys_addr,
+            slots: SlotAlloc::new(SlabPage(page)),
+            live: HashMap::new(),
+        });
+    }
+
+    fn free(&mut self, addr: u64) -> SlotFree {
+        let Some(index) = self.slabs.iter().position(|slab| slab.contains(addr)) else {
+            return SlotFree::NotOwned;
+        };
+        let Some(slab) = self.slabs.get_mut(index) else {
+            return SlotFree::NotOwned;
+        };
+        let Some(slot) = slab.live.remove(&addr) else {
+            return SlotFree::NotOwned;
+        };
+        let _foreign = slab.slots.dealloc(slot);
+        // Keep an empty slab unless another slab of the class has free slots
+        let spare = self
+            .slabs
+            .iter()
+            .enumerate()
+            .any(|(i, slab)| i != index && !slab.slots.is_empty());
+        let empty = self
+            .slabs
+            .get(index)
+            .is_some_and(|slab| slab.live.is_empty());
+        if empty && spare {
+            let slab = self.slabs.swap_remove(index);
+            return SlotFree::SlabReleased(slab.addr);
+        }
+        SlotFree::Freed
+    }
+
+    fn usage(&self) -> (usize, usize) {
+        let used = self.slabs.iter().map(|slab| slab.live.len()).sum();
+        (self.slabs.len(), used)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Backing allocator handing out anonymous mappings up to `limit` bytes
+    struct AnonAllocator {
+        limit: usize,
+        next_phys: u64,
+    }
+
+    impl AnonAllocator {
+        fn new(limit: usize) -> DmaPool<Self> {
+            DmaPool::new(Self {
+                limit,
+                next_phys: 0x1_0000_0000,
+            })
+        }
+    }
+
+    impl DmaBufAllocator for AnonAllocator {
+        #[allow(unsafe_code, clippy::as_conversions)]
+        fn alloc(&mut self, len: usize) -> io::Result<DmaBuf> {
+            if len > self.limit {
+                return Err(io::ErrorKind::OutOfMemory.into());
+            }
+            self.limit -= len;
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                    -1,
+                    0,
+                )
+            };
+            assert_ne!(ptr, libc::MAP_FAILED);
+            let phys_addr = self.next_phys;
+            self.next_phys += len.next_multiple_of(ARENA_SIZE) as u64;
+            Ok(DmaBuf::new(MmapMut::new(ptr, len), phys_addr))
+        }
+    }
+
+    #[test]
+    fn small_buffers_reuse_slab_slots() {
+        let mut pool = AnonAllocator::new(ARENA_SIZE);
+        let a = pool.alloc(100).unwrap();
+        let b = pool.alloc(100).unwrap();
+        assert_eq!(a.phys_addr().abs_diff(b.phys_addr()), 128);
+        let stats = pool.stats();
+        assert_eq!(stats.slab_bytes, GRANULE);
+        assert_eq!(stats.slab_used_bytes, 256);
+        let addr = a.phys_addr();
+        pool.free(a).unwrap();
+        let c = pool.alloc(128).unwrap();
+        assert_eq!(c.phys_addr(), addr);
+        pool.free(b).unwrap();
+        pool.free(c).unwrap();
+        assert_eq!(pool.stats().slab_used_bytes, 0);
+    }
+
+    #[test]
+    fn freed_pages_are_merged_and_reused() {
+        let mut pool = AnonAllocator::new(ARENA_SIZE);
+        let rings: Vec<_> = (0..4).map(|_| pool.alloc(0x2_0000).unwrap()).collect();
+        assert!(rings.iter().all(|ring| ring.phys_addr() % 0x2_0000 == 0));
+        let first = rings.first().unwrap().phys_addr();
+        for ring in rings {
+            pool.free(ring).unwrap();
+        }
+        let stats = pool.stats();
+        assert_eq!(stats.allocated_bytes, 0);
+        assert_eq!(stats.free_bytes, ARENA_SIZE);
+        assert_eq!(stats.fragmentation_permille(), 0);
+        let large = pool.alloc(0x8_0000).unwrap();
+        assert_eq!(large.phys_addr(), first);
+    }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn dropped_buffers_keep_the_arena_mapped() {
+        let mut pool = AnonAllocator::new(ARENA_SIZE);
+        let buf = pool.alloc(0x2000).unwrap();
+        let (ptr, len) = (buf.ptr, buf.len());
+        drop(buf);
+        // SAFETY: madvise only fails with ENOMEM if the range is not mapped
+        let ret = unsafe { libc::madvise(ptr, len, libc::MADV_NORMAL) };
+        assert_eq!(ret, 0, "the range is still mapped");
+        assert_eq!(pool.stats().reserved_bytes, ARENA_SIZE);
+    }
+
+    #[test]
+    fn exhaustion_is_reported() {
+        let mut pool = AnonAllocator::new(ARENA_SIZE);
+        let _whole = pool.alloc(ARENA_SIZE).unwrap();
+        let err = pool.alloc(GRANULE).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    }
+}
@@ -3,9 +3,11 @@ use nix::ioctl_write_ptr;
 use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
 use std::fs::{File, OpenOptions};
 use std::io;
+use std::os::fd::{AsRawFd, BorrowedFd};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 
 use super::page::{ContiguousPages, MmapMut, PageAllocator};
+use super::page_size;
 
 const UDMABUF_IOCTL_TYPE: u8 = b'u';
 const UDMABUF_CREATE_NR: u8 = 0x42;
@@ -90,3 +92,83 @@ impl PageAllocator<1> for DmaBufAllocator {
         Self::create().map(ContiguousPages::new)
     }
 }
+
+/// A range of a dma-buf or memfd mapped into the driver to resolve its pages.
+///
+/// The mapping holds a reference to the buffer, so the pages stay allocated until the
+/// mapping is dropped, which unmaps it, even if the exporter closes its fd.
+#[derive(Debug)]
+pub(crate) struct DmaBufMapping {
+    mmap: MmapMut,
+    /// Offset of the mapped range in its first page
+    page_offset: usize,
+}
+
+#[allow(unsafe_code, clippy::as_conversions)]
+impl DmaBufMapping {
+    /// Maps `[offset, offset + length)` of the buffer behind `fd` and faults its pages in
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range exceeds the buffer or the buffer can not be mapped.
+    pub(crate) fn map(fd: BorrowedFd<'_>, offset: u64, length: usize) -> io::Result<Self> {
+        let size = File::from(fd.try_clone_to_owned()?).metadata()?.len();
+        let in_bounds = offset
+            .checked_add(length as u64)
+            .is_some_and(|end| end <= size);
+        if length == 0 || !in_bounds {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let page_offset = (offset % page_size() as u64) as usize;
+        let map_offset = libc::off_t::try_from(offset - page_offset as u64)
+            .map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let len = length + page_offset;
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd.as_raw_fd(),
+                map_offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            mmap: MmapMut::new(ptr, len),
+            page_offset,
+        })
+    }
+
+    /// Address of the start of the range in the driver's address space
+    pub(crate) fn addr(&self) -> u64 {
+        self.mmap.ptr as u64 + self.page_offset as u64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::Write, os::fd::AsFd};
+
+    use super::*;
+
+    #[test]
+    #[allow(unsafe_code, clippy::as_conversions)]
+    fn maps_memfd_ranges() {
+        let name = std::ffi::CString::new("dmabuf_mapping").unwrap();
+        let memfd = memfd_create(&name, MemFdCreateFlag::MFD_CLOEXEC).unwrap();
+        let mut file = File::from(memfd);
+        file.write_all(&[7u8; 0x3000]).unwrap();
+
+        let mapping = DmaBufMapping::map(file.as_fd(), 0x1010, 0x100).unwrap();
+        let byte = unsafe { *(mapping.addr() as *const u8) };
+        assert_eq!(byte, 7);
+        assert_eq!(mapping.addr() % page_size() as u64, 0x10);
+
+        let err = DmaBufMapping::map(file.as_fd(), 0x2000, 0x1001).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
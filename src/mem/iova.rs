@@ -0,0 +1,430 @@
+use std::{collections::BTreeMap, io, ops::Range, ptr, sync::Arc};
+
+use parking_lot::Mutex;
+
+use super::{
+    page::MmapMut, virt_to_phy::AddressResolver, DmaBuf, DmaBufAllocator, MemoryPinner, UmemHandler,
+};
+
+/// IOVAs keep the offset of the virtual address in a 2MB page, so that regions
+/// translated through the IOMMU coalesce into huge pages like physically contiguous ones
+const IOVA_PHASE_ALIGN: u64 = 1 << 21;
+/// Size of the huge pages DMA buffers are preferably allocated from
+const HUGE_PAGE_SIZE: usize = 1 << 21;
+
+/// An IOMMU domain translating the DMA addresses of a device
+pub(crate) trait IommuMapper {
+    /// Maps `[vaddr, vaddr + length)` of the current process at `iova`. The range must
+    /// stay mapped in the process until it is unmapped from the domain.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the domain rejects the mapping.
+    fn map(&self, iova: u64, vaddr: u64, length: u64) -> io::Result<()>;
+
+    /// Removes the mapping at `iova`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the domain fails to remove the mapping.
+    fn unmap(&self, iova: u64, length: u64) -> io::Result<()>;
+
+    /// Alignment of the addresses and lengths of mappings
+    fn alignment(&self) -> u64;
+
+    /// IOVA ranges the domain can map
+    fn valid_iova_ranges(&self) -> Vec<Range<u64>>;
+}
+
+/// A mapping of a virtual range into the IO address space
+#[derive(Debug, Clone, Copy)]
+struct IovaMapping {
+    iova: u64,
+    /// Number of pins of the range
+    refs: usize,
+}
+
+struct IovaSpaceInner<M> {
+    mapper: M,
+    align: u64,
+    /// Free IOVA ranges, end by start, adjacent ranges are merged
+    free: BTreeMap<u64, u64>,
+    /// Mappings by aligned virtual start address and length
+    mappings: BTreeMap<(u64, u64), IovaMapping>,
+    /// Length of the longest mapping, bounds the lookup of translations
+    max_len: u64,
+}
+
+impl<M: IommuMapper> IovaSpaceInner<M> {
+    fn new(mapper: M) -> Self {
+        let align = mapper.alignment().max(1);
+        // IOVA 0 is never handed out, the device treats it as a null address
+        let free = mapper
+            .valid_iova_ranges()
+            .into_iter()
+            .map(|range| (range.start.max(align).next_multiple_of(align), range.end))
+            .filter(|&(start, end)| start < end)
+            .collect();
+        Self {
+            mapper,
+            align,
+            free,
+            mappings: BTreeMap::new(),
+            max_len: 0,
+        }
+    }
+
+    /// Returns the range of pages spanned by `[addr, addr + length)`
+    fn aligned(&self, addr: u64, length: usize) -> io::Result<(u64, u64)> {
+        if length == 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        let start = addr & !(self.align - 1);
+        let end = addr
+            .checked_add(length as u64)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?
+            .next_multiple_of(self.align);
+        Ok((start, end - start))
+    }
+
+    fn map(&mut self, addr: u64, length: usize) -> io::Result<u64> {
+        let (start, len) = self.aligned(addr, length)?;
+        if let Some(mapping) = self.mappings.get_mut(&(start, len)) {
+            mapping.refs += 1;
+            return Ok(mapping.iova + (addr - start));
+        }
+        let phase_align = IOVA_PHASE_ALIGN.max(self.align);
+        let iova = self
+            .alloc(len, phase_align, start % phase_align)
+            .or_else(|| self.alloc(len, self.align, 0))
+            .ok_or(io::Error::new(
+                io::ErrorKind::OutOfMemory,
+                "IOVA space exhausted",
+            ))?;
+        if let Err(err) = self.mapper.map(iova, start, len) {
+            self.release(iova, len);
+            return Err(err);
+        }
+        let _prev = self
+            .mappings
+            .insert((start, len), IovaMapping { iova, refs: 1 });
+        self.max_len = self.max_len.max(len);
+
+        Ok(iova + (addr - start))
+    }
+
+    fn unmap(&mut self, addr: u64, length: usize) -> io::Result<()> {
+        let key = self.aligned(addr, length)?;
+        let mapping = self
+            .mappings
+            .get_mut(&key)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        mapping.refs -= 1;
+        if mapping.refs > 0 {
+            return Ok(());
+        }
+        let iova = mapping.iova;
+        let _removed = self.mappings.remove(&key);
+        let (_, len) = key;
+        self.mapper.unmap(iova, len)?;
+        self.release(iova, len);
+
+        Ok(())
+    }
+
+    fn translate(&self, addr: u64) -> Option<u64> {
+        self.mappings
+            .range(..=(addr, u64::MAX))
+            .rev()
+            .take_while(|&(&(start, _), _)| addr - start < self.max_len)
+            .find(|&(&(start, len), _)| addr - start < len)
+            .map(|(&(start, _), mapping)| mapping.iova + (addr - start))
+    }
+
+    /// Allocates the first free range fitting `len` bytes at an IOVA congruent to
+    /// `phase` modulo `align`
+    fn alloc(&mut self, len: u64, align: u64, phase: u64) -> Option<u64> {
+        let (iova, free_start, free_end) = self.free.iter().find_map(|(&start, &end)| {
+            let iova = start.checked_add(phase.wrapping_sub(start) & (align - 1))?;
+            (iova.checked_add(len)? <= end).then_some((iova, start, end))
+        })?;
+        let _removed = self.free.remove(&free_start);
+        if iova > free_start {
+            let _prev = self.free.insert(free_start, iova);
+        }
+        if iova + len < free_end {
+            let _prev = self.free.insert(iova + len, free_end);
+        }
+        Some(iova)
+    }
+
+    /// Returns a range, merging it with the adjacent free ranges
+    fn release(&mut self, iova: u64, len: u64) {
+        let mut start = iova;
+        let mut end = iova + len;
+        if let Some((&prev, &prev_end)) = self.free.range(..iova).next_back() {
+            if prev_end == start {
+                let _removed = self.free.remove(&prev);
+                start = prev;
+            }
+        }
+        if let Some(next_end) = self.free.remove(&end) {
+            end = next_end;
+        }
+        let _prev = self.free.insert(start, end);
+    }
+}
+
+/// The IO address space of a device, shared by the allocator of its rings and the
+/// handler of its memory regions
+pub(crate) struct IovaSpace<M> {
+    inner: Arc<Mutex<IovaSpaceInner<M>>>,
+}
+
+impl<M> Clone for IovaSpace<M> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<M: IommuMapper> IovaSpace<M> {
+    pub(crate) fn new(mapper: M) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(IovaSpaceInner::new(mapper))),
+        }
+    }
+
+    /// Maps the pages spanned by a range, returns the IOVA of `addr`. Mapping the same
+    /// range again only takes another reference.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the space is exhausted or the domain rejects the mapping.
+    pub(crate) fn map(&self, addr: u64, length: usize) -> io::Result<u64> {
+        self.inner.lock().map(addr, length)
+    }
+
+    /// Drops a reference to a range mapped by [`Self::map`], unmapping it with the last
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range is not mapped or the domain fails to unmap it.
+    pub(crate) fn unmap(&self, addr: u64, length: usize) -> io::Result<()> {
+        self.inner.lock().unmap(addr, length)
+    }
+
+    /// Returns the IOVA of a virtual address, `None` if it is not mapped
+    pub(crate) fn translate(&self, addr: u64) -> Option<u64> {
+        self.inner.lock().translate(addr)
+    }
+
+    /// Returns the IOVAs of `start_addr + i * page_size` for each page `i`
+    pub(crate) fn translate_range(
+        &self,
+        start_addr: u64,
+        num_pages: usize,
+        page_size: u64,
+    ) -> Vec<Option<u64>> {
+        let inner = self.inner.lock();
+        (0..num_pages as u64)
+            .map(|i| inner.translate(start_addr + i * page_size))
+            .collect()
+    }
+}
+
+/// Allocates DMA buffers from anonymous memory mapped into an IO address space
+pub(crate) struct IommuDmaBufAllocator<M> {
+    space: IovaSpace<M>,
+}
+
+impl<M> IommuDmaBufAllocator<M> {
+    pub(crate) fn new(space: IovaSpace<M>) -> Self {
+        Self { space }
+    }
+}
+
+impl<M: IommuMapper> DmaBufAllocator for IommuDmaBufAllocator<M> {
+    #[allow(unsafe_code, clippy::as_conversions)]
+    fn alloc(&mut self, len: usize) -> io::Result<DmaBuf> {
+        let mmap = |len, flags| unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                flags | libc::MAP_SHARED | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        let mut ptr = libc::MAP_FAILED;
+        if len % HUGE_PAGE_SIZE == 0 {
+            ptr = mmap(len, libc::MAP_HUGETLB | libc::MAP_HUGE_2MB);
+        }
+        // Falls back to base pages if no huge page is reserved
+        let len = len.next_multiple_of(super::page_size());
+        if ptr == libc::MAP_FAILED {
+            ptr = mmap(len, 0);
+        }
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        let buf = MmapMut::new(ptr, len);
+        let iova = self.space.map(ptr as u64, len)?;
+
+        Ok(DmaBuf::new(buf, iova))
+    }
+
+    fn free(&mut self, buf: DmaBuf) -> io::Result<()> {
+        self.space.unmap(buf.ptr as u64, buf.len())
+    }
+}
+
+/// Pins memory regions by mapping them into an IO address space, the device then
+/// accesses them by IOVA
+pub(crate) struct IommuUmemHandler<M> {
+    space: IovaSpace<M>,
+}
+
+impl<M> IommuUmemHandler<M> {
+    pub(crate) fn new(space: IovaSpace<M>) -> Self {
+        Self { space }
+    }
+}
+
+impl<M: IommuMapper> MemoryPinner for IommuUmemHandler<M> {
+    fn pin_pages(&self, addr: u64, length: usize) -> io::Result<()> {
+        self.space.map(addr, length).map(|_iova| ())
+    }
+
+    fn unpin_pages(&self, addr: u64, length: usize) -> io::Result<()> {
+        self.space.unmap(addr, length)
+    }
+}
+
+impl<M: IommuMapper> AddressResolver for IommuUmemHandler<M> {
+    fn virt_to_phys(&self, virt_addr: u64) -> io::Result<Option<u64>> {
+        Ok(self.space.translate(virt_addr))
+    }
+
+    fn virt_to_phys_range(
+        &self,
+        start_addr: u64,
+        num_pages: usize,
+        page_size: u64,
+    ) -> io::Result<Vec<Option<u64>>> {
+        Ok(self.space.translate_range(start_addr, num_pages, page_size))
+    }
+}
+
+impl<M: IommuMapper> UmemHandler for IommuUmemHandler<M> {}
+
+#[cfg(test)]
+mod test {
+    use crate::mem::{get_num_page, mr_pages::MrPages, PGT_PAGE_SIZE};
+
+    use super::*;
+
+    const PAGE: u64 = 0x1000;
+
+    /// A domain recording its mappings
+    #[derive(Clone, Default)]
+    struct MockMapper {
+        mappings: Arc<Mutex<BTreeMap<u64, (u64, u64)>>>,
+    }
+
+    impl IommuMapper for MockMapper {
+        fn map(&self, iova: u64, vaddr: u64, length: u64) -> io::Result<()> {
+            let mut mappings = self.mappings.lock();
+            let overlaps = mappings
+                .iter()
+                .any(|(&start, &(_, len))| start < iova + length && iova < start + len);
+            assert!(!overlaps, "overlapping IOVA mappings");
+            let _prev = mappings.insert(iova, (vaddr, length));
+            Ok(())
+        }
+
+        fn unmap(&self, iova: u64, length: u64) -> io::Result<()> {
+            let (_, len) = self
+                .mappings
+                .lock()
+                .remove(&iova)
+                .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+            assert_eq!(len, length);
+            Ok(())
+        }
+
+        fn alignment(&self) -> u64 {
+            PAGE
+        }
+
+        fn valid_iova_ranges(&self) -> Vec<Range<u64>> {
+            vec![0..0x1000_0000]
+        }
+    }
+
+    #[test]
+    fn pinned_regions_translate_to_iovas() {
+        let mapper = MockMapper::default();
+        let handler = IommuUmemHandler::new(IovaSpace::new(mapper.clone()));
+        let addr = 0x7f00_0020_0010;
+        let length = 0x40_0000 - 0x10;
+        handler.pin_pages(addr, length).unwrap();
+        let (&iova, &(vaddr, len)) = mapper.mappings.lock().iter().next().unwrap();
+        assert_eq!((vaddr, len), (0x7f00_0020_0000, 0x40_0000));
+        assert_ne!(iova, 0);
+        assert_eq!(iova % IOVA_PHASE_ALIGN, vaddr % IOVA_PHASE_ALIGN);
+        assert_eq!(handler.virt_to_phys(addr).unwrap(), Some(iova + 0x10));
+        assert_eq!(handler.virt_to_phys(0x7f00_0000_0000).unwrap(), None);
+
+        // The region is contiguous in the IO address space
+        let pages = MrPages::resolve(&handler, addr, length).unwrap();
+        let expected: Vec<_> = (0..get_num_page(addr, length, PGT_PAGE_SIZE) as u64)
+            .map(|i| iova + 0x10 + i * PGT_PAGE_SIZE)
+            .collect();
+        assert_eq!(pages.page_size(), PGT_PAGE_SIZE);
+        assert_eq!(pages.phys_addrs(), expected);
+
+        // A second pin of the range shares the mapping
+        handler.pin_pages(addr, length).unwrap();
+        handler.unpin_pages(addr, length).unwrap();
+        assert_eq!(mapper.mappings.lock().len(), 1);
+        handler.unpin_pages(addr, length).unwrap();
+        assert!(mapper.mappings.lock().is_empty());
+        assert_eq!(handler.virt_to_phys(addr).unwrap(), None);
+        assert!(handler.unpin_pages(addr, length).is_err());
+    }
+
+    #[test]
+    fn freed_iovas_are_reused() {
+        let space = IovaSpace::new(MockMapper::default());
+        let a = space.map(0x10_0000, 0x1000).unwrap();
+        let b = space.map(0x20_0000, 0x1000).unwrap();
+        assert_ne!(a, b);
+        space.unmap(0x10_0000, 0x1000).unwrap();
+        assert_eq!(space.map(0x10_0000, 0x1000).unwrap(), a);
+        // Ranges larger than the space are rejected
+        let err = space.map(0x4000_0000, 0x1000_0000).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::OutOfMemory);
+    }
+
+    #[test]
+    fn dma_bufs_are_mapped_at_their_iova() {
+        let mapper = MockMapper::default();
+        let space = IovaSpace::new(mapper.clone());
+        let mut allocator = IommuDmaBufAllocator::new(space.clone());
+        let buf = allocator.alloc(0x3000).unwrap();
+        let addr = buf.ptr as u64;
+        assert_eq!(
+            space.translate(addr + 0x2000),
+            Some(buf.phys_addr() + 0x2000)
+        );
+        assert_eq!(
+            mapper.mappings.lock().get(&buf.phys_addr()),
+            Some(&(addr, 0x3000))
+        );
+        allocator.free(buf).unwrap();
+        assert!(mapper.mappings.lock().is_empty());
+    }
+}
@@ -18,30 +18,47 @@ pub(crate) mod u_dma_buf;
 
 mod utils;
 
+/// Physical page layout of memory regions
+pub(crate) mod mr_pages;
+
+/// Batched physical address resolving from the pagemap
+pub(crate) mod pagemap;
+
+/// DMA memory manager with free and reuse
+pub(crate) mod dma_pool;
+
+/// DMA through the IO address space of an IOMMU
+pub(crate) mod iova;
+
+/// Unmap notifications of application memory through userfaultfd
+pub(crate) mod unmap;
+
 use page::MmapMut;
+use pagemap::PagemapResolver;
 pub(crate) use utils::*;
-use virt_to_phy::{AddressResolver, PhysAddrResolverEmulated, PhysAddrResolverLinuxX86};
-
-/// Number of bits for a 4KB page size
-#[cfg(target_arch = "x86_64")]
-#[cfg(feature = "page_size_4k")]
-pub(crate) const PAGE_SIZE_BITS: u8 = 12;
+use virt_to_phy::{AddressResolver, PhysAddrResolverEmulated};
 
-/// Number of bits for a 2MB huge page size
-#[cfg(feature = "page_size_2m")]
+/// Number of bits of the 2MB pages the driver allocates its own buffers from
 pub(crate) const PAGE_SIZE_BITS: u8 = 21;
 
-/// Size of a 2MB huge page in bytes
+/// Size of the pages the driver allocates its own buffers from
 pub(crate) const PAGE_SIZE: usize = 1 << PAGE_SIZE_BITS;
 
-/// Asserts system page size matches the expected page size.
-///
-/// # Panics
+/// Number of bits of the pages mapped by a PGT entry, fixed by the bitstream
+#[cfg(feature = "page_size_2m")]
+pub(crate) const PGT_PAGE_SIZE_BITS: u8 = 21;
+
+/// Number of bits of the pages mapped by a PGT entry, fixed by the bitstream
+#[cfg(feature = "page_size_4k")]
+pub(crate) const PGT_PAGE_SIZE_BITS: u8 = 12;
+
+/// Size of the pages mapped by a PGT entry, the pages of memory regions are split or
+/// merged to this size.
 ///
-/// Panics if the system page size does not equal `HUGE_PAGE_2MB_SIZE`.
-pub(crate) fn assert_equal_page_size() {
-    assert_eq!(page_size(), PAGE_SIZE, "page size not match");
-}
+/// The MTT update descriptor carries no page size, so every region is mapped at the
+/// granularity the bitstream was built with, selected by the `page_size_2m` or
+/// `page_size_4k` feature. It is not chosen per region.
+pub(crate) const PGT_PAGE_SIZE: u64 = 1 << PGT_PAGE_SIZE_BITS;
 
 /// Returns the current page size
 #[allow(
@@ -109,12 +126,26 @@ impl DerefMut for DmaBuf {
 
 pub(crate) trait DmaBufAllocator {
     fn alloc(&mut self, len: usize) -> io::Result<DmaBuf>;
+
+    /// Returns a buffer for reuse
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the buffer was not allocated from this allocator or the
+    /// allocator can not reuse memory.
+    fn free(&mut self, buf: DmaBuf) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
 
 impl<A: DmaBufAllocator> DmaBufAllocator for &mut A {
     fn alloc(&mut self, len: usize) -> io::Result<DmaBuf> {
         (**self).alloc(len)
     }
+
+    fn free(&mut self, buf: DmaBuf) -> io::Result<()> {
+        (**self).free(buf)
+    }
 }
 
 pub(crate) trait MemoryPinner {
@@ -136,19 +167,25 @@ pub(crate) trait MemoryPinner {
 pub(crate) trait UmemHandler: AddressResolver + MemoryPinner {}
 
 pub(crate) struct HostUmemHandler {
-    resolver: PhysAddrResolverLinuxX86,
+    resolver: PagemapResolver,
 }
 
 impl HostUmemHandler {
-    pub(crate) fn new() -> Self {
-        Self {
-            resolver: PhysAddrResolverLinuxX86,
-        }
+    /// Opens the handler, the pagemap stays open until the handler is dropped
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the pagemap could not be opened.
+    pub(crate) fn open() -> io::Result<Self> {
+        Ok(Self {
+            resolver: PagemapResolver::open()?,
+        })
     }
 }
 
 impl MemoryPinner for HostUmemHandler {
     fn pin_pages(&self, addr: u64, length: usize) -> io::Result<()> {
+        mr_pages::prefault(addr, length);
         let result = unsafe { libc::mlock(addr as *const std::ffi::c_void, length) };
         if result != 0 {
             return Err(io::Error::new(io::ErrorKind::Other, "failed to lock pages"));
@@ -177,8 +214,10 @@ impl AddressResolver for HostUmemHandler {
         &self,
         start_addr: u64,
         num_pages: usize,
+        page_size: u64,
     ) -> io::Result<Vec<Option<u64>>> {
-        self.resolver.virt_to_phys_range(start_addr, num_pages)
+        self.resolver
+            .virt_to_phys_range(start_addr, num_pages, page_size)
     }
 }
 
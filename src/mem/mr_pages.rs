@@ -0,0 +1,255 @@
+use std::{cmp::Ordering, ffi::c_void, fs, io};
+
+use super::{get_num_page, page_size, virt_to_phy::AddressResolver, PGT_PAGE_SIZE};
+
+/// Populates the page tables writable, since Linux 5.14
+const MADV_POPULATE_WRITE: libc::c_int = 23;
+/// Populates the page tables readable, since Linux 5.14
+const MADV_POPULATE_READ: libc::c_int = 22;
+
+/// Physical pages backing a memory region
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct MrPages {
+    /// Size of the pages mapped by each PGT entry
+    page_size: u64,
+    /// Physical address of `addr + i * page_size` for each page `i` of the region
+    phys_addrs: Vec<u64>,
+}
+
+impl MrPages {
+    /// Resolves the pages backing a pinned region at the PGT page size.
+    ///
+    /// The region is translated at the hugetlbfs page size of its mappings, otherwise
+    /// the base page size, then its pages are split or merged to the PGT page size of the
+    /// build. With 2MB PGT pages, a region backed by base pages registers only if each
+    /// 2MB page it spans is physically contiguous, e.g. backed by a transparent huge page.
+    ///
+    /// # Errors
+    ///
+    /// Returns `Unsupported` if a PGT page spanned by the region is not physically
+    /// contiguous.
+    pub(crate) fn resolve<R: AddressResolver>(
+        resolver: &R,
+        addr: u64,
+        length: usize,
+    ) -> io::Result<Self> {
+        let page_size = fs::read_to_string("/proc/self/smaps")
+            .ok()
+            .and_then(|smaps| kernel_page_size(&smaps, addr, length))
+            .filter(|&size| size > page_size() as u64)
+            .unwrap_or(page_size() as u64);
+        Self::translate(resolver, addr, length, page_size)?.resize(addr, length, PGT_PAGE_SIZE)
+    }
+
+    pub(crate) fn page_size(&self) -> u64 {
+        self.page_size
+    }
+
+    pub(crate) fn phys_addrs(&self) -> &[u64] {
+        &self.phys_addrs
+    }
+
+    pub(crate) fn into_phys_addrs(self) -> Vec<u64> {
+        self.phys_addrs
+    }
+
+    fn translate<R: AddressResolver>(
+        resolver: &R,
+        addr: u64,
+        length: usize,
+        page_size: u64,
+    ) -> io::Result<Self> {
+        let phys_addrs = resolver
+            .virt_to_phys_range(addr, get_num_page(addr, length, page_size), page_size)?
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                "physical address not found",
+            ))?;
+        Ok(Self {
+            page_size,
+            phys_addrs,
+        })
+    }
+
+    /// Splits or merges the pages into pages of `size`
+    fn resize(self, addr: u64, length: usize, size: u64) -> io::Result<Self> {
+        let resized = match self.page_size.cmp(&size) {
+            Ordering::Equal => return Ok(self),
+            Ordering::Greater => self.split(addr, length, size),
+            Ordering::Less => self.coalesce(addr, size),
+        };
+        resized.ok_or(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "memory region is not physically contiguous at the PGT page size",
+        ))
+    }
+
+    /// Splits the pages into pages of `size`, a divisor of the page size
+    fn split(&self, addr: u64, length: usize, size: u64) -> Option<Self> {
+        let phys_addrs = (0..get_num_page(addr, length, size) as u64)
+            .map(|i| {
+                let virt_addr = addr + i * size;
+                let index = (virt_addr / self.page_size - addr / self.page_size) as usize;
+                let frame = self.phys_addrs.get(index)? & !(self.page_size - 1);
+                Some(frame + virt_addr % self.page_size)
+            })
+            .collect::<Option<_>>()?;
+        Some(Self {
+            page_size: size,
+            phys_addrs,
+        })
+    }
+
+    /// Merges the pages into pages of `huge_page_size`, returns `None` if a huge page
+    /// spanned by the region is not physically contiguous and aligned
+    fn coalesce(&self, addr: u64, huge_page_size: u64) -> Option<Self> {
+        if self.page_size >= huge_page_size {
+            return None;
+        }
+        let mut phys_addrs = Vec::new();
+        let mut current: Option<(u64, u64)> = None;
+        for (i, &phys_addr) in self.phys_addrs.iter().enumerate() {
+            let virt_addr = addr + i as u64 * self.page_size;
+            // Offset from the virtual to the physical address, the same for all pages of
+            // a contiguous huge page
+            let offset = phys_addr.wrapping_sub(virt_addr);
+            if offset % huge_page_size != 0 {
+                return None;
+            }
+            let huge_page = virt_addr / huge_page_size;
+            match current {
+                Some((last_huge_page, last_offset)) if last_huge_page == huge_page => {
+                    if last_offset != offset {
+                        return None;
+                    }
+                }
+                Some(_) | None => {
+                    current = Some((huge_page, offset));
+                    let index = phys_addrs.len() as u64;
+                    phys_addrs.push((addr + index * huge_page_size).wrapping_add(offset));
+                }
+            }
+        }
+        Some(Self {
+            page_size: huge_page_size,
+            phys_addrs,
+        })
+    }
+}
+
+/// Faults in the pages of a range so that they can be translated, ranges that can not
+/// be populated this way are left to `mlock`
+#[allow(unsafe_code)]
+pub(crate) fn prefault(addr: u64, length: usize) {
+    let base_page_size = page_size() as u64;
+    let start = addr & !(base_page_size - 1);
+    let len = (addr + length as u64 - start) as usize;
+    // SAFETY: populating page tables does not change the contents of the mapping
+    unsafe {
+        if libc::madvise(start as *mut c_void, len, MADV_POPULATE_WRITE) != 0 {
+            let _ignore = libc::madvise(start as *mut c_void, len, MADV_POPULATE_READ);
+        }
+    }
+}
+
+/// Returns the smallest `KernelPageSize` of the mappings in `smaps` overlapping the range
+fn kernel_page_size(smaps: &str, addr: u64, length: usize) -> Option<u64> {
+    let end = addr.saturating_add(length as u64);
+    let mut overlaps = false;
+    let mut size: Option<u64> = None;
+    for line in smaps.lines() {
+        let mapping = line
+            .split_once(' ')
+            .and_then(|(range, _)| range.split_once('-'))
+            .and_then(|(start, stop)| {
+                let start = u64::from_str_radix(start, 16).ok()?;
+                let stop = u64::from_str_radix(stop, 16).ok()?;
+                Some((start, stop))
+            });
+        if let Some((start, stop)) = mapping {
+            overlaps = start < end && addr < stop;
+            continue;
+        }
+        let Some(value) = line.strip_prefix("KernelPageSize:").filter(|_| overlaps) else {
+            continue;
+        };
+        let Some(kib) = value
+            .trim()
+            .strip_suffix("kB")
+            .and_then(|kib| kib.trim().parse::<u64>().ok())
+        else {
+            continue;
+        };
+        size = Some(size.map_or(kib * 1024, |size| size.min(kib * 1024)));
+    }
+    size
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const SMAPS: &str = "\
+55d4c8a00000-55d4c8a21000 rw-p 00000000 00:00 0                          [heap]
+Size:                132 kB
+KernelPageSize:        4 kB
+MMUPageSize:           4 kB
+7f0000000000-7f0040000000 rw-s 00000000 00:10 1234                       /dev/hugepages/buf
+Size:            1048576 kB
+KernelPageSize:     2048 kB
+MMUPageSize:        2048 kB
+VmFlags: rd wr sh mr mw me ms de ht sd
+";
+
+    #[test]
+    fn kernel_page_size_of_overlapping_mappings() {
+        assert_eq!(
+            kernel_page_size(SMAPS, 0x7f00_0020_0000, 0x40_0000),
+            Some(2 << 20)
+        );
+        assert_eq!(
+            kernel_page_size(SMAPS, 0x55d4_c8a0_0000, 0x1000),
+            Some(4096)
+        );
+        assert_eq!(kernel_page_size(SMAPS, 0x1000, 0x1000), None);
+    }
+
+    #[test]
+    fn contiguous_base_pages_coalesce() {
+        let huge = 1 << 21;
+        let pages = |phys_addrs: Vec<u64>| MrPages {
+            page_size: 4096,
+            phys_addrs,
+        };
+        // Two huge pages backed by physical huge pages at 0x4000_0000 and 0x1000_0000
+        let addr = 0x7f00_001f_f000;
+        let phys_addrs = [0x4000_0000 + huge - 4096, 0x1000_0000, 0x1000_1000];
+        let coalesced = pages(phys_addrs.to_vec()).coalesce(addr, huge).unwrap();
+        assert_eq!(coalesced.page_size(), huge);
+        assert_eq!(
+            coalesced.phys_addrs(),
+            &[0x4000_0000 + huge - 4096, 0x1000_0000 + huge - 4096]
+        );
+
+        let scattered = [0x4000_0000 + huge - 4096, 0x1000_0000, 0x2000_0000];
+        assert!(pages(scattered.to_vec()).coalesce(addr, huge).is_none());
+    }
+
+    #[test]
+    fn huge_pages_split() {
+        let huge = 1 << 21;
+        let pages = MrPages {
+            page_size: huge,
+            phys_addrs: vec![0x4000_0000 + huge - 0x10, 0x1000_0000 + huge - 0x10],
+        };
+        // The region spans the last page of the first huge page and two pages of the second
+        let split = pages.split(0x7f00_001f_fff0, 0x1020, 4096).unwrap();
+        assert_eq!(split.page_size(), 4096);
+        assert_eq!(
+            split.phys_addrs(),
+            &[0x4000_0000 + huge - 0x10, 0x1000_0ff0, 0x1000_1ff0]
+        );
+    }
+}
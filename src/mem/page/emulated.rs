@@ -54,4 +54,12 @@ impl DmaBufAllocator for EmulatedPageAllocator<1> {
         let phys_addr = resolver.virt_to_phys(buf.as_ptr() as u64)?.unwrap();
         Ok(DmaBuf::new(buf, phys_addr))
     }
+
+    fn free(&mut self, buf: DmaBuf) -> io::Result<()> {
+        if buf.len() != PAGE_SIZE {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.inner.push(buf.buf);
+        Ok(())
+    }
 }
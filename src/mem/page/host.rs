@@ -49,28 +49,22 @@ impl<const N: usize> HostPageAllocator<N> {
         let len = PAGE_SIZE_2M
             .checked_mul(num_pages)
             .ok_or(io::Error::from(io::ErrorKind::Unsupported))?;
-        #[cfg(feature = "page_size_2m")]
-        let ptr = unsafe {
+        let mmap = |flags| unsafe {
             libc::mmap(
                 std::ptr::null_mut(),
                 len,
                 libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED | libc::MAP_ANON | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB,
-                -1,
-                0,
-            )
-        };
-        #[cfg(feature = "page_size_4k")]
-        let ptr = unsafe {
-            libc::mmap(
-                std::ptr::null_mut(),
-                len,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_SHARED | libc::MAP_ANON,
+                flags,
                 -1,
                 0,
             )
         };
+        let mut ptr =
+            mmap(libc::MAP_SHARED | libc::MAP_ANON | libc::MAP_HUGETLB | libc::MAP_HUGE_2MB);
+        // Falls back to base pages if no huge page is reserved
+        if ptr == libc::MAP_FAILED {
+            ptr = mmap(libc::MAP_SHARED | libc::MAP_ANON);
+        }
 
         if ptr == libc::MAP_FAILED {
             return Err(io::Error::last_os_error());
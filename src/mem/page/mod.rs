@@ -68,12 +68,28 @@ pub(crate) struct MmapMut {
     pub(crate) ptr: *mut c_void,
     /// Length of the mapped memory region in bytes
     pub(crate) len: usize,
+    /// Unmaps the region on drop, `false` for a range of a mapping owned elsewhere
+    owned: bool,
 }
 
 impl MmapMut {
     /// Creates a new `MmapMut`
     pub(crate) fn new(ptr: *mut c_void, len: usize) -> Self {
-        Self { ptr, len }
+        Self {
+            ptr,
+            len,
+            owned: true,
+        }
+    }
+
+    /// Creates a view of a range of a mapping owned elsewhere, the range is not unmapped
+    /// on drop
+    pub(crate) fn view(ptr: *mut c_void, len: usize) -> Self {
+        Self {
+            ptr,
+            len,
+            owned: false,
+        }
     }
 
     pub(crate) fn len(&self) -> usize {
@@ -131,7 +147,9 @@ mod mmap_mut_impl {
 
     impl Drop for MmapMut {
         fn drop(&mut self) {
-            let _ignore = unsafe { libc::munmap(self.ptr, self.len) };
+            if self.owned {
+                let _ignore = unsafe { libc::munmap(self.ptr, self.len) };
+            }
         }
     }
 
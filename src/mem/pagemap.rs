@@ -0,0 +1,238 @@
+use std::{fs::File, io, os::unix::fs::FileExt};
+
+use super::{page_size, virt_to_phy::AddressResolver};
+
+/// Size of a pagemap entry in bytes
+const ENTRY_SIZE: usize = 8;
+/// PFN are bits 0-54 (see pagemap.txt in Linux Documentation)
+const PFN_MASK: u64 = (1 << 55) - 1;
+/// Bit indicating if a page is present in memory
+const PAGE_PRESENT_BIT: u8 = 63;
+/// Maximum number of entries read by a single `pread`, 512 KiB of entries
+const MAX_ENTRIES_PER_READ: usize = 1 << 16;
+
+/// A virtually contiguous run of base pages that is either physically contiguous or
+/// not present
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PhysRun {
+    /// Page aligned virtual start address
+    pub(crate) virt_addr: u64,
+    /// Length in bytes, a multiple of the base page size
+    pub(crate) length: u64,
+    /// Physical start address, `None` if the pages are not present
+    pub(crate) phys_addr: Option<u64>,
+}
+
+impl PhysRun {
+    fn end(&self) -> u64 {
+        self.virt_addr + self.length
+    }
+
+    /// Physical address of `virt_addr`, `None` if outside the run or not present
+    fn phys_at(&self, virt_addr: u64) -> Option<u64> {
+        if virt_addr < self.virt_addr || virt_addr >= self.end() {
+            return None;
+        }
+        self.phys_addr
+            .map(|phys_addr| phys_addr + (virt_addr - self.virt_addr))
+    }
+}
+
+/// Resolves physical addresses from `/proc/self/pagemap`.
+///
+/// The file stays open for the lifetime of the resolver and the entries of a range are
+/// read with as few `pread` calls as possible.
+#[derive(Debug)]
+pub(crate) struct PagemapResolver {
+    pagemap: File,
+    /// Translator of GPU memory, consulted for ranges without any present page
+    gpu_ptr_translator: Option<File>,
+    base_page_size: u64,
+}
+
+impl PagemapResolver {
+    /// Opens the pagemap of the current process
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if `/proc/self/pagemap` could not be opened.
+    pub(crate) fn open() -> io::Result<Self> {
+        Ok(Self {
+            pagemap: File::open("/proc/self/pagemap")?,
+            gpu_ptr_translator: File::open("/dev/gpu_ptr_translator").ok(),
+            base_page_size: page_size() as u64,
+        })
+    }
+
+    /// Resolves the base pages spanned by `[addr, addr + length)` into runs, ordered by
+    /// virtual address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if reading the pagemap fails.
+    pub(crate) fn resolve(&self, addr: u64, length: usize) -> io::Result<Vec<PhysRun>> {
+        let runs = Self::read_runs(&self.pagemap, addr, length, self.base_page_size)?;
+        let any_present = runs.iter().any(|run| run.phys_addr.is_some());
+        match self.gpu_ptr_translator {
+            Some(ref translator) if !any_present => {
+                Self::read_runs(translator, addr, length, self.base_page_size)
+            }
+            Some(_) | None => Ok(runs),
+        }
+    }
+
+    #[allow(clippy::host_endian_bytes)]
+    fn read_runs(
+        file: &File,
+        addr: u64,
+        length: usize,
+        base_page_size: u64,
+    ) -> io::Result<Vec<PhysRun>> {
+        let mut runs = Vec::new();
+        if length == 0 {
+            return Ok(runs);
+        }
+        let first_vpn = addr / base_page_size;
+        let last_vpn = addr.saturating_add(length as u64 - 1) / base_page_size;
+        let total = (last_vpn - first_vpn + 1) as usize;
+        let mut buf = vec![0u8; total.min(MAX_ENTRIES_PER_READ) * ENTRY_SIZE];
+        let mut vpn = first_vpn;
+        while vpn <= last_vpn {
+            let count = ((last_vpn - vpn + 1) as usize).min(MAX_ENTRIES_PER_READ);
+            let Some(chunk) = buf.get_mut(..count * ENTRY_SIZE) else {
+                break;
+            };
+            file.read_exact_at(chunk, vpn * ENTRY_SIZE as u64)?;
+            let entries = chunk
+                .chunks_exact(ENTRY_SIZE)
+                .filter_map(|bytes| bytes.try_into().ok())
+                .map(u64::from_ne_bytes);
+            push_entries(&mut runs, vpn * base_page_size, entries, base_page_size);
+            vpn += count as u64;
+        }
+
+        Ok(runs)
+    }
+}
+
+/// Appends the pages described by consecutive pagemap entries starting at `virt_addr`,
+/// extending the last run while the pages stay physically contiguous or not present
+fn push_entries<I>(runs: &mut Vec<PhysRun>, mut virt_addr: u64, entries: I, base_page_size: u64)
+where
+    I: IntoIterator<Item = u64>,
+{
+    for entry in entries {
+        let phys_addr =
+            ((entry >> PAGE_PRESENT_BIT) & 1 != 0).then(|| (entry & PFN_MASK) * base_page_size);
+        let extends = runs.last().is_some_and(|last| {
+            last.end() == virt_addr
+                && match (last.phys_addr, phys_addr) {
+                    (Some(last_phys), Some(phys)) => last_phys + last.length == phys,
+                    (None, None) => true,
+                    (Some(_), None) | (None, Some(_)) => false,
+                }
+        });
+        match runs.last_mut() {
+            Some(last) if extends => last.length += base_page_size,
+            Some(_) | None => runs.push(PhysRun {
+                virt_addr,
+                length: base_page_size,
+                phys_addr,
+            }),
+        }
+        virt_addr += base_page_size;
+    }
+}
+
+impl AddressResolver for PagemapResolver {
+    fn virt_to_phys(&self, virt_addr: u64) -> io::Result<Option<u64>> {
+        Ok(self
+            .resolve(virt_addr, 1)?
+            .first()
+            .and_then(|run| run.phys_at(virt_addr)))
+    }
+
+    fn virt_to_phys_range(
+        &self,
+        start_addr: u64,
+        num_pages: usize,
+        page_size: u64,
+    ) -> io::Result<Vec<Option<u64>>> {
+        if num_pages == 0 {
+            return Ok(Vec::new());
+        }
+        let span = (num_pages as u64 - 1) * page_size + 1;
+        let runs = self.resolve(start_addr, span as usize)?;
+        let mut runs = runs.iter().peekable();
+        Ok((0..num_pages as u64)
+            .map(|i| {
+                let virt_addr = start_addr + i * page_size;
+                while runs.next_if(|run| run.end() <= virt_addr).is_some() {}
+                runs.peek().and_then(|run| run.phys_at(virt_addr))
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: u64 = 0x1000;
+
+    fn present(pfn: u64) -> u64 {
+        (1 << PAGE_PRESENT_BIT) | pfn
+    }
+
+    #[test]
+    fn contiguous_pages_form_runs() {
+        let mut runs = Vec::new();
+        let entries = [present(0x100), present(0x101), present(0x200), 0, 0];
+        push_entries(&mut runs, 0x10_0000, entries, PAGE);
+        push_entries(&mut runs, 0x10_5000, [0, present(0x300)], PAGE);
+        assert_eq!(
+            runs,
+            vec![
+                PhysRun {
+                    virt_addr: 0x10_0000,
+                    length: 2 * PAGE,
+                    phys_addr: Some(0x10_0000),
+                },
+                PhysRun {
+                    virt_addr: 0x10_2000,
+                    length: PAGE,
+                    phys_addr: Some(0x20_0000),
+                },
+                PhysRun {
+                    virt_addr: 0x10_3000,
+                    length: 3 * PAGE,
+                    phys_addr: None,
+                },
+                PhysRun {
+                    virt_addr: 0x10_6000,
+                    length: PAGE,
+                    phys_addr: Some(0x30_0000),
+                },
+            ]
+        );
+        assert_eq!(runs.first().unwrap().phys_at(0x10_1010), Some(0x10_1010));
+        assert_eq!(runs.get(2).unwrap().phys_at(0x10_4000), None);
+    }
+
+    #[test]
+    fn resolves_own_pages() {
+        let resolver = PagemapResolver::open().unwrap();
+        let buf = vec![1u8; 8 * page_size()];
+        let addr = buf.as_ptr() as u64;
+        let runs = resolver.resolve(addr, buf.len()).unwrap();
+        let first = runs.first().unwrap();
+        let last = runs.last().unwrap();
+        assert!(first.virt_addr <= addr && last.end() >= addr + buf.len() as u64);
+        assert!(runs.iter().all(|run| run.phys_addr.is_some()));
+        let phys_addrs = resolver
+            .virt_to_phys_range(addr, 8, page_size() as u64)
+            .unwrap();
+        assert_eq!(phys_addrs.len(), 8);
+        assert!(phys_addrs.iter().all(Option::is_some));
+    }
+}
@@ -6,42 +6,89 @@ use std::{
     ptr,
 };
 
+use tracing::warn;
 use tracing_subscriber::Layer;
 
+use crate::affinity::phys_addr_numa_node;
+
 use super::{
     page::{ContiguousPages, MmapMut, PageAllocator},
     DmaBuf, DmaBufAllocator, PageWithPhysAddr,
 };
 
-const CLASS_PATH: &str = "/sys/class/u-dma-buf/udmabuf0";
+const CLASS_PATH: &str = "/sys/class/u-dma-buf";
+const DEFAULT_DEVICE_NAME: &str = "udmabuf0";
 
 pub(crate) struct UDmaBufAllocator {
     fd: File,
     offset: usize,
+    /// Name of the u-dma-buf device
+    name: String,
 }
 
 impl UDmaBufAllocator {
     pub(crate) fn open() -> io::Result<Self> {
+        Self::open_by_name(DEFAULT_DEVICE_NAME)
+    }
+
+    /// Opens the u-dma-buf device whose memory resides on the given NUMA node.
+    ///
+    /// Falls back to the default device if no device is found on the node.
+    pub(crate) fn open_on_node(node: Option<u32>) -> io::Result<Self> {
+        let Some(node) = node else {
+            return Self::open();
+        };
+        let mut names: Vec<_> = fs::read_dir(CLASS_PATH)?
+            .flatten()
+            .filter_map(|entry| entry.file_name().into_string().ok())
+            .collect();
+        names.sort();
+        let local = names.into_iter().find(|name| {
+            Self::read_phys_addr(name)
+                .ok()
+                .and_then(phys_addr_numa_node)
+                .is_some_and(|n| n == node)
+        });
+        match local {
+            Some(name) => Self::open_by_name(&name),
+            None => {
+                warn!("no u-dma-buf device found on NUMA node {node}, using {DEFAULT_DEVICE_NAME}");
+                Self::open()
+            }
+        }
+    }
+
+    fn open_by_name(name: &str) -> io::Result<Self> {
         let fd = OpenOptions::new()
             .read(true)
             .write(true)
             .custom_flags(libc::O_SYNC)
-            .open("/dev/udmabuf0")?;
+            .open(PathBuf::from("/dev").join(name))?;
 
-        Ok(Self { fd, offset: 0 })
+        Ok(Self {
+            fd,
+            offset: 0,
+            name: name.to_owned(),
+        })
     }
 
-    pub(crate) fn size_total() -> io::Result<usize> {
-        Self::read_attribute("size")?.parse().map_err(|e| {
-            io::Error::new(
-                io::ErrorKind::InvalidData,
-                format!("Failed to parse size: {e}"),
-            )
-        })
+    pub(crate) fn size_total(&self) -> io::Result<usize> {
+        Self::read_attribute(&self.name, "size")?
+            .parse()
+            .map_err(|e| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Failed to parse size: {e}"),
+                )
+            })
+    }
+
+    pub(crate) fn phys_addr(&self) -> io::Result<u64> {
+        Self::read_phys_addr(&self.name)
     }
 
-    pub(crate) fn phys_addr() -> io::Result<u64> {
-        let str = Self::read_attribute("phys_addr")?;
+    fn read_phys_addr(name: &str) -> io::Result<u64> {
+        let str = Self::read_attribute(name, "phys_addr")?;
         u64::from_str_radix(str.trim_start_matches("0x"), 16).map_err(|e| {
             io::Error::new(
                 io::ErrorKind::InvalidData,
@@ -50,8 +97,8 @@ impl UDmaBufAllocator {
         })
     }
 
-    fn read_attribute(attr: &str) -> io::Result<String> {
-        let path = PathBuf::from(CLASS_PATH).join(attr);
+    fn read_attribute(name: &str, attr: &str) -> io::Result<String> {
+        let path = PathBuf::from(CLASS_PATH).join(name).join(attr);
         let mut content = String::new();
         let _ignore = File::open(&path)?.read_to_string(&mut content)?;
         Ok(content.trim().to_owned())
@@ -59,7 +106,7 @@ impl UDmaBufAllocator {
 
     #[allow(clippy::cast_possible_wrap)]
     fn create(&mut self, len: usize) -> io::Result<DmaBuf> {
-        let size_total = Self::size_total()?;
+        let size_total = self.size_total()?;
         if self.offset.checked_add(len).is_none_or(|x| x > size_total) {
             return Err(io::Error::new(
                 io::ErrorKind::OutOfMemory,
@@ -87,7 +134,7 @@ impl UDmaBufAllocator {
         }
 
         let mmap = MmapMut::new(ptr, len);
-        let phys_addr = Self::phys_addr()? + self.offset as u64;
+        let phys_addr = self.phys_addr()? + self.offset as u64;
 
         self.offset += len;
 
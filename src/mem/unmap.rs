@@ -0,0 +1,256 @@
+use std::{
+    fs::File,
+    io::{self, Read},
+    ops::Range,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    thread,
+};
+
+use flume::{Receiver, Sender};
+use nix::ioctl_readwrite;
+use tracing::warn;
+
+use super::page_size;
+
+/// Type of the userfaultfd ioctls
+const UFFDIO_TYPE: u8 = 0xAA;
+/// Version of the userfaultfd API
+const UFFD_API: u64 = 0xAA;
+/// Only handles faults raised in user space, allowed without `CAP_SYS_PTRACE`
+const UFFD_USER_MODE_ONLY: libc::c_int = 1;
+/// Reports the ranges moved by `mremap`
+const UFFD_FEATURE_EVENT_REMAP: u64 = 1 << 2;
+/// Reports the ranges dropped by `madvise(MADV_DONTNEED)` and `madvise(MADV_REMOVE)`
+const UFFD_FEATURE_EVENT_REMOVE: u64 = 1 << 3;
+/// Reports the ranges unmapped by `munmap`
+const UFFD_FEATURE_EVENT_UNMAP: u64 = 1 << 6;
+/// Features the watcher relies on
+const FEATURES: u64 =
+    UFFD_FEATURE_EVENT_REMAP | UFFD_FEATURE_EVENT_REMOVE | UFFD_FEATURE_EVENT_UNMAP;
+/// Registers a range for write protection faults, none is raised as no page is ever
+/// write protected, unlike missing page faults which would block the application
+const UFFDIO_REGISTER_MODE_WP: u64 = 1 << 1;
+const UFFD_EVENT_REMAP: u8 = 0x14;
+const UFFD_EVENT_REMOVE: u8 = 0x15;
+const UFFD_EVENT_UNMAP: u8 = 0x16;
+/// Size of a `uffd_msg`
+const UFFD_MSG_SIZE: usize = 32;
+/// Offset of the event arguments in a `uffd_msg`
+const UFFD_MSG_ARG_OFFSET: usize = 8;
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Debug, Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+ioctl_readwrite!(uffdio_api, UFFDIO_TYPE, 0x3F, UffdioApi);
+ioctl_readwrite!(uffdio_register, UFFDIO_TYPE, 0x00, UffdioRegister);
+
+/// Reports the watched ranges the application unmaps, moves or drops, through a
+/// userfaultfd.
+///
+/// The kernel blocks `munmap`, `mremap` and `madvise` on a watched range until their
+/// event is read. A dedicated thread forwards the events, so a range is queued once the
+/// call returns, including the unmaps done by the allocator inside `free`. The ranges
+/// stay watched until they are unmapped.
+#[derive(Debug)]
+pub(crate) struct UnmapWatcher {
+    fd: OwnedFd,
+    unmapped: Receiver<Range<u64>>,
+}
+
+#[allow(unsafe_code)]
+impl UnmapWatcher {
+    /// Opens a userfaultfd and spawns the thread forwarding its events
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the kernel does not provide userfaultfd or its unmap events.
+    pub(crate) fn new() -> io::Result<Self> {
+        let fd = open_userfaultfd()?;
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            features: FEATURES,
+            ioctls: 0,
+        };
+        // SAFETY: fd is a userfaultfd and api is a valid uffdio_api
+        let _ret = unsafe { uffdio_api(fd.as_raw_fd(), &raw mut api)? };
+        if api.features & FEATURES != FEATURES {
+            return Err(io::ErrorKind::Unsupported.into());
+        }
+        let events = File::from(fd.try_clone()?);
+        let (tx, unmapped) = flume::unbounded();
+        let _handle = thread::Builder::new()
+            .name("unmap-watcher".into())
+            .spawn(move || forward(events, &tx))?;
+        Ok(Self { fd, unmapped })
+    }
+
+    /// Watches the pages covering `[addr, addr + length)`
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the range can not be registered, e.g. it is not mapped, it is
+    /// watched by another userfaultfd or the kernel can not write protect its mapping.
+    pub(crate) fn watch(&self, addr: u64, length: usize) -> io::Result<()> {
+        let page_size = page_size() as u64;
+        let start = addr & !(page_size - 1);
+        let end = addr
+            .checked_add(length as u64)
+            .and_then(|end| end.checked_next_multiple_of(page_size))
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        let mut register = UffdioRegister {
+            range: UffdioRange {
+                start,
+                len: end - start,
+            },
+            mode: UFFDIO_REGISTER_MODE_WP,
+            ioctls: 0,
+        };
+        // SAFETY: fd is a userfaultfd and register is a valid uffdio_register
+        let _ret = unsafe { uffdio_register(self.fd.as_raw_fd(), &raw mut register)? };
+        Ok(())
+    }
+
+    /// Returns the ranges reported since the last call
+    pub(crate) fn unmapped(&self) -> impl Iterator<Item = Range<u64>> + '_ {
+        self.unmapped.try_iter()
+    }
+}
+
+#[allow(unsafe_code)]
+fn open_userfaultfd() -> io::Result<OwnedFd> {
+    let flags = libc::O_CLOEXEC | UFFD_USER_MODE_ONLY;
+    // SAFETY: FFI call with valid flags
+    let mut ret = unsafe { libc::syscall(libc::SYS_userfaultfd, flags) };
+    if ret < 0 && io::Error::last_os_error().raw_os_error() == Some(libc::EINVAL) {
+        // Kernels before 5.11 do not know the user mode flag
+        // SAFETY: FFI call with valid flags
+        ret = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC) };
+    }
+    if ret < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let fd = libc::c_int::try_from(ret).map_err(|_err| io::Error::from(io::ErrorKind::Other))?;
+    // SAFETY: fd is a newly created file descriptor owned by us
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Forwards the reported ranges until the watcher is dropped. The thread never stops
+/// reading while the watcher exists, the unmapping threads wait for their event.
+fn forward(mut events: File, tx: &Sender<Range<u64>>) {
+    let mut buf = [0; UFFD_MSG_SIZE * 16];
+    loop {
+        let len = match events.read(&mut buf) {
+            Ok(len) => len,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => {
+                warn!("failed to read unmap events: {err}");
+                continue;
+            }
+        };
+        let msgs = buf.get(..len).unwrap_or_default();
+        for range in msgs.chunks_exact(UFFD_MSG_SIZE).filter_map(unmapped_range) {
+            if tx.send(range).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// Returns the range reported by a `uffd_msg`, moved ranges are reported at their
+/// source
+fn unmapped_range(msg: &[u8]) -> Option<Range<u64>> {
+    let arg = |index: usize| {
+        let offset = UFFD_MSG_ARG_OFFSET + index * 8;
+        msg.get(offset..offset + 8)
+            .and_then(|bytes| bytes.try_into().ok())
+            .map(u64::from_ne_bytes)
+    };
+    match *msg.first()? {
+        UFFD_EVENT_UNMAP | UFFD_EVENT_REMOVE => Some(arg(0)?..arg(1)?),
+        UFFD_EVENT_REMAP => {
+            let from = arg(0)?;
+            Some(from..from.saturating_add(arg(2)?))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn msg(event: u8, args: [u64; 3]) -> [u8; UFFD_MSG_SIZE] {
+        let mut msg = [0; UFFD_MSG_SIZE];
+        msg[0] = event;
+        for (i, arg) in args.iter().enumerate() {
+            let offset = UFFD_MSG_ARG_OFFSET + i * 8;
+            msg[offset..offset + 8].copy_from_slice(&arg.to_ne_bytes());
+        }
+        msg
+    }
+
+    #[test]
+    fn events_report_their_source_range() {
+        let unmap = msg(UFFD_EVENT_UNMAP, [0x1000, 0x3000, 0]);
+        assert_eq!(unmapped_range(&unmap), Some(0x1000..0x3000));
+        let remove = msg(UFFD_EVENT_REMOVE, [0x4000, 0x5000, 0]);
+        assert_eq!(unmapped_range(&remove), Some(0x4000..0x5000));
+        let remap = msg(UFFD_EVENT_REMAP, [0x8000, 0x2_0000, 0x2000]);
+        assert_eq!(unmapped_range(&remap), Some(0x8000..0xa000));
+        let fault = msg(0x12, [0, 0x8000, 0]);
+        assert_eq!(unmapped_range(&fault), None);
+    }
+
+    #[test]
+    #[allow(unsafe_code)]
+    fn munmap_of_a_watched_range_is_reported() {
+        let Ok(watcher) = UnmapWatcher::new() else {
+            // userfaultfd is disabled on this host
+            return;
+        };
+        let len = 4 * page_size();
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_POPULATE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let start = addr as u64;
+        watcher.watch(start + 1, len - 2).unwrap();
+        let page = page_size() as u64;
+        assert_eq!(
+            unsafe { libc::munmap(addr.add(page_size()), page_size()) },
+            0
+        );
+        assert_eq!(
+            watcher.unmapped().collect::<Vec<_>>(),
+            vec![start + page..start + 2 * page]
+        );
+        assert_eq!(unsafe { libc::munmap(addr, len) }, 0);
+    }
+}
@@ -1,7 +1,5 @@
 use std::io;
 
-use crate::mem::PAGE_SIZE;
-
 /// Pins pages in memory to prevent swapping
 ///
 /// # Errors
@@ -31,14 +29,14 @@ pub(crate) fn unpin_pages(addr: u64, length: usize) -> io::Result<()> {
     Ok(())
 }
 
-/// Calculates the number of pages spanned by a memory region.
+/// Calculates the number of pages of `page_size` spanned by a memory region.
 #[allow(clippy::arithmetic_side_effects)]
-pub(crate) fn get_num_page(addr: u64, length: usize) -> usize {
+pub(crate) fn get_num_page(addr: u64, length: usize, page_size: u64) -> usize {
     if length == 0 {
         return 0;
     }
     let last = addr.saturating_add(length as u64).saturating_sub(1);
-    let start_page = addr / PAGE_SIZE as u64;
-    let end_page = last / PAGE_SIZE as u64;
+    let start_page = addr / page_size;
+    let end_page = last / page_size;
     (end_page.saturating_sub(start_page) + 1) as usize
 }
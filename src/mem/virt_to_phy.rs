@@ -10,11 +10,6 @@ const PFN_MASK: u64 = (1 << 55) - 1;
 /// Bit indicating if a page is present in memory
 const PAGE_PRESENT_BIT: u8 = 63;
 
-#[cfg(feature = "page_size_2m")]
-const PAGE_SIZE: u64 = 0x20_0000;
-#[cfg(feature = "page_size_4k")]
-const PAGE_SIZE: u64 = 0x1000;
-
 /// Returns the system's base page size in bytes.
 #[allow(unsafe_code, clippy::cast_sign_loss)]
 fn get_base_page_size() -> u64 {
@@ -34,7 +29,8 @@ pub(crate) trait AddressResolver {
     /// Returns an IO error if address resolving fails.
     fn virt_to_phys(&self, virt_addr: u64) -> io::Result<Option<u64>>;
 
-    /// Converts a list of virtual addresses to physical addresses
+    /// Converts the addresses `start_addr + i * page_size` of `num_pages` pages to
+    /// physical addresses
     ///
     /// # Returns
     ///
@@ -49,9 +45,10 @@ pub(crate) trait AddressResolver {
         &self,
         start_addr: u64,
         num_pages: usize,
+        page_size: u64,
     ) -> io::Result<Vec<Option<u64>>> {
         (0..num_pages as u64)
-            .map(|x| self.virt_to_phys(start_addr.saturating_add(x * PAGE_SIZE)))
+            .map(|x| self.virt_to_phys(start_addr.saturating_add(x * page_size)))
             .collect::<Result<_, _>>()
     }
 }
@@ -107,6 +104,7 @@ impl AddressResolver for PhysAddrResolverLinuxX86 {
         &self,
         start_addr: u64,
         num_pages: usize,
+        page_size: u64,
     ) -> io::Result<Vec<Option<u64>>> {
         let base_page_size = get_base_page_size();
         let mut phy_addrs = vec![None; num_pages];
@@ -130,7 +128,7 @@ impl AddressResolver for PhysAddrResolverLinuxX86 {
                 maybe_gpu_ptr = false;
             }
 
-            addr += PAGE_SIZE;
+            addr += page_size;
         }
 
         if maybe_gpu_ptr {
@@ -153,7 +151,7 @@ impl AddressResolver for PhysAddrResolverLinuxX86 {
                     phy_addrs[i] = Some(phys_addr);
                 }
 
-                addr += PAGE_SIZE;
+                addr += page_size;
             }
         }
 
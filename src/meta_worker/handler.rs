@@ -1,12 +1,16 @@
+use std::time::Instant;
+
 use crate::{
     ack_responder::AckResponse,
     completion::{CompletionTask, Event, MessageMeta, RecvEvent, RecvEventOp},
     constants::PSN_MASK,
+    dcqcn::{NotificationPoint, ReactionPoint},
     device_protocol::{
-        AckMetaLocalHw, AckMetaRemoteDriver, HeaderReadMeta, HeaderType, HeaderWriteMeta,
+        AckMetaLocalHw, AckMetaRemoteDriver, CnpMeta, HeaderReadMeta, HeaderType, HeaderWriteMeta,
         NakMetaLocalHw, NakMetaRemoteDriver, NakMetaRemoteHw, PacketPos, WorkReqOpCode,
     },
-    packet_retransmit::PacketRetransmitTask,
+    metrics::{Metrics, NakKind},
+    packet_retransmit::{NakBitmaps, PacketRetransmitTask},
     rdma_write_worker::RdmaWriteTask,
     send::{SendWrBase, SendWrRdma},
     timeout_retransmit::RetransmitTask,
@@ -24,6 +28,9 @@ pub(crate) struct MetaHandler {
     pub(super) packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
     pub(super) completion_tx: flume::Sender<CompletionTask>,
     pub(super) rdma_write_tx: flume::Sender<RdmaWriteTask>,
+    pub(super) metrics: Metrics,
+    pub(super) reaction_point: ReactionPoint,
+    pub(super) notification_point: NotificationPoint,
 }
 
 impl MetaHandler {
@@ -33,15 +40,22 @@ impl MetaHandler {
         packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
         completion_tx: flume::Sender<CompletionTask>,
         rdma_write_tx: flume::Sender<RdmaWriteTask>,
+        metrics: Metrics,
+        reaction_point: ReactionPoint,
+        notification_point: NotificationPoint,
+        max_qp: usize,
     ) -> Self {
         Self {
-            send_table: QpTable::new(),
-            recv_table: QpTable::new(),
+            send_table: QpTable::new(max_qp),
+            recv_table: QpTable::new(max_qp),
             ack_tx,
             retransmit_tx,
             packet_retransmit_tx,
             completion_tx,
             rdma_write_tx,
+            metrics,
+            reaction_point,
+            notification_point,
         }
     }
 
@@ -54,7 +68,21 @@ impl MetaHandler {
             ReportMeta::NakLocalHw(x) => self.handle_nak_local_hw(x),
             ReportMeta::NakRemoteHw(x) => self.handle_nak_remote_hw(x),
             ReportMeta::NakRemoteDriver(x) => self.handle_nak_remote_driver(x),
-            ReportMeta::Cnp { .. } => todo!(),
+            ReportMeta::Cnp(x) => self.handle_cnp(x),
+        }
+    }
+
+    #[allow(clippy::unnecessary_wraps)]
+    fn handle_cnp(&mut self, meta: CnpMeta) -> Option<()> {
+        self.reaction_point.on_cnp(meta.qpn);
+
+        Some(())
+    }
+
+    /// Notifies the sender of the QP about congestion, rate limited by the notification point
+    fn notify_congestion(&mut self, qpn: u32) {
+        if self.notification_point.should_notify(qpn) {
+            let _ignore = self.ack_tx.send(AckResponse::Cnp { qpn });
         }
     }
 
@@ -77,6 +105,7 @@ impl MetaHandler {
     }
 
     fn handle_nak_local_hw(&mut self, meta: NakMetaLocalHw) -> Option<()> {
+        self.metrics.record_nak(meta.qpn, NakKind::LocalHw);
         let tracker = self.recv_table.get_qp_mut(meta.qpn)?;
         if let Some(psn) =
             tracker.nak_bitmap(meta.psn_pre, meta.pre_bitmap, meta.psn_now, meta.now_bitmap)
@@ -88,6 +117,7 @@ impl MetaHandler {
     }
 
     fn handle_nak_remote_hw(&mut self, meta: NakMetaRemoteHw) -> Option<()> {
+        self.metrics.record_nak(meta.qpn, NakKind::RemoteHw);
         let tracker = self.send_table.get_qp_mut(meta.qpn)?;
         if let Some(psn) = tracker.nak_bitmap(
             meta.msn,
@@ -101,10 +131,14 @@ impl MetaHandler {
 
         let _ignore = self
             .packet_retransmit_tx
-            .send(PacketRetransmitTask::RetransmitRange {
+            .send(PacketRetransmitTask::RetransmitMissing {
                 qpn: meta.qpn,
-                psn_low: meta.psn_pre,
-                psn_high: meta.psn_now + 128,
+                bitmaps: NakBitmaps::new(
+                    meta.psn_pre,
+                    meta.pre_bitmap,
+                    meta.psn_now,
+                    meta.now_bitmap,
+                ),
             });
 
         Some(())
@@ -112,6 +146,7 @@ impl MetaHandler {
 
     #[allow(clippy::unnecessary_wraps)]
     fn handle_nak_remote_driver(&mut self, meta: NakMetaRemoteDriver) -> Option<()> {
+        self.metrics.record_nak(meta.qpn, NakKind::RemoteDriver);
         let tracker = self.send_table.get_qp_mut(meta.qpn)?;
         if let Some(psn) = tracker.ack_before(meta.psn_pre) {
             self.sender_updates(meta.qpn, psn);
@@ -135,6 +170,11 @@ impl MetaHandler {
         let _ignore = self
             .packet_retransmit_tx
             .send(PacketRetransmitTask::Ack { qpn, psn: base_psn });
+        let _ignore = self.retransmit_tx.send(RetransmitTask::ReceiveACK {
+            qpn,
+            psn: base_psn,
+            at: Instant::now(),
+        });
         let _ignore = self
             .rdma_write_tx
             .send(RdmaWriteTask::new_ack(qpn, base_psn));
@@ -150,6 +190,9 @@ impl MetaHandler {
     }
 
     pub(super) fn handle_header_read(&mut self, meta: HeaderReadMeta) -> Option<()> {
+        if meta.ecn_marked {
+            self.notify_congestion(meta.dqpn);
+        }
         if meta.ack_req {
             let end_psn = meta.psn + 1;
             let event = Event::Recv(RecvEvent::new(
@@ -205,7 +248,11 @@ impl MetaHandler {
             rkey,
             imm,
             header_type,
+            ecn_marked,
         } = meta;
+        if ecn_marked {
+            self.notify_congestion(dqpn);
+        }
         let tracker = self.recv_table.get_qp_mut(dqpn)?;
 
         if matches!(pos, PacketPos::Last | PacketPos::Only) {
@@ -16,9 +16,11 @@ use tracing::error;
 
 use crate::{
     ack_responder::AckResponse,
+    affinity::{CpuAffinity, WorkerClass},
     completion::CompletionTask,
     device_protocol::{MetaReport, ReportMeta},
     packet_retransmit::PacketRetransmitTask,
+    polling::{Notifier, Poller, PollingConfig},
     qp::QueuePairAttrTable,
     rdma_write_worker::RdmaWriteTask,
     timeout_retransmit::RetransmitTask,
@@ -29,29 +31,50 @@ pub(crate) struct MetaWorker<T> {
     /// Inner meta report queue
     inner: T,
     handler: MetaHandler,
+    /// Polling strategy used when the queue is empty
+    polling: PollingConfig,
+    /// Notifier kicked by the send workers
+    notifier: Notifier,
 }
 
 impl<T: MetaReport + Send + 'static> MetaWorker<T> {
-    pub(crate) fn new(inner: T, handler: MetaHandler) -> Self {
-        Self { inner, handler }
+    pub(crate) fn new(
+        inner: T,
+        handler: MetaHandler,
+        polling: PollingConfig,
+        notifier: Notifier,
+    ) -> Self {
+        Self {
+            inner,
+            handler,
+            polling,
+            notifier,
+        }
     }
 
-    pub(crate) fn spawn(self, is_shutdown: Arc<AtomicBool>) {
+    pub(crate) fn spawn(self, is_shutdown: Arc<AtomicBool>, affinity: CpuAffinity) {
         let _handle = thread::Builder::new()
             .name("meta-worker".into())
-            .spawn(move || self.run(is_shutdown))
+            .spawn(move || {
+                affinity.apply(WorkerClass::Meta, 0);
+                self.run(is_shutdown)
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
     }
 
     #[allow(clippy::needless_pass_by_value)] // consume the flag
     /// Run the handler loop
     fn run(mut self, is_shutdown: Arc<AtomicBool>) -> io::Result<()> {
+        let mut poller = Poller::new(self.polling, self.notifier.clone());
         while !is_shutdown.load(Ordering::Relaxed) {
-            if let Some(meta) = self.inner.try_recv_meta()? {
-                if self.handler.handle_meta(meta).is_none() {
-                    error!("invalid meta: {meta:?}");
-                }
+            let Some(meta) = self.inner.try_recv_meta()? else {
+                poller.on_idle();
+                continue;
             };
+            poller.on_busy();
+            if self.handler.handle_meta(meta).is_none() {
+                error!("invalid meta: {meta:?}");
+            }
         }
 
         Ok(())
@@ -0,0 +1,555 @@
+#![allow(clippy::module_name_repetitions)] // exported
+
+use std::{
+    collections::HashMap,
+    fmt::Write as _,
+    fs, io,
+    io::Write as _,
+    iter,
+    os::unix::net::UnixListener,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, OnceLock,
+    },
+    thread,
+    time::Duration,
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::utils::qpn_index;
+
+/// Number of latency histogram buckets, bucket `i` covers latencies up to `2^i` microseconds
+const NUM_LATENCY_BUCKETS: usize = 21;
+
+/// Default interval of writing the snapshot file
+const DEFAULT_EXPORT_INTERVAL_MS: u64 = 1000;
+
+/// Registered metrics of all opened devices, keyed by sysfs name
+static REGISTRY: OnceLock<Mutex<HashMap<String, Metrics>>> = OnceLock::new();
+
+/// Returns the metrics of an opened device
+#[inline]
+#[must_use]
+pub fn device_metrics(sysfs_name: &str) -> Option<Metrics> {
+    REGISTRY.get()?.lock().get(sysfs_name).cloned()
+}
+
+/// Registers the metrics of a device
+pub(crate) fn register(sysfs_name: &str, metrics: Metrics) {
+    let _ignore = REGISTRY
+        .get_or_init(|| Mutex::new(HashMap::new()))
+        .lock()
+        .insert(sysfs_name.to_owned(), metrics);
+}
+
+/// Metrics exporter configuration
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct MetricsConfig {
+    /// Path of the file that the Prometheus snapshot is periodically written to
+    prometheus_file: Option<PathBuf>,
+    /// Path of the Unix socket serving the Prometheus snapshot on each connection
+    unix_socket: Option<PathBuf>,
+    /// Interval of writing the snapshot file
+    export_interval_ms: Option<u64>,
+}
+
+/// Kinds of received NAKs
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum NakKind {
+    /// Reported by the local hardware
+    LocalHw,
+    /// Generated by the remote hardware
+    RemoteHw,
+    /// Generated by the remote driver
+    RemoteDriver,
+}
+
+/// Per QP counters
+#[derive(Debug, Default)]
+struct QpCounters {
+    qpn: AtomicU32,
+    packets_sent: AtomicU64,
+    bytes_sent: AtomicU64,
+    retransmitted_packets: AtomicU64,
+    timeout_retransmits: AtomicU64,
+    naks_local_hw: AtomicU64,
+    naks_remote_hw: AtomicU64,
+    naks_remote_driver: AtomicU64,
+    acks_generated: AtomicU64,
+    naks_generated: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct SimpleNicCounters {
+    tx_frames: AtomicU64,
+    tx_bytes: AtomicU64,
+    rx_frames: AtomicU64,
+    rx_bytes: AtomicU64,
+}
+
+#[derive(Debug)]
+struct LatencyCounters {
+    buckets: [AtomicU64; NUM_LATENCY_BUCKETS],
+    overflow: AtomicU64,
+    sum_ns: AtomicU64,
+}
+
+impl Default for LatencyCounters {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| AtomicU64::new(0)),
+            overflow: AtomicU64::new(0),
+            sum_ns: AtomicU64::new(0),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct MetricsInner {
+    qps: Box<[QpCounters]>,
+    completions_per_cq: Box<[AtomicU64]>,
+    simple_nic: SimpleNicCounters,
+    latency: LatencyCounters,
+}
+
+/// Transport counters of a device, shared by all driver workers
+#[derive(Debug, Clone)]
+pub struct Metrics {
+    inner: Arc<MetricsInner>,
+}
+
+impl Metrics {
+    pub(crate) fn new(max_qp: usize, max_cq: usize) -> Self {
+        Self {
+            inner: Arc::new(MetricsInner {
+                qps: iter::repeat_with(QpCounters::default)
+                    .take(max_qp)
+                    .collect(),
+                completions_per_cq: iter::repeat_with(AtomicU64::default).take(max_cq).collect(),
+                simple_nic: SimpleNicCounters::default(),
+                latency: LatencyCounters::default(),
+            }),
+        }
+    }
+
+    fn qp(&self, qpn: u32) -> Option<&QpCounters> {
+        let counters = self.inner.qps.get(qpn_index(qpn))?;
+        counters.qpn.store(qpn, Ordering::Relaxed);
+        Some(counters)
+    }
+
+    pub(crate) fn record_send(&self, qpn: u32, num_packets: u64, num_bytes: u64) {
+        if let Some(qp) = self.qp(qpn) {
+            let _prev = qp.packets_sent.fetch_add(num_packets, Ordering::Relaxed);
+            let _prev = qp.bytes_sent.fetch_add(num_bytes, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_packet_retransmit(&self, qpn: u32) {
+        if let Some(qp) = self.qp(qpn) {
+            let _prev = qp.retransmitted_packets.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_timeout_retransmit(&self, qpn: u32) {
+        if let Some(qp) = self.qp(qpn) {
+            let _prev = qp.timeout_retransmits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_nak(&self, qpn: u32, kind: NakKind) {
+        if let Some(qp) = self.qp(qpn) {
+            let counter = match kind {
+                NakKind::LocalHw => &qp.naks_local_hw,
+                NakKind::RemoteHw => &qp.naks_remote_hw,
+                NakKind::RemoteDriver => &qp.naks_remote_driver,
+            };
+            let _prev = counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_ack_generated(&self, qpn: u32, is_nak: bool) {
+        if let Some(qp) = self.qp(qpn) {
+            let counter = if is_nak {
+                &qp.naks_generated
+            } else {
+                &qp.acks_generated
+            };
+            let _prev = counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_completion(&self, cq_handle: u32) {
+        if let Some(counter) = self.inner.completions_per_cq.get(cq_handle as usize) {
+            let _prev = counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_simple_nic_tx(&self, num_bytes: usize) {
+        let nic = &self.inner.simple_nic;
+        let _prev = nic.tx_frames.fetch_add(1, Ordering::Relaxed);
+        let _prev = nic.tx_bytes.fetch_add(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_simple_nic_rx(&self, num_bytes: usize) {
+        let nic = &self.inner.simple_nic;
+        let _prev = nic.rx_frames.fetch_add(1, Ordering::Relaxed);
+        let _prev = nic.rx_bytes.fetch_add(num_bytes as u64, Ordering::Relaxed);
+    }
+
+    /// Records the latency from posting a work request to its completion
+    pub(crate) fn record_latency(&self, latency: Duration) {
+        let latency_us = u64::try_from(latency.as_micros()).unwrap_or(u64::MAX);
+        let index = latency_us
+            .checked_next_power_of_two()
+            .map_or(usize::MAX, |x| x.trailing_zeros() as usize);
+        let lat = &self.inner.latency;
+        let counter = lat.buckets.get(index).unwrap_or(&lat.overflow);
+        let _prev = counter.fetch_add(1, Ordering::Relaxed);
+        let latency_ns = u64::try_from(latency.as_nanos()).unwrap_or(u64::MAX);
+        let _prev = lat.sum_ns.fetch_add(latency_ns, Ordering::Relaxed);
+    }
+
+    /// Takes a snapshot of all counters, only QPs and CQs with activity are included
+    #[inline]
+    #[must_use]
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let load = |x: &AtomicU64| x.load(Ordering::Relaxed);
+        let qps = self
+            .inner
+            .qps
+            .iter()
+            .map(|qp| QpStats {
+                qpn: qp.qpn.load(Ordering::Relaxed),
+                packets_sent: load(&qp.packets_sent),
+                bytes_sent: load(&qp.bytes_sent),
+                retransmitted_packets: load(&qp.retransmitted_packets),
+                timeout_retransmits: load(&qp.timeout_retransmits),
+                naks_local_hw: load(&qp.naks_local_hw),
+                naks_remote_hw: load(&qp.naks_remote_hw),
+                naks_remote_driver: load(&qp.naks_remote_driver),
+                acks_generated: load(&qp.acks_generated),
+                naks_generated: load(&qp.naks_generated),
+            })
+            .filter(|stats| *stats != QpStats::new(stats.qpn))
+            .collect();
+        let completions_per_cq = self
+            .inner
+            .completions_per_cq
+            .iter()
+            .enumerate()
+            .map(|(handle, x)| (handle as u32, load(x)))
+            .filter(|&(_, x)| x != 0)
+            .collect();
+        let nic = &self.inner.simple_nic;
+        let lat = &self.inner.latency;
+
+        MetricsSnapshot {
+            qps,
+            completions_per_cq,
+            simple_nic: SimpleNicStats {
+                tx_frames: load(&nic.tx_frames),
+                tx_bytes: load(&nic.tx_bytes),
+                rx_frames: load(&nic.rx_frames),
+                rx_bytes: load(&nic.rx_bytes),
+            },
+            latency: LatencyHistogram {
+                buckets: lat.buckets.iter().map(load).collect(),
+                overflow: load(&lat.overflow),
+                sum_ns: load(&lat.sum_ns),
+            },
+        }
+    }
+
+    /// Writes a Prometheus text format snapshot to the given file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file could not be written.
+    #[inline]
+    pub fn write_prometheus(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, self.snapshot().to_prometheus())?;
+        fs::rename(tmp, path)
+    }
+
+    /// Serves Prometheus text format snapshots on a Unix socket, one snapshot per connection
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the socket could not be bound.
+    #[inline]
+    pub fn serve_unix_socket(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        let metrics = self.clone();
+        let _handle = thread::Builder::new()
+            .name("metrics-socket-worker".into())
+            .spawn(move || {
+                for mut stream in listener.incoming().flatten() {
+                    if let Err(err) =
+                        stream.write_all(metrics.snapshot().to_prometheus().as_bytes())
+                    {
+                        error!("failed to write metrics: {err}");
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Starts the exporters enabled in the config
+    pub(crate) fn start_exporters(&self, config: &MetricsConfig) -> io::Result<()> {
+        if let Some(ref path) = config.unix_socket {
+            self.serve_unix_socket(path)?;
+        }
+        if let Some(ref path) = config.prometheus_file {
+            let interval = Duration::from_millis(
+                config
+                    .export_interval_ms
+                    .unwrap_or(DEFAULT_EXPORT_INTERVAL_MS),
+            );
+            let metrics = self.clone();
+            let path = path.clone();
+            let _handle = thread::Builder::new()
+                .name("metrics-file-worker".into())
+                .spawn(move || loop {
+                    if let Err(err) = metrics.write_prometheus(&path) {
+                        error!("failed to write metrics: {err}");
+                    }
+                    thread::sleep(interval);
+                })?;
+        }
+        Ok(())
+    }
+}
+
+/// Counters of a single QP
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct QpStats {
+    /// Queue pair number
+    pub qpn: u32,
+    /// Number of packets sent, including retransmissions
+    pub packets_sent: u64,
+    /// Number of payload bytes sent, including retransmissions
+    pub bytes_sent: u64,
+    /// Number of packets retransmitted on NAKs
+    pub retransmitted_packets: u64,
+    /// Number of retransmissions triggered by ACK timeouts
+    pub timeout_retransmits: u64,
+    /// Number of NAKs reported by the local hardware
+    pub naks_local_hw: u64,
+    /// Number of NAKs generated by the remote hardware
+    pub naks_remote_hw: u64,
+    /// Number of NAKs generated by the remote driver
+    pub naks_remote_driver: u64,
+    /// Number of ACKs generated by this driver
+    pub acks_generated: u64,
+    /// Number of NAKs generated by this driver
+    pub naks_generated: u64,
+}
+
+impl QpStats {
+    fn new(qpn: u32) -> Self {
+        Self {
+            qpn,
+            ..Default::default()
+        }
+    }
+}
+
+/// Simple NIC counters
+#[non_exhaustive]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SimpleNicStats {
+    /// Number of frames transmitted
+    pub tx_frames: u64,
+    /// Number of bytes transmitted
+    pub tx_bytes: u64,
+    /// Number of frames received
+    pub rx_frames: u64,
+    /// Number of bytes received
+    pub rx_bytes: u64,
+}
+
+/// Post to completion latency histogram
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LatencyHistogram {
+    /// Non-cumulative counts, bucket `i` covers latencies in `(2^(i-1), 2^i]` microseconds
+    pub buckets: Vec<u64>,
+    /// Number of latencies exceeding the last bucket
+    pub overflow: u64,
+    /// Sum of all latencies in nanoseconds
+    pub sum_ns: u64,
+}
+
+impl LatencyHistogram {
+    /// Total number of samples
+    #[inline]
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().sum::<u64>() + self.overflow
+    }
+}
+
+/// A point in time copy of the device metrics
+#[non_exhaustive]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    /// Counters of the active QPs
+    pub qps: Vec<QpStats>,
+    /// Number of completions generated per CQ handle
+    pub completions_per_cq: Vec<(u32, u64)>,
+    /// Simple NIC counters
+    pub simple_nic: SimpleNicStats,
+    /// Post to completion latency
+    pub latency: LatencyHistogram,
+}
+
+impl MetricsSnapshot {
+    /// Renders the snapshot in the Prometheus text exposition format
+    #[inline]
+    #[must_use]
+    #[allow(clippy::float_arithmetic, clippy::cast_precision_loss)]
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let qp_counters: [(&str, &str, fn(&QpStats) -> u64); 6] = [
+            ("packets_sent", "Packets sent", |x| x.packets_sent),
+            ("bytes_sent", "Payload bytes sent", |x| x.bytes_sent),
+            (
+                "retransmitted_packets",
+                "Packets retransmitted on NAK",
+                |x| x.retransmitted_packets,
+            ),
+            (
+                "timeout_retransmits",
+                "Retransmissions on ACK timeout",
+                |x| x.timeout_retransmits,
+            ),
+            ("acks_generated", "ACKs generated", |x| x.acks_generated),
+            ("naks_generated", "NAKs generated", |x| x.naks_generated),
+        ];
+        for (name, help, get) in qp_counters {
+            let _ignore = writeln!(out, "# HELP bluerdma_qp_{name}_total {help}");
+            let _ignore = writeln!(out, "# TYPE bluerdma_qp_{name}_total counter");
+            for qp in &self.qps {
+                let _ignore = writeln!(
+                    out,
+                    "bluerdma_qp_{name}_total{{qpn=\"{}\"}} {}",
+                    qp.qpn,
+                    get(qp)
+                );
+            }
+        }
+
+        let _ignore = writeln!(out, "# HELP bluerdma_qp_naks_received_total NAKs received");
+        let _ignore = writeln!(out, "# TYPE bluerdma_qp_naks_received_total counter");
+        for qp in &self.qps {
+            for (kind, value) in [
+                ("local_hw", qp.naks_local_hw),
+                ("remote_hw", qp.naks_remote_hw),
+                ("remote_driver", qp.naks_remote_driver),
+            ] {
+                let _ignore = writeln!(
+                    out,
+                    "bluerdma_qp_naks_received_total{{qpn=\"{}\",kind=\"{kind}\"}} {value}",
+                    qp.qpn
+                );
+            }
+        }
+
+        let _ignore = writeln!(
+            out,
+            "# HELP bluerdma_cq_completions_total Completions generated"
+        );
+        let _ignore = writeln!(out, "# TYPE bluerdma_cq_completions_total counter");
+        for &(handle, value) in &self.completions_per_cq {
+            let _ignore = writeln!(
+                out,
+                "bluerdma_cq_completions_total{{cq=\"{handle}\"}} {value}"
+            );
+        }
+
+        let nic = self.simple_nic;
+        for (name, value) in [
+            ("tx_frames", nic.tx_frames),
+            ("tx_bytes", nic.tx_bytes),
+            ("rx_frames", nic.rx_frames),
+            ("rx_bytes", nic.rx_bytes),
+        ] {
+            let _ignore = writeln!(out, "# TYPE bluerdma_simple_nic_{name}_total counter");
+            let _ignore = writeln!(out, "bluerdma_simple_nic_{name}_total {value}");
+        }
+
+        let name = "bluerdma_post_to_completion_latency_seconds";
+        let _ignore = writeln!(out, "# HELP {name} Latency from post to completion");
+        let _ignore = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (i, count) in self.latency.buckets.iter().enumerate() {
+            cumulative += count;
+            let le = (1u64 << i) as f64 / 1e6;
+            let _ignore = writeln!(out, "{name}_bucket{{le=\"{le}\"}} {cumulative}");
+        }
+        let count = self.latency.count();
+        let _ignore = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ignore = writeln!(out, "{name}_sum {}", self.latency.sum_ns as f64 / 1e9);
+        let _ignore = writeln!(out, "{name}_count {count}");
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn latency_buckets() {
+        let metrics = Metrics::new(4, 4);
+        metrics.record_latency(Duration::from_nanos(500));
+        metrics.record_latency(Duration::from_micros(3));
+        metrics.record_latency(Duration::from_secs(10));
+        let lat = metrics.snapshot().latency;
+        assert_eq!(lat.buckets[0], 1);
+        assert_eq!(lat.buckets[2], 1);
+        assert_eq!(lat.overflow, 1);
+        assert_eq!(lat.count(), 3);
+    }
+
+    #[test]
+    fn snapshot_only_contains_active_qps() {
+        let metrics = Metrics::new(4, 4);
+        metrics.record_send(0x100, 2, 8192);
+        metrics.record_nak(0x100, NakKind::RemoteHw);
+        metrics.record_completion(3);
+        let snapshot = metrics.snapshot();
+        assert_eq!(snapshot.qps.len(), 1);
+        assert_eq!(snapshot.qps[0].qpn, 0x100);
+        assert_eq!(snapshot.qps[0].packets_sent, 2);
+        assert_eq!(snapshot.qps[0].naks_remote_hw, 1);
+        assert_eq!(snapshot.completions_per_cq, [(3, 1)]);
+    }
+
+    #[test]
+    fn prometheus_format() {
+        let metrics = Metrics::new(4, 4);
+        metrics.record_send(0x100, 1, 64);
+        metrics.record_simple_nic_tx(60);
+        metrics.record_latency(Duration::from_micros(1));
+        let text = metrics.snapshot().to_prometheus();
+        assert!(text.contains("bluerdma_qp_packets_sent_total{qpn=\"256\"} 1"));
+        assert!(text.contains("bluerdma_simple_nic_tx_frames_total 1"));
+        assert!(text.contains("bluerdma_post_to_completion_latency_seconds_bucket{le=\"+Inf\"} 1"));
+        assert!(text.contains("bluerdma_post_to_completion_latency_seconds_count 1"));
+    }
+}
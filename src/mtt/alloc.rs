@@ -1,15 +1,12 @@
 use std::iter;
 
-use bitvec::{array::BitArray, bitarr};
+use bitvec::vec::BitVec;
 use rand::Rng;
 
 use super::PgtEntry;
 
-const MAX_MR_CNT: usize = 8192;
 const LR_KEY_KEY_PART_WIDTH: u32 = 8;
 const LR_KEY_IDX_PART_WIDTH: u32 = 32 - LR_KEY_KEY_PART_WIDTH;
-/// Maximum number of entries in the secodn stage table
-pub(super) const PGT_LEN: usize = 0x20000;
 
 /// Table memory allocator for MTT
 pub(crate) struct Alloc {
@@ -20,11 +17,11 @@ pub(crate) struct Alloc {
 }
 
 impl Alloc {
-    /// Creates a new allocator instance
-    pub(super) fn new() -> Self {
+    /// Creates a new allocator instance for `max_mr` regions and `pgt_len` second stage entries
+    pub(super) fn new(max_mr: usize, pgt_len: usize) -> Self {
         Self {
-            mr: MrTableAlloc::new(),
-            pgt: PgtAlloc::new(),
+            mr: MrTableAlloc::new(max_mr),
+            pgt: PgtAlloc::new(pgt_len),
         }
     }
 
@@ -76,9 +73,9 @@ pub(super) struct MrTableAlloc {
 
 impl MrTableAlloc {
     /// Creates a new `MrTableAlloc` instance with a pre-filled free list
-    pub(super) fn new() -> Self {
+    pub(super) fn new(max_mr: usize) -> Self {
         Self {
-            free_list: Self::fill_up_free_list(),
+            free_list: Self::fill_up_free_list(max_mr),
         }
     }
 
@@ -97,8 +94,8 @@ impl MrTableAlloc {
     }
 
     /// Creates initial free list containing all possible memory region keys
-    fn fill_up_free_list() -> Vec<MrKeyIndex> {
-        (0..u32::try_from(MAX_MR_CNT).unwrap_or_else(|_| unreachable!("invalid  MAX_MR_CNT")))
+    fn fill_up_free_list(max_mr: usize) -> Vec<MrKeyIndex> {
+        (0..u32::try_from(max_mr).unwrap_or_else(|_| unreachable!("invalid max_mr")))
             .map(MrKeyIndex)
             .collect()
     }
@@ -107,15 +104,15 @@ impl MrTableAlloc {
 /// A simple page table allocator that uses a bit array to track free/used entries
 pub(crate) struct PgtAlloc {
     /// Bit array tracking which entries are free `false` or used `true`
-    free_list: BitArray<[usize; PGT_LEN / 64]>,
+    free_list: BitVec,
 }
 
 impl PgtAlloc {
-    /// Creates a new empty `SimplePgtAlloc` with all entries marked as free
-    pub(crate) fn new() -> Self {
-        Self {
-            free_list: bitarr![0; PGT_LEN],
-        }
+    /// Creates a new empty `SimplePgtAlloc` of `len` entries all marked as free
+    pub(crate) fn new(len: usize) -> Self {
+        let mut free_list = BitVec::with_capacity(len);
+        free_list.resize(len, false);
+        Self { free_list }
     }
 
     /// Allocates a contiguous range of page table entries
@@ -178,9 +175,12 @@ impl PgtAlloc {
 mod test {
     use super::*;
 
+    const MAX_MR_CNT: usize = 8192;
+    const PGT_LEN: usize = 0x20000;
+
     #[test]
     fn mr_table_alloc_dealloc_ok() {
-        let mut alloc = MrTableAlloc::new();
+        let mut alloc = MrTableAlloc::new(MAX_MR_CNT);
         let mr_keys: Vec<_> = iter::repeat_with(|| alloc.alloc_mr_key_idx())
             .take(MAX_MR_CNT)
             .flatten()
@@ -193,7 +193,7 @@ mod test {
 
     #[test]
     fn simple_pgt_alloc_dealloc_ok() {
-        let mut alloc = PgtAlloc::new();
+        let mut alloc = PgtAlloc::new(PGT_LEN);
         let index = alloc.alloc(10).unwrap();
         assert!(alloc.alloc(PGT_LEN).is_none());
         assert!(alloc.dealloc(index, 10));
@@ -0,0 +1,314 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Pinned memory the cache may retain by default, 1 GiB
+const DEFAULT_MAX_PINNED_BYTES: usize = 1 << 30;
+/// Number of regions the cache may retain by default
+const DEFAULT_MAX_ENTRIES: usize = 4096;
+
+/// Memory registration cache configuration
+///
+/// Cached regions are invalidated when the application unmaps, moves or drops their
+/// pages, reported by a userfaultfd watching each cached range. The cache is disabled
+/// if the kernel does not provide userfaultfd, and ranges which can not be watched, e.g.
+/// hugetlb mappings before Linux 5.19, are registered without caching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct MrCacheConfig {
+    /// Reuses registrations of identical or contained ranges
+    enabled: bool,
+    /// Upper bound of the memory pinned by the cached regions
+    max_pinned_bytes: usize,
+    /// Upper bound of the number of cached regions
+    max_entries: usize,
+}
+
+impl Default for MrCacheConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_pinned_bytes: DEFAULT_MAX_PINNED_BYTES,
+            max_entries: DEFAULT_MAX_ENTRIES,
+        }
+    }
+}
+
+impl MrCacheConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+/// A registered region tracked by the cache
+#[derive(Debug)]
+pub(crate) struct CachedMr {
+    pub(crate) mr_key: u32,
+    pub(crate) addr: u64,
+    pub(crate) length: usize,
+    pub(crate) pd_handle: u32,
+    pub(crate) access: u8,
+}
+
+impl CachedMr {
+    fn end(&self) -> u64 {
+        self.addr.saturating_add(self.length as u64)
+    }
+
+    fn overlaps(&self, addr: u64, end: u64) -> bool {
+        self.addr < end && addr < self.end()
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    mr: CachedMr,
+    /// Number of registrations handed out and not yet deregistered
+    refcnt: usize,
+    /// Position in the LRU order
+    last_use: u64,
+    /// Whether the region is still reachable from the index
+    valid: bool,
+}
+
+/// Outcome of dropping a reference to a region
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Release {
+    /// The key is not managed by the cache
+    Uncached,
+    /// The region stays registered for reuse
+    Retained,
+    /// The region was invalidated and has no references left, it must be deregistered
+    Deregister,
+}
+
+/// Memory registration cache.
+///
+/// Regions are indexed by their start address, the longest cached region bounds the
+/// part of the index scanned for the regions covering a range. Unreferenced regions
+/// stay registered and are evicted in LRU order once the limits are exceeded.
+#[derive(Debug)]
+pub(crate) struct MrCache {
+    config: MrCacheConfig,
+    entries: HashMap<u32, Entry>,
+    /// Valid regions ordered by `(addr, mr_key)`
+    index: BTreeSet<(u64, u32)>,
+    /// Unreferenced regions ordered by last use
+    lru: BTreeMap<u64, u32>,
+    /// Longest region ever indexed
+    max_length: u64,
+    /// Memory pinned by the tracked regions
+    pinned_bytes: usize,
+    clock: u64,
+}
+
+impl MrCache {
+    pub(crate) fn new(config: MrCacheConfig) -> Self {
+        Self {
+            config,
+            entries: HashMap::new(),
+            index: BTreeSet::new(),
+            lru: BTreeMap::new(),
+            max_length: 0,
+            pinned_bytes: 0,
+            clock: 0,
+        }
+    }
+
+    /// Finds a valid region covering `[addr, addr + length)` registered with the same
+    /// protection domain and access flags, returns its key
+    pub(crate) fn lookup(
+        &self,
+        addr: u64,
+        length: usize,
+        pd_handle: u32,
+        access: u8,
+    ) -> Option<u32> {
+        let end = addr.checked_add(length as u64)?;
+        let low = addr.saturating_sub(self.max_length);
+        self.index
+            .range((low, 0)..=(addr, u32::MAX))
+            .rev()
+            .filter_map(|&(_, mr_key)| self.entries.get(&mr_key))
+            .map(|entry| &entry.mr)
+            .find(|mr| mr.end() >= end && mr.pd_handle == pd_handle && mr.access == access)
+            .map(|mr| mr.mr_key)
+    }
+
+    /// Hands out one more registration of a cached region
+    pub(crate) fn acquire(&mut self, mr_key: u32) {
+        let Some(entry) = self.entries.get_mut(&mr_key) else {
+            return;
+        };
+        if entry.refcnt == 0 {
+            let _prev = self.lru.remove(&entry.last_use);
+        }
+        entry.refcnt += 1;
+    }
+
+    /// Tracks a newly registered region with one reference
+    pub(crate) fn insert(&mut self, mr: CachedMr) {
+        self.pinned_bytes += mr.length;
+        self.max_length = self.max_length.max(mr.length as u64);
+        let _new = self.index.insert((mr.addr, mr.mr_key));
+        let _prev = self.entries.insert(
+            mr.mr_key,
+            Entry {
+                mr,
+                refcnt: 1,
+                last_use: 0,
+                valid: true,
+            },
+        );
+    }
+
+    /// Drops a reference to a region
+    pub(crate) fn release(&mut self, mr_key: u32) -> Release {
+        let Some(entry) = self.entries.get_mut(&mr_key) else {
+            return Release::Uncached;
+        };
+        entry.refcnt = entry.refcnt.saturating_sub(1);
+        if entry.refcnt > 0 {
+            return Release::Retained;
+        }
+        if !entry.valid {
+            let _entry = self.remove(mr_key);
+            return Release::Deregister;
+        }
+        self.clock += 1;
+        entry.last_use = self.clock;
+        let _prev = self.lru.insert(self.clock, mr_key);
+        Release::Retained
+    }
+
+    /// Invalidates the regions overlapping `range`, returns the keys of the unreferenced
+    /// ones which must be deregistered. Referenced regions are deregistered on their last
+    /// release.
+    pub(crate) fn invalidate(&mut self, range: Range<u64>) -> Vec<u32> {
+        let Range { start: addr, end } = range;
+        let low = addr.saturating_sub(self.max_length);
+        let overlapping: Vec<u32> = self
+            .index
+            .range((low, 0)..(end, 0))
+            .filter_map(|&(_, mr_key)| self.entries.get(&mr_key))
+            .filter(|entry| entry.mr.overlaps(addr, end))
+            .map(|entry| entry.mr.mr_key)
+            .collect();
+        let mut stale = Vec::new();
+        for mr_key in overlapping {
+            let Some(entry) = self.entries.get_mut(&mr_key) else {
+                continue;
+            };
+            entry.valid = false;
+            let _removed = self.index.remove(&(entry.mr.addr, mr_key));
+            if entry.refcnt == 0 {
+                let _entry = self.remove(mr_key);
+                stale.push(mr_key);
+            }
+        }
+        stale
+    }
+
+    /// Evicts unreferenced regions in LRU order until the cache fits its limits, regions
+    /// for which `busy` returns `true` are skipped. Returns the keys to deregister.
+    pub(crate) fn evict<F>(&mut self, busy: F) -> Vec<u32>
+    where
+        F: Fn(u32) -> bool,
+    {
+        let candidates: Vec<u32> = self.lru.values().copied().collect();
+        let mut evicted = Vec::new();
+        for mr_key in candidates {
+            if !self.exceeds_limits() {
+                break;
+            }
+            if busy(mr_key) {
+                continue;
+            }
+            let _entry = self.remove(mr_key);
+            evicted.push(mr_key);
+        }
+        evicted
+    }
+
+    fn exceeds_limits(&self) -> bool {
+        self.pinned_bytes > self.config.max_pinned_bytes
+            || self.entries.len() > self.config.max_entries
+    }
+
+    fn remove(&mut self, mr_key: u32) -> Option<CachedMr> {
+        let entry = self.entries.remove(&mr_key)?;
+        let _removed = self.index.remove(&(entry.mr.addr, mr_key));
+        if entry.refcnt == 0 {
+            let _prev = self.lru.remove(&entry.last_use);
+        }
+        self.pinned_bytes = self.pinned_bytes.saturating_sub(entry.mr.length);
+        Some(entry.mr)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PAGE: u64 = 0x1000;
+
+    fn mr(mr_key: u32, addr: u64, pages: u64) -> CachedMr {
+        CachedMr {
+            mr_key,
+            addr,
+            length: (pages * PAGE) as usize,
+            pd_handle: 1,
+            access: 7,
+        }
+    }
+
+    fn cache(max_entries: usize) -> MrCache {
+        MrCache::new(MrCacheConfig {
+            enabled: true,
+            max_pinned_bytes: usize::MAX,
+            max_entries,
+        })
+    }
+
+    #[test]
+    fn contained_ranges_hit() {
+        let mut cache = cache(8);
+        cache.insert(mr(1, 0x4000, 4));
+        assert_eq!(cache.lookup(0x5010, 0x1000, 1, 7), Some(1));
+        assert!(cache.lookup(0x4000, 0x4001, 1, 7).is_none());
+        assert!(cache.lookup(0x4000, 0x1000, 2, 7).is_none());
+        assert!(cache.lookup(0x4000, 0x1000, 1, 3).is_none());
+    }
+
+    #[test]
+    fn released_regions_are_evicted_in_lru_order() {
+        let mut cache = cache(2);
+        cache.insert(mr(1, 0x1_0000, 1));
+        cache.insert(mr(2, 0x2_0000, 1));
+        assert_eq!(cache.release(1), Release::Retained);
+        assert_eq!(cache.release(2), Release::Retained);
+        cache.acquire(1);
+        assert_eq!(cache.release(1), Release::Retained);
+        cache.insert(mr(3, 0x3_0000, 1));
+        assert_eq!(cache.evict(|_| false), vec![2]);
+        assert!(cache.lookup(0x2_0000, 1, 1, 7).is_none());
+        assert_eq!(cache.release(4), Release::Uncached);
+    }
+
+    #[test]
+    fn invalidation_defers_referenced_regions() {
+        let mut cache = cache(8);
+        cache.insert(mr(1, 0x1_0000, 2));
+        cache.insert(mr(2, 0x3_0000, 2));
+        assert_eq!(cache.release(2), Release::Retained);
+        let mut stale = cache.invalidate(0x1_1000..0x3_2000);
+        stale.sort_unstable();
+        assert_eq!(stale, vec![2]);
+        assert!(cache.lookup(0x1_0000, 1, 1, 7).is_none());
+        assert_eq!(cache.release(1), Release::Deregister);
+        assert_eq!(cache.release(1), Release::Uncached);
+    }
+}
@@ -1,9 +1,22 @@
 /// Mtt allocator
 mod alloc;
+/// Memory registration cache
+mod cache;
+/// Staging buffers of the page table updates
+mod staging;
 
-use std::{collections::HashMap, io, mem::take};
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    io,
+    mem::take,
+    sync::Arc,
+};
 
 use alloc::Alloc;
+use parking_lot::Mutex;
+
+pub(crate) use cache::{CachedMr, MrCache, MrCacheConfig, Release};
+pub(crate) use staging::PgtStaging;
 
 use crate::{
     device_protocol::MttUpdate,
@@ -19,10 +32,10 @@ pub(crate) struct Mtt {
 }
 
 impl Mtt {
-    /// Creates a new `Mtt`
-    pub(crate) fn new() -> Self {
+    /// Creates a new `Mtt` for `max_mr` regions and `max_entries` page table entries
+    pub(crate) fn new(max_mr: usize, max_entries: usize) -> Self {
         Self {
-            alloc: Alloc::new(),
+            alloc: Alloc::new(max_mr, max_entries),
             mrkey_map: HashMap::new(),
         }
     }
@@ -90,3 +103,49 @@ pub(crate) struct PgtEntry {
     pub(crate) index: u32,
     pub(crate) count: u32,
 }
+
+/// Number of outstanding work requests referencing each memory region
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MrRefs {
+    inner: Arc<Mutex<HashMap<u32, usize>>>,
+}
+
+impl MrRefs {
+    /// Records a work request referencing `mr_key`
+    pub(crate) fn acquire(&self, mr_key: u32) {
+        *self.inner.lock().entry(mr_key).or_default() += 1;
+    }
+
+    /// Releases a reference taken by `acquire`
+    pub(crate) fn release(&self, mr_key: u32) {
+        if let Entry::Occupied(mut entry) = self.inner.lock().entry(mr_key) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                let _count = entry.remove();
+            }
+        }
+    }
+
+    /// Returns `true` if any outstanding work request references `mr_key`
+    pub(crate) fn is_referenced(&self, mr_key: u32) -> bool {
+        self.inner.lock().contains_key(&mr_key)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mr_refs_count_outstanding_wrs() {
+        let refs = MrRefs::default();
+        refs.acquire(1);
+        refs.acquire(1);
+        refs.release(1);
+        assert!(refs.is_referenced(1));
+        refs.release(1);
+        assert!(!refs.is_referenced(1));
+        refs.release(1);
+        assert!(!refs.is_referenced(1));
+    }
+}
@@ -0,0 +1,61 @@
+use std::io;
+
+use crate::{
+    device_protocol::{DeviceCommand, PgtUpdate},
+    mem::DmaBuf,
+};
+
+/// Maximum number of Page Table entries (PGT entries) written by a single command.
+/// Each PGT entry is a u64 (8 bytes), 64 entries fill a 512 bytes slot.
+const ENTRIES_PER_UPDATE: usize = 64;
+/// Size of a staging slot in bytes
+const SLOT_SIZE: usize = ENTRIES_PER_UPDATE * 8;
+/// Maximum number of PGT commands in flight before waiting for the responses
+const MAX_PIPELINE_DEPTH: usize = 32;
+
+/// Ring of staging slots the second stage table updates are DMAed from
+pub(crate) struct PgtStaging {
+    /// The staging memory, split into slots of `SLOT_SIZE` bytes
+    buf: DmaBuf,
+    /// Number of slots in use
+    num_slots: usize,
+}
+
+impl PgtStaging {
+    pub(crate) fn new(buf: DmaBuf) -> Self {
+        let num_slots = (buf.len() / SLOT_SIZE).clamp(1, MAX_PIPELINE_DEPTH);
+        Self { buf, num_slots }
+    }
+
+    /// Writes `phys_addrs` to the PGT entries starting at `base_index`.
+    ///
+    /// Each round stages one command per slot and waits for all of them once, so a
+    /// slot is only overwritten after the device has read it.
+    pub(crate) fn write<C: DeviceCommand>(
+        &mut self,
+        cmd: &C,
+        base_index: u32,
+        phys_addrs: &[u64],
+    ) -> io::Result<()> {
+        let mut chunks = phys_addrs.chunks(ENTRIES_PER_UPDATE).peekable();
+        let mut index = base_index;
+        while chunks.peek().is_some() {
+            let mut updates = Vec::with_capacity(self.num_slots);
+            for (slot, chunk) in chunks.by_ref().take(self.num_slots).enumerate() {
+                let offset = slot * SLOT_SIZE;
+                let bytes: Vec<u8> = chunk.iter().copied().flat_map(u64::to_ne_bytes).collect();
+                self.buf.copy_from(offset, &bytes);
+                let count = chunk.len() as u32;
+                updates.push(PgtUpdate::new(
+                    self.buf.phys_addr + offset as u64,
+                    index,
+                    count - 1,
+                ));
+                index += count;
+            }
+            cmd.update_pgt_batch(&updates)?;
+        }
+
+        Ok(())
+    }
+}
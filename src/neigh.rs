@@ -0,0 +1,550 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use parking_lot::{Condvar, Mutex};
+use pnet::{
+    packet::{
+        arp::{ArpHardwareTypes, ArpOperations, ArpPacket, MutableArpPacket},
+        ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+        Packet,
+    },
+    util::MacAddr,
+};
+use tracing::{debug, warn};
+
+use crate::{
+    device_protocol::{FrameRx, FrameTx, SharedFrameTx},
+    net::config::{MacAddress, NetworkConfig, SharedNetworkConfig},
+};
+
+/// Interval between the ARP requests of a neighbor being resolved
+const RETRANS_INTERVAL: Duration = Duration::from_secs(1);
+/// Number of requests sent before a resolution fails
+const MAX_PROBES: u32 = 3;
+/// Time a confirmed address is used before it is reconfirmed
+const REACHABLE_TIME: Duration = Duration::from_secs(30);
+/// Interval of polling the simple NIC for frames while it is idle
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// Length of an Ethernet header
+const ETH_HEADER_LEN: usize = 14;
+/// Length of an ARP message for IPv4 over Ethernet
+const ARP_LEN: usize = 28;
+/// Minimum length of an Ethernet frame without the FCS
+const MIN_FRAME_LEN: usize = 60;
+const BROADCAST_MAC: MacAddress = MacAddress([0xff; 6]);
+
+/// Resolution state of a neighbor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum NeighState {
+    /// Being resolved, no address is known
+    Incomplete { probes: u32, next_probe: Instant },
+    /// Confirmed by the neighbor at `confirmed`
+    Reachable { mac: MacAddress, confirmed: Instant },
+    /// Past the reachable time and being reconfirmed, the address stays in use
+    Probe {
+        mac: MacAddress,
+        probes: u32,
+        next_probe: Instant,
+    },
+    /// Resolution failed, restarted by the next lookup
+    Failed,
+}
+
+/// Actions requested by the neighbor table
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum NeighEvent {
+    /// Sends an ARP request, unicast to the known address when reconfirming
+    Solicit {
+        ip: Ipv4Addr,
+        mac: Option<MacAddress>,
+    },
+    /// The address of a neighbor was learnt or changed
+    Changed { ip: Ipv4Addr, mac: MacAddress },
+    /// The resolution of a neighbor failed
+    Failed { ip: Ipv4Addr },
+}
+
+/// Cache of the link layer addresses of the neighbors
+#[derive(Debug, Default)]
+pub(crate) struct NeighborTable {
+    entries: HashMap<Ipv4Addr, NeighState>,
+}
+
+impl NeighborTable {
+    /// Returns the address of a neighbor, `None` if it is not resolved
+    pub(crate) fn lookup(&self, ip: Ipv4Addr) -> Option<MacAddress> {
+        match self.entries.get(&ip) {
+            Some(&NeighState::Reachable { mac, .. } | &NeighState::Probe { mac, .. }) => Some(mac),
+            Some(&NeighState::Incomplete { .. } | &NeighState::Failed) | None => None,
+        }
+    }
+
+    /// Returns `true` if the resolution of a neighbor failed
+    pub(crate) fn is_failed(&self, ip: Ipv4Addr) -> bool {
+        matches!(self.entries.get(&ip), Some(&NeighState::Failed))
+    }
+
+    /// Starts resolving a neighbor that is unknown or failed before
+    pub(crate) fn solicit(&mut self, ip: Ipv4Addr, now: Instant) -> Option<NeighEvent> {
+        match self.entries.get(&ip) {
+            Some(
+                &NeighState::Incomplete { .. }
+                | &NeighState::Reachable { .. }
+                | &NeighState::Probe { .. },
+            ) => None,
+            Some(&NeighState::Failed) | None => {
+                let _prev = self.entries.insert(
+                    ip,
+                    NeighState::Incomplete {
+                        probes: 1,
+                        next_probe: now + RETRANS_INTERVAL,
+                    },
+                );
+                Some(NeighEvent::Solicit { ip, mac: None })
+            }
+        }
+    }
+
+    /// Records the address of a neighbor learnt from an ARP message. Only neighbors in
+    /// the table are updated unless `create` is set.
+    pub(crate) fn confirm(
+        &mut self,
+        ip: Ipv4Addr,
+        mac: MacAddress,
+        now: Instant,
+        create: bool,
+    ) -> Option<NeighEvent> {
+        let previous = match self.entries.get(&ip) {
+            Some(&NeighState::Reachable { mac, .. } | &NeighState::Probe { mac, .. }) => Some(mac),
+            Some(&NeighState::Incomplete { .. } | &NeighState::Failed) => None,
+            None if create => None,
+            None => return None,
+        };
+        let _prev = self.entries.insert(
+            ip,
+            NeighState::Reachable {
+                mac,
+                confirmed: now,
+            },
+        );
+        (previous != Some(mac)).then_some(NeighEvent::Changed { ip, mac })
+    }
+
+    /// Advances the retransmission and aging timers
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<NeighEvent> {
+        let mut events = Vec::new();
+        for (&ip, state) in &mut self.entries {
+            let (next, event) = match *state {
+                NeighState::Reachable { mac, confirmed } if now >= confirmed + REACHABLE_TIME => (
+                    NeighState::Probe {
+                        mac,
+                        probes: 1,
+                        next_probe: now + RETRANS_INTERVAL,
+                    },
+                    NeighEvent::Solicit { ip, mac: Some(mac) },
+                ),
+                NeighState::Incomplete { probes, next_probe }
+                | NeighState::Probe {
+                    probes, next_probe, ..
+                } if now >= next_probe && probes >= MAX_PROBES => {
+                    (NeighState::Failed, NeighEvent::Failed { ip })
+                }
+                NeighState::Incomplete { probes, next_probe } if now >= next_probe => (
+                    NeighState::Incomplete {
+                        probes: probes + 1,
+                        next_probe: now + RETRANS_INTERVAL,
+                    },
+                    NeighEvent::Solicit { ip, mac: None },
+                ),
+                NeighState::Probe {
+                    mac,
+                    probes,
+                    next_probe,
+                } if now >= next_probe => (
+                    NeighState::Probe {
+                        mac,
+                        probes: probes + 1,
+                        next_probe: now + RETRANS_INTERVAL,
+                    },
+                    NeighEvent::Solicit { ip, mac: Some(mac) },
+                ),
+                NeighState::Reachable { .. }
+                | NeighState::Incomplete { .. }
+                | NeighState::Probe { .. }
+                | NeighState::Failed => continue,
+            };
+            *state = next;
+            events.push(event);
+        }
+        events
+    }
+}
+
+/// An ARP message for IPv4 over Ethernet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct ArpMessage {
+    pub(crate) is_request: bool,
+    pub(crate) sender_mac: MacAddress,
+    pub(crate) sender_ip: Ipv4Addr,
+    pub(crate) target_mac: MacAddress,
+    pub(crate) target_ip: Ipv4Addr,
+}
+
+impl ArpMessage {
+    /// Builds the Ethernet frame carrying the message to `dst_mac`
+    pub(crate) fn to_frame(&self, dst_mac: MacAddress) -> Vec<u8> {
+        let mut frame = vec![0u8; MIN_FRAME_LEN];
+        if let Some(mut eth) = MutableEthernetPacket::new(&mut frame) {
+            eth.set_destination(to_pnet(dst_mac));
+            eth.set_source(to_pnet(self.sender_mac));
+            eth.set_ethertype(EtherTypes::Arp);
+        }
+        if let Some(mut arp) = frame
+            .get_mut(ETH_HEADER_LEN..ETH_HEADER_LEN + ARP_LEN)
+            .and_then(MutableArpPacket::new)
+        {
+            arp.set_hardware_type(ArpHardwareTypes::Ethernet);
+            arp.set_protocol_type(EtherTypes::Ipv4);
+            arp.set_hw_addr_len(6);
+            arp.set_proto_addr_len(4);
+            arp.set_operation(if self.is_request {
+                ArpOperations::Request
+            } else {
+                ArpOperations::Reply
+            });
+            arp.set_sender_hw_addr(to_pnet(self.sender_mac));
+            arp.set_sender_proto_addr(self.sender_ip);
+            arp.set_target_hw_addr(to_pnet(self.target_mac));
+            arp.set_target_proto_addr(self.target_ip);
+        }
+        frame
+    }
+
+    /// Parses an Ethernet frame, `None` if it does not carry an IPv4 ARP message
+    pub(crate) fn parse(frame: &[u8]) -> Option<Self> {
+        let eth = EthernetPacket::new(frame)?;
+        if eth.get_ethertype() != EtherTypes::Arp {
+            return None;
+        }
+        let arp = ArpPacket::new(eth.payload())?;
+        if arp.get_hardware_type() != ArpHardwareTypes::Ethernet
+            || arp.get_protocol_type() != EtherTypes::Ipv4
+        {
+            return None;
+        }
+        let operation = arp.get_operation();
+        if operation != ArpOperations::Request && operation != ArpOperations::Reply {
+            return None;
+        }
+        Some(Self {
+            is_request: operation == ArpOperations::Request,
+            sender_mac: from_pnet(arp.get_sender_hw_addr()),
+            sender_ip: arp.get_sender_proto_addr(),
+            target_mac: from_pnet(arp.get_target_hw_addr()),
+            target_ip: arp.get_target_proto_addr(),
+        })
+    }
+}
+
+/// Returns `true` if the frame carries an ARP message
+pub(crate) fn is_arp_frame(frame: &[u8]) -> bool {
+    EthernetPacket::new(frame).is_some_and(|eth| eth.get_ethertype() == EtherTypes::Arp)
+}
+
+pub(crate) fn to_pnet(mac: MacAddress) -> MacAddr {
+    let [a, b, c, d, e, f] = mac.0;
+    MacAddr::new(a, b, c, d, e, f)
+}
+
+fn from_pnet(mac: MacAddr) -> MacAddress {
+    MacAddress([mac.0, mac.1, mac.2, mac.3, mac.4, mac.5])
+}
+
+/// Resolves the link layer addresses of next hops by ARP over the simple NIC
+#[derive(Clone)]
+pub(crate) struct Neighbors {
+    table: Arc<(Mutex<NeighborTable>, Condvar)>,
+    network: SharedNetworkConfig,
+    tx: SharedFrameTx,
+}
+
+impl Neighbors {
+    pub(crate) fn new(network: SharedNetworkConfig, tx: SharedFrameTx) -> Self {
+        Self {
+            table: Arc::new((Mutex::new(NeighborTable::default()), Condvar::new())),
+            network,
+            tx,
+        }
+    }
+
+    /// Returns the network configuration in effect
+    pub(crate) fn network(&self) -> NetworkConfig {
+        self.network.get()
+    }
+
+    /// Returns the next hop towards `dest`, the destination itself if it is on the
+    /// subnet and the gateway otherwise. `None` if `dest` is the local address.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an off subnet destination has no IPv4 gateway.
+    pub(crate) fn next_hop(&self, dest: Ipv4Addr) -> io::Result<Option<Ipv4Addr>> {
+        let network = self.network.get();
+        if dest == network.ip.ip() {
+            return Ok(None);
+        }
+        if network.ip.contains(dest) {
+            return Ok(Some(dest));
+        }
+        match network.gateway {
+            IpAddr::V4(gateway) if !gateway.is_unspecified() => Ok(Some(gateway)),
+            IpAddr::V4(_) | IpAddr::V6(_) => Err(io::Error::from_raw_os_error(libc::ENETUNREACH)),
+        }
+    }
+
+    /// Resolves the address of the next hop towards `dest`, blocking until the
+    /// neighbor replies or the requests are exhausted
+    ///
+    /// # Errors
+    ///
+    /// Returns `EHOSTUNREACH` if the next hop did not reply.
+    pub(crate) fn resolve(&mut self, dest: Ipv4Addr) -> io::Result<MacAddress> {
+        let Some(hop) = self.next_hop(dest)? else {
+            return Ok(self.network.get().mac);
+        };
+        let deadline = Instant::now() + RETRANS_INTERVAL * (MAX_PROBES + 1);
+        let (ref table, ref resolved) = *self.table;
+        let mut table = table.lock();
+        if let Some(event) = table.solicit(hop, Instant::now()) {
+            self.handle(event);
+        }
+        loop {
+            if let Some(mac) = table.lookup(hop) {
+                return Ok(mac);
+            }
+            if table.is_failed(hop) || resolved.wait_until(&mut table, deadline).timed_out() {
+                return Err(io::Error::from_raw_os_error(libc::EHOSTUNREACH));
+            }
+        }
+    }
+
+    /// Spawns the worker answering and learning from ARP messages received by the
+    /// simple NIC, `on_change` is called with the neighbors whose address changed
+    pub(crate) fn spawn<Rx, F>(&self, rx: Rx, on_change: F)
+    where
+        Rx: FrameRx + Send + 'static,
+        F: FnMut(Ipv4Addr, MacAddress) + Send + 'static,
+    {
+        let worker = NeighborWorker {
+            neighbors: self.clone(),
+            rx,
+            on_change,
+        };
+        let _handle = thread::Builder::new()
+            .name("neighbor-worker".into())
+            .spawn(move || worker.run())
+            .unwrap_or_else(|err| unreachable!("Failed to spawn neighbor thread: {err}"));
+    }
+
+    fn handle(&mut self, event: NeighEvent) {
+        match event {
+            NeighEvent::Solicit { ip, mac } => {
+                let network = self.network.get();
+                let request = ArpMessage {
+                    is_request: true,
+                    sender_mac: network.mac,
+                    sender_ip: network.ip.ip(),
+                    target_mac: mac.unwrap_or(MacAddress([0; 6])),
+                    target_ip: ip,
+                };
+                if let Err(err) = self
+                    .tx
+                    .send(&request.to_frame(mac.unwrap_or(BROADCAST_MAC)))
+                {
+                    warn!("failed to send ARP request for {ip}: {err}");
+                }
+            }
+            NeighEvent::Changed { ip, mac } => debug!("neighbor {ip} is at {mac}"),
+            NeighEvent::Failed { ip } => debug!("neighbor {ip} is unreachable"),
+        }
+    }
+}
+
+/// Worker receiving the ARP messages of the simple NIC
+struct NeighborWorker<Rx, F> {
+    neighbors: Neighbors,
+    rx: Rx,
+    on_change: F,
+}
+
+impl<Rx, F> NeighborWorker<Rx, F>
+where
+    Rx: FrameRx,
+    F: FnMut(Ipv4Addr, MacAddress),
+{
+    fn run(mut self) {
+        loop {
+            match self.rx.recv_nonblocking() {
+                Ok(frame) => {
+                    if let Some(message) = ArpMessage::parse(&frame) {
+                        self.process(message);
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    self.poll_timers();
+                    thread::sleep(RX_POLL_INTERVAL);
+                }
+                Err(err) => warn!("simple NIC receive error: {err}"),
+            }
+        }
+    }
+
+    fn process(&mut self, message: ArpMessage) {
+        let local = self.neighbors.network();
+        if message.sender_ip.is_unspecified() || message.sender_ip == local.ip.ip() {
+            return;
+        }
+        let for_us = message.target_ip == local.ip.ip();
+        let event = {
+            let (ref table, ref resolved) = *self.neighbors.table;
+            let event = table.lock().confirm(
+                message.sender_ip,
+                message.sender_mac,
+                Instant::now(),
+                for_us && message.is_request,
+            );
+            let _notified = resolved.notify_all();
+            event
+        };
+        if for_us && message.is_request {
+            let reply = ArpMessage {
+                is_request: false,
+                sender_mac: local.mac,
+                sender_ip: local.ip.ip(),
+                target_mac: message.sender_mac,
+                target_ip: message.sender_ip,
+            };
+            if let Err(err) = self.neighbors.tx.send(&reply.to_frame(message.sender_mac)) {
+                warn!("failed to send ARP reply to {}: {err}", message.sender_ip);
+            }
+        }
+        if let Some(event) = event {
+            self.neighbors.handle(event);
+            if let NeighEvent::Changed { ip, mac } = event {
+                (self.on_change)(ip, mac);
+            }
+        }
+    }
+
+    fn poll_timers(&mut self) {
+        let (ref table, ref resolved) = *self.neighbors.table;
+        let events = table.lock().poll(Instant::now());
+        if events
+            .iter()
+            .any(|event| matches!(*event, NeighEvent::Failed { .. }))
+        {
+            let _notified = resolved.notify_all();
+        }
+        for event in events {
+            self.neighbors.handle(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const PEER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+    const PEER_MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0x02]);
+
+    #[test]
+    fn arp_messages_round_trip() {
+        let request = ArpMessage {
+            is_request: true,
+            sender_mac: MacAddress([0x02, 0, 0, 0, 0, 0x01]),
+            sender_ip: Ipv4Addr::new(10, 0, 0, 1),
+            target_mac: MacAddress([0; 6]),
+            target_ip: PEER,
+        };
+        let frame = request.to_frame(BROADCAST_MAC);
+        assert_eq!(frame.len(), MIN_FRAME_LEN);
+        assert_eq!(frame.get(..6), Some(&[0xff; 6][..]));
+        assert_eq!(frame.get(12..14), Some(&[0x08, 0x06][..]));
+        assert_eq!(ArpMessage::parse(&frame), Some(request));
+        assert_eq!(ArpMessage::parse(&frame[..20]), None);
+    }
+
+    #[test]
+    fn unanswered_resolution_fails_after_retries() {
+        let start = Instant::now();
+        let mut table = NeighborTable::default();
+        assert_eq!(
+            table.solicit(PEER, start),
+            Some(NeighEvent::Solicit {
+                ip: PEER,
+                mac: None
+            })
+        );
+        assert_eq!(table.solicit(PEER, start), None);
+        assert!(table.poll(start).is_empty());
+        for i in 1..MAX_PROBES {
+            let events = table.poll(start + RETRANS_INTERVAL * i);
+            assert_eq!(
+                events,
+                vec![NeighEvent::Solicit {
+                    ip: PEER,
+                    mac: None
+                }]
+            );
+        }
+        let events = table.poll(start + RETRANS_INTERVAL * MAX_PROBES);
+        assert_eq!(events, vec![NeighEvent::Failed { ip: PEER }]);
+        assert!(table.is_failed(PEER));
+        assert!(table.solicit(PEER, start).is_some());
+    }
+
+    #[test]
+    fn confirmed_entries_age_and_report_changes() {
+        let start = Instant::now();
+        let mut table = NeighborTable::default();
+        // Unsolicited replies are ignored
+        assert_eq!(table.confirm(PEER, PEER_MAC, start, false), None);
+        assert_eq!(table.lookup(PEER), None);
+        let _solicit = table.solicit(PEER, start);
+        assert_eq!(
+            table.confirm(PEER, PEER_MAC, start, false),
+            Some(NeighEvent::Changed {
+                ip: PEER,
+                mac: PEER_MAC
+            })
+        );
+        assert_eq!(table.confirm(PEER, PEER_MAC, start, false), None);
+
+        // Past the reachable time the address is reconfirmed by unicast
+        let aged = start + REACHABLE_TIME;
+        assert_eq!(
+            table.poll(aged),
+            vec![NeighEvent::Solicit {
+                ip: PEER,
+                mac: Some(PEER_MAC)
+            }]
+        );
+        assert_eq!(table.lookup(PEER), Some(PEER_MAC));
+        let moved = MacAddress([0x02, 0, 0, 0, 0, 0x03]);
+        assert_eq!(
+            table.confirm(PEER, moved, aged, false),
+            Some(NeighEvent::Changed {
+                ip: PEER,
+                mac: moved
+            })
+        );
+        assert_eq!(table.lookup(PEER), Some(moved));
+    }
+}
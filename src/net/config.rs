@@ -4,9 +4,11 @@ use std::{
     io,
     net::{IpAddr, Ipv4Addr},
     str::FromStr,
+    sync::Arc,
 };
 
 use ipnetwork::{IpNetwork, Ipv4Network};
+use parking_lot::RwLock;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
@@ -110,6 +112,84 @@ pub struct NetworkConfig {
     pub gateway: IpAddr,
     /// MAC address
     pub mac: MacAddress,
+    /// 802.1Q VLAN ID of the frames built by the driver, `None` for untagged frames
+    #[serde(default)]
+    pub vlan: Option<u16>,
+    /// Priority code point of the tagged frames of QPs whose service level is 0
+    #[serde(default)]
+    pub priority: u8,
+}
+
+impl NetworkConfig {
+    /// Returns the configuration of an interface that has no address yet
+    pub(crate) fn unconfigured(mac: MacAddress) -> Self {
+        Self {
+            ip: Ipv4Network::from(Ipv4Addr::UNSPECIFIED),
+            gateway: Ipv4Addr::UNSPECIFIED.into(),
+            mac,
+            vlan: None,
+            priority: 0,
+        }
+    }
+
+    /// Returns the configuration with the addresses of a DHCP lease, the VLAN settings
+    /// are not leased and are kept
+    pub(crate) fn with_lease(self, lease: NetworkConfig) -> Self {
+        Self {
+            ip: lease.ip,
+            gateway: lease.gateway,
+            ..self
+        }
+    }
+}
+
+/// MTU of the port unless configured, the Ethernet default
+pub(crate) const DEFAULT_MTU: u16 = 1500;
+
+/// Bytes of a frame around its IP packet, an Ethernet header and a VLAN tag
+const FRAME_OVERHEAD: usize = 14 + 4;
+
+/// Returns the length of the largest frame carrying an IP packet of `mtu` bytes
+pub(crate) fn max_frame_len(mtu: u16) -> usize {
+    usize::from(mtu) + FRAME_OVERHEAD
+}
+
+/// Ethernet port configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct PortConfig {
+    /// Largest IP packet carried by a frame, up to 9000 for jumbo frames
+    mtu: u16,
+}
+
+impl Default for PortConfig {
+    fn default() -> Self {
+        Self { mtu: DEFAULT_MTU }
+    }
+}
+
+impl PortConfig {
+    pub(crate) fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
+
+/// The network configuration in effect, replaced when a DHCP lease changes
+#[derive(Debug, Clone)]
+pub(crate) struct SharedNetworkConfig(Arc<RwLock<NetworkConfig>>);
+
+impl SharedNetworkConfig {
+    pub(crate) fn new(config: NetworkConfig) -> Self {
+        Self(Arc::new(RwLock::new(config)))
+    }
+
+    pub(crate) fn get(&self) -> NetworkConfig {
+        *self.0.read()
+    }
+
+    pub(crate) fn set(&self, config: NetworkConfig) {
+        *self.0.write() = config;
+    }
 }
 
 /// Network mode configuration - either static or DHCP
@@ -159,6 +239,8 @@ mod tests {
                 ip: Ipv4Network::new("10.0.0.2".parse().unwrap(), 24).unwrap(),
                 gateway: "10.0.0.1".parse().unwrap(),
                 mac: MacAddress([0; 6]),
+                vlan: None,
+                priority: 0,
             })
         }
     }
@@ -0,0 +1,754 @@
+use std::{
+    io,
+    net::Ipv4Addr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use ipnetwork::Ipv4Network;
+use parking_lot::Mutex;
+use pnet::packet::{
+    ethernet::{EtherTypes, EthernetPacket, MutableEthernetPacket},
+    ip::IpNextHeaderProtocols,
+    ipv4::{self, Ipv4Packet, MutableIpv4Packet},
+    udp::{MutableUdpPacket, UdpPacket},
+    Packet,
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::device_protocol::{FrameRx, FrameTx, RoutedFrameRx, SharedFrameTx};
+
+use super::config::{MacAddress, NetworkConfig, NetworkResolver};
+
+const SERVER_PORT: u16 = 67;
+const CLIENT_PORT: u16 = 68;
+const ETH_HEADER_LEN: usize = 14;
+const IP_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+/// Length of the fixed BOOTP fields preceding the magic cookie
+const BOOTP_LEN: usize = 236;
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+const OPTIONS_OFFSET: usize = BOOTP_LEN + MAGIC_COOKIE.len();
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const FLAG_BROADCAST: u16 = 0x8000;
+const BROADCAST_MAC: MacAddress = MacAddress([0xff; 6]);
+
+const OPT_PAD: u8 = 0;
+const OPT_SUBNET_MASK: u8 = 1;
+const OPT_ROUTER: u8 = 3;
+const OPT_REQUESTED_IP: u8 = 50;
+const OPT_LEASE_TIME: u8 = 51;
+const OPT_MESSAGE_TYPE: u8 = 53;
+const OPT_SERVER_ID: u8 = 54;
+const OPT_PARAMETER_LIST: u8 = 55;
+const OPT_RENEWAL_TIME: u8 = 58;
+const OPT_REBINDING_TIME: u8 = 59;
+const OPT_CLIENT_ID: u8 = 61;
+const OPT_END: u8 = 255;
+
+/// Lower bound of the interval between requests while renewing, RFC 2131 section 4.4.5
+const MIN_RENEW_INTERVAL: Duration = Duration::from_secs(60);
+/// Interval of polling for replies while waiting
+const RX_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// DHCP client configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct DhcpConfig {
+    /// Time to wait for each reply in milliseconds
+    timeout_ms: u64,
+    /// Number of attempts to obtain a lease before giving up
+    retries: u32,
+}
+
+impl Default for DhcpConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 4000,
+            retries: 4,
+        }
+    }
+}
+
+impl DhcpConfig {
+    pub(crate) fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+
+    pub(crate) fn retries(&self) -> u32 {
+        self.retries
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MessageType {
+    Discover,
+    Offer,
+    Request,
+    Ack,
+    Nak,
+}
+
+impl MessageType {
+    fn code(self) -> u8 {
+        match self {
+            MessageType::Discover => 1,
+            MessageType::Offer => 2,
+            MessageType::Request => 3,
+            MessageType::Ack => 5,
+            MessageType::Nak => 6,
+        }
+    }
+
+    fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(MessageType::Discover),
+            2 => Some(MessageType::Offer),
+            3 => Some(MessageType::Request),
+            5 => Some(MessageType::Ack),
+            6 => Some(MessageType::Nak),
+            _ => None,
+        }
+    }
+}
+
+/// A message sent by the client
+#[derive(Debug, Clone, Copy)]
+struct ClientMessage {
+    kind: MessageType,
+    xid: u32,
+    mac: MacAddress,
+    /// Address of the client, set while renewing or rebinding
+    ciaddr: Ipv4Addr,
+    requested_ip: Option<Ipv4Addr>,
+    server_id: Option<Ipv4Addr>,
+}
+
+impl ClientMessage {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![0u8; OPTIONS_OFFSET];
+        let header = [BOOTREQUEST, HTYPE_ETHERNET, 6, 0];
+        write_bytes(&mut buf, 0, &header);
+        write_bytes(&mut buf, 4, &self.xid.to_be_bytes());
+        // Replies are broadcast until the client has an address
+        if self.ciaddr.is_unspecified() {
+            write_bytes(&mut buf, 10, &FLAG_BROADCAST.to_be_bytes());
+        }
+        write_bytes(&mut buf, 12, &self.ciaddr.octets());
+        write_bytes(&mut buf, 28, &self.mac.0);
+        write_bytes(&mut buf, BOOTP_LEN, &MAGIC_COOKIE);
+
+        buf.extend([OPT_MESSAGE_TYPE, 1, self.kind.code()]);
+        buf.extend([OPT_CLIENT_ID, 7, HTYPE_ETHERNET]);
+        buf.extend(self.mac.0);
+        if let Some(ip) = self.requested_ip {
+            buf.extend([OPT_REQUESTED_IP, 4]);
+            buf.extend(ip.octets());
+        }
+        if let Some(ip) = self.server_id {
+            buf.extend([OPT_SERVER_ID, 4]);
+            buf.extend(ip.octets());
+        }
+        buf.extend([
+            OPT_PARAMETER_LIST,
+            5,
+            OPT_SUBNET_MASK,
+            OPT_ROUTER,
+            OPT_LEASE_TIME,
+            OPT_RENEWAL_TIME,
+            OPT_REBINDING_TIME,
+        ]);
+        buf.push(OPT_END);
+        buf
+    }
+}
+
+fn write_bytes(buf: &mut [u8], offset: usize, bytes: &[u8]) {
+    if let Some(dst) = buf.get_mut(offset..offset + bytes.len()) {
+        dst.copy_from_slice(bytes);
+    }
+}
+
+/// A message sent by a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct ServerMessage {
+    kind: MessageType,
+    xid: u32,
+    chaddr: MacAddress,
+    /// The address offered to the client
+    yiaddr: Ipv4Addr,
+    server_id: Option<Ipv4Addr>,
+    subnet_mask: Option<Ipv4Addr>,
+    router: Option<Ipv4Addr>,
+    /// Times in seconds
+    lease_time: Option<u32>,
+    renewal_time: Option<u32>,
+    rebinding_time: Option<u32>,
+}
+
+impl ServerMessage {
+    fn decode(buf: &[u8]) -> Option<Self> {
+        if buf.get(..3)? != [BOOTREPLY, HTYPE_ETHERNET, 6]
+            || buf.get(BOOTP_LEN..OPTIONS_OFFSET)? != MAGIC_COOKIE
+        {
+            return None;
+        }
+        let mut kind = None;
+        let mut server_id = None;
+        let mut subnet_mask = None;
+        let mut router = None;
+        let mut lease_time = None;
+        let mut renewal_time = None;
+        let mut rebinding_time = None;
+        let mut options = buf.get(OPTIONS_OFFSET..)?;
+        while let Some((&code, rest)) = options.split_first() {
+            match code {
+                OPT_PAD => {
+                    options = rest;
+                    continue;
+                }
+                OPT_END => break,
+                _ => {}
+            }
+            let (&len, rest) = rest.split_first()?;
+            let value = rest.get(..usize::from(len))?;
+            options = rest.get(usize::from(len)..)?;
+            let ip = ipv4_at(value, 0);
+            let secs = value
+                .get(..4)
+                .and_then(|x| x.try_into().ok())
+                .map(u32::from_be_bytes);
+            match code {
+                OPT_MESSAGE_TYPE => kind = value.first().copied().and_then(MessageType::from_code),
+                OPT_SERVER_ID => server_id = ip,
+                OPT_SUBNET_MASK => subnet_mask = ip,
+                OPT_ROUTER => router = ip,
+                OPT_LEASE_TIME => lease_time = secs,
+                OPT_RENEWAL_TIME => renewal_time = secs,
+                OPT_REBINDING_TIME => rebinding_time = secs,
+                _ => {}
+            }
+        }
+        Some(Self {
+            kind: kind?,
+            xid: u32::from_be_bytes(buf.get(4..8)?.try_into().ok()?),
+            chaddr: MacAddress(buf.get(28..34)?.try_into().ok()?),
+            yiaddr: ipv4_at(buf, 16)?,
+            server_id,
+            subnet_mask,
+            router,
+            lease_time,
+            renewal_time,
+            rebinding_time,
+        })
+    }
+}
+
+fn ipv4_at(buf: &[u8], offset: usize) -> Option<Ipv4Addr> {
+    let octets: [u8; 4] = buf.get(offset..offset + 4)?.try_into().ok()?;
+    Some(Ipv4Addr::from(octets))
+}
+
+/// Wraps a client message into an Ethernet frame
+fn to_frame(
+    src_mac: MacAddress,
+    dst_mac: MacAddress,
+    src_ip: Ipv4Addr,
+    dst_ip: Ipv4Addr,
+    payload: &[u8],
+) -> Vec<u8> {
+    let udp_len = UDP_HEADER_LEN + payload.len();
+    let mut frame = vec![0u8; ETH_HEADER_LEN + IP_HEADER_LEN + udp_len];
+    if let Some(mut eth) = MutableEthernetPacket::new(&mut frame) {
+        eth.set_destination(dst_mac.0.into());
+        eth.set_source(src_mac.0.into());
+        eth.set_ethertype(EtherTypes::Ipv4);
+    }
+    if let Some(mut ip) = frame
+        .get_mut(ETH_HEADER_LEN..)
+        .and_then(MutableIpv4Packet::new)
+    {
+        ip.set_version(4);
+        ip.set_header_length(5);
+        ip.set_total_length((IP_HEADER_LEN + udp_len) as u16);
+        ip.set_ttl(64);
+        ip.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip.set_source(src_ip);
+        ip.set_destination(dst_ip);
+        let checksum = ipv4::checksum(&ip.to_immutable());
+        ip.set_checksum(checksum);
+    }
+    if let Some(mut udp) = frame
+        .get_mut(ETH_HEADER_LEN + IP_HEADER_LEN..)
+        .and_then(MutableUdpPacket::new)
+    {
+        udp.set_source(CLIENT_PORT);
+        udp.set_destination(SERVER_PORT);
+        udp.set_length(udp_len as u16);
+        // A zero checksum is not computed, which IPv4 allows
+        udp.set_payload(payload);
+    }
+    frame
+}
+
+/// Calls `f` with the source MAC and payload of a frame sent to the client port
+fn with_client_payload<F, T>(frame: &[u8], f: F) -> Option<T>
+where
+    F: FnOnce(MacAddress, &[u8]) -> Option<T>,
+{
+    let eth = EthernetPacket::new(frame)?;
+    if eth.get_ethertype() != EtherTypes::Ipv4 {
+        return None;
+    }
+    let ip = Ipv4Packet::new(eth.payload())?;
+    if ip.get_next_level_protocol() != IpNextHeaderProtocols::Udp {
+        return None;
+    }
+    let udp = UdpPacket::new(ip.payload())?;
+    if udp.get_destination() != CLIENT_PORT {
+        return None;
+    }
+    f(MacAddress(eth.get_source().octets()), udp.payload())
+}
+
+/// Returns `true` if the frame is sent to the DHCP client port
+pub(crate) fn is_dhcp_reply(frame: &[u8]) -> bool {
+    with_client_payload(frame, |_, _| Some(())).is_some()
+}
+
+/// Returns the source MAC and the server message carried by a frame
+fn parse_frame(frame: &[u8]) -> Option<(MacAddress, ServerMessage)> {
+    with_client_payload(frame, |src, payload| {
+        ServerMessage::decode(payload).map(|message| (src, message))
+    })
+}
+
+/// An address leased from a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Lease {
+    config: NetworkConfig,
+    server_id: Ipv4Addr,
+    /// MAC address of the server, or of the relay agent the server replied through
+    server_mac: MacAddress,
+    renew_at: Instant,
+    rebind_at: Instant,
+    expires_at: Instant,
+}
+
+impl Lease {
+    /// Creates a lease from an acknowledgment, `None` if it lacks the lease time
+    fn new(
+        ack: &ServerMessage,
+        server_id: Ipv4Addr,
+        server_mac: MacAddress,
+        mac: MacAddress,
+        now: Instant,
+    ) -> Option<Self> {
+        let lease_time = ack.lease_time?;
+        let renewal_time = ack.renewal_time.unwrap_or(lease_time / 2);
+        let rebinding_time = ack
+            .rebinding_time
+            .unwrap_or((u64::from(lease_time) * 7 / 8) as u32);
+        // Without a mask the address is a host route and all peers are reached
+        // through the router
+        let ip = match ack.subnet_mask {
+            Some(mask) => Ipv4Network::with_netmask(ack.yiaddr, mask).ok()?,
+            None => Ipv4Network::from(ack.yiaddr),
+        };
+        Some(Self {
+            config: NetworkConfig {
+                ip,
+                gateway: ack.router.unwrap_or(Ipv4Addr::UNSPECIFIED).into(),
+                ..NetworkConfig::unconfigured(mac)
+            },
+            server_id,
+            server_mac,
+            renew_at: now + Duration::from_secs(renewal_time.into()),
+            rebind_at: now + Duration::from_secs(rebinding_time.into()),
+            expires_at: now + Duration::from_secs(lease_time.into()),
+        })
+    }
+}
+
+/// Returns the time to wait before retrying a renewal that must complete by
+/// `deadline`, half of the remaining time but at least `MIN_RENEW_INTERVAL`
+fn renew_retry_delay(now: Instant, deadline: Instant) -> Duration {
+    let remaining = deadline.saturating_duration_since(now);
+    (remaining / 2).max(MIN_RENEW_INTERVAL).min(remaining)
+}
+
+/// Result of a renewal
+enum Renewal {
+    Renewed(Lease),
+    /// The server refused to extend the lease
+    Declined,
+    NoReply,
+}
+
+/// A DHCPv4 client running over the simple NIC
+#[derive(Clone)]
+pub(crate) struct DhcpClient {
+    inner: Arc<Mutex<ClientState>>,
+}
+
+impl DhcpClient {
+    pub(crate) fn new(
+        mac: MacAddress,
+        config: DhcpConfig,
+        tx: SharedFrameTx,
+        rx: RoutedFrameRx,
+    ) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(ClientState {
+                mac,
+                config,
+                tx,
+                rx,
+                lease: None,
+                xid: rand::thread_rng().gen(),
+            })),
+        }
+    }
+
+    /// Spawns the worker renewing the lease, `on_change` is called with the new
+    /// configuration whenever a renewal or a new lease changes it
+    pub(crate) fn spawn_renewal<F>(&self, mut on_change: F)
+    where
+        F: FnMut(NetworkConfig) + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        let _handle = thread::Builder::new()
+            .name("dhcp-client".into())
+            .spawn(move || loop {
+                let wait = inner.lock().step(&mut on_change);
+                thread::sleep(wait);
+            })
+            .unwrap_or_else(|err| unreachable!("Failed to spawn DHCP thread: {err}"));
+    }
+}
+
+impl NetworkResolver for DhcpClient {
+    fn resolve_dynamic(&self) -> io::Result<NetworkConfig> {
+        let mut state = self.inner.lock();
+        let lease = state.acquire()?;
+        info!(
+            "leased {} from DHCP server {}",
+            lease.config.ip, lease.server_id
+        );
+        state.lease = Some(lease);
+        Ok(lease.config)
+    }
+}
+
+struct ClientState {
+    mac: MacAddress,
+    config: DhcpConfig,
+    tx: SharedFrameTx,
+    rx: RoutedFrameRx,
+    lease: Option<Lease>,
+    /// Transaction ID of the last request
+    xid: u32,
+}
+
+impl ClientState {
+    /// Runs the lease timers, returns the time to wait until the next step
+    fn step<F: FnMut(NetworkConfig)>(&mut self, on_change: &mut F) -> Duration {
+        let now = Instant::now();
+        let Some(lease) = self.lease else {
+            return match self.acquire() {
+                Ok(lease) => {
+                    self.update(lease, on_change);
+                    Duration::ZERO
+                }
+                Err(err) => {
+                    warn!("failed to obtain a DHCP lease: {err}");
+                    MIN_RENEW_INTERVAL
+                }
+            };
+        };
+        if now >= lease.expires_at {
+            warn!("DHCP lease of {} expired", lease.config.ip);
+            self.lease = None;
+            return Duration::ZERO;
+        }
+        if now < lease.renew_at {
+            return lease.renew_at - now;
+        }
+        let rebinding = now >= lease.rebind_at;
+        match self.renew(&lease, rebinding) {
+            Ok(Renewal::Renewed(renewed)) => {
+                self.update(renewed, on_change);
+                Duration::ZERO
+            }
+            Ok(Renewal::Declined) => {
+                warn!("DHCP server declined the lease of {}", lease.config.ip);
+                self.lease = None;
+                Duration::ZERO
+            }
+            Ok(Renewal::NoReply) => renew_retry_delay(
+                Instant::now(),
+                if rebinding {
+                    lease.expires_at
+                } else {
+                    lease.rebind_at
+                },
+            ),
+            Err(err) => {
+                warn!("failed to renew DHCP lease: {err}");
+                renew_retry_delay(Instant::now(), lease.expires_at)
+            }
+        }
+    }
+
+    fn update<F: FnMut(NetworkConfig)>(&mut self, lease: Lease, on_change: &mut F) {
+        let changed = self.lease.map(|current| current.config) != Some(lease.config);
+        self.lease = Some(lease);
+        if changed {
+            info!(
+                "leased {} from DHCP server {}",
+                lease.config.ip, lease.server_id
+            );
+            on_change(lease.config);
+        }
+    }
+
+    /// Obtains a new lease by discovering the servers
+    fn acquire(&mut self) -> io::Result<Lease> {
+        for _ in 0..self.config.retries() {
+            let discover = self.message(MessageType::Discover, Ipv4Addr::UNSPECIFIED);
+            let frame = self.broadcast_frame(Ipv4Addr::UNSPECIFIED, &discover.encode());
+            let Some((_, offer)) = self.exchange(&frame, |reply| {
+                reply.kind == MessageType::Offer && reply.server_id.is_some()
+            })?
+            else {
+                continue;
+            };
+            let request = ClientMessage {
+                requested_ip: Some(offer.yiaddr),
+                server_id: offer.server_id,
+                ..self.message(MessageType::Request, Ipv4Addr::UNSPECIFIED)
+            };
+            let frame = self.broadcast_frame(Ipv4Addr::UNSPECIFIED, &request.encode());
+            let Some((server_mac, ack)) = self.exchange(&frame, |reply| {
+                matches!(reply.kind, MessageType::Ack | MessageType::Nak)
+                    && reply.server_id == offer.server_id
+            })?
+            else {
+                continue;
+            };
+            if ack.kind == MessageType::Nak {
+                continue;
+            }
+            let server_id = offer.server_id.unwrap_or(Ipv4Addr::UNSPECIFIED);
+            if let Some(lease) = Lease::new(&ack, server_id, server_mac, self.mac, Instant::now()) {
+                return Ok(lease);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "no DHCP server offered a lease",
+        ))
+    }
+
+    /// Extends a lease, from the server that granted it unless `rebinding`
+    fn renew(&mut self, lease: &Lease, rebinding: bool) -> io::Result<Renewal> {
+        let ip = lease.config.ip.ip();
+        let request = self.message(MessageType::Request, ip).encode();
+        let frame = if rebinding {
+            self.broadcast_frame(ip, &request)
+        } else {
+            to_frame(self.mac, lease.server_mac, ip, lease.server_id, &request)
+        };
+        let reply = self.exchange(&frame, |reply| {
+            matches!(reply.kind, MessageType::Ack | MessageType::Nak)
+        })?;
+        Ok(match reply {
+            Some((_, nak)) if nak.kind == MessageType::Nak => Renewal::Declined,
+            Some((server_mac, ack)) => {
+                let server_id = ack.server_id.unwrap_or(lease.server_id);
+                Lease::new(&ack, server_id, server_mac, self.mac, Instant::now())
+                    .map_or(Renewal::NoReply, Renewal::Renewed)
+            }
+            None => Renewal::NoReply,
+        })
+    }
+
+    /// Creates a message of a new transaction
+    fn message(&mut self, kind: MessageType, ciaddr: Ipv4Addr) -> ClientMessage {
+        self.xid = self.xid.wrapping_add(1);
+        ClientMessage {
+            kind,
+            xid: self.xid,
+            mac: self.mac,
+            ciaddr,
+            requested_ip: None,
+            server_id: None,
+        }
+    }
+
+    fn broadcast_frame(&self, src_ip: Ipv4Addr, payload: &[u8]) -> Vec<u8> {
+        to_frame(
+            self.mac,
+            BROADCAST_MAC,
+            src_ip,
+            Ipv4Addr::BROADCAST,
+            payload,
+        )
+    }
+
+    /// Sends a request and waits for a reply of the current transaction accepted by
+    /// `accept`, `None` if none arrived in time
+    fn exchange<F>(
+        &mut self,
+        frame: &[u8],
+        accept: F,
+    ) -> io::Result<Option<(MacAddress, ServerMessage)>>
+    where
+        F: Fn(&ServerMessage) -> bool,
+    {
+        self.tx.send(frame)?;
+        let deadline = Instant::now() + self.config.timeout();
+        while Instant::now() < deadline {
+            match self.rx.recv_nonblocking() {
+                Ok(frame) => {
+                    if let Some((src, reply)) = parse_frame(&frame) {
+                        if reply.xid == self.xid && reply.chaddr == self.mac && accept(&reply) {
+                            return Ok(Some((src, reply)));
+                        }
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(RX_POLL_INTERVAL);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0x01]);
+    const SERVER_MAC: MacAddress = MacAddress([0x02, 0, 0, 0, 0, 0xfe]);
+    const SERVER: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 254);
+
+    fn reply(kind: MessageType, xid: u32, options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut buf = vec![0u8; OPTIONS_OFFSET];
+        write_bytes(&mut buf, 0, &[BOOTREPLY, HTYPE_ETHERNET, 6, 0]);
+        write_bytes(&mut buf, 4, &xid.to_be_bytes());
+        write_bytes(&mut buf, 16, &[10, 0, 0, 7]);
+        write_bytes(&mut buf, 28, &MAC.0);
+        write_bytes(&mut buf, BOOTP_LEN, &MAGIC_COOKIE);
+        buf.extend([OPT_MESSAGE_TYPE, 1, kind.code(), OPT_PAD]);
+        for &(code, value) in options {
+            buf.extend([code, value.len() as u8]);
+            buf.extend(value);
+        }
+        buf.push(OPT_END);
+        buf
+    }
+
+    #[test]
+    fn client_messages_carry_the_transaction() {
+        let message = ClientMessage {
+            kind: MessageType::Request,
+            xid: 0x1234_5678,
+            mac: MAC,
+            ciaddr: Ipv4Addr::UNSPECIFIED,
+            requested_ip: Some(Ipv4Addr::new(10, 0, 0, 7)),
+            server_id: Some(SERVER),
+        };
+        let payload = message.encode();
+        assert_eq!(
+            payload.get(..4),
+            Some(&[BOOTREQUEST, HTYPE_ETHERNET, 6, 0][..])
+        );
+        assert_eq!(payload.get(4..8), Some(&0x1234_5678u32.to_be_bytes()[..]));
+        assert_eq!(payload.get(10..12), Some(&FLAG_BROADCAST.to_be_bytes()[..]));
+        assert_eq!(payload.get(28..34), Some(&MAC.0[..]));
+        assert_eq!(
+            payload.get(OPTIONS_OFFSET..OPTIONS_OFFSET + 3),
+            Some(&[OPT_MESSAGE_TYPE, 1, 3][..])
+        );
+        assert_eq!(payload.last(), Some(&OPT_END));
+
+        let frame = to_frame(
+            MAC,
+            BROADCAST_MAC,
+            Ipv4Addr::UNSPECIFIED,
+            Ipv4Addr::BROADCAST,
+            &payload,
+        );
+        let ip = Ipv4Packet::new(frame.get(ETH_HEADER_LEN..).unwrap()).unwrap();
+        assert_eq!(ipv4::checksum(&ip), ip.get_checksum());
+        // Frames sent to the server port are not replies
+        assert!(!is_dhcp_reply(&frame));
+    }
+
+    #[test]
+    fn server_replies_decode_into_a_lease() {
+        let payload = reply(
+            MessageType::Ack,
+            7,
+            &[
+                (OPT_SERVER_ID, &SERVER.octets()),
+                (OPT_SUBNET_MASK, &[255, 255, 255, 0]),
+                (OPT_ROUTER, &[10, 0, 0, 1]),
+                (OPT_LEASE_TIME, &3600u32.to_be_bytes()),
+            ],
+        );
+        let mut frame = to_frame(SERVER_MAC, MAC, SERVER, Ipv4Addr::BROADCAST, &payload);
+        // Swap the ports to turn the request frame into a reply
+        let udp = ETH_HEADER_LEN + IP_HEADER_LEN;
+        frame.get_mut(udp..udp + 4).unwrap().rotate_left(2);
+        assert!(is_dhcp_reply(&frame));
+        let (src, ack) = parse_frame(&frame).unwrap();
+        assert_eq!(src, SERVER_MAC);
+        assert_eq!(ack.kind, MessageType::Ack);
+        assert_eq!(ack.xid, 7);
+        assert_eq!(ack.chaddr, MAC);
+        assert_eq!(ack.server_id, Some(SERVER));
+
+        let now = Instant::now();
+        let lease = Lease::new(&ack, SERVER, src, MAC, now).unwrap();
+        assert_eq!(
+            lease.config.ip,
+            "10.0.0.7/24".parse::<Ipv4Network>().unwrap()
+        );
+        assert_eq!(lease.config.gateway, Ipv4Addr::new(10, 0, 0, 1));
+        assert_eq!(lease.renew_at, now + Duration::from_secs(1800));
+        assert_eq!(lease.rebind_at, now + Duration::from_secs(3150));
+        assert_eq!(lease.expires_at, now + Duration::from_secs(3600));
+
+        // A lease needs its lease time and a reply its message type
+        let offer = ServerMessage::decode(&reply(MessageType::Offer, 7, &[])).unwrap();
+        assert!(Lease::new(&offer, SERVER, src, MAC, now).is_none());
+        let mut truncated = reply(MessageType::Ack, 7, &[]);
+        truncated.truncate(OPTIONS_OFFSET);
+        assert!(ServerMessage::decode(&truncated).is_none());
+    }
+
+    #[test]
+    fn renewal_retries_halve_the_remaining_time() {
+        let now = Instant::now();
+        let hour = Duration::from_secs(3600);
+        assert_eq!(renew_retry_delay(now, now + hour), hour / 2);
+        assert_eq!(
+            renew_retry_delay(now, now + Duration::from_secs(100)),
+            MIN_RENEW_INTERVAL
+        );
+        assert_eq!(
+            renew_retry_delay(now, now + Duration::from_secs(30)),
+            Duration::from_secs(30)
+        );
+        assert_eq!(renew_retry_delay(now + hour, now), Duration::ZERO);
+    }
+}
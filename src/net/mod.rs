@@ -3,3 +3,6 @@ pub mod config;
 
 /// Tap device implementation
 pub mod tap;
+
+/// DHCPv4 client
+pub(crate) mod dhcp;
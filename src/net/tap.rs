@@ -31,14 +31,15 @@ impl TapDevice {
         Arc::clone(&self.inner)
     }
 
-    /// Creates a TUN device that operates at L2
+    /// Creates a TUN device that operates at L2, with the MTU of the port
     #[allow(unused_results)] // ignore the config construction result
     pub(crate) fn create(
         mac_addr: Option<MacAddress>,
         network: Option<IpNetwork>,
+        mtu: u16,
     ) -> io::Result<Self> {
         let mut config = tun::Configuration::default();
-        config.layer(tun::Layer::L2);
+        config.layer(tun::Layer::L2).mtu(mtu);
         if let Some(network) = network {
             config.address(network.ip()).netmask(network.mask());
         }
@@ -1,15 +1,27 @@
-use std::{cmp::Ordering, collections::VecDeque, iter, thread};
+use std::{
+    cmp::Ordering,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    iter, mem, thread,
+    time::{Duration, Instant},
+};
 
 use crate::{
-    constants::{MAX_PSN_WINDOW, MAX_QP_CNT},
+    affinity::{CpuAffinity, WorkerClass},
+    constants::MAX_PSN_WINDOW,
     device_protocol::{QpParams, WorkReqOpCode, WorkReqSend},
     fragmenter::WrPacketFragmenter,
+    metrics::Metrics,
+    mtt::MrRefs,
     protocol_impl::SendQueueScheduler,
     send::SendWrRdma,
     utils::qpn_index,
     utils::{Psn, QpTable},
 };
 
+/// Time a retransmitted PSN is not retransmitted again, absorbs the duplicate NAKs
+/// generated for the same loss
+const RETRANSMIT_HOLDOFF: Duration = Duration::from_micros(500);
+
 #[allow(variant_size_differences)]
 pub(crate) enum PacketRetransmitTask {
     NewWr {
@@ -23,18 +35,36 @@ pub(crate) enum PacketRetransmitTask {
         // Exclusive
         psn_high: Psn,
     },
+    /// Retransmits only the PSNs missing from the NAK bitmaps
+    RetransmitMissing {
+        qpn: u32,
+        bitmaps: NakBitmaps,
+    },
     Ack {
         qpn: u32,
         psn: Psn,
     },
+    /// Drops the outstanding WRs of a destroyed QP, they will never be acknowledged
+    Destroy {
+        qpn: u32,
+        /// Signaled once the memory region references of the WRs are released
+        done: oneshot::Sender<()>,
+    },
 }
 
 impl PacketRetransmitTask {
+    pub(crate) fn new_destroy(qpn: u32) -> (Self, oneshot::Receiver<()>) {
+        let (done, done_rx) = oneshot::channel();
+        (Self::Destroy { qpn, done }, done_rx)
+    }
+
     fn qpn(&self) -> u32 {
         match *self {
             PacketRetransmitTask::RetransmitRange { qpn, .. }
+            | PacketRetransmitTask::RetransmitMissing { qpn, .. }
             | PacketRetransmitTask::NewWr { qpn, .. }
-            | PacketRetransmitTask::Ack { qpn, .. } => qpn,
+            | PacketRetransmitTask::Ack { qpn, .. }
+            | PacketRetransmitTask::Destroy { qpn, .. } => qpn,
         }
     }
 }
@@ -43,24 +73,34 @@ pub(crate) struct PacketRetransmitWorker {
     receiver: flume::Receiver<PacketRetransmitTask>,
     wr_sender: SendQueueScheduler,
     table: QpTable<IbvSendQueue>,
+    metrics: Metrics,
+    mr_refs: MrRefs,
 }
 
 impl PacketRetransmitWorker {
     pub(crate) fn new(
         receiver: flume::Receiver<PacketRetransmitTask>,
         wr_sender: SendQueueScheduler,
+        metrics: Metrics,
+        mr_refs: MrRefs,
+        max_qp: usize,
     ) -> Self {
         Self {
             receiver,
             wr_sender,
-            table: QpTable::new(),
+            table: QpTable::new(max_qp),
+            metrics,
+            mr_refs,
         }
     }
 
-    pub(crate) fn spawn(self) {
+    pub(crate) fn spawn(self, affinity: CpuAffinity) {
         let _handle = thread::Builder::new()
             .name("timer-worker".into())
-            .spawn(move || self.run())
+            .spawn(move || {
+                affinity.apply(WorkerClass::Timer, 0);
+                self.run();
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
     }
 
@@ -79,30 +119,129 @@ impl PacketRetransmitWorker {
                 PacketRetransmitTask::RetransmitRange {
                     psn_low, psn_high, ..
                 } => {
-                    let sqes = sq.range(psn_low, psn_high);
-                    let packets = sqes
-                        .into_iter()
-                        .flat_map(|sqe| {
-                            WrPacketFragmenter::new(sqe.wr(), sqe.qp_param(), sqe.psn())
-                        })
-                        .skip_while(|x| x.psn < psn_low)
-                        .take_while(|x| x.psn < psn_high);
-                    for mut packet in packets {
-                        packet.set_is_retry();
-                        self.wr_sender.send(packet);
-                    }
+                    Self::retransmit(
+                        sq,
+                        &self.wr_sender,
+                        &self.metrics,
+                        qpn,
+                        psn_low,
+                        psn_high,
+                        |_| true,
+                    );
+                }
+                PacketRetransmitTask::RetransmitMissing { bitmaps, .. } => {
+                    Self::retransmit(
+                        sq,
+                        &self.wr_sender,
+                        &self.metrics,
+                        qpn,
+                        bitmaps.low(),
+                        bitmaps.high(),
+                        |psn| bitmaps.is_missing(psn),
+                    );
                 }
                 PacketRetransmitTask::Ack { psn, .. } => {
-                    sq.pop_until(psn);
+                    for mr_key in sq.pop_until(psn).iter().filter_map(SendQueueElem::mr_key) {
+                        self.mr_refs.release(mr_key);
+                    }
                 }
+                PacketRetransmitTask::Destroy { done, .. } => {
+                    for mr_key in mem::take(sq).inner.iter().filter_map(SendQueueElem::mr_key) {
+                        self.mr_refs.release(mr_key);
+                    }
+                    let _ignore = done.send(());
+                }
+            }
+        }
+    }
+
+    /// Resends the packets in [`psn_low`, `psn_high`) selected by `filter`, skipping
+    /// the ones retransmitted recently
+    fn retransmit<F>(
+        sq: &mut IbvSendQueue,
+        wr_sender: &SendQueueScheduler,
+        metrics: &Metrics,
+        qpn: u32,
+        psn_low: Psn,
+        psn_high: Psn,
+        filter: F,
+    ) where
+        F: Fn(Psn) -> bool,
+    {
+        let now = Instant::now();
+        sq.expire_retransmitted(now);
+        let packets: Vec<_> = sq
+            .range(psn_low, psn_high)
+            .into_iter()
+            .flat_map(|sqe| WrPacketFragmenter::new(sqe.wr(), sqe.qp_param(), sqe.psn()))
+            .skip_while(|x| x.psn < psn_low)
+            .take_while(|x| x.psn < psn_high)
+            .filter(|x| filter(x.psn))
+            .collect();
+        for mut packet in packets {
+            if !sq.mark_retransmitted(packet.psn, now) {
+                continue;
             }
+            packet.set_is_retry();
+            metrics.record_packet_retransmit(qpn);
+            let _ignore = wr_sender.send(packet);
+        }
+    }
+}
+
+/// Bitmaps carried by a NAK, bit `i` of a bitmap is set if the PSN at offset `i` of the
+/// window was received
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct NakBitmaps {
+    psn_pre: Psn,
+    pre_bitmap: u128,
+    psn_now: Psn,
+    now_bitmap: u128,
+}
+
+impl NakBitmaps {
+    pub(crate) fn new(psn_pre: Psn, pre_bitmap: u128, psn_now: Psn, now_bitmap: u128) -> Self {
+        Self {
+            psn_pre,
+            pre_bitmap,
+            psn_now,
+            now_bitmap,
+        }
+    }
+
+    /// First PSN that may be missing
+    fn low(&self) -> Psn {
+        self.psn_pre
+    }
+
+    /// End of the PSNs that may be missing, packets after the last received one may
+    /// still be in flight
+    fn high(&self) -> Psn {
+        self.psn_now + (u128::BITS - self.now_bitmap.leading_zeros())
+    }
+
+    /// Returns `true` if the PSN is reported missing
+    fn is_missing(&self, psn: Psn) -> bool {
+        if psn < self.low() || psn >= self.high() {
+            return false;
+        }
+        if psn >= self.psn_now {
+            return !bit_set(self.now_bitmap, (psn - self.psn_now).into_inner());
         }
+        let offset = (psn - self.psn_pre).into_inner();
+        offset >= u128::BITS || !bit_set(self.pre_bitmap, offset)
     }
 }
 
+fn bit_set(bitmap: u128, offset: u32) -> bool {
+    bitmap.checked_shr(offset).is_some_and(|x| x & 1 == 1)
+}
+
 #[derive(Default)]
 pub(crate) struct IbvSendQueue {
     inner: VecDeque<SendQueueElem>,
+    /// PSNs retransmitted within the holdoff and the time they were sent
+    retransmitted: HashMap<u32, Instant>,
 }
 
 impl IbvSendQueue {
@@ -110,9 +249,28 @@ impl IbvSendQueue {
         self.inner.push_back(elem);
     }
 
-    pub(crate) fn pop_until(&mut self, psn: Psn) {
-        let mut a = self.inner.partition_point(|x| x.psn < psn);
-        let _drop = self.inner.drain(..a.saturating_sub(1));
+    /// Removes the WRs fully acknowledged by `psn` and returns them
+    pub(crate) fn pop_until(&mut self, psn: Psn) -> Vec<SendQueueElem> {
+        let a = self.inner.partition_point(|x| x.end_psn <= psn);
+        self.retransmitted.retain(|&x, _| Psn(x) >= psn);
+        self.inner.drain(..a).collect()
+    }
+
+    /// Records a retransmission, returns `false` if the PSN was already retransmitted
+    /// within the holdoff
+    fn mark_retransmitted(&mut self, psn: Psn, now: Instant) -> bool {
+        match self.retransmitted.entry(psn.into_inner()) {
+            Entry::Occupied(_) => false,
+            Entry::Vacant(entry) => {
+                let _ignore = entry.insert(now);
+                true
+            }
+        }
+    }
+
+    fn expire_retransmitted(&mut self, now: Instant) {
+        self.retransmitted
+            .retain(|_, &mut sent| now.saturating_duration_since(sent) < RETRANSMIT_HOLDOFF);
     }
 
     /// Find range [`psn_low`, `psn_high`)
@@ -127,13 +285,20 @@ impl IbvSendQueue {
 #[derive(Clone, Copy)]
 pub(crate) struct SendQueueElem {
     psn: Psn,
+    /// PSN following the last packet of the WR
+    end_psn: Psn,
     wr: SendWrRdma,
     qp_param: QpParams,
 }
 
 impl SendQueueElem {
-    pub(crate) fn new(wr: SendWrRdma, psn: Psn, qp_param: QpParams) -> Self {
-        Self { psn, wr, qp_param }
+    pub(crate) fn new(wr: SendWrRdma, psn: Psn, end_psn: Psn, qp_param: QpParams) -> Self {
+        Self {
+            psn,
+            end_psn,
+            wr,
+            qp_param,
+        }
     }
 
     pub(crate) fn psn(&self) -> Psn {
@@ -151,4 +316,87 @@ impl SendQueueElem {
     pub(crate) fn opcode(&self) -> WorkReqOpCode {
         self.wr.opcode()
     }
+
+    /// Returns the key of the local memory region referenced until the WR is acknowledged,
+    /// read responses are never acknowledged and hold no reference
+    pub(crate) fn mr_key(&self) -> Option<u32> {
+        (!matches!(self.opcode(), WorkReqOpCode::RdmaReadResp)).then(|| self.wr.lkey())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        constants::PSN_MASK,
+        dcqcn::{DcqcnConfig, ReactionPoint},
+        polling::{Notifier, PollingConfig},
+        rate_limit::RateLimiter,
+        send::SendWrBase,
+    };
+
+    use super::*;
+
+    #[test]
+    fn nak_bitmaps_missing_psns() {
+        // pre window [0, 128) received all but 3, now window [200, 328) received 200 and 202
+        let pre_bitmap = !(1u128 << 3);
+        let bitmaps = NakBitmaps::new(Psn(0), pre_bitmap, Psn(200), 0b101);
+        let missing: Vec<_> = (0..400)
+            .map(Psn)
+            .filter(|&psn| bitmaps.is_missing(psn))
+            .map(Psn::into_inner)
+            .collect();
+        let expected: Vec<_> = iter::once(3).chain(128..200).chain([201]).collect();
+        assert_eq!(missing, expected);
+    }
+
+    #[test]
+    fn nak_bitmaps_handle_psn_wrap() {
+        let psn_pre = Psn(PSN_MASK - 1);
+        let bitmaps = NakBitmaps::new(psn_pre, u128::MAX, psn_pre + 2, 0b10);
+        assert!(bitmaps.is_missing(psn_pre + 2));
+        assert!(!bitmaps.is_missing(psn_pre + 3));
+        assert!(!bitmaps.is_missing(psn_pre + 4), "may still be in flight");
+    }
+
+    #[test]
+    fn duplicate_retransmits_are_suppressed() {
+        let mut sq = IbvSendQueue::default();
+        let now = Instant::now();
+        assert!(sq.mark_retransmitted(Psn(5), now));
+        assert!(!sq.mark_retransmitted(Psn(5), now));
+        sq.expire_retransmitted(now + RETRANSMIT_HOLDOFF);
+        assert!(sq.mark_retransmitted(Psn(5), now + RETRANSMIT_HOLDOFF));
+        let _acked = sq.pop_until(Psn(6));
+        assert!(sq.mark_retransmitted(Psn(5), now + RETRANSMIT_HOLDOFF));
+    }
+
+    #[test]
+    fn destroy_releases_unacked_mrs() {
+        let notifier = Notifier::new(PollingConfig::Spin).unwrap();
+        let scheduler = SendQueueScheduler::new(
+            notifier,
+            ReactionPoint::new(DcqcnConfig::default(), 4),
+            RateLimiter::new(4),
+        );
+        let mr_refs = MrRefs::default();
+        let (tx, rx) = flume::unbounded();
+        let worker =
+            PacketRetransmitWorker::new(rx, scheduler, Metrics::new(4, 4), mr_refs.clone(), 4);
+        let base = SendWrBase::new(0, 0, 0x1000, 64, 7, 0, WorkReqOpCode::RdmaWrite);
+        let wr = SendWrRdma::new_from_base(base, 0x2000, 9);
+        let params = QpParams::new(0, 0, 0, 0, 0, 0, 0, false);
+        for psn in 0..2 {
+            mr_refs.acquire(7);
+            let elem = SendQueueElem::new(wr, Psn(psn), Psn(psn + 1), params);
+            tx.send(PacketRetransmitTask::NewWr { qpn: 0, wr: elem })
+                .unwrap();
+        }
+        let (task, done_rx) = PacketRetransmitTask::new_destroy(0);
+        tx.send(task).unwrap();
+        drop(tx);
+        worker.run();
+        done_rx.recv().unwrap();
+        assert!(!mr_refs.is_referenced(7), "the MR can be deregistered");
+    }
 }
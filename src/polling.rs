@@ -0,0 +1,291 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+    sync::Arc,
+    thread::{self, Thread},
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_SPIN_ITERS: u32 = 1024;
+const DEFAULT_MIN_PARK_US: u64 = 1;
+const DEFAULT_MAX_PARK_US: u64 = 1000;
+
+/// Strategy used by worker threads when there is no work available
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "strategy", rename_all = "snake_case")]
+pub(crate) enum PollingConfig {
+    /// Busy poll forever, lowest latency but consumes a full core per worker
+    #[default]
+    Spin,
+    /// Spin for `spin_iters` empty polls, then park with exponential backoff
+    SpinPark {
+        #[serde(default = "default_spin_iters")]
+        spin_iters: u32,
+        #[serde(default = "default_min_park_us")]
+        min_park_us: u64,
+        #[serde(default = "default_max_park_us")]
+        max_park_us: u64,
+    },
+    /// Spin for `spin_iters` empty polls, then block on an eventfd kicked by the submitter.
+    ///
+    /// The wait is bounded by `max_park_us` so queues filled by the hardware are still polled.
+    Eventfd {
+        #[serde(default = "default_spin_iters")]
+        spin_iters: u32,
+        #[serde(default = "default_max_park_us")]
+        max_park_us: u64,
+    },
+}
+
+fn default_spin_iters() -> u32 {
+    DEFAULT_SPIN_ITERS
+}
+
+fn default_min_park_us() -> u64 {
+    DEFAULT_MIN_PARK_US
+}
+
+fn default_max_park_us() -> u64 {
+    DEFAULT_MAX_PARK_US
+}
+
+/// Wakes up idle workers, shared between submitters and workers
+#[derive(Debug, Clone)]
+pub(crate) struct Notifier {
+    inner: Arc<NotifierInner>,
+}
+
+#[derive(Debug)]
+enum NotifierInner {
+    /// Workers never sleep, nothing to do
+    Noop,
+    /// Parked worker threads
+    Park(Mutex<Vec<Thread>>),
+    /// Eventfd shared by all workers
+    Eventfd(OwnedFd),
+}
+
+impl Notifier {
+    pub(crate) fn new(config: PollingConfig) -> io::Result<Self> {
+        let inner = match config {
+            PollingConfig::Spin => NotifierInner::Noop,
+            PollingConfig::SpinPark { .. } => NotifierInner::Park(Mutex::new(Vec::new())),
+            PollingConfig::Eventfd { .. } => NotifierInner::Eventfd(new_eventfd()?),
+        };
+        Ok(Self {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// Wakes up the idle workers
+    pub(crate) fn notify(&self) {
+        match *self.inner {
+            NotifierInner::Noop => {}
+            NotifierInner::Park(ref threads) => {
+                for thread in threads.lock().iter() {
+                    thread.unpark();
+                }
+            }
+            NotifierInner::Eventfd(ref fd) => {
+                let val = 1u64.to_ne_bytes();
+                #[allow(unsafe_code)]
+                // SAFETY: the fd is a valid eventfd and the buffer is 8 bytes long
+                let _ret = unsafe { libc::write(fd.as_raw_fd(), val.as_ptr().cast(), val.len()) };
+            }
+        }
+    }
+
+    /// Registers the current thread for wakeups
+    fn register_current(&self) {
+        if let NotifierInner::Park(ref threads) = *self.inner {
+            threads.lock().push(thread::current());
+        }
+    }
+}
+
+/// Per worker polling state, tracks consecutive empty polls
+#[derive(Debug)]
+pub(crate) struct Poller {
+    config: PollingConfig,
+    notifier: Notifier,
+    idle_iters: u32,
+    park_duration: Duration,
+}
+
+impl Poller {
+    /// Creates a new poller, must be called from the polling thread
+    pub(crate) fn new(config: PollingConfig, notifier: Notifier) -> Self {
+        notifier.register_current();
+        Self {
+            config,
+            notifier,
+            idle_iters: 0,
+            park_duration: Self::min_park_duration(config),
+        }
+    }
+
+    /// Resets the backoff state after work has been found
+    pub(crate) fn on_busy(&mut self) {
+        self.idle_iters = 0;
+        self.park_duration = Self::min_park_duration(self.config);
+    }
+
+    /// Called after an empty poll, may block the current thread
+    pub(crate) fn on_idle(&mut self) {
+        self.idle(None);
+    }
+
+    /// Called after an empty poll while work is held until `deadline`, the current thread
+    /// is blocked at most until the deadline
+    pub(crate) fn on_idle_until(&mut self, deadline: Instant) {
+        self.idle(Some(deadline.saturating_duration_since(Instant::now())));
+    }
+
+    fn idle(&mut self, limit: Option<Duration>) {
+        let bounded = |timeout: Duration| limit.map_or(timeout, |limit| timeout.min(limit));
+        match self.config {
+            PollingConfig::Spin => std::hint::spin_loop(),
+            PollingConfig::SpinPark {
+                spin_iters,
+                max_park_us,
+                ..
+            } => {
+                if self.spin(spin_iters) {
+                    return;
+                }
+                thread::park_timeout(bounded(self.park_duration));
+                self.park_duration = self
+                    .park_duration
+                    .saturating_mul(2)
+                    .min(Duration::from_micros(max_park_us));
+            }
+            PollingConfig::Eventfd {
+                spin_iters,
+                max_park_us,
+            } => {
+                if self.spin(spin_iters) {
+                    return;
+                }
+                if let NotifierInner::Eventfd(ref fd) = *self.notifier.inner {
+                    wait_eventfd(fd, bounded(Duration::from_micros(max_park_us)));
+                }
+            }
+        }
+    }
+
+    /// Returns `true` if the worker should keep spinning
+    fn spin(&mut self, spin_iters: u32) -> bool {
+        if self.idle_iters < spin_iters {
+            self.idle_iters += 1;
+            std::hint::spin_loop();
+            return true;
+        }
+        false
+    }
+
+    fn min_park_duration(config: PollingConfig) -> Duration {
+        match config {
+            PollingConfig::SpinPark { min_park_us, .. } => Duration::from_micros(min_park_us),
+            PollingConfig::Spin | PollingConfig::Eventfd { .. } => Duration::ZERO,
+        }
+    }
+}
+
+#[allow(unsafe_code)]
+fn new_eventfd() -> io::Result<OwnedFd> {
+    // SAFETY: FFI call with valid flags
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: fd is a newly created file descriptor owned by us
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Blocks until the eventfd is kicked or the timeout expires, then drains the counter
+#[allow(unsafe_code, clippy::cast_possible_wrap)]
+fn wait_eventfd(fd: &OwnedFd, timeout: Duration) {
+    let mut pfd = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // Microsecond precision, the send workers wait for pacing deadlines
+    let timeout = libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: libc::c_long::from(timeout.subsec_nanos()),
+    };
+    // SAFETY: pfd points to a single valid pollfd, the timeout is a valid timespec
+    let ret = unsafe { libc::ppoll(&mut pfd, 1, &timeout, std::ptr::null()) };
+    if ret > 0 {
+        let mut buf = [0u8; 8];
+        // SAFETY: the buffer is 8 bytes long, fd is non-blocking
+        let _ret = unsafe { libc::read(fd.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len()) };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn spin_park_backoff_is_bounded() {
+        let config = PollingConfig::SpinPark {
+            spin_iters: 2,
+            min_park_us: 1,
+            max_park_us: 8,
+        };
+        let mut poller = Poller::new(config, Notifier::new(config).unwrap());
+        for _ in 0..16 {
+            poller.on_idle();
+        }
+        assert_eq!(poller.park_duration, Duration::from_micros(8));
+        poller.on_busy();
+        assert_eq!(poller.park_duration, Duration::from_micros(1));
+        assert_eq!(poller.idle_iters, 0);
+    }
+
+    #[test]
+    fn eventfd_wakeup() {
+        let config = PollingConfig::Eventfd {
+            spin_iters: 0,
+            max_park_us: 10_000_000,
+        };
+        let notifier = Notifier::new(config).unwrap();
+        let mut poller = Poller::new(config, notifier.clone());
+        notifier.notify();
+        let start = Instant::now();
+        poller.on_idle();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn idle_wait_is_bounded_by_deadline() {
+        let config = PollingConfig::Eventfd {
+            spin_iters: 0,
+            max_park_us: 10_000_000,
+        };
+        let mut poller = Poller::new(config, Notifier::new(config).unwrap());
+        let start = Instant::now();
+        poller.on_idle_until(start + Duration::from_millis(10));
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn parse_polling_config() {
+        let config: PollingConfig = toml::from_str("strategy = \"spin_park\"").unwrap();
+        assert_eq!(
+            config,
+            PollingConfig::SpinPark {
+                spin_iters: DEFAULT_SPIN_ITERS,
+                min_park_us: DEFAULT_MIN_PARK_US,
+                max_park_us: DEFAULT_MAX_PARK_US,
+            }
+        );
+        let config: PollingConfig = toml::from_str("strategy = \"spin\"").unwrap();
+        assert_eq!(config, PollingConfig::Spin);
+    }
+}
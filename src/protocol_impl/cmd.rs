@@ -1,6 +1,7 @@
 use std::{
     io,
     net::IpAddr,
+    slice,
     sync::atomic::{fence, Ordering},
     time::Duration,
 };
@@ -109,28 +110,40 @@ impl<Dev: DeviceAdaptor> DeviceCommand for CommandController<Dev> {
             update.base_pgt_offset,
         );
         let mut qp = self.cmd_qp.lock();
-        let mut qp_update = qp.update();
+        let mut qp_update = qp.update(&self.req_csr_proxy, &self.resp_csr_proxy);
         qp_update.push(CmdQueueDesc::UpdateMrTable(update_mr_table));
-        qp_update.flush(&self.req_csr_proxy);
-        qp_update.wait(&self.resp_csr_proxy);
+        qp_update.flush();
+        qp_update.wait()
+    }
 
-        Ok(())
+    fn invalidate_mtt(&self, mr_key: u32) -> io::Result<()> {
+        // An entry with zero length and no access flags matches no request
+        let update_mr_table = CmdQueueReqDescUpdateMrTable::new(0, 0, 0, mr_key, 0, 0, 0);
+        let mut qp = self.cmd_qp.lock();
+        let mut qp_update = qp.update(&self.req_csr_proxy, &self.resp_csr_proxy);
+        qp_update.push(CmdQueueDesc::UpdateMrTable(update_mr_table));
+        qp_update.flush();
+        qp_update.wait()
     }
 
     fn update_pgt(&self, update: PgtUpdate) -> io::Result<()> {
-        let desc = CmdQueueReqDescUpdatePGT::new(
-            0,
-            update.dma_addr,
-            update.pgt_offset,
-            update.zero_based_entry_count,
-        );
-        let mut qp = self.cmd_qp.lock();
-        let mut qp_update = qp.update();
-        qp_update.push(CmdQueueDesc::UpdatePGT(desc));
-        qp_update.flush(&self.req_csr_proxy);
-        qp_update.wait(&self.resp_csr_proxy);
+        self.update_pgt_batch(slice::from_ref(&update))
+    }
 
-        Ok(())
+    fn update_pgt_batch(&self, updates: &[PgtUpdate]) -> io::Result<()> {
+        let mut qp = self.cmd_qp.lock();
+        let mut qp_update = qp.update(&self.req_csr_proxy, &self.resp_csr_proxy);
+        for update in updates {
+            let desc = CmdQueueReqDescUpdatePGT::new(
+                0,
+                update.dma_addr,
+                update.pgt_offset,
+                update.zero_based_entry_count,
+            );
+            qp_update.push(CmdQueueDesc::UpdatePGT(desc));
+        }
+        qp_update.flush();
+        qp_update.wait()
     }
 
     fn update_qp(&self, entry: UpdateQp) -> io::Result<()> {
@@ -149,12 +162,10 @@ impl<Dev: DeviceAdaptor> DeviceCommand for CommandController<Dev> {
         );
 
         let mut qp = self.cmd_qp.lock();
-        let mut update = qp.update();
+        let mut update = qp.update(&self.req_csr_proxy, &self.resp_csr_proxy);
         update.push(CmdQueueDesc::ManageQP(desc));
-        update.flush(&self.req_csr_proxy);
-        update.wait(&self.resp_csr_proxy);
-
-        Ok(())
+        update.flush();
+        update.wait()
     }
 
     fn set_network(&self, param: NetworkConfig) -> io::Result<()> {
@@ -170,23 +181,19 @@ impl<Dev: DeviceAdaptor> DeviceCommand for CommandController<Dev> {
             param.mac.into(),
         );
         let mut qp = self.cmd_qp.lock();
-        let mut update = qp.update();
+        let mut update = qp.update(&self.req_csr_proxy, &self.resp_csr_proxy);
         update.push(CmdQueueDesc::SetNetworkParam(desc));
-        update.flush(&self.req_csr_proxy);
-        update.wait(&self.resp_csr_proxy);
-
-        Ok(())
+        update.flush();
+        update.wait()
     }
 
     fn set_raw_packet_recv_buffer(&self, meta: RecvBufferMeta) -> io::Result<()> {
         let desc = CmdQueueReqDescSetRawPacketReceiveMeta::new(0, meta.phys_addr);
         let mut qp = self.cmd_qp.lock();
-        let mut update = qp.update();
+        let mut update = qp.update(&self.req_csr_proxy, &self.resp_csr_proxy);
         update.push(CmdQueueDesc::SetRawPacketReceiveMeta(desc));
-        update.flush(&self.req_csr_proxy);
-        update.wait(&self.resp_csr_proxy);
-
-        Ok(())
+        update.flush();
+        update.wait()
     }
 }
 
@@ -208,51 +215,89 @@ impl CmdQp {
     }
 
     /// Creates a queue pair update handle to process commands
-    fn update(&mut self) -> QpUpdate<'_> {
+    fn update<'a, Dev>(
+        &'a mut self,
+        req_csr_proxy: &'a CmdQueueCsrProxy<Dev>,
+        resp_csr_proxy: &'a CmdRespQueueCsrProxy<Dev>,
+    ) -> QpUpdate<'a, Dev> {
         QpUpdate {
             num: 0,
+            num_failed: 0,
             req_queue: &mut self.req_queue,
             resp_queue: &mut self.resp_queue,
+            req_csr_proxy,
+            resp_csr_proxy,
         }
     }
 }
 
 /// An updates handle
-struct QpUpdate<'a> {
-    /// Number of updates
+struct QpUpdate<'a, Dev> {
+    /// Number of updates waiting for a response
     num: usize,
+    /// Number of updates the device reported as failed
+    num_failed: usize,
     /// The command request queue
     req_queue: &'a mut CmdQueue,
     /// The command response queue
     resp_queue: &'a mut CmdRespQueue,
+    /// Proxy for accessing command queue CSRs
+    req_csr_proxy: &'a CmdQueueCsrProxy<Dev>,
+    /// Proxy for accessing command response queue CSRs
+    resp_csr_proxy: &'a CmdRespQueueCsrProxy<Dev>,
 }
 
-impl QpUpdate<'_> {
-    /// Pushes a new command queue descriptor to the queue.
+impl<Dev: DeviceAdaptor> QpUpdate<'_, Dev> {
+    /// Pushes a new command queue descriptor to the queue, waits for the device to
+    /// consume the pushed commands while the queue is full.
     fn push(&mut self, desc: CmdQueueDesc) {
+        while !self.req_queue.push(desc) {
+            // The responses are drained so that the device does not stall on a full
+            // response queue
+            self.flush();
+            self.poll();
+        }
         self.num = self.num.wrapping_add(1);
-        //FIXME: handle failed condition
-        let _ignore = self.req_queue.push(desc);
     }
 
     /// Flushes the command queue by writing the head pointer to the CSR proxy.
-    fn flush<Dev: DeviceAdaptor>(&mut self, req_csr_proxy: &CmdQueueCsrProxy<Dev>) {
-        req_csr_proxy.write_head(self.req_queue.head());
-        if let Ok(tail_ptr) = req_csr_proxy.read_tail() {
+    fn flush(&mut self) {
+        self.req_csr_proxy.write_head(self.req_queue.head());
+        if let Ok(tail_ptr) = self.req_csr_proxy.read_tail() {
             self.req_queue.set_tail(tail_ptr);
         }
     }
 
-    /// Waits for responses to all pushed commands.
-    fn wait<Dev: DeviceAdaptor>(mut self, resp_csr_proxy: &CmdRespQueueCsrProxy<Dev>) {
+    /// Pops the available responses of the pushed commands
+    fn poll(&mut self) {
         while self.num != 0 {
-            if let Some(resp) = self.resp_queue.try_pop() {
-                self.num = self.num.wrapping_sub(1);
-                resp_csr_proxy.write_tail(self.resp_queue.tail());
-                if let Ok(head_ptr) = resp_csr_proxy.read_head() {
-                    self.resp_queue.set_head(head_ptr);
-                }
+            let Some(resp) = self.resp_queue.try_pop() else {
+                return;
+            };
+            self.num = self.num.wrapping_sub(1);
+            if !resp.headers().cmd_queue_common_header().is_success() {
+                self.num_failed = self.num_failed.wrapping_add(1);
+            }
+            self.resp_csr_proxy.write_tail(self.resp_queue.tail());
+            if let Ok(head_ptr) = self.resp_csr_proxy.read_head() {
+                self.resp_queue.set_head(head_ptr);
             }
         }
     }
+
+    /// Waits for responses to all pushed commands.
+    ///
+    /// Returns an error if the device reported any of the commands as failed.
+    fn wait(mut self) -> io::Result<()> {
+        while self.num != 0 {
+            self.poll();
+        }
+        if self.num_failed != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("{} device commands failed", self.num_failed),
+            ));
+        }
+        Ok(())
+    }
 }
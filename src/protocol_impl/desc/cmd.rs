@@ -12,6 +12,20 @@ pub(crate) enum CmdQueueDescOperators {
     SetRawPacketReceiveMeta = 0x04,
 }
 
+impl CmdQueueDescOperators {
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        let variant = match value {
+            0x00 => Self::UpdateMrTable,
+            0x01 => Self::UpdatePgt,
+            0x02 => Self::ManageQp,
+            0x03 => Self::SetNetworkParam,
+            0x04 => Self::SetRawPacketReceiveMeta,
+            _ => return None,
+        };
+        Some(variant)
+    }
+}
+
 #[bitsize(16)]
 #[derive(Clone, Copy, DebugBits, FromBits)]
 pub(crate) struct RingbufDescCmdQueueCommonHead {
@@ -136,6 +150,9 @@ impl CmdQueueReqDescUpdateMrTable {
     pub(crate) fn set_pgt_offset(&mut self, val: u32) {
         self.c0.set_pgt_offset(u17::masked_new(val));
     }
+    pub(crate) fn pd_handler(&self) -> u32 {
+        self.c0.reserved1()
+    }
 }
 
 #[bitsize(64)]
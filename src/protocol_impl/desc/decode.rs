@@ -0,0 +1,457 @@
+use std::{fmt, io, net::Ipv4Addr, str::FromStr};
+
+use crate::{device_protocol::WorkReqOpCode, net::config::MacAddress, qp::convert_ibv_mtu_to_u16};
+
+use super::{
+    meta_report::RdmaOpCode, CmdQueueDescOperators, CmdQueueReqDescQpManagement,
+    CmdQueueReqDescSetNetworkParam, CmdQueueReqDescSetRawPacketReceiveMeta,
+    CmdQueueReqDescUpdateMrTable, CmdQueueReqDescUpdatePGT, CmdQueueRespDescOnlyCommonHeader,
+    DescFromBytes, MetaReportQueueAckDesc, MetaReportQueueAckExtraDesc,
+    MetaReportQueuePacketBasicInfoDesc, MetaReportQueueReadReqExtendInfoDesc, RingBufDescUntyped,
+    SendQueueReqDescSeg0, SendQueueReqDescSeg1, SimpleNicRxQueueDesc, SimpleNicTxQueueDesc,
+    DESC_SIZE,
+};
+
+/// Ring a descriptor was taken from, determines how its opcode is interpreted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RingKind {
+    SendQueue,
+    MetaReport,
+    CmdReq,
+    CmdResp,
+    SimpleNicTx,
+    SimpleNicRx,
+}
+
+impl FromStr for RingKind {
+    type Err = io::Error;
+
+    /// Parses a ring name as used by the CSR map, channel suffixes like `sq0` are accepted
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let kind = match s.trim_end_matches(|c: char| c.is_ascii_digit()) {
+            "sq" => Self::SendQueue,
+            "mrq" => Self::MetaReport,
+            "cmd_req" => Self::CmdReq,
+            "cmd_resp" => Self::CmdResp,
+            "simple_nic_tx" => Self::SimpleNicTx,
+            "simple_nic_rx" => Self::SimpleNicRx,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("unknown ring: {s}"),
+                ))
+            }
+        };
+        Ok(kind)
+    }
+}
+
+/// A descriptor decoded into named fields
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DecodedDesc {
+    /// Descriptor layout, e.g. `SendQueueReqDescSeg0`
+    pub(crate) kind: &'static str,
+    /// Opcode name and raw value
+    pub(crate) opcode: String,
+    pub(crate) valid: bool,
+    pub(crate) has_next: bool,
+    /// Field names and rendered values
+    pub(crate) fields: Vec<(&'static str, String)>,
+}
+
+impl DecodedDesc {
+    fn new(kind: &'static str, opcode: String, desc: RingBufDescUntyped) -> Self {
+        Self {
+            kind,
+            opcode,
+            valid: desc.head.valid(),
+            has_next: desc.head.has_next(),
+            fields: Vec::new(),
+        }
+    }
+
+    fn field(mut self, name: &'static str, value: impl fmt::Display) -> Self {
+        self.fields.push((name, value.to_string()));
+        self
+    }
+
+    /// Returns the rendered value of a field
+    pub(crate) fn get(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find_map(|(n, v)| (*n == name).then_some(v.as_str()))
+    }
+}
+
+impl fmt::Display for DecodedDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} op={} valid={} has_next={}",
+            self.kind, self.opcode, self.valid, self.has_next
+        )?;
+        for (name, value) in &self.fields {
+            write!(f, " {name}={value}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Decodes a single descriptor.
+///
+/// `is_next` must be set if the previous descriptor of the ring had `has_next` set, as the
+/// second descriptor of a send request or meta report uses a different layout.
+pub(crate) fn decode(ring: RingKind, desc: RingBufDescUntyped, is_next: bool) -> DecodedDesc {
+    match ring {
+        RingKind::SendQueue => decode_send(desc, is_next),
+        RingKind::MetaReport => decode_meta_report(desc, is_next),
+        RingKind::CmdReq => decode_cmd_req(desc),
+        RingKind::CmdResp => decode_cmd_resp(desc),
+        RingKind::SimpleNicTx => {
+            let d = SimpleNicTxQueueDesc::from(desc);
+            DecodedDesc::new("SimpleNicTxQueueDesc", "-".into(), desc)
+                .field("addr", Hex(d.addr()))
+                .field("len", Bytes(d.len()))
+        }
+        RingKind::SimpleNicRx => {
+            let d = SimpleNicRxQueueDesc::from(desc);
+            DecodedDesc::new("SimpleNicRxQueueDesc", "-".into(), desc)
+                .field("slot_idx", d.slot_idx())
+                .field("len", Bytes(d.len()))
+        }
+    }
+}
+
+/// Decodes consecutive descriptors of a ring, starting at the first descriptor of a request
+pub(crate) fn decode_ring(ring: RingKind, descs: &[RingBufDescUntyped]) -> Vec<DecodedDesc> {
+    let mut is_next = false;
+    descs
+        .iter()
+        .map(|desc| {
+            let decoded = decode(ring, *desc, is_next);
+            is_next = desc.head.has_next() && !is_next;
+            decoded
+        })
+        .collect()
+}
+
+/// Parses a hex dump of descriptors in memory order.
+///
+/// Bytes are whitespace separated or grouped, e.g. the output of `xxd -p` or `od -An -tx1`.
+/// Offset columns ending with `:` and `0x` prefixes are skipped.
+pub(crate) fn parse_hex_dump(dump: &str) -> io::Result<Vec<RingBufDescUntyped>> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let mut bytes = Vec::new();
+    for line in dump.lines() {
+        let data = line.split_once(':').map_or(line, |(_, rest)| rest);
+        for token in data.split_whitespace() {
+            let token = token.strip_prefix("0x").unwrap_or(token);
+            if token.len() % 2 != 0 {
+                return Err(invalid(format!("odd number of hex digits: {token}")));
+            }
+            for i in (0..token.len()).step_by(2) {
+                let byte = token
+                    .get(i..i + 2)
+                    .and_then(|b| u8::from_str_radix(b, 16).ok())
+                    .ok_or_else(|| invalid(format!("invalid hex: {token}")))?;
+                bytes.push(byte);
+            }
+        }
+    }
+    if bytes.len() % DESC_SIZE != 0 {
+        return Err(invalid(format!(
+            "dump length {} is not a multiple of {DESC_SIZE} bytes",
+            bytes.len()
+        )));
+    }
+    Ok(bytes
+        .chunks_exact(DESC_SIZE)
+        .filter_map(|chunk| chunk.try_into().ok())
+        .map(RingBufDescUntyped::from_bytes)
+        .collect())
+}
+
+fn opcode_name(name: Option<impl fmt::Debug>, raw: u8) -> String {
+    match name {
+        Some(name) => format!("{name:?}({raw:#04x})"),
+        None => format!("Unknown({raw:#04x})"),
+    }
+}
+
+fn decode_send(desc: RingBufDescUntyped, is_next: bool) -> DecodedDesc {
+    let raw = desc.head.op_code();
+    let opcode = opcode_name(WorkReqOpCode::from_u8(raw), raw);
+    if is_next {
+        let d = SendQueueReqDescSeg1::from(desc);
+        let sqpn = (u32::from(d.sqpn_high_16bits()) << 8) | u32::from(d.sqpn_low_8bits());
+        DecodedDesc::new("SendQueueReqDescSeg1", opcode, desc)
+            .field("pmtu", Pmtu(d.pmtu()))
+            .field("is_first", d.is_first())
+            .field("is_last", d.is_last())
+            .field("is_retry", d.is_retry())
+            .field("enable_ecn", d.enable_ecn())
+            .field("sqpn", sqpn)
+            .field("imm", Hex(d.imm()))
+            .field("mac_addr", MacAddress::from(d.mac_addr()))
+            .field("lkey", Hex(d.lkey()))
+            .field("len", Bytes(d.len()))
+            .field("laddr", Hex(d.laddr()))
+    } else {
+        let d = SendQueueReqDescSeg0::from(desc);
+        DecodedDesc::new("SendQueueReqDescSeg0", opcode, desc)
+            .field("msn", d.msn())
+            .field("psn", d.psn())
+            .field("qp_type", d.qp_type())
+            .field("dqpn", d.dqpn())
+            .field("flags", Hex(d.flags()))
+            .field("dqp_ip", Ipv4Addr::from_bits(d.dqp_ip()))
+            .field("raddr", Hex(d.raddr()))
+            .field("rkey", Hex(d.rkey()))
+            .field("total_len", Bytes(d.total_len()))
+    }
+}
+
+fn decode_meta_report(desc: RingBufDescUntyped, is_next: bool) -> DecodedDesc {
+    let raw = desc.head.op_code();
+    let op = RdmaOpCode::from_u8(raw);
+    let opcode = opcode_name(op, raw);
+    match (op, is_next) {
+        (Some(op), false) if op.is_packet() || op.is_cnp() => {
+            let d = MetaReportQueuePacketBasicInfoDesc::from(desc);
+            DecodedDesc::new("MetaReportQueuePacketBasicInfoDesc", opcode, desc)
+                .field("msn", d.msn())
+                .field("psn", d.psn())
+                .field("ecn_marked", d.ecn_marked())
+                .field("solicited", d.solicited())
+                .field("ack_req", d.ack_req())
+                .field("is_retry", d.is_retry())
+                .field("dqpn", d.dqpn())
+                .field("total_len", Bytes(d.total_len()))
+                .field("raddr", Hex(d.raddr()))
+                .field("rkey", Hex(d.rkey()))
+                .field("imm_data", Hex(d.imm_data()))
+        }
+        (Some(op), true) if op.is_packet() => {
+            let d = MetaReportQueueReadReqExtendInfoDesc::from(desc);
+            DecodedDesc::new("MetaReportQueueReadReqExtendInfoDesc", opcode, desc)
+                .field("total_len", Bytes(d.total_len()))
+                .field("laddr", Hex(d.laddr()))
+                .field("lkey", Hex(d.lkey()))
+        }
+        (Some(op), false) if op.is_ack() => {
+            let d = MetaReportQueueAckDesc::from(desc);
+            DecodedDesc::new("MetaReportQueueAckDesc", opcode, desc)
+                .field("msn", d.msn())
+                .field("qpn", d.qpn())
+                .field("psn_now", d.psn_now())
+                .field("psn_before_slide", d.psn_before_slide())
+                .field("is_packet_lost", d.is_packet_lost())
+                .field("is_window_slided", d.is_window_slided())
+                .field("is_send_by_driver", d.is_send_by_driver())
+                .field("is_send_by_local_hw", d.is_send_by_local_hw())
+                .field("now_bitmap", Bitmap(d.now_bitmap()))
+        }
+        (Some(op), true) if op.is_ack() => {
+            let d = MetaReportQueueAckExtraDesc::from(desc);
+            DecodedDesc::new("MetaReportQueueAckExtraDesc", opcode, desc)
+                .field("pre_bitmap", Bitmap(d.pre_bitmap()))
+        }
+        _ => DecodedDesc::new("Unsupported", opcode, desc),
+    }
+}
+
+fn decode_cmd_req(desc: RingBufDescUntyped) -> DecodedDesc {
+    let raw = desc.head.op_code();
+    let op = CmdQueueDescOperators::from_u8(raw);
+    let opcode = opcode_name(op, raw);
+    let Some(op) = op else {
+        return DecodedDesc::new("Unsupported", opcode, desc);
+    };
+    match op {
+        CmdQueueDescOperators::UpdateMrTable => {
+            let d = CmdQueueReqDescUpdateMrTable::from(desc);
+            DecodedDesc::new("CmdQueueReqDescUpdateMrTable", opcode, desc)
+                .field(
+                    "user_data",
+                    d.headers().cmd_queue_common_header().user_data(),
+                )
+                .field("mr_base_va", Hex(d.mr_base_va()))
+                .field("mr_length", Bytes(d.mr_length()))
+                .field("mr_key", Hex(d.mr_key()))
+                .field("pd_handler", d.pd_handler())
+                .field("acc_flags", Hex(d.acc_flags()))
+                .field("pgt_offset", d.pgt_offset())
+        }
+        CmdQueueDescOperators::UpdatePgt => {
+            let d = CmdQueueReqDescUpdatePGT::from(desc);
+            DecodedDesc::new("CmdQueueReqDescUpdatePGT", opcode, desc)
+                .field(
+                    "user_data",
+                    d.headers().cmd_queue_common_header().user_data(),
+                )
+                .field("dma_addr", Hex(d.dma_addr()))
+                .field("start_index", d.start_index())
+                .field("entry_count", d.zero_based_entry_count().saturating_add(1))
+        }
+        CmdQueueDescOperators::ManageQp => {
+            let d = CmdQueueReqDescQpManagement::from(desc);
+            DecodedDesc::new("CmdQueueReqDescQpManagement", opcode, desc)
+                .field("user_data", d.cmd_queue_common_header().user_data())
+                .field("qpn", d.qpn())
+                .field("is_valid", d.is_valid())
+                .field("is_error", d.is_error())
+                .field("ip_addr", Ipv4Addr::from_bits(d.ip_addr()))
+                .field("peer_qpn", d.peer_qpn())
+                .field("rq_access_flags", Hex(d.rq_access_flags()))
+                .field("qp_type", d.qp_type())
+                .field("pmtu", Pmtu(d.pmtu()))
+                .field("local_udp_port", d.local_udp_port())
+                .field("peer_mac_addr", MacAddress::from(d.peer_mac_addr()))
+        }
+        CmdQueueDescOperators::SetNetworkParam => {
+            let d = CmdQueueReqDescSetNetworkParam::from(desc);
+            DecodedDesc::new("CmdQueueReqDescSetNetworkParam", opcode, desc)
+                .field("user_data", d.cmd_queue_common_header().user_data())
+                .field("ip_addr", Ipv4Addr::from_bits(d.ip_addr()))
+                .field("netmask", Ipv4Addr::from_bits(d.netmask()))
+                .field("gateway", Ipv4Addr::from_bits(d.gateway()))
+                .field("mac_addr", MacAddress::from(d.mac_addr()))
+        }
+        CmdQueueDescOperators::SetRawPacketReceiveMeta => {
+            let d = CmdQueueReqDescSetRawPacketReceiveMeta::from(desc);
+            DecodedDesc::new("CmdQueueReqDescSetRawPacketReceiveMeta", opcode, desc)
+                .field("user_data", d.cmd_queue_common_header().user_data())
+                .field("write_base_addr", Hex(d.write_base_addr()))
+        }
+    }
+}
+
+fn decode_cmd_resp(desc: RingBufDescUntyped) -> DecodedDesc {
+    let raw = desc.head.op_code();
+    let opcode = opcode_name(CmdQueueDescOperators::from_u8(raw), raw);
+    let header = CmdQueueRespDescOnlyCommonHeader::from(desc)
+        .headers()
+        .cmd_queue_common_header();
+    DecodedDesc::new("CmdQueueRespDescOnlyCommonHeader", opcode, desc)
+        .field("user_data", header.user_data())
+        .field("is_success", header.is_success())
+}
+
+/// Renders a value in hex
+struct Hex<T>(T);
+
+impl<T: fmt::LowerHex> fmt::Display for Hex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// Renders a length in bytes
+struct Bytes(u32);
+
+impl fmt::Display for Bytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}B", self.0)
+    }
+}
+
+/// Renders a 128-bit PSN bitmap with all digits
+struct Bitmap(u128);
+
+impl fmt::Display for Bitmap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#034x}", self.0)
+    }
+}
+
+/// Renders an `ibv_mtu` value as its size in bytes
+struct Pmtu(u8);
+
+impl fmt::Display for Pmtu {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match convert_ibv_mtu_to_u16(self.0) {
+            Some(mtu) => write!(f, "{mtu}B"),
+            None => write!(f, "invalid({})", self.0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn decode_send_queue_segments() {
+        let seg0 = SendQueueReqDescSeg0::new(
+            WorkReqOpCode::RdmaWrite,
+            7,
+            100,
+            2,
+            0x123,
+            0,
+            0x0a00_0002,
+            0x1000,
+            0xabcd,
+            8192,
+        );
+        let seg1 = SendQueueReqDescSeg1::new(
+            WorkReqOpCode::RdmaWrite,
+            5,
+            true,
+            true,
+            false,
+            false,
+            0x42_0001,
+            0,
+            0,
+            0x11,
+            8192,
+            0x2000,
+        );
+        let decoded = decode_ring(RingKind::SendQueue, &[seg0.into(), seg1.into()]);
+        let [ref d0, ref d1] = decoded[..] else {
+            panic!("expected two descriptors");
+        };
+        assert_eq!(d0.kind, "SendQueueReqDescSeg0");
+        assert_eq!(d0.opcode, "RdmaWrite(0x00)");
+        assert!(d0.has_next);
+        assert_eq!(d0.get("dqpn"), Some("291"));
+        assert_eq!(d0.get("dqp_ip"), Some("10.0.0.2"));
+        assert_eq!(d0.get("total_len"), Some("8192B"));
+        assert_eq!(d1.kind, "SendQueueReqDescSeg1");
+        assert_eq!(d1.get("sqpn"), Some("4325377"));
+        assert_eq!(d1.get("pmtu"), Some("4096B"));
+        assert_eq!(d1.get("laddr"), Some("0x2000"));
+    }
+
+    #[test]
+    fn decode_cmd_queue() {
+        let desc = CmdQueueReqDescUpdatePGT::new(3, 0x8000, 16, 63);
+        let decoded = decode(RingKind::CmdReq, desc.into(), false);
+        assert_eq!(decoded.opcode, "UpdatePgt(0x01)");
+        assert_eq!(decoded.get("entry_count"), Some("64"));
+        assert_eq!(
+            decoded.to_string(),
+            "CmdQueueReqDescUpdatePGT op=UpdatePgt(0x01) valid=true has_next=false \
+             user_data=3 dma_addr=0x8000 start_index=16 entry_count=64"
+        );
+    }
+
+    #[test]
+    fn hex_dump_parsing() {
+        let dump = "\
+            00000000: 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00\n\
+            00000010: 0010000000000000 40000000 0000 0080\n";
+        let descs = parse_hex_dump(dump).unwrap();
+        assert_eq!(descs.len(), 1);
+        let decoded = decode(RingKind::SimpleNicTx, descs[0], false);
+        assert!(decoded.valid);
+        assert_eq!(decoded.get("addr"), Some("0x1000"));
+        assert_eq!(decoded.get("len"), Some("64B"));
+
+        assert!(parse_hex_dump("00 11").is_err());
+        assert!(parse_hex_dump("zz").is_err());
+        assert_eq!("sq2".parse::<RingKind>().unwrap(), RingKind::SendQueue);
+        assert!("foo".parse::<RingKind>().is_err());
+    }
+}
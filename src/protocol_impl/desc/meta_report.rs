@@ -2,11 +2,14 @@ use bilge::prelude::*;
 
 use crate::device_protocol::{HeaderType, PacketPos};
 
-use super::{RingBufDescCommonHead, RingBufDescUntyped};
+use super::{
+    decode::{decode, RingKind},
+    RingBufDescCommonHead, RingBufDescUntyped,
+};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
-enum RdmaOpCode {
+pub(super) enum RdmaOpCode {
     SendFirst = 0x00,
     SendMiddle = 0x01,
     SendLast = 0x02,
@@ -31,10 +34,11 @@ enum RdmaOpCode {
     Resync = 0x15,
     SendLastWithInvalidate = 0x16,
     SendOnlyWithInvalidate = 0x17,
+    Cnp = 0x81,
 }
 
 impl RdmaOpCode {
-    fn from_u8(value: u8) -> Option<Self> {
+    pub(super) fn from_u8(value: u8) -> Option<Self> {
         let variant = match value {
             0x00 => Self::SendFirst,
             0x01 => Self::SendMiddle,
@@ -60,12 +64,13 @@ impl RdmaOpCode {
             0x15 => Self::Resync,
             0x16 => Self::SendLastWithInvalidate,
             0x17 => Self::SendOnlyWithInvalidate,
+            0x81 => Self::Cnp,
             _ => return None,
         };
         Some(variant)
     }
 
-    fn is_packet(self) -> bool {
+    pub(super) fn is_packet(self) -> bool {
         matches!(
             self,
             RdmaOpCode::SendFirst
@@ -114,16 +119,21 @@ impl RdmaOpCode {
             | RdmaOpCode::AtomicAcknowledge
             | RdmaOpCode::CompareSwap
             | RdmaOpCode::FetchAdd
-            | RdmaOpCode::Resync => None,
+            | RdmaOpCode::Resync
+            | RdmaOpCode::Cnp => None,
         }
     }
 
-    fn is_ack(self) -> bool {
+    pub(super) fn is_ack(self) -> bool {
         matches!(
             self,
             RdmaOpCode::Acknowledge | RdmaOpCode::AtomicAcknowledge
         )
     }
+
+    pub(super) fn is_cnp(self) -> bool {
+        matches!(self, RdmaOpCode::Cnp)
+    }
 }
 
 /// Meta report queue descriptor types that can be submitted
@@ -155,10 +165,14 @@ pub(crate) enum MetaReportQueueDescNext {
 
 impl From<RingBufDescUntyped> for MetaReportQueueDescFirst {
     fn from(desc: RingBufDescUntyped) -> Self {
-        let rdma_opcode = RdmaOpCode::from_u8(desc.head.op_code())
-            .unwrap_or_else(|| unreachable!("invalid opcode, desc: {:?}", desc));
+        let rdma_opcode = RdmaOpCode::from_u8(desc.head.op_code()).unwrap_or_else(|| {
+            unreachable!(
+                "invalid opcode, desc: {}",
+                decode(RingKind::MetaReport, desc, false)
+            )
+        });
         match rdma_opcode {
-            op if rdma_opcode.is_packet() => Self::PacketInfo(desc.into()),
+            op if rdma_opcode.is_packet() || rdma_opcode.is_cnp() => Self::PacketInfo(desc.into()),
             op if rdma_opcode.is_ack() => Self::Ack(desc.into()),
             _ => unreachable!("opcode unsupported"),
         }
@@ -273,6 +287,11 @@ impl MetaReportQueuePacketBasicInfoDesc {
         self.c3.set_psn(u24::masked_new(val));
     }
 
+    /// Returns `true` if the packet is a Congestion Notification Packet
+    pub(crate) fn is_cnp(&self) -> bool {
+        RdmaOpCode::from_u8(self.c3.common_header().op_code()).is_some_and(RdmaOpCode::is_cnp)
+    }
+
     pub(crate) fn ecn_marked(&self) -> bool {
         self.c3.ecn_marked()
     }
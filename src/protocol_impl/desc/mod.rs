@@ -13,6 +13,9 @@ pub(crate) mod meta_report;
 /// Send queue descriptors
 pub(crate) mod send;
 
+/// Human-readable descriptor decoding
+pub(crate) mod decode;
+
 pub(crate) use cmd::*;
 pub(crate) use meta_report::*;
 pub(crate) use send::*;
@@ -292,7 +295,11 @@ impl MetaReportQueueDescBthReth {
     }
 }
 
-impl_from_bytes!(MetaReportQueueDescBthReth, SendQueueReqDescSeg0);
+impl_from_bytes!(
+    MetaReportQueueDescBthReth,
+    SendQueueReqDescSeg0,
+    RingBufDescUntyped
+);
 
 #[cfg(test)]
 mod test {
@@ -0,0 +1,146 @@
+use super::constants::{
+    CSR_ADDR_CMD_REQ_QUEUE_ADDR_HIGH, CSR_ADDR_CMD_REQ_QUEUE_ADDR_LOW, CSR_ADDR_CMD_REQ_QUEUE_HEAD,
+    CSR_ADDR_CMD_REQ_QUEUE_TAIL, CSR_ADDR_CMD_RESP_QUEUE_ADDR_HIGH,
+    CSR_ADDR_CMD_RESP_QUEUE_ADDR_LOW, CSR_ADDR_CMD_RESP_QUEUE_HEAD, CSR_ADDR_CMD_RESP_QUEUE_TAIL,
+    CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_BASE_ADDR_HIGH,
+    CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_BASE_ADDR_LOW,
+    CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_HEAD, CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_TAIL,
+    CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_BASE_ADDR_HIGH,
+    CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_BASE_ADDR_LOW,
+    CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_HEAD, CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_TAIL,
+    CSR_DEVICE_MODE_ADDR, NUM_QPS, QP_RECV_ADDR_HIGH, QP_RECV_ADDR_LOW, QP_RECV_HEAD, QP_RECV_TAIL,
+    QP_WQE_ADDR_HIGH, QP_WQE_ADDR_LOW, QP_WQE_HEAD, QP_WQE_TAIL,
+};
+
+/// Address of the device mode register
+pub(crate) const DEVICE_MODE: usize = CSR_DEVICE_MODE_ADDR;
+
+/// CSR addresses of a descriptor ring
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct RingCsrs {
+    pub(crate) name: String,
+    pub(crate) base_addr_low: usize,
+    pub(crate) base_addr_high: usize,
+    pub(crate) head: usize,
+    pub(crate) tail: usize,
+}
+
+impl RingCsrs {
+    fn new(
+        name: String,
+        base_addr_low: usize,
+        base_addr_high: usize,
+        head: usize,
+        tail: usize,
+    ) -> Self {
+        Self {
+            name,
+            base_addr_low,
+            base_addr_high,
+            head,
+            tail,
+        }
+    }
+}
+
+/// Returns all descriptor rings of the device.
+///
+/// `sq{N}` are the send queues and `mrq{N}` are the meta report queues of channel `N`.
+pub(crate) fn rings() -> Vec<RingCsrs> {
+    let send_queues = (0..NUM_QPS).filter_map(|i| {
+        Some(RingCsrs::new(
+            format!("sq{i}"),
+            *QP_WQE_ADDR_LOW.get(i)?,
+            *QP_WQE_ADDR_HIGH.get(i)?,
+            *QP_WQE_HEAD.get(i)?,
+            *QP_WQE_TAIL.get(i)?,
+        ))
+    });
+    let meta_report_queues = (0..NUM_QPS).filter_map(|i| {
+        Some(RingCsrs::new(
+            format!("mrq{i}"),
+            *QP_RECV_ADDR_LOW.get(i)?,
+            *QP_RECV_ADDR_HIGH.get(i)?,
+            *QP_RECV_HEAD.get(i)?,
+            *QP_RECV_TAIL.get(i)?,
+        ))
+    });
+    send_queues
+        .chain(meta_report_queues)
+        .chain([
+            RingCsrs::new(
+                "cmd_req".into(),
+                CSR_ADDR_CMD_REQ_QUEUE_ADDR_LOW,
+                CSR_ADDR_CMD_REQ_QUEUE_ADDR_HIGH,
+                CSR_ADDR_CMD_REQ_QUEUE_HEAD,
+                CSR_ADDR_CMD_REQ_QUEUE_TAIL,
+            ),
+            RingCsrs::new(
+                "cmd_resp".into(),
+                CSR_ADDR_CMD_RESP_QUEUE_ADDR_LOW,
+                CSR_ADDR_CMD_RESP_QUEUE_ADDR_HIGH,
+                CSR_ADDR_CMD_RESP_QUEUE_HEAD,
+                CSR_ADDR_CMD_RESP_QUEUE_TAIL,
+            ),
+            RingCsrs::new(
+                "simple_nic_tx".into(),
+                CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_BASE_ADDR_LOW,
+                CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_BASE_ADDR_HIGH,
+                CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_HEAD,
+                CSR_ADDR_OFFSET_SIMPLE_NIC_TX_Q_RINGBUF_TAIL,
+            ),
+            RingCsrs::new(
+                "simple_nic_rx".into(),
+                CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_BASE_ADDR_LOW,
+                CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_BASE_ADDR_HIGH,
+                CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_HEAD,
+                CSR_ADDR_OFFSET_SIMPLE_NIC_RX_Q_RINGBUF_TAIL,
+            ),
+        ])
+        .collect()
+}
+
+/// Returns the names and addresses of all known CSRs, e.g. `sq0_head` or `cmd_req_base_addr_low`
+pub(crate) fn named_csrs() -> Vec<(String, usize)> {
+    let mut csrs = vec![("device_mode".to_owned(), DEVICE_MODE)];
+    for ring in rings() {
+        csrs.push((format!("{}_base_addr_low", ring.name), ring.base_addr_low));
+        csrs.push((format!("{}_base_addr_high", ring.name), ring.base_addr_high));
+        csrs.push((format!("{}_head", ring.name), ring.head));
+        csrs.push((format!("{}_tail", ring.name), ring.tail));
+    }
+    csrs
+}
+
+/// Looks up the address of a named CSR
+pub(crate) fn lookup(name: &str) -> Option<usize> {
+    named_csrs()
+        .into_iter()
+        .find_map(|(n, addr)| (n == name).then_some(addr))
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn csr_names_are_unique() {
+        let csrs = named_csrs();
+        let names: HashSet<_> = csrs.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(names.len(), csrs.len());
+    }
+
+    #[test]
+    fn lookup_matches_constants() {
+        assert_eq!(lookup("cmd_req_head"), Some(CSR_ADDR_CMD_REQ_QUEUE_HEAD));
+        assert_eq!(lookup("sq1_tail"), QP_WQE_TAIL.get(1).copied());
+        assert_eq!(
+            lookup("mrq3_base_addr_high"),
+            QP_RECV_ADDR_HIGH.get(3).copied()
+        );
+        assert_eq!(lookup("device_mode"), Some(0));
+        assert_eq!(lookup("nonexistent"), None);
+    }
+}
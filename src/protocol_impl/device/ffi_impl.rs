@@ -1,15 +1,23 @@
-use std::{io, net::Ipv4Addr, ptr};
+use std::{io, net::Ipv4Addr, os::fd::BorrowedFd, ptr};
 
 use ipnetwork::{IpNetwork, Ipv4Network};
 
 use crate::{
+    affinity::AffinityConfig,
     completion::Completion,
-    config::{ConfigLoader, DeviceConfig},
+    config::{ConfigLoader, DeviceConfig, NetworkSettings},
+    ctl::CtlConfig,
     ctx_ops::RdmaCtxOps,
+    dcqcn::DcqcnConfig,
+    gid::{GidConfig, GID_TABLE_LEN, IBV_GID_TYPE_ROCE_V2},
+    limits::LimitsConfig,
     mem::{
         page::EmulatedPageAllocator, virt_to_phy::PhysAddrResolverEmulated, EmulatedUmemHandler,
     },
-    net::config::{MacAddress, NetworkConfig},
+    metrics::{self, MetricsConfig},
+    mtt::MrCacheConfig,
+    net::config::{MacAddress, NetworkConfig, PortConfig},
+    polling::PollingConfig,
     recv::RecvWr,
     send::SendWr,
     timeout_retransmit::AckTimeoutConfig,
@@ -17,7 +25,10 @@ use crate::{
 
 use super::{
     emulated::EmulatedDevice,
-    hardware::PciHwDevice,
+    hardware::{
+        vfio::{VfioConfig, VfioHwDevice},
+        PciHwDevice,
+    },
     ops_impl::{
         qp_attr::{IbvQpAttr, IbvQpInitAttr},
         DeviceOps, HwDevice, HwDeviceCtx,
@@ -46,15 +57,25 @@ impl BlueRdmaCore {
     }
 
     #[allow(clippy::unwrap_used, clippy::unwrap_in_result)]
-    fn new_hw(sysfs_name: &str) -> Result<HwDeviceCtx<PciHwDevice>, Box<dyn std::error::Error>> {
+    fn new_hw(sysfs_name: &str) -> Result<Box<dyn DeviceOps>, Box<dyn std::error::Error>> {
         Self::init_logger();
         let config = ConfigLoader::load_default()?;
+        if config.vfio().enabled() {
+            let device = VfioHwDevice::open_default()?;
+            device.reset()?;
+            device.init_dma_engine()?;
+            device.set_custom()?;
+            let ctx = HwDeviceCtx::initialize(device, config)?;
+            metrics::register(sysfs_name, ctx.metrics());
+            return Ok(Box::new(ctx));
+        }
         let device = PciHwDevice::open_default()?;
         device.reset()?;
         device.init_dma_engine()?;
         device.set_custom()?;
-        let mut ctx = HwDeviceCtx::initialize(device, config)?;
-        Ok(ctx)
+        let ctx = HwDeviceCtx::initialize(device, config)?;
+        metrics::register(sysfs_name, ctx.metrics());
+        Ok(Box::new(ctx))
     }
 
     #[allow(clippy::unwrap_used, clippy::unwrap_in_result)]
@@ -86,11 +107,28 @@ impl BlueRdmaCore {
             ip: Ipv4Network::new(Ipv4Addr::from_bits(CARD_IP_ADDRESS), 24).unwrap(),
             gateway: Ipv4Addr::new(127, 0, 0, 1).into(),
             mac: MacAddress([0x0A, 0xEE, 0xDD, 0xCC, 0xBB, 0xAA]),
+            vlan: None,
+            priority: 0,
         };
         let ack = AckTimeoutConfig::new(16, 18, 100);
-        let config = DeviceConfig { network, ack };
+        let config = DeviceConfig {
+            network: NetworkSettings::Static(network),
+            port: PortConfig::default(),
+            ack,
+            polling: PollingConfig::default(),
+            affinity: AffinityConfig::default(),
+            metrics: MetricsConfig::default(),
+            ctl: CtlConfig::default(),
+            dcqcn: DcqcnConfig::default(),
+            limits: LimitsConfig::default(),
+            mr_cache: MrCacheConfig::default(),
+            vfio: VfioConfig::default(),
+            gid: GidConfig::default(),
+        };
         // (check_duration, local_ack_timeout) : (256ms, 1s) because emulator is slow
-        HwDeviceCtx::initialize(device, config)
+        let ctx = HwDeviceCtx::initialize(device, config)?;
+        metrics::register(sysfs_name, ctx.metrics());
+        Ok(ctx)
     }
 }
 
@@ -121,8 +159,18 @@ impl HwDevice for EmulatedHwDevice {
         ))
     }
 
-    fn new_umem_handler(&self) -> Self::UmemHandler {
-        EmulatedUmemHandler::new(bluesimalloc::shm_start_addr() as u64)
+    fn new_umem_handler(&self) -> io::Result<Self::UmemHandler> {
+        Ok(EmulatedUmemHandler::new(
+            bluesimalloc::shm_start_addr() as u64
+        ))
+    }
+
+    fn numa_node(&self) -> Option<u32> {
+        None
+    }
+
+    fn local_cpus(&self) -> Option<Vec<usize>> {
+        None
     }
 }
 
@@ -145,6 +193,7 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         let Ok(ctx) = BlueRdmaCore::new_hw(&name) else {
             return ptr::null_mut();
         };
+        // The fat pointer of the context is boxed again to pass it as a thin pointer
         Box::into_raw(Box::new(ctx)).cast()
     }
 
@@ -153,9 +202,7 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
     fn free(driver_data: *const std::ffi::c_void) {
         if !driver_data.is_null() {
             unsafe {
-                drop(Box::from_raw(
-                    driver_data as *mut HwDeviceCtx<EmulatedHwDevice>,
-                ));
+                drop(Box::from_raw(driver_data as *mut Box<dyn DeviceOps>));
             }
         }
     }
@@ -175,19 +222,22 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
 
     #[inline]
     fn query_device_ex(
-        _blue_context: *mut ibverbs_sys::ibv_context,
+        blue_context: *mut ibverbs_sys::ibv_context,
         _input: *const ibverbs_sys::ibv_query_device_ex_input,
         device_attr: *mut ibverbs_sys::ibv_device_attr,
         _attr_size: usize,
     ) -> ::std::os::raw::c_int {
+        let bluerdma = unsafe { get_device(blue_context) };
+        let limits = bluerdma.limits();
+        let to_c_int = |value: usize| i32::try_from(value).unwrap_or(i32::MAX);
         unsafe {
             (*device_attr) = ibverbs_sys::ibv_device_attr {
-                max_qp: 256,
-                max_qp_wr: 64,
+                max_qp: to_c_int(limits.max_qp()),
+                max_qp_wr: to_c_int(limits.max_qp_wr()),
                 max_sge: 8,
-                max_cq: 256,
+                max_cq: to_c_int(limits.max_cq()),
                 max_cqe: 4096,
-                max_mr: 256,
+                max_mr: to_c_int(limits.max_mr()),
                 max_pd: 256,
                 phys_port_cnt: 1,
                 ..Default::default()
@@ -207,7 +257,7 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
                 state: ibverbs_sys::ibv_port_state::IBV_PORT_ACTIVE,
                 max_mtu: ibverbs_sys::IBV_MTU_4096,
                 active_mtu: ibverbs_sys::IBV_MTU_4096,
-                gid_tbl_len: 256,
+                gid_tbl_len: GID_TABLE_LEN as i32,
                 port_cap_flags: 0x0000_2c00,
                 max_msg_sz: 1 << 31,
                 lid: 1,
@@ -217,6 +267,62 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         0
     }
 
+    #[inline]
+    fn query_gid(
+        blue_context: *mut ibverbs_sys::ibv_context,
+        _port_num: u8,
+        index: core::ffi::c_int,
+        gid: *mut ibverbs_sys::ibv_gid,
+    ) -> ::std::os::raw::c_int {
+        if gid.is_null() {
+            return libc::EINVAL;
+        }
+        let bluerdma = unsafe { get_device(blue_context) };
+        let Some(entry) = u32::try_from(index)
+            .ok()
+            .and_then(|index| bluerdma.query_gid(index).ok())
+        else {
+            return libc::EINVAL;
+        };
+        // A free index reads as the zero GID
+        let raw = entry.map_or([0; 16], |entry| entry.gid.octets());
+        unsafe {
+            (*gid) = ibverbs_sys::ibv_gid { raw };
+        }
+        0
+    }
+
+    #[inline]
+    fn query_gid_ex(
+        blue_context: *mut ibverbs_sys::ibv_context,
+        port_num: u32,
+        gid_index: u32,
+        entry: *mut ibverbs_sys::ibv_gid_entry,
+        _flags: u32,
+    ) -> ::std::os::raw::c_int {
+        if entry.is_null() {
+            return libc::EINVAL;
+        }
+        let bluerdma = unsafe { get_device(blue_context) };
+        let gid = match bluerdma.query_gid(gid_index) {
+            Ok(Some(gid)) => gid,
+            Ok(None) => return libc::ENODATA,
+            Err(err) => return err.raw_os_error().unwrap_or(libc::EINVAL),
+        };
+        unsafe {
+            (*entry) = ibverbs_sys::ibv_gid_entry {
+                gid: ibverbs_sys::ibv_gid {
+                    raw: gid.gid.octets(),
+                },
+                gid_index,
+                port_num,
+                gid_type: IBV_GID_TYPE_ROCE_V2,
+                ndev_ifindex: gid.ifindex,
+            };
+        }
+        0
+    }
+
     #[inline]
     fn create_cq(
         blue_context: *mut ibverbs_sys::ibv_context,
@@ -314,9 +420,32 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         attr_mask: core::ffi::c_int,
         init_attr: *mut ibverbs_sys::ibv_qp_init_attr,
     ) -> ::std::os::raw::c_int {
+        if attr.is_null() || init_attr.is_null() {
+            return libc::EINVAL;
+        }
         let qp = unsafe { *qp };
         let context = qp.context;
         let bluerdma = unsafe { get_device(context) };
+        let Ok(current) = bluerdma.query_qp(qp.qp_num) else {
+            return libc::EINVAL;
+        };
+        let attr = unsafe { &mut *attr };
+        attr.path_mtu = current.pmtu.into();
+        attr.dest_qp_num = current.dqpn;
+        attr.qp_access_flags = current.access_flags.into();
+        attr.rate_limit = current.rate_limit;
+        attr.timeout = current.timeout.unwrap_or_default();
+        attr.retry_cnt = current.retry_cnt.unwrap_or_default();
+        attr.ah_attr.grh.sgid_index = current.sgid_index;
+        attr.ah_attr.grh.traffic_class = current.traffic_class;
+        attr.ah_attr.grh.flow_label = current.flow_label;
+        attr.ah_attr.sl = current.sl;
+        let init_attr = unsafe { &mut *init_attr };
+        init_attr.qp_context = qp.qp_context;
+        init_attr.send_cq = qp.send_cq;
+        init_attr.recv_cq = qp.recv_cq;
+        init_attr.srq = qp.srq;
+        init_attr.qp_type = qp.qp_type;
 
         0
     }
@@ -359,13 +488,46 @@ unsafe impl RdmaCtxOps for BlueRdmaCore {
         }
         let pd = unsafe { *mr.pd };
         let bluerdma = unsafe { get_device(mr.context) };
-        if bluerdma.dereg_mr(mr.handle).is_err() {
-            return libc::EINVAL;
+        if let Err(err) = bluerdma.dereg_mr(mr.handle) {
+            return err.raw_os_error().unwrap_or(libc::EINVAL);
         };
 
         0
     }
 
+    #[inline]
+    fn reg_dmabuf_mr(
+        pd: *mut ibverbs_sys::ibv_pd,
+        offset: u64,
+        length: usize,
+        iova: u64,
+        fd: core::ffi::c_int,
+        access: core::ffi::c_int,
+    ) -> *mut ibverbs_sys::ibv_mr {
+        if fd < 0 {
+            return ptr::null_mut();
+        }
+        let context = unsafe { (*pd) }.context;
+        let bluerdma = unsafe { get_device(context) };
+        let pd_handle = unsafe { *pd }.handle;
+        // SAFETY: the caller keeps `fd` open for the duration of the call
+        let fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        let Ok(mr_key) = bluerdma.reg_dmabuf_mr(fd, offset, length, iova, pd_handle, access as u8)
+        else {
+            return ptr::null_mut();
+        };
+        let ibv_mr = Box::new(ibverbs_sys::ibv_mr {
+            context,
+            pd,
+            addr: iova as *mut std::ffi::c_void,
+            length,
+            handle: mr_key,
+            lkey: mr_key,
+            rkey: mr_key,
+        });
+        Box::into_raw(ibv_mr)
+    }
+
     #[inline]
     fn post_send(
         qp: *mut ibverbs_sys::ibv_qp,
@@ -451,15 +613,9 @@ struct BlueRdmaDevice {
 }
 
 #[allow(unsafe_code)]
-unsafe fn get_device(
-    context: *mut ibverbs_sys::ibv_context,
-) -> &'static mut HwDeviceCtx<PciHwDevice> {
+unsafe fn get_device(context: *mut ibverbs_sys::ibv_context) -> &'static mut dyn DeviceOps {
     let dev_ptr = unsafe { *context }.device.cast::<BlueRdmaDevice>();
-    unsafe {
-        (*dev_ptr)
-            .driver
-            .cast::<HwDeviceCtx<PciHwDevice>>()
-            .as_mut()
-    }
-    .unwrap_or_else(|| unreachable!("null device pointer"))
+    unsafe { (*dev_ptr).driver.cast::<Box<dyn DeviceOps>>().as_mut() }
+        .unwrap_or_else(|| unreachable!("null device pointer"))
+        .as_mut()
 }
@@ -13,19 +13,30 @@ use std::{
     sync::Arc,
 };
 
-use crate::mem::{
-    dmabuf::DmaBufAllocator, page::HostPageAllocator, u_dma_buf::UDmaBufAllocator,
-    virt_to_phy::PhysAddrResolverLinuxX86, HostUmemHandler,
+use crate::{
+    affinity::{pci_local_cpus, pci_numa_node},
+    mem::{
+        dmabuf::DmaBufAllocator, page::HostPageAllocator, u_dma_buf::UDmaBufAllocator,
+        virt_to_phy::PhysAddrResolverLinuxX86, HostUmemHandler,
+    },
 };
 
 use super::{ops_impl::HwDevice, DeviceAdaptor};
 
+/// Device access through VFIO with DMA translated by the IOMMU
+pub(crate) mod vfio;
+
 const BAR_INDEX: usize = 0;
 const BAR_INDEX_DMA_ENGINE: usize = 1;
 const BAR_MAP_RANGE_END: u64 = 4096;
 const VENDER_ID: u16 = 0x10ee;
 const DEVICE_ID: u16 = 0x903f;
 const PCI_SYSFS_BUS_PATH: &str = "/sys/bus/pci/devices";
+/// Enable registers of the two channels of the DMA engine, in `BAR_INDEX_DMA_ENGINE`
+const DMA_ENGINE_ENABLE_ADDRS: [usize; 2] = [0x0004, 0x1004];
+const CSR_ADDR_LOOPBACK: usize = 0x180;
+const CSR_ADDR_SEED: usize = 0x184;
+const CSR_ADDR_DROP_THRESH: usize = 0x188;
 
 #[derive(Clone, Debug)]
 pub(crate) struct VfioPciCsrAdaptor {
@@ -33,14 +44,7 @@ pub(crate) struct VfioPciCsrAdaptor {
 }
 
 impl VfioPciCsrAdaptor {
-    fn new(sysfs_path: impl AsRef<Path>) -> io::Result<Self> {
-        let path = sysfs_path.as_ref();
-        let device = VfioPciDevice::open(path).map_err(|err| {
-            io::Error::new(
-                io::ErrorKind::Other,
-                format!("Failed to open sysfs_path: {err}"),
-            )
-        })?;
+    fn new(device: &VfioPciDevice) -> io::Result<Self> {
         let bar = device.bar(BAR_INDEX).ok_or_else(|| {
             io::Error::new(io::ErrorKind::NotFound, "Expected device to have BAR")
         })?;
@@ -127,17 +131,9 @@ impl PciHwDevice {
     }
 
     pub(crate) fn open_default() -> io::Result<Self> {
-        let build_err = || io::Error::new(io::ErrorKind::Other, "Failed to open device");
-        let info = PciInfo::enumerate_pci().map_err(|_err| build_err())?;
-        let device = info
-            .iter()
-            .flatten()
-            .find(|d| d.vendor_id() == VENDER_ID && d.device_id() == DEVICE_ID)
-            .ok_or_else(build_err)?;
-        let location = device.location().map_err(|_err| build_err())?;
-        let sysfs_path = PathBuf::from(PCI_SYSFS_BUS_PATH).join(location.to_string());
-
-        Ok(Self { sysfs_path })
+        Ok(Self {
+            sysfs_path: find_sysfs_path()?,
+        })
     }
 
     pub(crate) fn reset(&self) -> io::Result<()> {
@@ -161,6 +157,20 @@ impl PciHwDevice {
     }
 }
 
+/// Returns the sysfs path of the first device
+fn find_sysfs_path() -> io::Result<PathBuf> {
+    let build_err = || io::Error::new(io::ErrorKind::Other, "Failed to open device");
+    let info = PciInfo::enumerate_pci().map_err(|_err| build_err())?;
+    let device = info
+        .iter()
+        .flatten()
+        .find(|d| d.vendor_id() == VENDER_ID && d.device_id() == DEVICE_ID)
+        .ok_or_else(build_err)?;
+    let location = device.location().map_err(|_err| build_err())?;
+
+    Ok(PathBuf::from(PCI_SYSFS_BUS_PATH).join(location.to_string()))
+}
+
 impl HwDevice for PciHwDevice {
     type Adaptor = SysfsPciCsrAdaptor;
 
@@ -173,11 +183,19 @@ impl HwDevice for PciHwDevice {
     }
 
     fn new_dma_buf_allocator(&self) -> io::Result<Self::DmaBufAllocator> {
-        UDmaBufAllocator::open()
+        UDmaBufAllocator::open_on_node(self.numa_node())
+    }
+
+    fn new_umem_handler(&self) -> io::Result<Self::UmemHandler> {
+        HostUmemHandler::open()
+    }
+
+    fn numa_node(&self) -> Option<u32> {
+        pci_numa_node(&self.sysfs_path)
     }
 
-    fn new_umem_handler(&self) -> Self::UmemHandler {
-        HostUmemHandler::new()
+    fn local_cpus(&self) -> Option<Vec<usize>> {
+        pci_local_cpus(&self.sysfs_path)
     }
 }
 
@@ -198,19 +216,14 @@ impl DmaEngineConfigurator {
     }
 
     pub(crate) fn configure(&mut self) {
-        const ADDR0: usize = 0x0004;
-        const ADDR1: usize = 0x1004;
-        unsafe {
-            self.bar
-                .as_mut_ptr()
-                .add(ADDR0)
-                .cast::<u32>()
-                .write_volatile(1);
-            self.bar
-                .as_mut_ptr()
-                .add(ADDR1)
-                .cast::<u32>()
-                .write_volatile(1);
+        for addr in DMA_ENGINE_ENABLE_ADDRS {
+            unsafe {
+                self.bar
+                    .as_mut_ptr()
+                    .add(addr)
+                    .cast::<u32>()
+                    .write_volatile(1);
+            }
         }
     }
 }
@@ -230,33 +243,30 @@ impl CustomCsrConfigurator {
     }
 
     pub(crate) fn set_loopback(&mut self) {
-        const ADDR: usize = 0x180;
         unsafe {
             self.bar
                 .as_mut_ptr()
-                .add(ADDR)
+                .add(CSR_ADDR_LOOPBACK)
                 .cast::<u32>()
                 .write_volatile(1);
         }
     }
 
     pub(crate) fn set_seed(&mut self, seed: u32) {
-        const ADDR: usize = 0x184;
         unsafe {
             self.bar
                 .as_mut_ptr()
-                .add(ADDR)
+                .add(CSR_ADDR_SEED)
                 .cast::<u32>()
                 .write_volatile(seed);
         }
     }
 
     pub(crate) fn set_drop_thresh(&mut self, rate: u8) {
-        const ADDR: usize = 0x188;
         unsafe {
             self.bar
                 .as_mut_ptr()
-                .add(ADDR)
+                .add(CSR_ADDR_DROP_THRESH)
                 .cast::<u32>()
                 .write_volatile(u32::from(rate));
         }
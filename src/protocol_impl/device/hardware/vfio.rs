@@ -0,0 +1,184 @@
+use std::{
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use pci_driver::{
+    backends::vfio::VfioPciDevice,
+    device::PciDevice,
+    regions::{PciRegion, Permissions},
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    affinity::{pci_local_cpus, pci_numa_node},
+    mem::{
+        iova::{IommuDmaBufAllocator, IommuMapper, IommuUmemHandler, IovaSpace},
+        page_size,
+    },
+    protocol_impl::device::{ops_impl::HwDevice, DeviceAdaptor},
+};
+
+use super::{
+    find_sysfs_path, VfioPciCsrAdaptor, BAR_INDEX_DMA_ENGINE, CSR_ADDR_DROP_THRESH,
+    CSR_ADDR_LOOPBACK, CSR_ADDR_SEED, DMA_ENGINE_ENABLE_ADDRS,
+};
+
+/// VFIO backend configuration
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct VfioConfig {
+    /// Accesses the device through VFIO and programs the IOVAs of its IOMMU domain
+    /// instead of physical addresses. The device must be bound to `vfio-pci`.
+    enabled: bool,
+}
+
+impl VfioConfig {
+    pub(crate) fn enabled(&self) -> bool {
+        self.enabled
+    }
+}
+
+/// The IOMMU domain of a VFIO device
+pub(crate) struct VfioIommu {
+    device: Arc<VfioPciDevice>,
+}
+
+impl IommuMapper for VfioIommu {
+    #[allow(unsafe_code, clippy::as_conversions)]
+    fn map(&self, iova: u64, vaddr: u64, length: u64) -> io::Result<()> {
+        let iommu = self
+            .device
+            .iommu()
+            .ok_or(io::Error::from(io::ErrorKind::Unsupported))?;
+        // SAFETY: the callers keep the range mapped until it is unmapped from the domain
+        unsafe {
+            iommu.map(
+                iova,
+                length as usize,
+                vaddr as *const u8,
+                Permissions::ReadWrite,
+            )
+        }
+    }
+
+    #[allow(clippy::as_conversions)]
+    fn unmap(&self, iova: u64, length: u64) -> io::Result<()> {
+        self.device
+            .iommu()
+            .ok_or(io::Error::from(io::ErrorKind::Unsupported))?
+            .unmap(iova, length as usize)
+    }
+
+    #[allow(clippy::as_conversions)]
+    fn alignment(&self) -> u64 {
+        self.device
+            .iommu()
+            .map_or(page_size(), |iommu| iommu.alignment()) as u64
+    }
+
+    fn valid_iova_ranges(&self) -> Vec<Range<u64>> {
+        self.device
+            .iommu()
+            .map(|iommu| iommu.valid_iova_ranges().to_vec())
+            .unwrap_or_default()
+    }
+}
+
+/// A device bound to `vfio-pci`. Its rings and memory regions are mapped into the
+/// IOMMU domain of the device, which then accesses them by IOVA.
+pub(crate) struct VfioHwDevice {
+    sysfs_path: PathBuf,
+    device: Arc<VfioPciDevice>,
+    space: IovaSpace<VfioIommu>,
+}
+
+impl VfioHwDevice {
+    /// Opens the device at a sysfs path
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the device is not bound to `vfio-pci` or is not behind an
+    /// IOMMU.
+    pub(crate) fn open(sysfs_path: impl AsRef<Path>) -> io::Result<Self> {
+        let sysfs_path = sysfs_path.as_ref().to_path_buf();
+        let device = VfioPciDevice::open(&sysfs_path).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::Other,
+                format!("Failed to open sysfs_path: {err}"),
+            )
+        })?;
+        if device.iommu().is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "VFIO device is not behind an IOMMU",
+            ));
+        }
+        let device = Arc::new(device);
+        let space = IovaSpace::new(VfioIommu {
+            device: Arc::clone(&device),
+        });
+
+        Ok(Self {
+            sysfs_path,
+            device,
+            space,
+        })
+    }
+
+    pub(crate) fn open_default() -> io::Result<Self> {
+        Self::open(find_sysfs_path()?)
+    }
+
+    pub(crate) fn reset(&self) -> io::Result<()> {
+        self.device.reset()
+    }
+
+    pub(crate) fn init_dma_engine(&self) -> io::Result<()> {
+        let bar = self.device.bar(BAR_INDEX_DMA_ENGINE).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "Expected device to have BAR")
+        })?;
+        for addr in DMA_ENGINE_ENABLE_ADDRS {
+            bar.write_le_u32(addr as u64, 1)?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) fn set_custom(&self) -> io::Result<()> {
+        let adaptor = self.new_adaptor()?;
+        adaptor.write_csr(CSR_ADDR_LOOPBACK, 1)?;
+        adaptor.write_csr(CSR_ADDR_DROP_THRESH, 1)?;
+        adaptor.write_csr(CSR_ADDR_SEED, 0x3131_3131)
+    }
+}
+
+impl HwDevice for VfioHwDevice {
+    type Adaptor = VfioPciCsrAdaptor;
+
+    type DmaBufAllocator = IommuDmaBufAllocator<VfioIommu>;
+
+    type UmemHandler = IommuUmemHandler<VfioIommu>;
+
+    fn new_adaptor(&self) -> io::Result<Self::Adaptor> {
+        VfioPciCsrAdaptor::new(&self.device)
+    }
+
+    fn new_dma_buf_allocator(&self) -> io::Result<Self::DmaBufAllocator> {
+        Ok(IommuDmaBufAllocator::new(self.space.clone()))
+    }
+
+    fn new_umem_handler(&self) -> io::Result<Self::UmemHandler> {
+        Ok(IommuUmemHandler::new(self.space.clone()))
+    }
+
+    fn numa_node(&self) -> Option<u32> {
+        pci_numa_node(&self.sysfs_path)
+    }
+
+    fn local_cpus(&self) -> Option<Vec<usize>> {
+        pci_local_cpus(&self.sysfs_path)
+    }
+}
@@ -17,6 +17,9 @@ pub(crate) mod adaptor;
 /// Device mode reader
 pub(crate) mod mode;
 
+/// Named CSR addresses
+pub(crate) mod csr_map;
+
 pub(crate) mod ops_impl;
 
 pub(crate) mod ffi_impl;
@@ -17,6 +17,24 @@ impl Mode {
         }
     }
 
+    /// Decodes the value of the device mode register
+    pub(crate) const fn from_csr_value(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(Mode::Mode100G),
+            1 => Some(Mode::Mode200G),
+            2 => Some(Mode::Mode400G),
+            _ => None,
+        }
+    }
+
+    pub(crate) const fn name(self) -> &'static str {
+        match self {
+            Mode::Mode100G => "100G",
+            Mode::Mode200G => "200G",
+            Mode::Mode400G => "400G",
+        }
+    }
+
     pub(crate) const fn channel_ids(self) -> &'static [usize] {
         match self {
             Mode::Mode100G => &[0],
@@ -36,11 +54,6 @@ impl<Dev: DeviceAdaptor> ModeProxy<Dev> {
             .dev
             .read_csr(CSR_DEVICE_MODE_ADDR)
             .unwrap_or_else(|_| unreachable!("failed to read mode from device"));
-        match mode {
-            0 => Mode::Mode100G,
-            1 => Mode::Mode200G,
-            2 => Mode::Mode400G,
-            _ => unreachable!("invalid mode"),
-        }
+        Mode::from_csr_value(mode).unwrap_or_else(|| unreachable!("invalid mode"))
     }
 }
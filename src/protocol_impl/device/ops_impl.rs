@@ -1,42 +1,65 @@
 use std::{
+    collections::HashMap,
     io, iter,
-    net::Ipv4Addr,
+    net::{IpAddr, Ipv4Addr},
+    os::fd::BorrowedFd,
     sync::{atomic::AtomicBool, Arc},
 };
 
 use crossbeam_deque::Worker;
 use parking_lot::Mutex;
 use qp_attr::{IbvQpAttr, IbvQpInitAttr};
+use tracing::{debug, warn};
 
 use crate::{
     ack_responder::AckResponder,
+    affinity::CpuAffinity,
     completion::{
         Completion, CompletionQueueTable, CompletionTask, CompletionWorker, CqManager, Event,
         PostRecvEvent,
     },
-    config::DeviceConfig,
+    config::{DeviceConfig, NetworkSettings},
+    ctl::{CtlServer, MrInfo, MrTable},
+    dcqcn::ReactionPoint,
     device_protocol::{
-        DeviceCommand, MttUpdate, PgtUpdate, RecvBufferMeta, SimpleNicTunnel, UpdateQp,
+        DeviceCommand, FrameDispatcher, MttUpdate, RecvBufferMeta, SharedFrameTx, SimpleNicTunnel,
+        UpdateQp,
     },
+    gid::{GidEntry, GidTable},
+    limits::{DeviceCaps, DeviceLimits},
     mem::{
-        get_num_page, page::PageAllocator, pin_pages, virt_to_phy::AddressResolver, DmaBuf,
-        DmaBufAllocator, MemoryPinner, PageWithPhysAddr, UmemHandler,
+        dma_pool::{DmaPool, DmaPoolStats},
+        dmabuf::DmaBufMapping,
+        get_num_page,
+        mr_pages::MrPages,
+        page::PageAllocator,
+        pin_pages,
+        unmap::UnmapWatcher,
+        virt_to_phy::AddressResolver,
+        DmaBufAllocator, MemoryPinner, PageWithPhysAddr, UmemHandler, PGT_PAGE_SIZE,
     },
-    mtt::{Mtt, PgtEntry},
-    net::config::NetworkConfig,
-    packet_retransmit::PacketRetransmitWorker,
+    metrics::Metrics,
+    mtt::{CachedMr, MrCache, MrRefs, Mtt, PgtStaging, Release},
+    neigh::{is_arp_frame, Neighbors},
+    net::{
+        config::{MacAddress, NetworkConfig, NetworkMode, SharedNetworkConfig},
+        dhcp::{is_dhcp_reply, DhcpClient},
+    },
+    packet_retransmit::{PacketRetransmitTask, PacketRetransmitWorker},
+    polling::Notifier,
     protocol_impl::{
         queue::{alloc::DescRingBufAllocator, meta_report_queue::init_and_spawn_meta_worker},
         spawn_send_workers, CommandController, SendQueueScheduler, SimpleNicController,
     },
-    qp::{QpManager, QueuePairAttrTable},
+    qp::{QpManager, QueuePairAttr, QueuePairAttrTable},
+    rate_limit::RateLimiter,
     rdma_write_worker::{RdmaWriteTask, RdmaWriteWorker},
     recv::{
         post_recv_channel, PostRecvTx, PostRecvTxTable, RecvWorker, RecvWr, RecvWrQueueTable,
         TcpChannel,
     },
     send::{SendWr, SendWrBase, SendWrRdma},
-    timeout_retransmit::TimeoutRetransmitWorker,
+    timeout_retransmit::{RetransmitTask, TimeoutRetransmitWorker},
 };
 
 use super::{mode::Mode, DeviceAdaptor};
@@ -48,54 +71,105 @@ pub(crate) trait HwDevice {
 
     fn new_adaptor(&self) -> io::Result<Self::Adaptor>;
     fn new_dma_buf_allocator(&self) -> io::Result<Self::DmaBufAllocator>;
-    fn new_umem_handler(&self) -> Self::UmemHandler;
+    fn new_umem_handler(&self) -> io::Result<Self::UmemHandler>;
+    /// NUMA node of the device, `None` if unknown
+    fn numa_node(&self) -> Option<u32>;
+    /// Cpus local to the device, `None` if unknown
+    fn local_cpus(&self) -> Option<Vec<usize>>;
 }
 
 pub(crate) trait DeviceOps {
     fn reg_mr(&mut self, addr: u64, length: usize, pd_handle: u32, access: u8) -> io::Result<u32>;
     fn dereg_mr(&mut self, mr_key: u32) -> io::Result<()>;
+    fn reg_dmabuf_mr(
+        &mut self,
+        fd: BorrowedFd<'_>,
+        offset: u64,
+        length: usize,
+        iova: u64,
+        pd_handle: u32,
+        access: u8,
+    ) -> io::Result<u32>;
     fn create_qp(&mut self, attr: IbvQpInitAttr) -> io::Result<u32>;
     fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()>;
+    fn query_qp(&self, qpn: u32) -> io::Result<QueuePairAttr>;
     fn destroy_qp(&mut self, qpn: u32);
     fn create_cq(&mut self) -> Option<u32>;
     fn destroy_cq(&mut self, handle: u32);
     fn poll_cq(&mut self, handle: u32, max_num_entries: usize) -> Vec<Completion>;
     fn post_send(&mut self, qpn: u32, wr: SendWr) -> io::Result<()>;
     fn post_recv(&mut self, qpn: u32, wr: RecvWr) -> io::Result<()>;
+    fn limits(&self) -> DeviceLimits;
+    fn query_gid(&self, index: u32) -> io::Result<Option<GidEntry>>;
 }
 
 pub(crate) struct HwDeviceCtx<H: HwDevice> {
     device: H,
     mtt: Mtt,
-    mtt_buffer: DmaBuf,
+    pgt_staging: PgtStaging,
     qp_manager: QpManager,
     cq_manager: CqManager,
     cq_table: CompletionQueueTable,
-    cmd_controller: CommandController<H::Adaptor>,
+    cmd_controller: Arc<CommandController<H::Adaptor>>,
     post_recv_tx_table: PostRecvTxTable,
     recv_wr_queue_table: RecvWrQueueTable,
     rdma_write_tx: flume::Sender<RdmaWriteTask>,
     completion_tx: flume::Sender<CompletionTask>,
+    retransmit_tx: flume::Sender<RetransmitTask>,
+    packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
     config: DeviceConfig,
-    allocator: H::DmaBufAllocator,
+    allocator: DmaPool<H::DmaBufAllocator>,
+    umem_handler: H::UmemHandler,
+    metrics: Metrics,
+    mr_table: MrTable,
+    mr_refs: MrRefs,
+    /// Registration cache and the watcher of the unmaps invalidating it, `None` if
+    /// disabled
+    mr_cache: Option<(MrCache, UnmapWatcher)>,
+    /// Mappings of the dma-buf regions, keep the pages of the regions allocated
+    dmabuf_mrs: HashMap<u32, DmaBufMapping>,
+    reaction_point: ReactionPoint,
+    rate_limiter: RateLimiter,
+    limits: DeviceLimits,
+    /// Network configuration in effect, updated on DHCP lease changes
+    network: SharedNetworkConfig,
+    /// Resolves the MAC addresses of the peers
+    neighbors: Neighbors,
+    gids: GidTable,
 }
 
 #[allow(private_bounds)]
 impl<H> HwDeviceCtx<H>
 where
     H: HwDevice,
-    H::Adaptor: DeviceAdaptor + Send + 'static,
+    H::Adaptor: DeviceAdaptor + Send + Sync + 'static,
     H::DmaBufAllocator: DmaBufAllocator,
     H::UmemHandler: UmemHandler,
 {
     pub(crate) fn initialize(device: H, config: DeviceConfig) -> io::Result<Self> {
         let mode = Mode::default();
+        let affinity = CpuAffinity::resolve(config.affinity(), device.local_cpus());
         let adaptor = device.new_adaptor()?;
-        let mut allocator = device.new_dma_buf_allocator()?;
+        // The bitstream has no capability registers, the limits come from the config
+        let limits = DeviceLimits::resolve(DeviceCaps::default(), config.limits());
+        let metrics = Metrics::new(limits.max_qp(), limits.max_cq());
+        metrics.start_exporters(config.metrics())?;
+        let mut allocator = DmaPool::new(device.new_dma_buf_allocator()?);
+        let umem_handler = device.new_umem_handler()?;
         let mut rb_allocator = DescRingBufAllocator::new(&mut allocator);
-        let cmd_controller =
-            CommandController::init_v2(&adaptor, rb_allocator.alloc()?, rb_allocator.alloc()?)?;
-        let send_scheduler = SendQueueScheduler::new();
+        let cmd_controller = Arc::new(CommandController::init_v2(
+            &adaptor,
+            rb_allocator.alloc()?,
+            rb_allocator.alloc()?,
+        )?);
+        let reaction_point = ReactionPoint::new(config.dcqcn(), limits.max_qp());
+        let rate_limiter = RateLimiter::new(limits.max_qp());
+        let send_scheduler = SendQueueScheduler::new(
+            Notifier::new(config.polling())?,
+            reaction_point.clone(),
+            rate_limiter.clone(),
+        );
+        let meta_notifier = Notifier::new(config.polling())?;
         let send_bufs = iter::repeat_with(|| rb_allocator.alloc())
             .take(mode.num_channel())
             .collect::<Result<_, _>>()?;
@@ -111,10 +185,23 @@ where
         let (rdma_write_tx, rdma_write_rx) = flume::unbounded();
         let rx_buffer = rb_allocator.alloc()?;
         let rx_buffer_pa = rx_buffer.phys_addr;
-        let qp_attr_table = QueuePairAttrTable::new();
+        let qp_attr_table = QueuePairAttrTable::new(limits.max_qp());
         let qp_manager = QpManager::new(qp_attr_table.clone_arc());
-        let cq_manager = CqManager::new();
-        let cq_table = CompletionQueueTable::new();
+        let cq_manager = CqManager::new(limits.max_cq());
+        let cq_table = CompletionQueueTable::new(limits.max_cq());
+        let mr_table = MrTable::default();
+        let mr_refs = MrRefs::default();
+        let network = SharedNetworkConfig::new(config.network().initial());
+        if let Some(ref path) = config.ctl().socket {
+            CtlServer::new(
+                network.clone(),
+                mode,
+                qp_attr_table.clone_arc(),
+                mr_table.clone(),
+                metrics.clone(),
+            )
+            .serve(path)?;
+        }
 
         let simple_nic_controller = SimpleNicController::init_v2(
             &adaptor,
@@ -122,8 +209,19 @@ where
             rb_allocator.alloc()?,
             rb_allocator.alloc()?,
             rx_buffer,
+            config.port().mtu(),
+            &metrics,
+        )?;
+        spawn_send_workers(
+            &adaptor,
+            send_bufs,
+            mode,
+            &send_scheduler,
+            config.polling(),
+            &meta_notifier,
+            &affinity,
+            &metrics,
         )?;
-        spawn_send_workers(&adaptor, send_bufs, mode, &send_scheduler.injector())?;
         init_and_spawn_meta_worker(
             &adaptor,
             meta_bufs,
@@ -134,33 +232,115 @@ where
             completion_tx.clone(),
             rdma_write_tx.clone(),
             Arc::clone(&is_shutdown),
+            config.polling(),
+            meta_notifier,
+            affinity.clone(),
+            metrics.clone(),
+            config.dcqcn(),
+            reaction_point.clone(),
+            limits.max_qp(),
         )?;
         CompletionWorker::new(
             completion_rx,
             cq_table.clone_arc(),
             qp_attr_table.clone_arc(),
             ack_tx,
+            metrics.clone(),
         )
-        .spawn();
-        cmd_controller.set_network(config.network())?;
+        .spawn(affinity.clone());
+        cmd_controller.set_network(network.get())?;
         cmd_controller.set_raw_packet_recv_buffer(RecvBufferMeta::new(rx_buffer_pa))?;
 
         let (simple_nic_tx, simple_nic_rx) = simple_nic_controller.into_split();
-        #[allow(clippy::mem_forget)]
-        std::mem::forget(simple_nic_rx); // prevent libc::munmap being called
-        AckResponder::new(qp_attr_table.clone_arc(), ack_rx, Box::new(simple_nic_tx)).spawn();
-        TimeoutRetransmitWorker::new(retransmit_rx, send_scheduler.clone_arc(), config.ack())
-            .spawn();
-        PacketRetransmitWorker::new(packet_retransmit_rx, send_scheduler.clone_arc()).spawn();
+        let simple_nic_tx = SharedFrameTx::new(simple_nic_tx);
+        let mut dispatcher = FrameDispatcher::default();
+        let arp_rx = dispatcher.route(is_arp_frame);
+        let dhcp_rx = dispatcher.route(is_dhcp_reply);
+        // The dispatcher owns the receive half for the lifetime of the process
+        dispatcher.spawn(simple_nic_rx);
+        let gids = GidTable::new(&network.get());
+        if let NetworkSettings::Dynamic { mac, dhcp, .. } = config.network() {
+            let client = DhcpClient::new(mac, dhcp, simple_nic_tx.clone(), dhcp_rx);
+            let mode = NetworkMode::Dynamic {
+                device: Box::new(client.clone()),
+            };
+            let initial = network.get();
+            let leased = initial.with_lease(mode.resolve()?);
+            network.set(leased);
+            gids.set_network(&leased);
+            cmd_controller.set_network(leased)?;
+            client.spawn_renewal({
+                let network = network.clone();
+                let gids = gids.clone();
+                let cmd_controller = Arc::clone(&cmd_controller);
+                move |lease| {
+                    let leased = initial.with_lease(lease);
+                    network.set(leased);
+                    gids.set_network(&leased);
+                    if let Err(err) = cmd_controller.set_network(leased) {
+                        warn!("failed to update network parameters: {err}");
+                    }
+                }
+            });
+        }
+        if let Some(interface) = config.gid().interface() {
+            gids.watch(interface)?;
+        }
+        let neighbors = Neighbors::new(network.clone(), simple_nic_tx.clone());
+        neighbors.spawn(arp_rx, {
+            let neighbors = neighbors.clone();
+            let gids = gids.clone();
+            let qp_attr_table = qp_attr_table.clone_arc();
+            let cmd_controller = Arc::clone(&cmd_controller);
+            move |ip, mac| {
+                reprogram_peer_mac(&neighbors, &gids, &qp_attr_table, &*cmd_controller, ip, mac);
+            }
+        });
+        AckResponder::new(
+            qp_attr_table.clone_arc(),
+            network.clone(),
+            ack_rx,
+            Box::new(simple_nic_tx),
+            metrics.clone(),
+        )
+        .spawn(affinity.clone());
+        TimeoutRetransmitWorker::new(
+            retransmit_rx,
+            send_scheduler.clone_arc(),
+            config.ack(),
+            metrics.clone(),
+            limits.max_qp(),
+        )
+        .spawn(affinity.clone());
+        PacketRetransmitWorker::new(
+            packet_retransmit_rx,
+            send_scheduler.clone_arc(),
+            metrics.clone(),
+            mr_refs.clone(),
+            limits.max_qp(),
+        )
+        .spawn(affinity.clone());
         RdmaWriteWorker::new(
             rdma_write_rx,
             qp_attr_table,
             send_scheduler,
-            retransmit_tx,
-            packet_retransmit_tx,
+            retransmit_tx.clone(),
+            packet_retransmit_tx.clone(),
             completion_tx.clone(),
+            mr_refs.clone(),
+            config.dcqcn().enabled(),
         )
-        .spawn();
+        .spawn(affinity);
+        let mr_cache = config
+            .mr_cache()
+            .enabled()
+            .then(UnmapWatcher::new)
+            .transpose()
+            .unwrap_or_else(|err| {
+                warn!("registration cache disabled, unmaps cannot be watched: {err}");
+                None
+            })
+            .map(|watcher| (MrCache::new(config.mr_cache()), watcher));
 
         Ok(Self {
             device,
@@ -168,19 +348,287 @@ where
             qp_manager,
             cq_manager,
             cq_table,
-            mtt_buffer: rb_allocator.alloc()?,
-            mtt: Mtt::new(),
-            post_recv_tx_table: PostRecvTxTable::new(),
-            recv_wr_queue_table: RecvWrQueueTable::new(),
+            pgt_staging: PgtStaging::new(rb_allocator.alloc()?),
+            mtt: Mtt::new(limits.max_mr(), limits.max_mtt_entries()),
+            post_recv_tx_table: PostRecvTxTable::new(limits.max_qp()),
+            recv_wr_queue_table: RecvWrQueueTable::new(limits.max_qp()),
             rdma_write_tx,
             completion_tx,
+            retransmit_tx,
+            packet_retransmit_tx,
             config,
             allocator,
+            umem_handler,
+            metrics,
+            mr_table,
+            mr_refs,
+            mr_cache,
+            dmabuf_mrs: HashMap::new(),
+            reaction_point,
+            rate_limiter,
+            limits,
+            network,
+            neighbors,
+            gids,
         })
     }
 }
 
+/// Reprograms the QPs whose next hop is `hop` after its MAC address changed to `mac`
+fn reprogram_peer_mac<Cmd: DeviceCommand>(
+    neighbors: &Neighbors,
+    gids: &GidTable,
+    qp_attr_table: &QueuePairAttrTable,
+    cmd_controller: &Cmd,
+    hop: Ipv4Addr,
+    mac: MacAddress,
+) {
+    let local_ip = neighbors.network().ip.ip();
+    let mac_addr = u64::from(mac);
+    for attr in qp_attr_table.active() {
+        if attr.dqp_ip == 0 || attr.mac_addr == mac_addr {
+            continue;
+        }
+        let next_hop = neighbors.next_hop(Ipv4Addr::from_bits(attr.dqp_ip));
+        if next_hop.ok().flatten() != Some(hop) {
+            continue;
+        }
+        let _ignore = qp_attr_table.map_qp_mut(attr.qpn, |current| current.mac_addr = mac_addr);
+        let entry = UpdateQp {
+            qpn: attr.qpn,
+            ip_addr: source_ipv4(gids, attr.sgid_index)
+                .unwrap_or(local_ip)
+                .to_bits(),
+            local_udp_port: 0x100,
+            peer_mac_addr: mac_addr,
+            qp_type: attr.qp_type,
+            peer_qpn: attr.dqpn,
+            rq_access_flags: attr.access_flags,
+            pmtu: attr.pmtu,
+        };
+        if let Err(err) = cmd_controller.update_qp(entry) {
+            warn!("failed to update peer MAC of QP {}: {err}", attr.qpn);
+        }
+    }
+}
+
+/// Records a modify in the QP attributes, the attributes not carried by the modify are
+/// kept
+fn apply_qp_attr(
+    current: &mut QueuePairAttr,
+    attr: &IbvQpAttr,
+    entry: &UpdateQp,
+    dest_ip: Option<Ipv4Addr>,
+) {
+    current.dqpn = entry.peer_qpn;
+    current.access_flags = entry.rq_access_flags;
+    current.pmtu = entry.pmtu;
+    current.dqp_ip = dest_ip.map_or(current.dqp_ip, Ipv4Addr::to_bits);
+    if let Some(ah_attr) = attr.ah_attr() {
+        current.sgid_index = ah_attr.grh.sgid_index;
+        current.traffic_class = ah_attr.grh.traffic_class;
+        current.flow_label = ah_attr.grh.flow_label;
+        current.sl = ah_attr.sl;
+    }
+    current.mac_addr = entry.peer_mac_addr;
+    current.rate_limit = attr.rate_limit().unwrap_or(current.rate_limit);
+    current.timeout = attr.timeout().or(current.timeout);
+    current.retry_cnt = attr.retry_cnt().or(current.retry_cnt);
+}
+
+/// Returns the source address of a QP, the IPv4 address of the GID at `sgid_index`
+fn source_ipv4(gids: &GidTable, sgid_index: u8) -> io::Result<Ipv4Addr> {
+    match gids.source_ip(sgid_index) {
+        Some(IpAddr::V4(ip)) => Ok(ip),
+        // The QP context of the device holds an IPv4 source address
+        Some(IpAddr::V6(_)) => Err(io::ErrorKind::Unsupported.into()),
+        None => Err(io::ErrorKind::InvalidInput.into()),
+    }
+}
+
+/// A failed registration of pinned pages
+struct RegisterFailure {
+    /// Cause of the failure
+    err: io::Error,
+    /// The device may still translate the region, its pages must stay pinned
+    stale: bool,
+}
+
+impl From<io::Error> for RegisterFailure {
+    fn from(err: io::Error) -> Self {
+        Self { err, stale: false }
+    }
+}
+
 impl<H: HwDevice> HwDeviceCtx<H> {
+    /// Registers a pinned memory region, the MR table entry is published only after all
+    /// PGT entries are written. On failure the key and the PGT range are released, unless
+    /// the device could not invalidate the MR entry, then the key is leaked and the
+    /// failure is marked stale.
+    fn register_pinned(
+        &mut self,
+        pages: &MrPages,
+        addr: u64,
+        length: usize,
+        pd_handle: u32,
+        access: u8,
+    ) -> Result<u32, RegisterFailure>
+    where
+        H::Adaptor: DeviceAdaptor,
+    {
+        let length_u32 =
+            u32::try_from(length).map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let (mr_key, pgt_entry) = self.mtt.register(pages.phys_addrs().len())?;
+        if let Err(err) =
+            self.pgt_staging
+                .write(&self.cmd_controller, pgt_entry.index, pages.phys_addrs())
+        {
+            let _ignore = self.mtt.deregister(mr_key);
+            return Err(err.into());
+        }
+        let mtt_update =
+            MttUpdate::new(addr, length_u32, mr_key, pd_handle, access, pgt_entry.index);
+        if let Err(err) = self.cmd_controller.update_mtt(mtt_update) {
+            // The device may have applied the entry, reusing the key or the pages before
+            // it is invalidated would let it translate to freed memory
+            if let Err(invalidate_err) = self.cmd_controller.invalidate_mtt(mr_key) {
+                warn!(
+                    "failed to invalidate MR {mr_key}, leaking its key and pages: {invalidate_err}"
+                );
+                return Err(RegisterFailure { err, stale: true });
+            }
+            let _ignore = self.mtt.deregister(mr_key);
+            return Err(err.into());
+        }
+        self.mr_table.insert(
+            mr_key,
+            MrInfo {
+                addr,
+                length,
+                pd_handle,
+                access,
+                pgt_index: pgt_entry.index,
+                pgt_count: pgt_entry.count,
+            },
+        );
+
+        Ok(mr_key)
+    }
+
+    /// Deregisters a memory region, refuses while outstanding WRs reference it
+    fn deregister(&mut self, mr_key: u32) -> io::Result<()>
+    where
+        H::Adaptor: DeviceAdaptor,
+        H::UmemHandler: UmemHandler,
+    {
+        let info = self.unregister(mr_key)?;
+        // The device addresses a dma-buf region by IOVA, it was pinned at its mapping,
+        // which releases its pages when dropped
+        if let Some(mapping) = self.dmabuf_mrs.remove(&mr_key) {
+            return self.umem_handler.unpin_pages(mapping.addr(), info.length);
+        }
+        self.umem_handler.unpin_pages(info.addr, info.length)
+    }
+
+    /// Invalidates a memory region in the device and releases its key, its pages are
+    /// left pinned
+    fn unregister(&mut self, mr_key: u32) -> io::Result<MrInfo>
+    where
+        H::Adaptor: DeviceAdaptor,
+    {
+        if self.mr_refs.is_referenced(mr_key) {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        let info = self
+            .mr_table
+            .get(mr_key)
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?;
+        self.cmd_controller.invalidate_mtt(mr_key)?;
+        self.mtt.deregister(mr_key)?;
+        self.mr_table.remove(mr_key);
+        Ok(info)
+    }
+
+    /// Returns the key of a cached region covering the range
+    fn lookup_cached_mr(
+        &mut self,
+        addr: u64,
+        length: usize,
+        pd_handle: u32,
+        access: u8,
+    ) -> Option<u32>
+    where
+        H::Adaptor: DeviceAdaptor,
+        H::UmemHandler: UmemHandler,
+    {
+        self.invalidate_unmapped();
+        let (cache, _watcher) = self.mr_cache.as_mut()?;
+        let mr_key = cache.lookup(addr, length, pd_handle, access)?;
+        cache.acquire(mr_key);
+        Some(mr_key)
+    }
+
+    /// Deregisters the cached regions overlapping the ranges unmapped since the last call,
+    /// the referenced ones are deregistered on their last release
+    fn invalidate_unmapped(&mut self)
+    where
+        H::Adaptor: DeviceAdaptor,
+        H::UmemHandler: UmemHandler,
+    {
+        let Some((cache, watcher)) = self.mr_cache.as_mut() else {
+            return;
+        };
+        let stale: Vec<u32> = watcher
+            .unmapped()
+            .flat_map(|range| cache.invalidate(range))
+            .collect();
+        for mr_key in stale {
+            match self.unregister(mr_key) {
+                // The kernel drops the locks of unmapped pages, unlocking them may fail
+                Ok(info) => {
+                    let _ignore = self.umem_handler.unpin_pages(info.addr, info.length);
+                }
+                Err(err) => warn!("failed to deregister stale MR {mr_key}: {err}"),
+            }
+        }
+    }
+
+    /// Watches the unmaps of a range before it is registered, so an unmap racing the
+    /// registration is reported. Returns whether the registration may be cached.
+    fn watch_unmaps(&self, addr: u64, length: usize) -> bool {
+        let Some((_cache, watcher)) = self.mr_cache.as_ref() else {
+            return false;
+        };
+        match watcher.watch(addr, length) {
+            Ok(()) => true,
+            Err(err) => {
+                debug!(
+                    "registration of {addr:#x} is not cached, its unmaps cannot be watched: {err}"
+                );
+                false
+            }
+        }
+    }
+
+    /// Tracks a new registration in the cache, deregisters the regions evicted to fit
+    /// the cache limits
+    fn cache_mr(&mut self, mr: CachedMr)
+    where
+        H::Adaptor: DeviceAdaptor,
+        H::UmemHandler: UmemHandler,
+    {
+        let Some((cache, _watcher)) = self.mr_cache.as_mut() else {
+            return;
+        };
+        cache.insert(mr);
+        let mr_refs = &self.mr_refs;
+        for mr_key in cache.evict(|mr_key| mr_refs.is_referenced(mr_key)) {
+            if let Err(err) = self.deregister(mr_key) {
+                warn!("failed to deregister evicted MR {mr_key}: {err}");
+            }
+        }
+    }
+
     fn send(&self, qpn: u32, mut wr: SendWrBase) -> io::Result<()> {
         match self.recv_wr_queue_table.pop(qpn) {
             Some(x) => {
@@ -211,70 +659,111 @@ impl<H: HwDevice> HwDeviceCtx<H> {
     }
 
     fn network_config(&self) -> NetworkConfig {
-        self.config.network()
+        self.network.get()
+    }
+
+    /// Returns the source address of a QP, the configured address if the GID at
+    /// `sgid_index` is not an IPv4 address
+    fn local_ip(&self, sgid_index: u8) -> Ipv4Addr {
+        source_ipv4(&self.gids, sgid_index).unwrap_or_else(|_err| self.network_config().ip.ip())
+    }
+
+    pub(crate) fn metrics(&self) -> Metrics {
+        self.metrics.clone()
+    }
+
+    /// Usage of the driver's DMA memory
+    pub(crate) fn dma_stats(&self) -> DmaPoolStats
+    where
+        H::DmaBufAllocator: DmaBufAllocator,
+    {
+        self.allocator.stats()
     }
 }
 
 impl<H> DeviceOps for HwDeviceCtx<H>
 where
     H: HwDevice,
-    H::Adaptor: DeviceAdaptor + Send + 'static,
+    H::Adaptor: DeviceAdaptor + Send + Sync + 'static,
     H::UmemHandler: UmemHandler,
 {
     fn reg_mr(&mut self, addr: u64, length: usize, pd_handle: u32, access: u8) -> io::Result<u32> {
-        fn chunks(entry: PgtEntry) -> Vec<PgtEntry> {
-            /// Maximum number of Page Table entries (PGT entries) that can be allocated in a single `PCIe` transaction.
-            /// A `PCIe` transaction size is 128 bytes, and each PGT entry is a u64 (8 bytes).
-            /// Therefore, 512 bytes / 8 bytes per entry = 16 entries per allocation.
-            const MAX_NUM_PGT_ENTRY_PER_ALLOC: usize = 64;
-
-            let base_index = entry.index;
-            let end_index = base_index + entry.count;
-            (base_index..end_index)
-                .step_by(MAX_NUM_PGT_ENTRY_PER_ALLOC)
-                .map(|index| PgtEntry {
-                    index,
-                    count: (MAX_NUM_PGT_ENTRY_PER_ALLOC as u32).min(end_index - index),
-                })
-                .collect()
-        }
-
-        let umem_handler = self.device.new_umem_handler();
-        umem_handler.pin_pages(addr, length)?;
-        let num_pages = get_num_page(addr, length);
-        let (mr_key, pgt_entry) = self.mtt.register(num_pages)?;
-        let length_u32 =
-            u32::try_from(length).map_err(|_err| io::Error::from(io::ErrorKind::InvalidInput))?;
-        let mut phys_addrs = umem_handler
-            .virt_to_phys_range(addr, num_pages)?
-            .into_iter()
-            .collect::<Option<Vec<_>>>()
-            .ok_or(io::Error::new(
-                io::ErrorKind::NotFound,
-                "physical address not found",
-            ))?
-            .into_iter();
-        let buf = &mut self.mtt_buffer.buf;
-        let base_index = pgt_entry.index;
-        let mtt_update = MttUpdate::new(addr, length_u32, mr_key, pd_handle, access, base_index);
-        // TODO: makes updates atomic
-        self.cmd_controller.update_mtt(mtt_update)?;
-        for PgtEntry { index, count } in chunks(pgt_entry) {
-            let bytes: Vec<u8> = phys_addrs
-                .by_ref()
-                .take(count as usize)
-                .flat_map(u64::to_ne_bytes)
-                .collect();
-            buf.copy_from(0, &bytes);
-            let pgt_update = PgtUpdate::new(self.mtt_buffer.phys_addr, index, count - 1);
-            self.cmd_controller.update_pgt(pgt_update)?;
+        if let Some(mr_key) = self.lookup_cached_mr(addr, length, pd_handle, access) {
+            return Ok(mr_key);
+        }
+        let cacheable = self.watch_unmaps(addr, length);
+        self.umem_handler.pin_pages(addr, length)?;
+        let result = MrPages::resolve(&self.umem_handler, addr, length)
+            .map_err(RegisterFailure::from)
+            .and_then(|pages| self.register_pinned(&pages, addr, length, pd_handle, access));
+        let mr_key = match result {
+            Ok(mr_key) => mr_key,
+            Err(failure) => {
+                if !failure.stale {
+                    let _ignore = self.umem_handler.unpin_pages(addr, length);
+                }
+                return Err(failure.err);
+            }
+        };
+        if cacheable {
+            self.cache_mr(CachedMr {
+                mr_key,
+                addr,
+                length,
+                pd_handle,
+                access,
+            });
         }
 
         Ok(mr_key)
     }
 
     fn dereg_mr(&mut self, mr_key: u32) -> io::Result<()> {
-        self.mtt.deregister(mr_key)
+        self.invalidate_unmapped();
+        let release = self
+            .mr_cache
+            .as_mut()
+            .map_or(Release::Uncached, |(cache, _watcher)| cache.release(mr_key));
+        match release {
+            Release::Retained => Ok(()),
+            Release::Uncached | Release::Deregister => self.deregister(mr_key),
+        }
+    }
+
+    fn reg_dmabuf_mr(
+        &mut self,
+        fd: BorrowedFd<'_>,
+        offset: u64,
+        length: usize,
+        iova: u64,
+        pd_handle: u32,
+        access: u8,
+    ) -> io::Result<u32> {
+        let mapping = DmaBufMapping::map(fd, offset, length)?;
+        let addr = mapping.addr();
+        // The device translates `iova`, its offset in a PGT page must match the mapping's
+        if (iova ^ addr) % PGT_PAGE_SIZE != 0 {
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+        self.umem_handler.pin_pages(addr, length)?;
+        let result = MrPages::resolve(&self.umem_handler, addr, length)
+            .map_err(RegisterFailure::from)
+            .and_then(|pages| self.register_pinned(&pages, iova, length, pd_handle, access));
+        let mr_key = match result {
+            Ok(mr_key) => mr_key,
+            Err(failure) => {
+                if failure.stale {
+                    // Unmapping would release the pages the device may still translate
+                    std::mem::forget(mapping);
+                } else {
+                    let _ignore = self.umem_handler.unpin_pages(addr, length);
+                }
+                return Err(failure.err);
+            }
+        };
+        let _prev = self.dmabuf_mrs.insert(mr_key, mapping);
+
+        Ok(mr_key)
     }
 
     fn create_qp(&mut self, attr: IbvQpInitAttr) -> io::Result<u32> {
@@ -291,7 +780,7 @@ where
             current.pmtu = ibverbs_sys::IBV_MTU_4096 as u8;
         });
         let entry = UpdateQp {
-            ip_addr: self.network_config().ip.ip().to_bits(),
+            ip_addr: self.local_ip(0).to_bits(),
             peer_mac_addr: self.network_config().mac.into(),
             local_udp_port: 0x100,
             qp_type: attr.qp_type(),
@@ -304,14 +793,30 @@ where
     }
 
     fn update_qp(&mut self, qpn: u32, attr: IbvQpAttr) -> io::Result<()> {
+        let dest_ip = match attr.dest_qp_ip() {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            // The QP context and send descriptors of the device hold IPv4 addresses
+            Some(IpAddr::V6(_)) => return Err(io::ErrorKind::Unsupported.into()),
+            None => None,
+        };
+        let source_ip = attr
+            .ah_attr()
+            .map(|ah_attr| source_ipv4(&self.gids, ah_attr.grh.sgid_index))
+            .transpose()?;
+        let peer_mac = dest_ip
+            .map(|ip| self.neighbors.resolve(ip))
+            .transpose()?
+            .map(u64::from);
         let entry = self
             .qp_manager
             .update_qp(qpn, |current| {
                 let entry = UpdateQp {
                     qpn,
-                    ip_addr: self.network_config().ip.ip().to_bits(),
+                    ip_addr: source_ip
+                        .unwrap_or_else(|| self.local_ip(current.sgid_index))
+                        .to_bits(),
                     local_udp_port: 0x100,
-                    peer_mac_addr: self.network_config().mac.into(),
+                    peer_mac_addr: peer_mac.unwrap_or(current.mac_addr),
                     qp_type: current.qp_type,
                     peer_qpn: attr.dest_qp_num().unwrap_or(current.dqpn),
                     rq_access_flags: attr
@@ -319,24 +824,31 @@ where
                         .map_or(current.access_flags, |x| x as u8),
                     pmtu: attr.path_mtu().map_or(current.pmtu, |x| x as u8),
                 };
-                current.dqpn = entry.peer_qpn;
-                current.access_flags = entry.rq_access_flags;
-                current.pmtu = entry.pmtu;
-                current.dqp_ip = attr.dest_qp_ip().map_or(0, Ipv4Addr::to_bits);
+                apply_qp_attr(current, &attr, &entry, dest_ip);
                 entry
             })
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
 
         self.cmd_controller.update_qp(entry);
+        if let Some(rate_limit) = attr.rate_limit() {
+            self.rate_limiter.set(qpn, rate_limit);
+        }
 
         let qp = self
             .qp_manager
             .get_qp(qpn)
             .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        if attr.timeout().is_some() || attr.retry_cnt().is_some() {
+            let _ignore = self.retransmit_tx.send(RetransmitTask::Configure {
+                qpn,
+                timeout: qp.timeout,
+                retry_cnt: qp.retry_cnt,
+            });
+        }
         if qp.dqpn != 0 && qp.dqp_ip != 0 && self.post_recv_tx_table.get_qp_mut(qpn).is_none() {
             let dqp_ip = Ipv4Addr::from_bits(qp.dqp_ip);
             let (tx, rx) = post_recv_channel::<TcpChannel>(
-                self.network_config().ip.ip(),
+                self.local_ip(qp.sgid_index),
                 dqp_ip,
                 qpn,
                 qp.dqpn,
@@ -354,6 +866,32 @@ where
 
     fn destroy_qp(&mut self, qpn: u32) {
         self.qp_manager.destroy_qp(qpn);
+        self.reaction_point.reset(qpn);
+        self.rate_limiter.set(qpn, 0);
+        let _ignore = self.retransmit_tx.send(RetransmitTask::Configure {
+            qpn,
+            timeout: None,
+            retry_cnt: None,
+        });
+        // The outstanding WRs are never acknowledged, release their memory regions before
+        // returning so that they can be deregistered
+        let (task, done_rx) = PacketRetransmitTask::new_destroy(qpn);
+        let _ignore = self.packet_retransmit_tx.send(task);
+        let _ignore = done_rx.recv();
+    }
+
+    fn query_qp(&self, qpn: u32) -> io::Result<QueuePairAttr> {
+        let mut attr = self
+            .qp_manager
+            .get_qp(qpn)
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?;
+        let ack = self.config.ack();
+        attr.timeout = Some(attr.timeout.unwrap_or(ack.local_ack_timeout_exp()));
+        attr.retry_cnt = Some(
+            attr.retry_cnt
+                .unwrap_or(u8::try_from(ack.init_retry_count()).unwrap_or(u8::MAX)),
+        );
+        Ok(attr)
     }
 
     fn create_cq(&mut self) -> Option<u32> {
@@ -398,11 +936,19 @@ where
 
         Ok(())
     }
+
+    fn limits(&self) -> DeviceLimits {
+        self.limits
+    }
+
+    fn query_gid(&self, index: u32) -> io::Result<Option<GidEntry>> {
+        self.gids.get(index)
+    }
 }
 
 #[allow(unsafe_code, clippy::wildcard_imports)]
 pub(crate) mod qp_attr {
-    use std::net::Ipv4Addr;
+    use std::net::{IpAddr, Ipv6Addr};
 
     use ibverbs_sys::*;
 
@@ -446,18 +992,18 @@ pub(crate) mod qp_attr {
             Self { inner, attr_mask }
         }
 
-        pub(crate) fn dest_qp_ip(&self) -> Option<Ipv4Addr> {
+        pub(crate) fn dest_qp_ip(&self) -> Option<IpAddr> {
             if self.attr_mask & ibv_qp_attr_mask::IBV_QP_AV.0 == 0 {
                 return None;
             }
 
-            let gid = unsafe { self.inner.ah_attr.grh.dgid.raw };
-
-            // Format: ::ffff:a.b.c.d
-            let is_ipv4_mapped =
-                gid[..10].iter().all(|&x| x == 0) && gid[10] == 0xFF && gid[11] == 0xFF;
+            let gid = Ipv6Addr::from(unsafe { self.inner.ah_attr.grh.dgid.raw });
+            if gid.is_unspecified() {
+                return None;
+            }
 
-            is_ipv4_mapped.then(|| Ipv4Addr::new(gid[12], gid[13], gid[14], gid[15]))
+            // IPv4 addresses are in the format ::ffff:a.b.c.d
+            Some(gid.to_ipv4_mapped().map_or(IpAddr::V6(gid), IpAddr::V4))
         }
 
         impl_getter!(qp_state, ibv_qp_state::Type, ibv_qp_attr_mask::IBV_QP_STATE);
@@ -507,3 +1053,140 @@ pub(crate) mod qp_attr {
         impl_getter!(rate_limit, u32, ibv_qp_attr_mask::IBV_QP_RATE_LIMIT);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use ibverbs_sys::{ibv_qp_attr, ibv_qp_attr_mask};
+
+    use crate::{
+        constants::QPN_KEY_PART_WIDTH,
+        device_protocol::{FrameTx, PgtUpdate},
+    };
+
+    use super::*;
+
+    /// Records the QP updates sent to the device
+    #[derive(Default)]
+    struct RecordingCmd(Mutex<Vec<UpdateQp>>);
+
+    impl DeviceCommand for RecordingCmd {
+        fn update_mtt(&self, _update: MttUpdate) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn invalidate_mtt(&self, _mr_key: u32) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn update_pgt(&self, _update: PgtUpdate) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn update_pgt_batch(&self, _updates: &[PgtUpdate]) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn update_qp(&self, entry: UpdateQp) -> io::Result<()> {
+            self.0.lock().push(entry);
+            Ok(())
+        }
+
+        fn set_network(&self, _param: NetworkConfig) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_raw_packet_recv_buffer(&self, _buffer: RecvBufferMeta) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct NullTx;
+
+    impl FrameTx for NullTx {
+        fn send(&mut self, _buf: &[u8]) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Applies a modify to the QP the way `update_qp` does
+    fn modify(table: &QueuePairAttrTable, qpn: u32, attr: &IbvQpAttr, mac_addr: u64) {
+        let dest_ip = match attr.dest_qp_ip() {
+            Some(IpAddr::V4(ip)) => Some(ip),
+            Some(IpAddr::V6(_)) | None => None,
+        };
+        let _ignore = table.map_qp_mut(qpn, |current| {
+            let entry = UpdateQp {
+                qpn,
+                peer_mac_addr: mac_addr,
+                peer_qpn: attr.dest_qp_num().unwrap_or(current.dqpn),
+                ..Default::default()
+            };
+            apply_qp_attr(current, attr, &entry, dest_ip);
+        });
+    }
+
+    #[test]
+    fn peer_mac_change_after_rts_modify() {
+        let mut network = NetworkConfig::unconfigured(MacAddress([2, 0, 0, 0, 0, 1]));
+        network.ip = "10.0.0.1/24".parse().unwrap();
+        let neighbors = Neighbors::new(
+            SharedNetworkConfig::new(network),
+            SharedFrameTx::new(NullTx),
+        );
+        let gids = GidTable::new(&network);
+        let table = QueuePairAttrTable::new(4);
+        let qpn = 1 << QPN_KEY_PART_WIDTH;
+        let _ignore = table.map_qp_mut(qpn, |current| current.qpn = qpn);
+        let peer = Ipv4Addr::new(10, 0, 0, 2);
+
+        let mut rtr = ibv_qp_attr {
+            dest_qp_num: 5,
+            ..Default::default()
+        };
+        rtr.ah_attr.grh.dgid.raw = peer.to_ipv6_mapped().octets();
+        let rtr_mask = ibv_qp_attr_mask::IBV_QP_AV.0 | ibv_qp_attr_mask::IBV_QP_DEST_QPN.0;
+        modify(&table, qpn, &IbvQpAttr::new(rtr, rtr_mask), 0xaa);
+        let rts = ibv_qp_attr {
+            timeout: 14,
+            ..Default::default()
+        };
+        let rts_mask = ibv_qp_attr_mask::IBV_QP_STATE.0 | ibv_qp_attr_mask::IBV_QP_TIMEOUT.0;
+        modify(&table, qpn, &IbvQpAttr::new(rts, rts_mask), 0xaa);
+        assert_eq!(table.get(qpn).unwrap().dqp_ip, peer.to_bits());
+
+        let cmd = RecordingCmd::default();
+        let mac = MacAddress([2, 0, 0, 0, 0, 2]);
+        reprogram_peer_mac(&neighbors, &gids, &table, &cmd, peer, mac);
+        let updates = cmd.0.lock();
+        assert_eq!(updates.len(), 1, "the QP follows the new peer MAC");
+        let entry = updates.first().unwrap();
+        assert_eq!(entry.peer_mac_addr, u64::from(mac));
+        assert_eq!(entry.peer_qpn, 5);
+    }
+
+    #[test]
+    fn av_marking_is_kept_across_modifies() {
+        let table = QueuePairAttrTable::new(4);
+        let qpn = 1 << QPN_KEY_PART_WIDTH;
+        let mut rtr = ibv_qp_attr::default();
+        rtr.ah_attr.sl = 5;
+        rtr.ah_attr.grh.traffic_class = 26 << 2;
+        rtr.ah_attr.grh.flow_label = 0x1234;
+        modify(
+            &table,
+            qpn,
+            &IbvQpAttr::new(rtr, ibv_qp_attr_mask::IBV_QP_AV.0),
+            0xaa,
+        );
+        let rts = ibv_qp_attr {
+            timeout: 14,
+            ..Default::default()
+        };
+        let rts_mask = ibv_qp_attr_mask::IBV_QP_STATE.0 | ibv_qp_attr_mask::IBV_QP_TIMEOUT.0;
+        modify(&table, qpn, &IbvQpAttr::new(rts, rts_mask), 0xaa);
+        let attr = table.get(qpn).unwrap();
+        assert_eq!(attr.sl, 5);
+        assert_eq!(attr.traffic_class, 26 << 2);
+        assert_eq!(attr.flow_label, 0x1234);
+    }
+}
@@ -78,6 +78,7 @@ impl<Dev: DeviceAdaptor> MetaReport for MetaReportQueueHandler<Dev> {
                         rkey: d.rkey(),
                         imm: d.imm_data(),
                         header_type: d.header_type(),
+                        ecn_marked: d.ecn_marked(),
                     })
                 }
                 MetaReportQueueDesc::ReadPacketInfo((f, n)) => {
@@ -91,6 +92,7 @@ impl<Dev: DeviceAdaptor> MetaReport for MetaReportQueueHandler<Dev> {
                         ack_req: f.ack_req(),
                         msn: f.msn(),
                         psn: f.psn().into(),
+                        ecn_marked: f.ecn_marked(),
                     })
                 }
                 MetaReportQueueDesc::CnpPacketInfo(d) => ReportMeta::Cnp(CnpMeta { qpn: d.dqpn() }),
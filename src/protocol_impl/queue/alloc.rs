@@ -58,4 +58,9 @@ impl<A: DmaBufAllocator> DescRingBufAllocator<A> {
     pub(crate) fn alloc(&mut self) -> io::Result<DmaBuf> {
         self.dma_buf_allocator.alloc(RING_BUF_LEN * DESC_SIZE)
     }
+
+    /// Returns the buffer of a destroyed ring for reuse
+    pub(crate) fn free(&mut self, buf: DmaBuf) -> io::Result<()> {
+        self.dma_buf_allocator.free(buf)
+    }
 }
@@ -1,5 +1,7 @@
 use std::io;
 
+use tracing::trace;
+
 use crate::{
     device_protocol::DeviceCommand,
     net::config::NetworkConfig,
@@ -10,6 +12,7 @@ use crate::{
 };
 
 use super::super::desc::{
+    decode::{decode, RingKind},
     CmdQueueReqDescQpManagement, CmdQueueReqDescSetNetworkParam,
     CmdQueueReqDescSetRawPacketReceiveMeta, CmdQueueReqDescUpdateMrTable, CmdQueueReqDescUpdatePGT,
     CmdQueueRespDescOnlyCommonHeader, RingBufDescUntyped,
@@ -45,13 +48,15 @@ impl CmdQueue {
 
     /// Produces command descriptors to the queue
     pub(crate) fn push(&mut self, desc: CmdQueueDesc) -> bool {
-        match desc {
-            CmdQueueDesc::UpdateMrTable(d) => self.inner.push(d.into()),
-            CmdQueueDesc::UpdatePGT(d) => self.inner.push(d.into()),
-            CmdQueueDesc::ManageQP(d) => self.inner.push(d.into()),
-            CmdQueueDesc::SetNetworkParam(d) => self.inner.push(d.into()),
-            CmdQueueDesc::SetRawPacketReceiveMeta(d) => self.inner.push(d.into()),
-        }
+        let desc: RingBufDescUntyped = match desc {
+            CmdQueueDesc::UpdateMrTable(d) => d.into(),
+            CmdQueueDesc::UpdatePGT(d) => d.into(),
+            CmdQueueDesc::ManageQP(d) => d.into(),
+            CmdQueueDesc::SetNetworkParam(d) => d.into(),
+            CmdQueueDesc::SetRawPacketReceiveMeta(d) => d.into(),
+        };
+        trace!("cmd_req push: {}", decode(RingKind::CmdReq, desc, false));
+        self.inner.push(desc)
     }
 
     /// Returns the head pointer
@@ -90,7 +95,9 @@ impl CmdRespQueue {
 
     /// Tries to poll next valid entry from the queue
     pub(crate) fn try_pop(&mut self) -> Option<CmdRespQueueDesc> {
-        self.inner.pop().map(Into::into).map(CmdRespQueueDesc)
+        let desc = self.inner.pop()?;
+        trace!("cmd_resp pop: {}", decode(RingKind::CmdResp, desc, false));
+        Some(CmdRespQueueDesc(desc.into()))
     }
 
     /// Return tail pointer
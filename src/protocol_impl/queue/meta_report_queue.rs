@@ -3,17 +3,24 @@ use std::{
     sync::{atomic::AtomicBool, Arc},
 };
 
+use tracing::trace;
+
 use crate::{
     ack_responder::AckResponse,
+    affinity::CpuAffinity,
     completion::CompletionTask,
+    dcqcn::{DcqcnConfig, NotificationPoint, ReactionPoint},
     mem::{
         virt_to_phy::{AddressResolver, PhysAddrResolverLinuxX86},
         DmaBuf, PageWithPhysAddr,
     },
     meta_worker::{MetaHandler, MetaWorker},
+    metrics::Metrics,
     packet_retransmit::PacketRetransmitTask,
+    polling::{Notifier, PollingConfig},
     protocol_impl::{
         desc::{
+            decode::{decode, RingKind},
             MetaReportQueueAckDesc, MetaReportQueueAckExtraDesc, MetaReportQueueDescFirst,
             MetaReportQueueDescNext, MetaReportQueuePacketBasicInfoDesc,
             MetaReportQueueReadReqExtendInfoDesc, RingBufDescUntyped,
@@ -41,7 +48,7 @@ pub(crate) enum MetaReportQueueDesc {
             MetaReportQueueReadReqExtendInfoDesc,
         ),
     ),
-    /// Packet info for congestion event
+    /// Packet info of a received Congestion Notification Packet
     CnpPacketInfo(MetaReportQueuePacketBasicInfoDesc),
     /// Ack
     Ack(MetaReportQueueAckDesc),
@@ -62,11 +69,16 @@ impl MetaReportQueue {
 
     /// Tries to poll next valid entry from the queue
     pub(crate) fn try_pop(&mut self) -> Option<MetaReportQueueDesc> {
-        let first = self.inner.pop().map(MetaReportQueueDescFirst::from)?;
+        let first_raw = self.inner.pop()?;
+        trace!(
+            "mrq pop: {}",
+            decode(RingKind::MetaReport, first_raw, false)
+        );
+        let first = MetaReportQueueDescFirst::from(first_raw);
 
         if !first.has_next() {
             return match first {
-                MetaReportQueueDescFirst::PacketInfo(d) if d.ecn_marked() => {
+                MetaReportQueueDescFirst::PacketInfo(d) if d.is_cnp() => {
                     Some(MetaReportQueueDesc::CnpPacketInfo(d))
                 }
                 MetaReportQueueDescFirst::PacketInfo(d) => {
@@ -76,10 +88,12 @@ impl MetaReportQueue {
             };
         }
 
-        let next = self.inner.pop().map_or_else(
-            || unreachable!("failed to read next descriptor"),
-            MetaReportQueueDescNext::from,
-        );
+        let next_raw = self
+            .inner
+            .pop()
+            .unwrap_or_else(|| unreachable!("failed to read next descriptor"));
+        trace!("mrq pop: {}", decode(RingKind::MetaReport, next_raw, true));
+        let next = MetaReportQueueDescNext::from(next_raw);
         match (first, next) {
             (MetaReportQueueDescFirst::PacketInfo(f), MetaReportQueueDescNext::ReadInfo(n)) => {
                 Some(MetaReportQueueDesc::ReadPacketInfo((f, n)))
@@ -118,6 +132,13 @@ pub(crate) fn init_and_spawn_meta_worker<Dev>(
     completion_tx: flume::Sender<CompletionTask>,
     rdma_write_tx: flume::Sender<RdmaWriteTask>,
     is_shutdown: Arc<AtomicBool>,
+    polling: PollingConfig,
+    notifier: Notifier,
+    affinity: CpuAffinity,
+    metrics: Metrics,
+    dcqcn: DcqcnConfig,
+    reaction_point: ReactionPoint,
+    max_qp: usize,
 ) -> io::Result<()>
 where
     Dev: Clone + DeviceAdaptor + Send + 'static,
@@ -139,8 +160,18 @@ where
         packet_retransmit_tx,
         completion_tx,
         rdma_write_tx,
+        metrics,
+        reaction_point,
+        NotificationPoint::new(dcqcn, max_qp),
+        max_qp,
     );
-    MetaWorker::new(MetaReportQueueHandler::new(ctxs), handler).spawn(is_shutdown);
+    MetaWorker::new(
+        MetaReportQueueHandler::new(ctxs),
+        handler,
+        polling,
+        notifier,
+    )
+    .spawn(is_shutdown, affinity);
 
     Ok(())
 }
@@ -1,8 +1,13 @@
 use std::io;
 
+use tracing::trace;
+
 use crate::{
     mem::virt_to_phy::{AddressResolver, PhysAddrResolverLinuxX86},
-    protocol_impl::desc::{RingBufDescUntyped, SendQueueReqDescSeg0, SendQueueReqDescSeg1},
+    protocol_impl::desc::{
+        decode::{decode, RingKind},
+        RingBufDescUntyped, SendQueueReqDescSeg0, SendQueueReqDescSeg1,
+    },
 };
 
 use super::DescRingBuffer;
@@ -37,7 +42,10 @@ impl SendQueue {
     }
 
     pub(crate) fn push(&mut self, desc: SendQueueDesc) -> bool {
-        self.inner.push(desc.into())
+        let is_next = matches!(desc, SendQueueDesc::Seg1(_));
+        let desc = desc.into();
+        trace!("sq push: {}", decode(RingKind::SendQueue, desc, is_next));
+        self.inner.push(desc)
     }
 
     /// Returns the head pointer of the buffer
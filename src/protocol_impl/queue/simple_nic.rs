@@ -3,7 +3,12 @@ use std::{
     ops::{Deref, DerefMut},
 };
 
-use super::super::desc::simple_nic::{SimpleNicRxQueueDesc, SimpleNicTxQueueDesc};
+use tracing::trace;
+
+use super::super::desc::{
+    decode::{decode, RingKind},
+    simple_nic::{SimpleNicRxQueueDesc, SimpleNicTxQueueDesc},
+};
 
 use super::DescRingBuffer;
 
@@ -19,7 +24,12 @@ impl SimpleNicTxQueue {
     }
 
     pub(crate) fn push(&mut self, desc: SimpleNicTxQueueDesc) -> bool {
-        self.inner.push(desc.into())
+        let desc = desc.into();
+        trace!(
+            "simple_nic_tx push: {}",
+            decode(RingKind::SimpleNicTx, desc, false)
+        );
+        self.inner.push(desc)
     }
 
     pub(crate) fn head(&self) -> u32 {
@@ -47,6 +57,11 @@ impl SimpleNicRxQueue {
     }
 
     pub(crate) fn pop(&mut self) -> Option<SimpleNicRxQueueDesc> {
-        self.inner.pop().map(Into::into)
+        let desc = self.inner.pop()?;
+        trace!(
+            "simple_nic_rx pop: {}",
+            decode(RingKind::SimpleNicRx, desc, false)
+        );
+        Some(desc.into())
     }
 }
@@ -1,12 +1,23 @@
-use std::{io, iter, sync::Arc, time::Duration};
+use std::{
+    collections::BTreeMap,
+    io, iter,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crossbeam_deque::{Injector, Steal, Stealer, Worker};
 use tracing::error;
 
 use crate::{
+    affinity::{CpuAffinity, WorkerClass},
+    dcqcn::ReactionPoint,
     device_protocol::{WorkReqSend, WrChunk},
     mem::{DmaBuf, PageWithPhysAddr},
+    metrics::Metrics,
+    polling::{Notifier, Poller, PollingConfig},
     protocol_impl::device::CsrWriterAdaptor,
+    qp::convert_ibv_mtu_to_u16,
+    rate_limit::RateLimiter,
 };
 
 use super::{
@@ -33,18 +44,34 @@ type WrWorker = Worker<WrChunk>;
 pub(crate) struct SendQueueScheduler {
     /// Work request injector for distributing work to worker threads
     injector: Arc<WrInjector>,
+    /// Wakes up idle send workers
+    notifier: Notifier,
+    /// Per QP congestion control rates enforced by the send workers
+    reaction_point: ReactionPoint,
+    /// Per QP rate limits enforced by the send workers
+    rate_limiter: RateLimiter,
 }
 
 impl SendQueueScheduler {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(
+        notifier: Notifier,
+        reaction_point: ReactionPoint,
+        rate_limiter: RateLimiter,
+    ) -> Self {
         Self {
             injector: WrInjector::new().into(),
+            notifier,
+            reaction_point,
+            rate_limiter,
         }
     }
 
     pub(crate) fn clone_arc(&self) -> Self {
         Self {
             injector: Arc::clone(&self.injector),
+            notifier: self.notifier.clone(),
+            reaction_point: self.reaction_point.clone(),
+            rate_limiter: self.rate_limiter.clone(),
         }
     }
 
@@ -58,6 +85,7 @@ impl SendQueueScheduler {
     /// * `wr` - The work request chunk to be scheduled
     fn send_wr_task(&self, wr: WrChunk) {
         self.injector.push(wr);
+        self.notifier.notify();
     }
 }
 
@@ -82,22 +110,48 @@ pub(crate) struct SendWorker<Dev> {
     send_queue: SendQueue,
     /// Csr proxy
     csr_adaptor: SendQueueProxy<Dev>,
+    /// Polling strategy used when no task is available
+    polling: PollingConfig,
+    /// Notifier of the send workers
+    notifier: Notifier,
+    /// Notifier of the meta worker, kicked after descriptors are submitted to the NIC
+    meta_notifier: Notifier,
+    /// Transport counters
+    metrics: Metrics,
+    /// Per QP rates of the congestion control
+    reaction_point: ReactionPoint,
+    /// Per QP rate limits
+    rate_limiter: RateLimiter,
+    /// Chunks waiting for their pacing slot, ordered by the slot time and arrival
+    deferred: BTreeMap<(Instant, u64), WrChunk>,
+    /// Arrival sequence of deferred chunks
+    deferred_seq: u64,
 }
 
 impl<Dev: DeviceAdaptor + Send + 'static> SendWorker<Dev> {
-    pub(crate) fn spawn(self) {
+    pub(crate) fn spawn(self, affinity: CpuAffinity) {
         let _handle = std::thread::Builder::new()
             .name(format!("send-worker-{}", self.id))
-            .spawn(move || self.run())
+            .spawn(move || {
+                affinity.apply(WorkerClass::Send, self.id);
+                self.run();
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn thread: {err}"));
     }
 
     /// Run the worker
     pub(crate) fn run(mut self) {
+        let mut poller = Poller::new(self.polling, self.notifier.clone());
         loop {
-            let Some(wr) = Self::find_task(&self.local, &self.global, &self.remotes) else {
+            let Some(wr) = self.next_task() else {
+                // Deferred chunks wake the worker at the earliest pacing slot
+                match self.deferred.first_key_value() {
+                    Some((&(at, _), _)) => poller.on_idle_until(at),
+                    None => poller.on_idle(),
+                }
                 continue;
             };
+            poller.on_busy();
             let desc0 = SendQueueReqDescSeg0::new(
                 wr.opcode,
                 wr.msn,
@@ -125,23 +179,58 @@ impl<Dev: DeviceAdaptor + Send + 'static> SendWorker<Dev> {
                 wr.laddr,
             );
 
-            if !self.send_queue.push(SendQueueDesc::Seg0(desc0)) {
-                self.local.push(wr);
-                continue;
-            }
-            if !self.send_queue.push(SendQueueDesc::Seg1(desc1)) {
-                self.local.push(wr);
-                continue;
+            // Both segments must fit, a chunk deferred after its first segment was pushed
+            // would write that segment to the ring again
+            if self.send_queue.remaining() < 2 {
+                if let Ok(tail_ptr) = self.csr_adaptor.read_tail() {
+                    self.send_queue.set_tail(tail_ptr);
+                }
+                if self.send_queue.remaining() < 2 {
+                    self.defer(wr, Instant::now());
+                    continue;
+                }
             }
+            let pushed = self.send_queue.push(SendQueueDesc::Seg0(desc0))
+                && self.send_queue.push(SendQueueDesc::Seg1(desc1));
+            debug_assert!(pushed, "send queue has room for both segments");
             if self.csr_adaptor.write_head(self.send_queue.head()).is_err() {
                 error!("failed to flush queue pointer");
             }
+            self.meta_notifier.notify();
+            let pmtu = convert_ibv_mtu_to_u16(wr.pmtu).map_or(1, u32::from);
+            self.metrics
+                .record_send(wr.sqpn, wr.len.div_ceil(pmtu).max(1).into(), wr.len.into());
             if let Ok(tail_ptr) = self.csr_adaptor.read_tail() {
                 self.send_queue.set_tail(tail_ptr);
             }
         }
     }
 
+    /// Returns the next chunk allowed to be sent by the congestion control and rate limits
+    fn next_task(&mut self) -> Option<WrChunk> {
+        let now = Instant::now();
+        if let Some(entry) = self.deferred.first_entry() {
+            if entry.key().0 <= now {
+                return Some(entry.remove());
+            }
+        }
+        loop {
+            let wr = Self::find_task(&self.local, &self.global, &self.remotes)?;
+            let congestion = self.reaction_point.reserve(wr.sqpn, wr.len);
+            let limit = self.rate_limiter.reserve(wr.sqpn, wr.len);
+            match congestion.max(limit) {
+                Some(at) => self.defer(wr, at),
+                None => return Some(wr),
+            }
+        }
+    }
+
+    /// Holds the chunk until `at`, the chunk has already reserved its slot
+    fn defer(&mut self, wr: WrChunk, at: Instant) {
+        let _prev = self.deferred.insert((at, self.deferred_seq), wr);
+        self.deferred_seq = self.deferred_seq.wrapping_add(1);
+    }
+
     /// Find a task
     fn find_task<T>(local: &Worker<T>, global: &Injector<T>, stealers: &[Stealer<T>]) -> Option<T> {
         // Pop a task from the local queue, if not empty.
@@ -166,7 +255,11 @@ pub(crate) fn spawn_send_workers<Dev>(
     dev: &Dev,
     bufs: Vec<DmaBuf>,
     mode: Mode,
-    global_injector: &Arc<WrInjector>,
+    scheduler: &SendQueueScheduler,
+    polling: PollingConfig,
+    meta_notifier: &Notifier,
+    affinity: &CpuAffinity,
+    metrics: &Metrics,
 ) -> io::Result<()>
 where
     Dev: DeviceAdaptor + Clone + Send + 'static,
@@ -191,7 +284,7 @@ where
         .map(|(id, ((local, send_queue), csr_adaptor))| SendWorker {
             id,
             local,
-            global: Arc::clone(global_injector),
+            global: scheduler.injector(),
             remotes: stealers
                 .clone()
                 .into_iter()
@@ -200,8 +293,16 @@ where
                 .collect(),
             send_queue,
             csr_adaptor,
+            polling,
+            notifier: scheduler.notifier.clone(),
+            meta_notifier: meta_notifier.clone(),
+            metrics: metrics.clone(),
+            reaction_point: scheduler.reaction_point.clone(),
+            rate_limiter: scheduler.rate_limiter.clone(),
+            deferred: BTreeMap::new(),
+            deferred_seq: 0,
         })
-        .for_each(SendWorker::spawn);
+        .for_each(|worker| worker.spawn(affinity.clone()));
 
     Ok(())
 }
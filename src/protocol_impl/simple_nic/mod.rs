@@ -19,7 +19,7 @@ use worker::SimpleNicWorker;
 
 use crate::{
     device_protocol::{FrameRx, FrameTx, RecvBuffer, SimpleNicTunnel},
-    net::tap::TapDevice,
+    net::{config::DEFAULT_MTU, tap::TapDevice},
 };
 
 #[allow(clippy::module_name_repetitions)]
@@ -28,14 +28,26 @@ use crate::{
 pub struct SimpleNicDeviceConfig {
     /// IP network assigned to the NIC
     network: IpNetwork,
+    /// MTU of the NIC
+    mtu: u16,
 }
 
 impl SimpleNicDeviceConfig {
-    /// Creates a new `SimpleNicDeviceConfig`
+    /// Creates a new `SimpleNicDeviceConfig` with the Ethernet MTU
     #[inline]
     #[must_use]
     pub fn new(network: IpNetwork) -> Self {
-        Self { network }
+        Self {
+            network,
+            mtu: DEFAULT_MTU,
+        }
+    }
+
+    /// Sets the MTU of the NIC, up to 9000 for jumbo frames
+    #[inline]
+    #[must_use]
+    pub fn with_mtu(self, mtu: u16) -> Self {
+        Self { mtu, ..self }
     }
 }
 
@@ -50,18 +62,19 @@ struct SimpleNicDevice {
 impl SimpleNicDevice {
     /// Creates a new `SimpleNicDevice`
     fn new(config: SimpleNicDeviceConfig) -> io::Result<Self> {
-        let tun_dev = Arc::new(Self::create_tun(config.network)?);
+        let tun_dev = Arc::new(Self::create_tun(config.network, config.mtu)?);
         Ok(Self { tun_dev, config })
     }
 
     /// Creates a TUN device that operates at L2
     #[allow(unused_results)] // ignore the config construction result
-    fn create_tun(network: IpNetwork) -> io::Result<tun::Device> {
+    fn create_tun(network: IpNetwork, mtu: u16) -> io::Result<tun::Device> {
         let mut config = tun::Configuration::default();
         config
             .layer(tun::Layer::L2)
             .address(network.ip())
             .netmask(network.mask())
+            .mtu(mtu)
             .up();
 
         #[cfg(target_os = "linux")]
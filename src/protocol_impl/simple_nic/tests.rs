@@ -5,9 +5,15 @@ use std::{
 
 use ipnetwork::IpNetwork;
 
-use crate::device_protocol::{FrameRx, FrameTx};
+use crate::{
+    device_protocol::{FrameRx, FrameTx},
+    net::config::max_frame_len,
+};
 
-use super::{worker::SimpleNicWorker, SimpleNicDevice, SimpleNicDeviceConfig};
+use super::{
+    worker::{FrameSlots, SimpleNicWorker},
+    SimpleNicDevice, SimpleNicDeviceConfig,
+};
 
 struct FrameTxSocket(UdpSocket);
 
@@ -49,3 +55,22 @@ fn worker_loopback() {
     let worker = SimpleNicWorker::new(dev.tun_dev, frame_tx, frame_rx, Arc::clone(&shutdown));
     let handle = worker.run();
 }
+
+#[test]
+fn frame_slots_wrap_and_release_in_order() {
+    // 16 slots of 128 bytes
+    let mut slots = FrameSlots::new(2048);
+    assert!(slots.fits(2048));
+    assert!(!slots.fits(max_frame_len(9000)));
+    assert_eq!(slots.reserve(1500), Some(0));
+    assert_eq!(slots.reserve(60), Some(1536));
+    // Only 3 slots are left at the end, the frame starts over at the front
+    assert_eq!(slots.reserve(400), None);
+    slots.release(1);
+    assert_eq!(slots.reserve(400), Some(0));
+    // The skipped slots are released with the frame
+    slots.release(1);
+    assert_eq!(slots.reserve(1500), None);
+    slots.release(1);
+    assert_eq!(slots.reserve(2048), Some(0));
+}
@@ -1,4 +1,5 @@
 use std::{
+    collections::VecDeque,
     io::{self, Read},
     sync::{
         atomic::{AtomicBool, Ordering},
@@ -8,6 +9,7 @@ use std::{
 };
 
 use tracing::error;
+use tun::AbstractDevice;
 
 use crate::{
     device_protocol::{FrameRx, FrameTx},
@@ -15,6 +17,8 @@ use crate::{
         page::{ContiguousPages, MmapMut},
         DmaBuf, PageWithPhysAddr,
     },
+    metrics::Metrics,
+    net::config::max_frame_len,
     protocol_impl::device::{
         proxy::{SimpleNicRxQueueCsrProxy, SimpleNicTxQueueCsrProxy},
         CsrBaseAddrAdaptor, CsrWriterAdaptor, DeviceAdaptor,
@@ -42,7 +46,18 @@ impl<Dev: DeviceAdaptor> SimpleNicController<Dev> {
         rx_rb_buf: DmaBuf,
         tx_buffer: DmaBuf,
         rx_buffer: DmaBuf,
+        mtu: u16,
+        metrics: &Metrics,
     ) -> io::Result<Self> {
+        let max_frame_len = max_frame_len(mtu);
+        if !FrameSlots::new(tx_buffer.buf.len()).fits(max_frame_len)
+            || max_frame_len > rx_buffer.buf.len()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("MTU of {mtu} bytes exceeds the frame buffers"),
+            ));
+        }
         let mut tx_queue = SimpleNicTxQueue::new(DescRingBuffer::new(tx_rb_buf.buf));
         let mut rx_queue = SimpleNicRxQueue::new(DescRingBuffer::new(rx_rb_buf.buf));
         let req_csr_proxy = SimpleNicTxQueueCsrProxy(dev.clone());
@@ -51,8 +66,21 @@ impl<Dev: DeviceAdaptor> SimpleNicController<Dev> {
         resp_csr_proxy.write_base_addr(rx_rb_buf.phys_addr)?;
 
         Ok(Self {
-            tx: FrameTxQueue::new(tx_queue, tx_buffer.buf, tx_buffer.phys_addr, req_csr_proxy),
-            rx: FrameRxQueue::new(rx_queue, rx_buffer.buf, resp_csr_proxy),
+            tx: FrameTxQueue::new(
+                tx_queue,
+                tx_buffer.buf,
+                tx_buffer.phys_addr,
+                max_frame_len,
+                req_csr_proxy,
+                metrics.clone(),
+            ),
+            rx: FrameRxQueue::new(
+                rx_queue,
+                rx_buffer.buf,
+                max_frame_len,
+                resp_csr_proxy,
+                metrics.clone(),
+            ),
         })
     }
 }
@@ -72,9 +100,76 @@ impl<Dev: DeviceAdaptor + Send + 'static> SimpleNicTunnel for SimpleNicControlle
     }
 }
 
-/// A buffer slot size for a single frame
+/// Granularity of the frame buffers, a frame occupies consecutive slots
 const FRAME_SLOT_SIZE: usize = 128;
 
+/// Allocation of the slots of a frame buffer to the frames in flight, in ring order
+#[derive(Debug)]
+pub(crate) struct FrameSlots {
+    /// Total number of slots of the buffer
+    num_slots: usize,
+    /// Slot of the next frame
+    head: usize,
+    /// Number of slots held by frames in flight
+    in_use: usize,
+    /// Slots held by each frame in flight, oldest first
+    in_flight: VecDeque<usize>,
+}
+
+impl FrameSlots {
+    /// Creates the slots of a buffer of `buf_len` bytes
+    pub(crate) fn new(buf_len: usize) -> Self {
+        Self {
+            num_slots: buf_len / FRAME_SLOT_SIZE,
+            head: 0,
+            in_use: 0,
+            in_flight: VecDeque::new(),
+        }
+    }
+
+    /// Returns `true` if a frame of `len` bytes fits into the buffer at all
+    pub(crate) fn fits(&self, len: usize) -> bool {
+        Self::slots_of(len) <= self.num_slots
+    }
+
+    /// Reserves contiguous slots for a frame of `len` bytes, returns the byte offset of
+    /// the first slot, or `None` if the slots are still held by frames in flight.
+    ///
+    /// A frame never wraps around the end of the buffer, the slots left there are
+    /// skipped and released together with the frame.
+    pub(crate) fn reserve(&mut self, len: usize) -> Option<usize> {
+        if self.in_use == 0 {
+            self.head = 0;
+        }
+        let needed = Self::slots_of(len);
+        let skipped = if self.head + needed > self.num_slots {
+            self.num_slots - self.head
+        } else {
+            0
+        };
+        if self.in_use + skipped + needed > self.num_slots {
+            return None;
+        }
+        let slot = (self.head + skipped) % self.num_slots;
+        self.head = (slot + needed) % self.num_slots;
+        self.in_use += skipped + needed;
+        self.in_flight.push_back(skipped + needed);
+
+        Some(slot * FRAME_SLOT_SIZE)
+    }
+
+    /// Releases the slots of the `count` oldest frames
+    pub(crate) fn release(&mut self, count: usize) {
+        for slots in self.in_flight.drain(..count.min(self.in_flight.len())) {
+            self.in_use -= slots;
+        }
+    }
+
+    fn slots_of(len: usize) -> usize {
+        len.div_ceil(FRAME_SLOT_SIZE).max(1)
+    }
+}
+
 /// Send frame through `SimpleNicTxQueue`
 pub(crate) struct FrameTxQueue<Dev> {
     /// Inner
@@ -85,8 +180,12 @@ pub(crate) struct FrameTxQueue<Dev> {
     buf: MmapMut,
     /// Base physical address of the buffer
     buf_base_phys_addr: u64,
-    /// Pointer to the next slot of the buffer
-    buf_head: usize,
+    /// Largest frame carried by the port
+    max_frame_len: usize,
+    /// Slots of the buffer held by the frames not yet fetched by the device
+    slots: FrameSlots,
+    /// Transport counters
+    metrics: Metrics,
 }
 
 impl<Dev> FrameTxQueue<Dev> {
@@ -95,52 +194,71 @@ impl<Dev> FrameTxQueue<Dev> {
         inner: SimpleNicTxQueue,
         buf: MmapMut,
         buf_base_phys_addr: u64,
+        max_frame_len: usize,
         csr_proxy: SimpleNicTxQueueCsrProxy<Dev>,
+        metrics: Metrics,
     ) -> Self {
+        let slots = FrameSlots::new(buf.len());
         Self {
             inner,
             csr_proxy,
             buf,
             buf_base_phys_addr,
-            buf_head: 0,
+            max_frame_len,
+            slots,
+            metrics,
         }
     }
+}
 
-    /// Build the descriptor from the given buffer
-    #[allow(clippy::as_conversions)] // convert *const u8 to u64 is safe
-    fn build_desc(&mut self, buf: &[u8]) -> Option<SimpleNicTxQueueDesc> {
-        let addr = self.write_next(buf)?;
-        let len: u32 = buf.len().try_into().ok()?;
-        Some(SimpleNicTxQueueDesc::new(addr, len))
+impl<Dev: DeviceAdaptor> FrameTxQueue<Dev> {
+    /// Reads the tail of the TX ring and releases the slots of the frames the device
+    /// fetched since the last read
+    fn update_tail(&mut self) {
+        if let Ok(tail_ptr) = self.csr_proxy.read_tail() {
+            let remaining = self.inner.remaining();
+            self.inner.set_tail(tail_ptr);
+            self.slots
+                .release(self.inner.remaining().saturating_sub(remaining));
+        }
     }
 
+    /// Copies the frame into the buffer, waiting for the device to release slots if
+    /// needed, returns its physical address
     #[allow(clippy::as_conversions)]
-    fn write_next(&mut self, data: &[u8]) -> Option<u64> {
-        if data.len() > FRAME_SLOT_SIZE {
-            return None;
-        }
-        let phys_addr = self.buf_base_phys_addr.wrapping_add(self.buf_head as u64);
-        self.buf.copy_from(self.buf_head, data);
-        self.buf_head = self
-            .buf_head
-            .wrapping_add(FRAME_SLOT_SIZE)
-            .checked_rem(self.buf.len())?;
-        Some(phys_addr)
+    fn write_next(&mut self, data: &[u8]) -> u64 {
+        let offset = loop {
+            if let Some(offset) = self.slots.reserve(data.len()) {
+                break offset;
+            }
+            self.update_tail();
+            std::hint::spin_loop();
+        };
+        self.buf.copy_from(offset, data);
+        self.buf_base_phys_addr.wrapping_add(offset as u64)
     }
 }
 
 impl<Dev: DeviceAdaptor + Send + 'static> FrameTx for FrameTxQueue<Dev> {
     fn send(&mut self, buf: &[u8]) -> io::Result<()> {
-        let mut desc = self
-            .build_desc(buf)
-            .unwrap_or_else(|| unreachable!("buffer is smaller than u32::MAX"));
+        if buf.len() > self.max_frame_len || !self.slots.fits(buf.len()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("frame of {} bytes exceeds the MTU", buf.len()),
+            ));
+        }
+        let len: u32 = buf
+            .len()
+            .try_into()
+            .unwrap_or_else(|_| unreachable!("frame is smaller than u32::MAX"));
+        let desc = SimpleNicTxQueueDesc::new(self.write_next(buf), len);
         while !self.inner.push(desc) {
+            self.update_tail();
             std::hint::spin_loop();
         }
         self.csr_proxy.write_head(self.inner.head());
-        if let Ok(tail_ptr) = self.csr_proxy.read_tail() {
-            self.inner.set_tail(tail_ptr);
-        }
+        self.update_tail();
+        self.metrics.record_simple_nic_tx(buf.len());
 
         Ok(())
     }
@@ -152,8 +270,12 @@ pub(crate) struct FrameRxQueue<Dev> {
     rx_queue: SimpleNicRxQueue,
     /// Buffer for storing received frames
     rx_buf: MmapMut,
+    /// Largest frame carried by the port
+    max_frame_len: usize,
     /// CSR Proxy
     csr_proxy: SimpleNicRxQueueCsrProxy<Dev>,
+    /// Transport counters
+    metrics: Metrics,
 }
 
 impl<Dev> FrameRxQueue<Dev> {
@@ -161,12 +283,16 @@ impl<Dev> FrameRxQueue<Dev> {
     pub(crate) fn new(
         rx_queue: SimpleNicRxQueue,
         rx_buf: MmapMut,
+        max_frame_len: usize,
         csr_proxy: SimpleNicRxQueueCsrProxy<Dev>,
+        metrics: Metrics,
     ) -> Self {
         Self {
             rx_queue,
             rx_buf,
+            max_frame_len,
             csr_proxy,
+            metrics,
         }
     }
 }
@@ -178,12 +304,24 @@ impl<Dev: Send + 'static> FrameRx for FrameRxQueue<Dev> {
         let Some(desc) = self.rx_queue.pop() else {
             return Err(io::ErrorKind::WouldBlock.into());
         };
+        let buf_len = self.rx_buf.len();
+        let len = desc.len() as usize;
+        if len > self.max_frame_len || len > buf_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame of {len} bytes exceeds the MTU"),
+            ));
+        }
+        // A frame longer than a slot continues in the following slots and wraps
+        // around at the end of the buffer
         let pos = (desc.slot_idx() as usize)
             .checked_mul(FRAME_SLOT_SIZE)
-            .unwrap_or_else(|| unreachable!("invalid index"));
-
-        let len = desc.len() as usize;
-        let frame = self.rx_buf.get(pos, len);
+            .unwrap_or_else(|| unreachable!("invalid index"))
+            % buf_len;
+        let first = len.min(buf_len - pos);
+        let mut frame = self.rx_buf.get(pos, first);
+        frame.extend(self.rx_buf.get(0, len - first));
+        self.metrics.record_simple_nic_rx(len);
 
         Ok(frame)
     }
@@ -228,7 +366,8 @@ impl<Tx: FrameTx + Send + 'static> TxWorker<Tx> {
         thread::Builder::new()
             .name("simple-nic-tx-worker".into())
             .spawn(move || {
-                let mut buf = vec![0; FRAME_SLOT_SIZE];
+                // The TAP interface hands over frames up to its MTU
+                let mut buf = vec![0; max_frame_len(self.dev.mtu()?)];
                 while !self.shutdown.load(Ordering::Relaxed) {
                     if let Err(err) = self.process_frame(&mut buf) {
                         error!("Tx processing error: {err}");
@@ -47,6 +47,8 @@ impl TestDevice {
             ip: Ipv4Network::new("10.0.0.2".parse().unwrap(), 24).unwrap(),
             gateway: "10.0.0.1".parse().unwrap(),
             mac: MacAddress([0; 6]),
+            vlan: None,
+            priority: 0,
         };
         cmd_controller.set_network(network_config).unwrap();
 
@@ -76,6 +78,8 @@ impl TestDevice {
             ip: Ipv4Network::new("10.0.0.2".parse().unwrap(), 24).unwrap(),
             gateway: "10.0.0.1".parse().unwrap(),
             mac: MacAddress([1; 6]),
+            vlan: None,
+            priority: 0,
         };
         cmd_controller.set_network(network_config).unwrap();
 
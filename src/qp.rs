@@ -12,7 +12,7 @@ use parking_lot::{Mutex, RwLock};
 use rand::Rng;
 
 use crate::{
-    constants::{MAX_MSN_WINDOW, MAX_PSN_WINDOW, MAX_QP_CNT, MAX_SEND_WR, QPN_KEY_PART_WIDTH},
+    constants::{MAX_MSN_WINDOW, MAX_PSN_WINDOW, MAX_SEND_WR, QPN_KEY_PART_WIDTH},
     device_protocol::{WithQpParams, WrChunkBuilder},
     send::SendWrRdma,
     utils::Psn,
@@ -24,11 +24,25 @@ pub(crate) struct QueuePairAttr {
     pub(crate) qpn: u32,
     pub(crate) dqpn: u32,
     pub(crate) dqp_ip: u32,
+    /// Index of the source GID, selects the local address
+    pub(crate) sgid_index: u8,
+    /// Traffic class of the AV, its DSCP marks the frames built by the driver
+    pub(crate) traffic_class: u8,
+    /// Flow label of the AV
+    pub(crate) flow_label: u32,
+    /// Service level of the AV, the VLAN priority of the frames built by the driver
+    pub(crate) sl: u8,
     pub(crate) mac_addr: u64,
     pub(crate) pmtu: u8,
     pub(crate) access_flags: u8,
     pub(crate) send_cq: Option<u32>,
     pub(crate) recv_cq: Option<u32>,
+    /// Rate limit in kbps, 0 for unlimited
+    pub(crate) rate_limit: u32,
+    /// Local ACK timeout exponent, `None` for the config default
+    pub(crate) timeout: Option<u8>,
+    /// Retry count, `None` for the config default
+    pub(crate) retry_cnt: Option<u8>,
 }
 
 pub(crate) struct QueuePairAttrTable {
@@ -36,14 +50,17 @@ pub(crate) struct QueuePairAttrTable {
 }
 
 impl QueuePairAttrTable {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_qp: usize) -> Self {
         Self {
-            inner: iter::repeat_with(RwLock::default)
-                .take(MAX_QP_CNT)
-                .collect(),
+            inner: iter::repeat_with(RwLock::default).take(max_qp).collect(),
         }
     }
 
+    /// Returns the number of QPs the table holds
+    pub(crate) fn capacity(&self) -> usize {
+        self.inner.len()
+    }
+
     pub(crate) fn clone_arc(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
@@ -70,6 +87,15 @@ impl QueuePairAttrTable {
         let index = index(qpn);
         self.inner.get(index).map(|x| f(&mut x.write()))
     }
+
+    /// Returns the attributes of all created QPs
+    pub(crate) fn active(&self) -> Vec<QueuePairAttr> {
+        self.inner
+            .iter()
+            .map(|x| *x.read())
+            .filter(|attr| attr.qpn != 0)
+            .collect()
+    }
 }
 
 /// Manages QPs
@@ -84,8 +110,9 @@ pub(crate) struct QpManager {
 impl QpManager {
     /// Creates a new `QpManager`
     pub(crate) fn new(table: QueuePairAttrTable) -> Self {
-        let mut bitmap = BitVec::with_capacity(MAX_QP_CNT);
-        bitmap.resize(MAX_QP_CNT, false);
+        let max_qp = table.capacity();
+        let mut bitmap = BitVec::with_capacity(max_qp);
+        bitmap.resize(max_qp, false);
         bitmap.set(0, true);
         Self { bitmap, table }
     }
@@ -103,10 +130,13 @@ impl QpManager {
     /// Removes and returns the QP associated with the given QPN
     pub(crate) fn destroy_qp(&mut self, qpn: u32) {
         let index = index(qpn);
-        if index >= MAX_QP_CNT {
+        if index >= self.bitmap.len() {
             return;
         }
         self.bitmap.set(index, false);
+        let _ignore = self
+            .table
+            .map_qp_mut(qpn, |attr| *attr = QueuePairAttr::default());
     }
 
     pub(crate) fn get_qp(&self, qpn: u32) -> Option<QueuePairAttr> {
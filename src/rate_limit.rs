@@ -0,0 +1,168 @@
+use std::{
+    iter,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use parking_lot::Mutex;
+
+use crate::utils::qpn_index;
+
+/// Bytes a QP may send back to back after being idle, one WR chunk
+const BURST_BYTES: u32 = 0x10000;
+
+/// Per QP token bucket pacers configured by `IBV_QP_RATE_LIMIT`
+#[derive(Debug, Clone)]
+pub(crate) struct RateLimiter {
+    table: Arc<[Mutex<Option<TokenBucket>>]>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(max_qp: usize) -> Self {
+        Self {
+            table: iter::repeat_with(Mutex::default).take(max_qp).collect(),
+        }
+    }
+
+    /// Sets the rate limit of the QP in kbps, 0 removes the limit
+    pub(crate) fn set(&self, qpn: u32, rate_kbps: u32) {
+        self.set_at(qpn, rate_kbps, Instant::now());
+    }
+
+    /// Reserves a transmission slot for `bytes` of the QP.
+    ///
+    /// Returns the time the data may be sent, or `None` if it could be sent immediately.
+    pub(crate) fn reserve(&self, qpn: u32, bytes: u32) -> Option<Instant> {
+        self.reserve_at(qpn, bytes, Instant::now())
+    }
+
+    fn set_at(&self, qpn: u32, rate_kbps: u32, now: Instant) {
+        let Some(slot) = self.table.get(qpn_index(qpn)) else {
+            return;
+        };
+        let mut guard = slot.lock();
+        if rate_kbps == 0 {
+            *guard = None;
+            return;
+        }
+        match guard.as_mut() {
+            Some(bucket) => bucket.set_rate(rate_kbps, now),
+            None => *guard = Some(TokenBucket::new(rate_kbps, now)),
+        }
+    }
+
+    fn reserve_at(&self, qpn: u32, bytes: u32, now: Instant) -> Option<Instant> {
+        self.table
+            .get(qpn_index(qpn))?
+            .lock()
+            .as_mut()?
+            .reserve(bytes.into(), now)
+    }
+}
+
+/// A token bucket counted in bits, tokens may go negative to reserve future slots
+#[derive(Debug)]
+struct TokenBucket {
+    /// Rate in kbps, equals bits per millisecond
+    rate_kbps: u64,
+    /// Available tokens in bits
+    tokens: i64,
+    /// Last time the tokens were refilled
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate_kbps: u32, now: Instant) -> Self {
+        Self {
+            rate_kbps: rate_kbps.into(),
+            tokens: Self::burst(),
+            last_refill: now,
+        }
+    }
+
+    fn burst() -> i64 {
+        i64::from(BURST_BYTES) * 8
+    }
+
+    fn set_rate(&mut self, rate_kbps: u32, now: Instant) {
+        self.refill(now);
+        self.rate_kbps = rate_kbps.into();
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed_ns = now.saturating_duration_since(self.last_refill).as_nanos();
+        let bits = elapsed_ns.saturating_mul(self.rate_kbps.into()) / 1_000_000;
+        let bits = i64::try_from(bits).unwrap_or(i64::MAX);
+        self.tokens = self.tokens.saturating_add(bits).min(Self::burst());
+        self.last_refill = now;
+    }
+
+    fn reserve(&mut self, bytes: u64, now: Instant) -> Option<Instant> {
+        self.refill(now);
+        let start =
+            (self.tokens < 0).then(|| now + self.time_to_refill(self.tokens.unsigned_abs()));
+        self.tokens = self
+            .tokens
+            .saturating_sub(i64::try_from(bytes * 8).unwrap_or(i64::MAX));
+        start
+    }
+
+    /// Time to accumulate `bits` tokens
+    fn time_to_refill(&self, bits: u64) -> Duration {
+        Duration::from_nanos(
+            bits.saturating_mul(1_000_000)
+                .div_ceil(self.rate_kbps.max(1)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const QPN: u32 = 1 << 8;
+    const MAX_QP: usize = 4;
+
+    #[test]
+    fn unlimited_qp_is_not_paced() {
+        let limiter = RateLimiter::new(MAX_QP);
+        let now = Instant::now();
+        assert_eq!(limiter.reserve_at(QPN, u32::MAX, now), None);
+    }
+
+    #[test]
+    fn burst_then_paced() {
+        let limiter = RateLimiter::new(MAX_QP);
+        let now = Instant::now();
+        // 1 Gbps, 1 bit per ns
+        limiter.set_at(QPN, 1_000_000, now);
+        assert_eq!(limiter.reserve_at(QPN, BURST_BYTES, now), None);
+        assert_eq!(limiter.reserve_at(QPN, 1000, now), None);
+        assert_eq!(
+            limiter.reserve_at(QPN, 1000, now),
+            Some(now + Duration::from_nanos(8000))
+        );
+    }
+
+    #[test]
+    fn tokens_refill_up_to_burst() {
+        let limiter = RateLimiter::new(MAX_QP);
+        let now = Instant::now();
+        limiter.set_at(QPN, 1_000_000, now);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(limiter.reserve_at(QPN, BURST_BYTES, later), None);
+        assert!(limiter.reserve_at(QPN, 1, later).is_none());
+        assert!(limiter.reserve_at(QPN, 1, later).is_some());
+    }
+
+    #[test]
+    fn zero_removes_the_limit() {
+        let limiter = RateLimiter::new(MAX_QP);
+        let now = Instant::now();
+        limiter.set_at(QPN, 1000, now);
+        let _ignore = limiter.reserve_at(QPN, BURST_BYTES * 2, now);
+        assert!(limiter.reserve_at(QPN, 1, now).is_some());
+        limiter.set_at(QPN, 0, now);
+        assert_eq!(limiter.reserve_at(QPN, 1, now), None);
+    }
+}
@@ -1,12 +1,14 @@
-use std::io;
+use std::{io, time::Instant};
 
 use parking_lot::Mutex;
 
 use crate::{
+    affinity::{CpuAffinity, WorkerClass},
     completion::{Completion, CompletionTask, Event, MessageMeta, SendEvent, SendEventOp},
     constants::PSN_MASK,
     device_protocol::{ChunkPos, QpParams, WorkReqOpCode, WorkReqSend, WrChunkBuilder},
     fragmenter::{WrChunkFragmenter, WrPacketFragmenter},
+    mtt::MrRefs,
     packet_retransmit::{PacketRetransmitTask, SendQueueElem},
     protocol_impl::SendQueueScheduler,
     qp::{num_psn, QueuePairAttrTable, SqContext},
@@ -47,9 +49,13 @@ pub(crate) struct RdmaWriteWorker {
     retransmit_tx: flume::Sender<RetransmitTask>,
     packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
     completion_tx: flume::Sender<CompletionTask>,
+    mr_refs: MrRefs,
+    /// Marks outgoing packets as ECN capable
+    enable_ecn: bool,
 }
 
 impl RdmaWriteWorker {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         rdma_write_rx: flume::Receiver<RdmaWriteTask>,
         qp_attr_table: QueuePairAttrTable,
@@ -57,22 +63,29 @@ impl RdmaWriteWorker {
         retransmit_tx: flume::Sender<RetransmitTask>,
         packet_retransmit_tx: flume::Sender<PacketRetransmitTask>,
         completion_tx: flume::Sender<CompletionTask>,
+        mr_refs: MrRefs,
+        enable_ecn: bool,
     ) -> Self {
         Self {
             rdma_write_rx,
-            sq_ctx_table: QpTable::new(),
+            sq_ctx_table: QpTable::new(qp_attr_table.capacity()),
             qp_attr_table,
             send_scheduler,
             retransmit_tx,
             packet_retransmit_tx,
             completion_tx,
+            mr_refs,
+            enable_ecn,
         }
     }
 
-    pub(crate) fn spawn(self) {
+    pub(crate) fn spawn(self, affinity: CpuAffinity) {
         let _handle = std::thread::Builder::new()
             .name("rdma-write-worker".into())
-            .spawn(move || self.run())
+            .spawn(move || {
+                affinity.apply(WorkerClass::RdmaWrite, 0);
+                self.run();
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
     }
 
@@ -124,6 +137,7 @@ impl RdmaWriteWorker {
             qp.dqpn,
             qp.dqp_ip,
             qp.pmtu,
+            self.enable_ecn,
         );
         let opcode = WorkReqOpCode::RdmaRead;
         let chunk = WrChunkBuilder::new_with_opcode(opcode)
@@ -135,8 +149,12 @@ impl RdmaWriteWorker {
                 wr.lkey(),
                 wr.imm(),
             )
-            .set_chunk_meta(psn, wr.laddr(), wr.raddr(), wr.length(), ChunkPos::Only)
-            .build();
+            .set_chunk_meta(psn, wr.laddr(), wr.raddr(), wr.length(), ChunkPos::Only);
+        let chunk = if self.enable_ecn {
+            chunk.set_enable_ecn().build()
+        } else {
+            chunk.build()
+        };
         let flags = wr.send_flags();
         let mut ack_req = false;
         if flags & ibverbs_sys::ibv_send_flags::IBV_SEND_SIGNALED.0 != 0 {
@@ -158,19 +176,28 @@ impl RdmaWriteWorker {
             let _ignore = self.retransmit_tx.send(RetransmitTask::NewAckReq {
                 qpn,
                 last_packet_chunk: chunk,
+                at: Instant::now(),
             });
         }
 
-        let _ignore = self.packet_retransmit_tx.send(PacketRetransmitTask::NewWr {
-            qpn,
-            wr: SendQueueElem::new(wr, psn, qp_params),
-        });
+        self.push_send_queue(qpn, SendQueueElem::new(wr, psn, end_psn, qp_params));
 
         self.send_scheduler.send(chunk)?;
 
         Ok(())
     }
 
+    /// Keeps the WR for retransmission, its memory region cannot be deregistered until the
+    /// WR is acknowledged
+    fn push_send_queue(&self, qpn: u32, wr: SendQueueElem) {
+        if let Some(mr_key) = wr.mr_key() {
+            self.mr_refs.acquire(mr_key);
+        }
+        let _ignore = self
+            .packet_retransmit_tx
+            .send(PacketRetransmitTask::NewWr { qpn, wr });
+    }
+
     fn write(&mut self, qpn: u32, wr: SendWrRdma) -> io::Result<()> {
         let qp = self
             .qp_attr_table
@@ -215,6 +242,7 @@ impl RdmaWriteWorker {
             qp.dqpn,
             qp.dqp_ip,
             qp.pmtu,
+            self.enable_ecn,
         );
 
         if ack_req {
@@ -225,13 +253,11 @@ impl RdmaWriteWorker {
             let _ignore = self.retransmit_tx.send(RetransmitTask::NewAckReq {
                 qpn,
                 last_packet_chunk,
+                at: Instant::now(),
             });
         }
 
-        let _ignore = self.packet_retransmit_tx.send(PacketRetransmitTask::NewWr {
-            qpn,
-            wr: SendQueueElem::new(wr, psn, qp_params),
-        });
+        self.push_send_queue(qpn, SendQueueElem::new(wr, psn, end_psn, qp_params));
 
         let fragmenter = WrChunkFragmenter::new(wr, qp_params, psn);
         for chunk in fragmenter {
@@ -156,9 +156,9 @@ pub(crate) struct PostRecvTxTable<Tx = TcpChannelTx> {
 }
 
 impl<Tx> PostRecvTxTable<Tx> {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_qp: usize) -> Self {
         Self {
-            inner: QpTable::new(),
+            inner: QpTable::new(max_qp),
         }
     }
 
@@ -178,9 +178,9 @@ pub(crate) struct RecvWrQueueTable {
 }
 
 impl RecvWrQueueTable {
-    pub(crate) fn new() -> Self {
+    pub(crate) fn new(max_qp: usize) -> Self {
         Self {
-            inner: QpTable::new(),
+            inner: QpTable::new(max_qp),
         }
     }
 
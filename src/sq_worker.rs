@@ -31,10 +31,10 @@ pub(crate) struct SqWorker {
 }
 
 impl SqWorker {
-    pub(crate) fn new(receiver: flume::Receiver<SqTask>) -> Self {
+    pub(crate) fn new(receiver: flume::Receiver<SqTask>, max_qp: usize) -> Self {
         Self {
             receiver,
-            table: QpTable::new(),
+            table: QpTable::new(max_qp),
         }
     }
 
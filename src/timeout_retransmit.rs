@@ -1,27 +1,44 @@
-use std::{io, iter, thread, time::Duration};
+use std::{
+    io, iter, thread,
+    time::{Duration, Instant},
+};
 
+use flume::RecvTimeoutError;
 use serde::{Deserialize, Serialize};
 use tracing::error;
 
 use crate::{
-    constants::MAX_QP_CNT,
+    affinity::{CpuAffinity, WorkerClass},
     device_protocol::{WorkReqSend, WrChunk},
+    metrics::Metrics,
     protocol_impl::SendQueueScheduler,
-    timer::TransportTimer,
-    utils::qpn_index,
+    timer::{RttEstimator, TransportTimer},
+    timer_wheel::TimerWheel,
+    utils::{qpn_index, Psn},
 };
 
 const DEFAULT_INIT_RETRY_COUNT: usize = 5;
 const DEFAULT_TIMEOUT_CHECK_DURATION: u8 = 8;
 const DEFAULT_LOCAL_ACK_TIMEOUT: u8 = 4;
+const DEFAULT_MIN_ADAPTIVE_TIMEOUT_US: u64 = 100;
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub(crate) struct AckTimeoutConfig {
-    // 4.096 uS * 2^(CHECK DURATION)
+    // 4.096 uS * 2^(CHECK DURATION), unused since the worker wakes at the timer deadlines
     check_duration_exp: u8,
     // 4.096 uS * 2^(Local ACK Timeout)
     local_ack_timeout_exp: u8,
     init_retry_count: usize,
+    /// Derives the timeout of each QP from the measured ACK RTT, bounded by the QP timeout
+    #[serde(default)]
+    adaptive: bool,
+    /// Lower bound of the adaptive timeout in microseconds
+    #[serde(default = "default_min_adaptive_timeout_us")]
+    min_adaptive_timeout_us: u64,
+}
+
+fn default_min_adaptive_timeout_us() -> u64 {
+    DEFAULT_MIN_ADAPTIVE_TIMEOUT_US
 }
 
 impl Default for AckTimeoutConfig {
@@ -30,6 +47,8 @@ impl Default for AckTimeoutConfig {
             check_duration_exp: DEFAULT_TIMEOUT_CHECK_DURATION,
             local_ack_timeout_exp: DEFAULT_LOCAL_ACK_TIMEOUT,
             init_retry_count: DEFAULT_INIT_RETRY_COUNT,
+            adaptive: false,
+            min_adaptive_timeout_us: DEFAULT_MIN_ADAPTIVE_TIMEOUT_US,
         }
     }
 }
@@ -40,27 +59,67 @@ impl AckTimeoutConfig {
             check_duration_exp: check_duration,
             local_ack_timeout_exp: local_ack_timeout,
             init_retry_count,
+            adaptive: false,
+            min_adaptive_timeout_us: DEFAULT_MIN_ADAPTIVE_TIMEOUT_US,
         }
     }
+
+    /// Local ACK timeout used by QPs that did not set `IBV_QP_TIMEOUT`
+    pub(crate) fn local_ack_timeout_exp(&self) -> u8 {
+        self.local_ack_timeout_exp
+    }
+
+    /// Retry count used by QPs that did not set `IBV_QP_RETRY_CNT`
+    pub(crate) fn init_retry_count(&self) -> usize {
+        self.init_retry_count
+    }
+
+    fn timer(&self, timeout: Option<u8>, retry_cnt: Option<u8>) -> TransportTimer {
+        let (timeout, retry_cnt) = self.timer_params(timeout, retry_cnt);
+        TransportTimer::new(timeout, retry_cnt)
+    }
+
+    /// Returns the timeout exponent and retry count of a QP, falling back to the defaults
+    fn timer_params(&self, timeout: Option<u8>, retry_cnt: Option<u8>) -> (u8, usize) {
+        (
+            timeout.unwrap_or(self.local_ack_timeout_exp),
+            retry_cnt.map_or(self.init_retry_count, usize::from),
+        )
+    }
 }
 
-/// Timer per QP
+/// Timer per QP, the running timers are armed in a timing wheel
 struct TransportTimerTable {
     inner: Box<[Entry]>,
+    wheel: TimerWheel,
 }
 
 impl TransportTimerTable {
-    fn new(local_ack_timeout: u8, init_retry_counter: usize) -> Self {
-        let timer = TransportTimer::new(local_ack_timeout, init_retry_counter);
+    fn new(config: &AckTimeoutConfig, max_qp: usize) -> Self {
         Self {
-            inner: iter::repeat_with(|| Entry::new(timer.clone()))
-                .take(MAX_QP_CNT)
+            inner: iter::repeat_with(|| Entry::new(config.timer(None, None)))
+                .take(max_qp)
                 .collect(),
+            wheel: TimerWheel::new(max_qp),
         }
     }
 
-    fn get_qp_mut(&mut self, qpn: u32) -> Option<&mut Entry> {
-        self.inner.get_mut(qpn_index(qpn))
+    /// Applies `f` to the entry at `index` and rearms its timer
+    fn update<F: FnOnce(&mut Entry)>(&mut self, index: usize, f: F) {
+        let Some(entry) = self.inner.get_mut(index) else {
+            return;
+        };
+        f(entry);
+        self.wheel.update(index, entry.timer.deadline());
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.wheel.next_deadline()
+    }
+
+    /// Returns the indices of the entries whose timer expired
+    fn poll(&mut self, now: Instant) -> Vec<usize> {
+        self.wheel.poll(now)
     }
 }
 
@@ -68,18 +127,77 @@ struct Entry {
     timer: TransportTimer,
     // contains the last packet which ack_req bit is set
     last_packet_chunk: Option<WrChunk>,
+    /// Time the last packet was posted, cleared on retransmission so that
+    /// ambiguous samples are not taken (Karn's algorithm)
+    sent_at: Option<Instant>,
+    /// Timeout configured for the QP, the upper bound in adaptive mode
+    max_timeout: Option<Duration>,
+    rtt: RttEstimator,
 }
 
 impl Entry {
     fn new(timer: TransportTimer) -> Self {
         Self {
+            max_timeout: timer.timeout_interval(),
             timer,
             last_packet_chunk: None,
+            sent_at: None,
+            rtt: RttEstimator::default(),
         }
     }
 
-    fn set_last_packet(&mut self, packet: WrChunk) {
+    fn set_last_packet(&mut self, packet: WrChunk, at: Instant) {
         self.last_packet_chunk = Some(packet);
+        self.sent_at = Some(at);
+    }
+
+    /// Handles an ACK advancing the acknowledged PSN of the QP to `psn`
+    fn ack(&mut self, psn: Psn, at: Instant, config: &AckTimeoutConfig) {
+        let Some(last) = self.last_packet_chunk else {
+            return;
+        };
+        if psn <= last.psn {
+            return;
+        }
+        self.timer.stop();
+        self.last_packet_chunk = None;
+        let Some(sent_at) = self.sent_at.take() else {
+            return;
+        };
+        self.rtt.sample(at.saturating_duration_since(sent_at));
+        if config.adaptive {
+            self.adapt(config);
+        }
+    }
+
+    /// Handles a timeout of the last packet
+    fn on_timeout(&mut self, config: &AckTimeoutConfig) {
+        self.sent_at = None;
+        if !config.adaptive {
+            return;
+        }
+        if let (Some(current), Some(max)) = (self.timer.timeout_interval(), self.max_timeout) {
+            self.timer
+                .set_timeout_interval(current.saturating_mul(2).min(max));
+        }
+    }
+
+    fn adapt(&mut self, config: &AckTimeoutConfig) {
+        let (Some(rto), Some(max)) = (self.rtt.rto(), self.max_timeout) else {
+            return;
+        };
+        let min = Duration::from_micros(config.min_adaptive_timeout_us);
+        self.timer.set_timeout_interval(rto.max(min).min(max));
+    }
+
+    /// Applies new QP attributes, the running timer and the last packet are kept
+    fn configure(&mut self, timeout: Option<u8>, retry_cnt: Option<u8>, config: &AckTimeoutConfig) {
+        let (timeout, retry_cnt) = config.timer_params(timeout, retry_cnt);
+        self.timer.reconfigure(timeout, retry_cnt);
+        self.max_timeout = self.timer.timeout_interval();
+        if config.adaptive {
+            self.adapt(config);
+        }
     }
 }
 
@@ -90,16 +208,29 @@ pub(crate) enum RetransmitTask {
         qpn: u32,
         // contains the last packet which ack_req bit is set
         last_packet_chunk: WrChunk,
+        at: Instant,
     },
     ReceiveACK {
         qpn: u32,
+        // the first unacknowledged PSN
+        psn: Psn,
+        at: Instant,
+    },
+    /// Applies the `IBV_QP_TIMEOUT` and `IBV_QP_RETRY_CNT` attributes of the QP,
+    /// `None` falls back to the config default
+    Configure {
+        qpn: u32,
+        timeout: Option<u8>,
+        retry_cnt: Option<u8>,
     },
 }
 
 impl RetransmitTask {
     fn qpn(&self) -> u32 {
         match *self {
-            RetransmitTask::NewAckReq { qpn, .. } | RetransmitTask::ReceiveACK { qpn } => qpn,
+            RetransmitTask::NewAckReq { qpn, .. }
+            | RetransmitTask::ReceiveACK { qpn, .. }
+            | RetransmitTask::Configure { qpn, .. } => qpn,
         }
     }
 }
@@ -109,6 +240,7 @@ pub(crate) struct TimeoutRetransmitWorker {
     table: TransportTimerTable,
     wr_sender: SendQueueScheduler,
     config: AckTimeoutConfig,
+    metrics: Metrics,
 }
 
 impl TimeoutRetransmitWorker {
@@ -116,54 +248,194 @@ impl TimeoutRetransmitWorker {
         receiver: flume::Receiver<RetransmitTask>,
         wr_sender: SendQueueScheduler,
         config: AckTimeoutConfig,
+        metrics: Metrics,
+        max_qp: usize,
     ) -> Self {
         Self {
             receiver,
             wr_sender,
-            table: TransportTimerTable::new(config.local_ack_timeout_exp, config.init_retry_count),
+            table: TransportTimerTable::new(&config, max_qp),
             config,
+            metrics,
         }
     }
 
-    pub(crate) fn spawn(self) {
+    pub(crate) fn spawn(self, affinity: CpuAffinity) {
         let _handle = thread::Builder::new()
             .name("timer-worker".into())
-            .spawn(move || self.run())
+            .spawn(move || {
+                affinity.apply(WorkerClass::Timer, 0);
+                self.run();
+            })
             .unwrap_or_else(|err| unreachable!("Failed to spawn rx thread: {err}"));
     }
 
     #[allow(clippy::needless_pass_by_value)] // consume the flag
     /// Run the handler loop
     fn run(mut self) {
-        let check_duration_ns = Duration::from_nanos(4096u64 << self.config.check_duration_exp);
         loop {
-            spin_sleep::sleep(check_duration_ns);
-            for task in self.receiver.try_iter() {
-                let Some(entry) = self.table.get_qp_mut(task.qpn()) else {
-                    continue;
-                };
-                if let RetransmitTask::NewAckReq {
-                    last_packet_chunk, ..
-                } = task
-                {
-                    entry.timer.reset();
-                    entry.set_last_packet(last_packet_chunk);
+            // Tasks wake the worker immediately, so timers armed while waiting are not delayed
+            let task = match self.table.next_deadline() {
+                Some(deadline) => self.receiver.recv_deadline(deadline),
+                None => self
+                    .receiver
+                    .recv()
+                    .map_err(|_err| RecvTimeoutError::Disconnected),
+            };
+            match task {
+                Ok(task) => {
+                    self.handle_task(task);
+                    for task in self.receiver.try_iter() {
+                        self.handle_task(task);
+                    }
                 }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
             }
-            for (index, entry) in self.table.inner.iter_mut().enumerate() {
-                match entry.timer.check_timeout() {
-                    Ok(true) => {
-                        if let Some(mut packet) = entry.last_packet_chunk {
-                            packet.set_is_retry();
-                            if let Err(err) = self.wr_sender.send(packet) {
-                                error!("failed to send packet: {err}");
-                            }
+            let now = Instant::now();
+            for index in self.table.poll(now) {
+                self.handle_timeout(index, now);
+            }
+        }
+    }
+
+    fn handle_task(&mut self, task: RetransmitTask) {
+        let config = &self.config;
+        self.table
+            .update(qpn_index(task.qpn()), |entry| match task {
+                RetransmitTask::NewAckReq {
+                    last_packet_chunk,
+                    at,
+                    ..
+                } => {
+                    entry.timer.reset(at);
+                    entry.set_last_packet(last_packet_chunk, at);
+                }
+                RetransmitTask::ReceiveACK { psn, at, .. } => entry.ack(psn, at, config),
+                RetransmitTask::Configure {
+                    timeout, retry_cnt, ..
+                } => entry.configure(timeout, retry_cnt, config),
+            });
+    }
+
+    fn handle_timeout(&mut self, index: usize, now: Instant) {
+        let config = &self.config;
+        let metrics = &self.metrics;
+        let wr_sender = &self.wr_sender;
+        self.table
+            .update(index, |entry| match entry.timer.check_timeout(now) {
+                Ok(true) => {
+                    entry.on_timeout(config);
+                    if let Some(mut packet) = entry.last_packet_chunk {
+                        packet.set_is_retry();
+                        metrics.record_timeout_retransmit(packet.sqpn);
+                        if let Err(err) = wr_sender.send(packet) {
+                            error!("failed to send packet: {err}");
                         }
                     }
-                    Ok(false) => {}
-                    Err(_) => todo!("handles retry failure"),
                 }
-            }
+                Ok(false) => {}
+                Err(_) => todo!("handles retry failure"),
+            });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn config(adaptive: bool) -> AckTimeoutConfig {
+        AckTimeoutConfig {
+            adaptive,
+            ..AckTimeoutConfig::default()
         }
     }
+
+    fn chunk(psn: u32) -> WrChunk {
+        WrChunk {
+            psn: Psn(psn),
+            ..WrChunk::default()
+        }
+    }
+
+    #[test]
+    fn per_qp_attributes_override_default() {
+        let config = config(false);
+        let mut entry = Entry::new(config.timer(None, None));
+        assert_eq!(
+            entry.timer.timeout_interval(),
+            Some(Duration::from_nanos(4096 << DEFAULT_LOCAL_ACK_TIMEOUT))
+        );
+        entry.configure(Some(14), Some(7), &config);
+        assert_eq!(
+            entry.timer.timeout_interval(),
+            Some(Duration::from_nanos(4096 << 14))
+        );
+        entry.configure(Some(0), None, &config);
+        assert_eq!(entry.timer.timeout_interval(), None);
+    }
+
+    #[test]
+    fn configure_keeps_running_timer() {
+        let config = config(false);
+        let mut entry = Entry::new(config.timer(None, None));
+        let now = Instant::now();
+        entry.timer.reset(now);
+        entry.set_last_packet(chunk(10), now);
+        entry.configure(Some(14), Some(7), &config);
+        assert_eq!(
+            entry.timer.deadline(),
+            Some(now + Duration::from_nanos(4096 << 14))
+        );
+        assert!(entry.last_packet_chunk.is_some());
+        entry.ack(Psn(11), now, &config);
+        assert!(!entry.timer.is_running());
+    }
+
+    #[test]
+    fn ack_stops_timer() {
+        let config = config(false);
+        let mut entry = Entry::new(config.timer(None, None));
+        let now = Instant::now();
+        entry.timer.reset(now);
+        entry.set_last_packet(chunk(10), now);
+        entry.ack(Psn(10), now, &config);
+        assert!(entry.timer.is_running());
+        entry.ack(Psn(11), now, &config);
+        assert!(!entry.timer.is_running());
+        assert!(entry.last_packet_chunk.is_none());
+    }
+
+    #[test]
+    fn only_running_timers_are_armed() {
+        let config = config(false);
+        let mut table = TransportTimerTable::new(&config, 4);
+        let now = Instant::now();
+        assert_eq!(table.next_deadline(), None);
+        table.update(1, |entry| {
+            entry.timer.reset(now);
+            entry.set_last_packet(chunk(0), now);
+        });
+        assert!(table.next_deadline().is_some());
+        table.update(1, |entry| entry.ack(Psn(1), now, &config));
+        assert_eq!(table.next_deadline(), None);
+    }
+
+    #[test]
+    fn adaptive_timeout_follows_rtt() {
+        let config = config(true);
+        let mut entry = Entry::new(config.timer(Some(20), None));
+        let max = entry.max_timeout.unwrap();
+        let now = Instant::now();
+        for i in 0..32 {
+            entry.set_last_packet(chunk(i), now);
+            entry.ack(Psn(i + 1), now + Duration::from_millis(1), &config);
+        }
+        let timeout = entry.timer.timeout_interval().unwrap();
+        assert!(timeout < max, "timeout: {timeout:?}");
+        assert!(timeout >= Duration::from_millis(1), "timeout: {timeout:?}");
+
+        entry.on_timeout(&config);
+        assert_eq!(entry.timer.timeout_interval(), Some((timeout * 2).min(max)));
+    }
 }
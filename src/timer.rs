@@ -12,25 +12,38 @@ pub(crate) struct TransportTimer {
 
 impl TransportTimer {
     pub(crate) fn new(local_ack_timeout: u8, init_retry_counter: usize) -> Self {
-        let timeout_nanos = if local_ack_timeout == 0 {
-            // disabled
-            None
-        } else {
-            // 4.096 uS * 2^(Local ACK Timeout)
-            Some(4096u64 << local_ack_timeout)
-        };
-
         Self {
-            timeout_interval: timeout_nanos.map(Duration::from_nanos),
+            timeout_interval: Self::interval(local_ack_timeout),
             last_start: None,
             init_retry_counter,
             current_retry_counter: init_retry_counter,
         }
     }
 
-    pub(crate) fn reset(&mut self) {
+    /// Replaces the timeout and the retry count, a running timer keeps its start and the
+    /// retries it already used
+    pub(crate) fn reconfigure(&mut self, local_ack_timeout: u8, init_retry_counter: usize) {
+        let used = self
+            .init_retry_counter
+            .saturating_sub(self.current_retry_counter);
+        self.timeout_interval = Self::interval(local_ack_timeout);
+        self.init_retry_counter = init_retry_counter;
+        self.current_retry_counter = init_retry_counter.saturating_sub(used);
+    }
+
+    fn interval(local_ack_timeout: u8) -> Option<Duration> {
+        if local_ack_timeout == 0 {
+            // disabled
+            None
+        } else {
+            // 4.096 uS * 2^(Local ACK Timeout)
+            Some(Duration::from_nanos(4096u64 << local_ack_timeout))
+        }
+    }
+
+    pub(crate) fn reset(&mut self, now: Instant) {
         self.current_retry_counter = self.init_retry_counter;
-        self.restart();
+        self.restart(now);
     }
 
     pub(crate) fn stop(&mut self) {
@@ -38,31 +51,75 @@ impl TransportTimer {
     }
 
     /// Returns `Ok(true)` if timeout
-    pub(crate) fn check_timeout(&mut self) -> Result<bool, TimerError> {
-        let Some(timeout_interval) = self.timeout_interval else {
-            return Ok(false);
-        };
-        let Some(start_time) = self.last_start else {
+    pub(crate) fn check_timeout(&mut self, now: Instant) -> Result<bool, TimerError> {
+        let Some(deadline) = self.deadline() else {
             return Ok(false);
         };
-        let elapsed = start_time.elapsed();
-        if elapsed < timeout_interval {
+        if now < deadline {
             return Ok(false);
         }
         if self.current_retry_counter == 0 {
             return Err(TimerError);
         }
         self.current_retry_counter -= 1;
-        self.restart();
+        self.restart(now);
         Ok(true)
     }
 
+    /// Returns the expiry time of a running timer
+    pub(crate) fn deadline(&self) -> Option<Instant> {
+        self.last_start
+            .zip(self.timeout_interval)
+            .map(|(start, interval)| start + interval)
+    }
+
     pub(crate) fn is_running(&self) -> bool {
         self.last_start.is_some()
     }
 
-    fn restart(&mut self) {
-        self.last_start = Some(Instant::now());
+    /// Returns the timeout interval, `None` if the timer is disabled
+    pub(crate) fn timeout_interval(&self) -> Option<Duration> {
+        self.timeout_interval
+    }
+
+    /// Overrides the timeout interval of an enabled timer
+    pub(crate) fn set_timeout_interval(&mut self, interval: Duration) {
+        if self.timeout_interval.is_some() {
+            self.timeout_interval = Some(interval);
+        }
+    }
+
+    fn restart(&mut self, now: Instant) {
+        self.last_start = Some(now);
+    }
+}
+
+/// Smoothed RTT estimator following RFC 6298
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct RttEstimator {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+}
+
+impl RttEstimator {
+    /// Updates the estimation with a new RTT sample
+    pub(crate) fn sample(&mut self, rtt: Duration) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.rttvar = rtt / 2;
+            }
+            Some(srtt) => {
+                let delta = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+                self.rttvar = self.rttvar * 3 / 4 + delta / 4;
+                self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+            }
+        }
+    }
+
+    /// Returns the retransmission timeout, `None` before the first sample
+    pub(crate) fn rto(&self) -> Option<Duration> {
+        self.srtt.map(|srtt| srtt + self.rttvar * 4)
     }
 }
 
@@ -70,3 +127,74 @@ impl TransportTimer {
 #[derive(Debug, Error, Clone, Copy)]
 #[error("reached maximum retry limit")]
 pub(crate) struct TimerError;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rtt_estimator_converges() {
+        let mut rtt = RttEstimator::default();
+        assert_eq!(rtt.rto(), None);
+        rtt.sample(Duration::from_micros(100));
+        assert_eq!(rtt.rto(), Some(Duration::from_micros(300)));
+        for _ in 0..64 {
+            rtt.sample(Duration::from_micros(100));
+        }
+        let rto = rtt.rto().unwrap();
+        assert!(rto >= Duration::from_micros(100), "rto: {rto:?}");
+        assert!(rto < Duration::from_micros(101), "rto: {rto:?}");
+    }
+
+    #[test]
+    fn timer_expires_at_deadline() {
+        let now = Instant::now();
+        let mut timer = TransportTimer::new(4, 1);
+        assert_eq!(timer.deadline(), None);
+        timer.reset(now);
+        let deadline = now + Duration::from_nanos(4096 << 4);
+        assert_eq!(timer.deadline(), Some(deadline));
+        assert!(!timer
+            .check_timeout(deadline - Duration::from_nanos(1))
+            .unwrap());
+        assert!(timer.check_timeout(deadline).unwrap());
+        assert_eq!(
+            timer.deadline(),
+            Some(deadline + Duration::from_nanos(4096 << 4))
+        );
+        assert!(timer
+            .check_timeout(deadline + Duration::from_secs(1))
+            .is_err());
+    }
+
+    #[test]
+    fn reconfigure_keeps_start_and_used_retries() {
+        let now = Instant::now();
+        let mut timer = TransportTimer::new(4, 3);
+        timer.reset(now);
+        let deadline = now + Duration::from_nanos(4096 << 4);
+        assert!(timer.check_timeout(deadline).unwrap());
+        timer.reconfigure(6, 2);
+        assert_eq!(
+            timer.deadline(),
+            Some(deadline + Duration::from_nanos(4096 << 6))
+        );
+        assert!(timer
+            .check_timeout(deadline + Duration::from_secs(1))
+            .unwrap());
+        assert!(timer
+            .check_timeout(deadline + Duration::from_secs(2))
+            .is_err());
+    }
+
+    #[test]
+    fn timer_interval_override() {
+        let mut timer = TransportTimer::new(4, 3);
+        timer.set_timeout_interval(Duration::from_micros(10));
+        assert_eq!(timer.timeout_interval(), Some(Duration::from_micros(10)));
+
+        let mut disabled = TransportTimer::new(0, 3);
+        disabled.set_timeout_interval(Duration::from_micros(10));
+        assert_eq!(disabled.timeout_interval(), None);
+    }
+}
@@ -0,0 +1,266 @@
+use std::{
+    iter, mem,
+    time::{Duration, Instant},
+};
+
+/// Duration of a tick, the 4.096 uS unit of the IB timeouts
+const TICK_NS: u64 = 4096;
+/// Bits of the tick consumed by each level
+const LEVEL_BITS: usize = 6;
+/// Slots of each level
+const SLOTS: usize = 1 << LEVEL_BITS;
+const SLOT_MASK: u64 = (SLOTS as u64) - 1;
+/// Number of levels, the wheel spans 64^4 ticks, about 68 seconds
+const LEVELS: usize = 4;
+const TOTAL_BITS: usize = LEVEL_BITS * LEVELS;
+const TOTAL_MASK: u64 = (1 << TOTAL_BITS) - 1;
+/// Bucket of the timers beyond the span of the wheel
+const OVERFLOW: usize = LEVELS * SLOTS;
+
+/// Hierarchical timing wheel of the timers indexed by `0..capacity`.
+///
+/// A timer is placed at the level of the highest tick bits differing from the
+/// current tick, and moves to lower levels as the current tick approaches its
+/// deadline. Only the slots holding timers are visited.
+#[derive(Debug)]
+pub(crate) struct TimerWheel {
+    origin: Instant,
+    /// The last processed tick
+    now: u64,
+    buckets: Box<[Vec<Timer>]>,
+    positions: Box<[Option<Position>]>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Timer {
+    key: usize,
+    tick: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Position {
+    bucket: usize,
+    index: usize,
+}
+
+impl TimerWheel {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self::new_at(capacity, Instant::now())
+    }
+
+    fn new_at(capacity: usize, origin: Instant) -> Self {
+        Self {
+            origin,
+            now: 0,
+            buckets: iter::repeat_with(Vec::new).take(OVERFLOW + 1).collect(),
+            positions: iter::repeat(None).take(capacity).collect(),
+        }
+    }
+
+    /// Arms the timer of `key` to expire at `deadline`, replacing the previous one
+    pub(crate) fn insert(&mut self, key: usize, deadline: Instant) {
+        if key >= self.positions.len() {
+            return;
+        }
+        self.remove(key);
+        let tick = self.tick_ceil(deadline).max(self.now + 1);
+        self.place(Timer { key, tick });
+    }
+
+    /// Disarms the timer of `key`
+    pub(crate) fn remove(&mut self, key: usize) {
+        let Some(Position { bucket, index }) = self.positions.get_mut(key).and_then(Option::take)
+        else {
+            return;
+        };
+        let Some(timers) = self.buckets.get_mut(bucket) else {
+            return;
+        };
+        let _removed = timers.swap_remove(index);
+        if let Some(moved) = timers.get(index) {
+            if let Some(position) = self.positions.get_mut(moved.key) {
+                *position = Some(Position { bucket, index });
+            }
+        }
+    }
+
+    /// Arms the timer of `key` if `deadline` is some, otherwise disarms it
+    pub(crate) fn update(&mut self, key: usize, deadline: Option<Instant>) {
+        match deadline {
+            Some(deadline) => self.insert(key, deadline),
+            None => self.remove(key),
+        }
+    }
+
+    /// Returns the next time the wheel needs to be polled
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.next_tick().map(|tick| self.instant(tick))
+    }
+
+    /// Advances the wheel to `now`, returns the keys of the expired timers
+    pub(crate) fn poll(&mut self, now: Instant) -> Vec<usize> {
+        let target = self.tick_floor(now);
+        let mut expired = Vec::new();
+        while self.now < target {
+            match self.next_tick() {
+                Some(tick) if tick <= target => {
+                    self.now = tick;
+                    self.process(&mut expired);
+                }
+                _ => self.now = target,
+            }
+        }
+        expired
+    }
+
+    fn place(&mut self, timer: Timer) {
+        let diff = timer.tick ^ self.now;
+        let bucket = if diff >> TOTAL_BITS == 0 {
+            let level = (63 - (diff | SLOT_MASK).leading_zeros() as usize) / LEVEL_BITS;
+            let slot = (timer.tick >> (level * LEVEL_BITS)) & SLOT_MASK;
+            level * SLOTS + slot as usize
+        } else {
+            OVERFLOW
+        };
+        let Some(timers) = self.buckets.get_mut(bucket) else {
+            return;
+        };
+        if let Some(position) = self.positions.get_mut(timer.key) {
+            *position = Some(Position {
+                bucket,
+                index: timers.len(),
+            });
+        }
+        timers.push(timer);
+    }
+
+    /// Finds the first occupied slot, lower levels always expire before higher ones
+    fn next_tick(&self) -> Option<u64> {
+        for level in 0..LEVELS {
+            let shift = level * LEVEL_BITS;
+            let current = ((self.now >> shift) & SLOT_MASK) as usize;
+            let occupied = (current + 1..SLOTS).find(|slot| {
+                self.buckets
+                    .get(level * SLOTS + slot)
+                    .is_some_and(|timers| !timers.is_empty())
+            });
+            if let Some(slot) = occupied {
+                let block = self.now >> (shift + LEVEL_BITS) << (shift + LEVEL_BITS);
+                return Some(block | ((slot as u64) << shift));
+            }
+        }
+        self.buckets
+            .get(OVERFLOW)
+            .is_some_and(|timers| !timers.is_empty())
+            .then(|| ((self.now >> TOTAL_BITS) + 1) << TOTAL_BITS)
+    }
+
+    /// Cascades the slots starting at the current tick from the top level down
+    fn process(&mut self, expired: &mut Vec<usize>) {
+        if self.now & TOTAL_MASK == 0 {
+            self.cascade(OVERFLOW, expired);
+        }
+        for level in (0..LEVELS).rev() {
+            let shift = level * LEVEL_BITS;
+            if self.now & ((1 << shift) - 1) == 0 {
+                let slot = ((self.now >> shift) & SLOT_MASK) as usize;
+                self.cascade(level * SLOTS + slot, expired);
+            }
+        }
+    }
+
+    fn cascade(&mut self, bucket: usize, expired: &mut Vec<usize>) {
+        let Some(timers) = self.buckets.get_mut(bucket).map(mem::take) else {
+            return;
+        };
+        for timer in timers {
+            if timer.tick <= self.now {
+                if let Some(position) = self.positions.get_mut(timer.key) {
+                    *position = None;
+                }
+                expired.push(timer.key);
+            } else {
+                self.place(timer);
+            }
+        }
+    }
+
+    fn tick_ceil(&self, at: Instant) -> u64 {
+        let nanos = at.saturating_duration_since(self.origin).as_nanos();
+        u64::try_from(nanos.div_ceil(TICK_NS.into())).unwrap_or(u64::MAX)
+    }
+
+    fn tick_floor(&self, at: Instant) -> u64 {
+        let nanos = at.saturating_duration_since(self.origin).as_nanos();
+        u64::try_from(nanos / u128::from(TICK_NS)).unwrap_or(u64::MAX)
+    }
+
+    fn instant(&self, tick: u64) -> Instant {
+        self.origin + Duration::from_nanos(tick.saturating_mul(TICK_NS))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ticks(n: u64) -> Duration {
+        Duration::from_nanos(n * TICK_NS)
+    }
+
+    #[test]
+    fn expires_in_deadline_order() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new_at(8, origin);
+        wheel.insert(0, origin + ticks(5000));
+        wheel.insert(1, origin + ticks(3));
+        wheel.insert(2, origin + ticks(70));
+        assert_eq!(wheel.next_deadline(), Some(origin + ticks(3)));
+        assert!(wheel.poll(origin + ticks(2)).is_empty());
+        assert_eq!(wheel.poll(origin + ticks(3)), vec![1]);
+        assert!(wheel.poll(origin + ticks(69)).is_empty());
+        assert_eq!(wheel.poll(origin + ticks(70)), vec![2]);
+        assert!(wheel.poll(origin + ticks(4999)).is_empty());
+        assert_eq!(wheel.poll(origin + ticks(6000)), vec![0]);
+        assert_eq!(wheel.next_deadline(), None);
+    }
+
+    #[test]
+    fn remove_and_rearm() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new_at(8, origin);
+        wheel.insert(0, origin + ticks(10));
+        wheel.insert(1, origin + ticks(10));
+        wheel.insert(2, origin + ticks(10));
+        wheel.remove(0);
+        wheel.insert(1, origin + ticks(20));
+        let mut expired = wheel.poll(origin + ticks(10));
+        assert_eq!(expired, vec![2]);
+        wheel.insert(2, origin + ticks(15));
+        expired = wheel.poll(origin + ticks(30));
+        expired.sort_unstable();
+        assert_eq!(expired, vec![1, 2]);
+    }
+
+    #[test]
+    fn timers_beyond_the_span_overflow() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new_at(2, origin);
+        let far = (1 << TOTAL_BITS) * 3 + 7;
+        wheel.insert(0, origin + ticks(far));
+        wheel.insert(1, origin + ticks(1));
+        assert_eq!(wheel.poll(origin + ticks(1)), vec![1]);
+        assert!(wheel.poll(origin + ticks(far - 1)).is_empty());
+        assert_eq!(wheel.poll(origin + ticks(far)), vec![0]);
+    }
+
+    #[test]
+    fn past_deadline_expires_on_next_tick() {
+        let origin = Instant::now();
+        let mut wheel = TimerWheel::new_at(1, origin);
+        assert!(wheel.poll(origin + ticks(100)).is_empty());
+        wheel.insert(0, origin);
+        assert_eq!(wheel.next_deadline(), Some(origin + ticks(101)));
+        assert_eq!(wheel.poll(origin + ticks(101)), vec![0]);
+    }
+}
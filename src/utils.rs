@@ -7,7 +7,7 @@ use std::{
     ops::{Add, AddAssign, Sub, SubAssign},
 };
 
-use crate::constants::{MAX_PSN_WINDOW, MAX_QP_CNT, MAX_SEND_WR, PSN_MASK, QPN_KEY_PART_WIDTH};
+use crate::constants::{MAX_PSN_WINDOW, MAX_SEND_WR, PSN_MASK, QPN_KEY_PART_WIDTH};
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 pub(crate) struct Psn(pub(crate) u32);
@@ -169,15 +169,10 @@ impl Sub for Msn {
 }
 
 impl<T: Default> QpTable<T> {
-    pub(crate) fn new() -> Self {
-        Self::default()
-    }
-}
-
-impl<T: Default> Default for QpTable<T> {
-    fn default() -> Self {
+    /// Creates a table with an entry for each of the `max_qp` QPs
+    pub(crate) fn new(max_qp: usize) -> Self {
         Self {
-            inner: iter::repeat_with(T::default).take(MAX_QP_CNT).collect(),
+            inner: iter::repeat_with(T::default).take(max_qp).collect(),
         }
     }
 }